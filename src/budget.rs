@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+/// Buffers are trimmed in this order when the budget is exceeded. The request snapshots are the
+/// only buffer a chat keeps besides its tree so far.
+pub const EVICTION_ORDER: [&str; 1] = ["snapshots"];
+
+/// Tracks the byte estimates of the in-memory buffers of a chat against a shared limit.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    limit: usize,
+    usage: BTreeMap<&'static str, usize>,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_LIMIT)
+    }
+}
+
+impl MemoryBudget {
+    pub const DEFAULT_LIMIT: usize = 4 * 1024 * 1024;
+
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            usage: BTreeMap::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    pub fn record(&mut self, buffer: &'static str, bytes: usize) {
+        self.usage.insert(buffer, bytes);
+    }
+
+    pub fn total(&self) -> usize {
+        self.usage.values().sum()
+    }
+
+    pub fn excess(&self) -> usize {
+        self.total().saturating_sub(self.limit)
+    }
+
+    pub fn usage(&self) -> BTreeMap<&'static str, usize> {
+        self.usage.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accounts_the_latest_estimate_of_each_buffer() {
+        let mut budget = MemoryBudget::new(100);
+        budget.record("snapshots", 60);
+        budget.record("other", 30);
+        assert_eq!(budget.total(), 90);
+        assert_eq!(budget.excess(), 0);
+
+        budget.record("snapshots", 80);
+        assert_eq!(budget.total(), 110);
+        assert_eq!(budget.excess(), 10);
+        assert_eq!(budget.usage().get("snapshots"), Some(&80));
+
+        budget.set_limit(200);
+        assert_eq!(budget.excess(), 0);
+    }
+}
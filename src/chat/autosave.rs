@@ -0,0 +1,120 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use log::{error, trace};
+use tokio::{
+    sync::{broadcast, watch},
+    task::JoinHandle,
+};
+
+use crate::chat::{Chat, ChatUpdate, Node, meta::ChatMeta, persist::SavedChat, store::ChatStore};
+
+/// The save task of a chat. Dropping the chat closes the channel, the task saves the last
+/// change and exits.
+#[derive(Debug)]
+pub(crate) struct Autosave {
+    /// Everything but the tree and the metadata, read from their mutexes when saving.
+    pub(crate) tx: watch::Sender<SavedChat>,
+    task: JoinHandle<()>,
+}
+
+/// What the save task reads from the chat, the rest comes through the channel.
+struct Sources {
+    root: Arc<Mutex<Node>>,
+    meta: Arc<Mutex<ChatMeta>>,
+    clock: Arc<AtomicU64>,
+    tx: broadcast::Sender<ChatUpdate>,
+}
+
+impl Chat {
+    /// Saves the chat into `store` once `debounce` passed after a change, the changes made in
+    /// the meantime being saved with it. The streamed tokens count as changes, so a long reply
+    /// is saved every `debounce` while it streams. Each save ends with `ChatUpdate::Saved`.
+    ///
+    /// The file is written on a blocking thread from a copy of the tree, which stays unlocked.
+    /// Must be called within a Tokio runtime, the updates go to the subscribers of this time.
+    pub fn enable_autosave(&mut self, store: ChatStore, debounce: Duration) {
+        trace!("Autosaving every {debounce:?}");
+        let (tx, rx) = watch::channel(self.saved_parts());
+        let path = store.path(self.meta().id);
+        let sources = Sources {
+            root: self.root.clone(),
+            meta: self.meta.clone(),
+            clock: self.clock.clone(),
+            tx: self.tx.clone(),
+        };
+        let task = tokio::spawn(autosave(rx, sources, path, debounce));
+        if let Some(previous) = self.autosave.replace(Autosave { tx, task }) {
+            previous.task.abort();
+        }
+    }
+
+    pub fn disable_autosave(&mut self) {
+        if let Some(autosave) = self.autosave.take() {
+            trace!("Autosave disabled");
+            autosave.task.abort();
+        }
+    }
+
+    /// Stops the autosave once the pending change is saved, resolving when the task exited. Its
+    /// last `ChatUpdate::Saved` goes to the current subscribers.
+    pub async fn finish_autosave(&mut self) {
+        if let Some(Autosave { tx, task }) = self.autosave.take() {
+            trace!("Finishing the autosave");
+            // Closing the channel lets the task save the last change and exit
+            drop(tx);
+            let _ = task.await;
+        }
+    }
+
+    pub fn is_autosaving(&self) -> bool {
+        self.autosave.is_some()
+    }
+
+    /// Hands the current state to the save task, which saves it after the debounce.
+    pub(crate) fn schedule_autosave(&self) {
+        if let Some(autosave) = &self.autosave {
+            autosave.tx.send_replace(self.saved_parts());
+        }
+    }
+}
+
+async fn autosave(
+    mut rx: watch::Receiver<SavedChat>,
+    sources: Sources,
+    path: PathBuf,
+    debounce: Duration,
+) {
+    // Fails once the chat is dropped and its last change saved
+    while rx.changed().await.is_ok() {
+        // Closing the channel saves without waiting for the rest of the debounce
+        tokio::select! {
+            _ = tokio::time::sleep(debounce) => (),
+            _ = closed(&mut rx) => (),
+        }
+        let mut saved = rx.borrow_and_update().clone();
+        saved.root = sources.root.lock().unwrap().clone();
+        saved.meta = Some(sources.meta.lock().unwrap().clone());
+        saved.clock = sources.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        let path = path.clone();
+        match tokio::task::spawn_blocking(move || saved.save(&path)).await {
+            Ok(Ok(())) => {
+                trace!("Autosaved");
+                Chat::send_update(&sources.tx, ChatUpdate::Saved);
+            }
+            Ok(Err(e)) => error!("Autosave failed: {e}"),
+            Err(e) => error!("Autosave task failed: {e}"),
+        }
+    }
+}
+
+/// Resolves once the chat dropped its sender, the changes made meanwhile marked as seen.
+async fn closed(rx: &mut watch::Receiver<SavedChat>) {
+    while rx.changed().await.is_ok() {}
+}
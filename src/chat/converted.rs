@@ -0,0 +1,88 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use llm::chat::ChatMessage;
+
+use crate::message::PromptMessage;
+
+/// The `llm` messages of the last request, by the prompt content they were made from, so the
+/// next request copies only the messages that changed.
+#[derive(Debug, Default)]
+pub(crate) struct ConvertedHistory {
+    vision: bool,
+    entries: Vec<(Arc<str>, Vec<ChatMessage>)>,
+    /// Prompt messages converted, the reused ones left out.
+    conversions: usize,
+}
+
+/// The history of a request while its stream holds it, the untouched front goes back to the
+/// cache with `give_back`.
+#[derive(Debug)]
+pub(crate) struct Lent {
+    cache: Arc<Mutex<ConvertedHistory>>,
+    vision: bool,
+    /// Content and number of `llm` messages of the entries to cache again.
+    entries: Vec<(Arc<str>, usize)>,
+}
+
+impl ConvertedHistory {
+    /// The `llm` messages of `prompt`, the ones made from the content of the last request taken
+    /// from the cache. The first `keep` entries are cached again when the history is given back.
+    pub(crate) fn lend(
+        cache: &Arc<Mutex<Self>>,
+        prompt: &[PromptMessage],
+        vision: bool,
+        keep: usize,
+    ) -> (Vec<ChatMessage>, Lent) {
+        let mut converted = cache.lock().unwrap();
+        let same_vision = converted.vision == vision;
+        // Keyed by address, which no other content can have while the cached one is alive
+        let mut reusable: HashMap<*const u8, (Arc<str>, Vec<ChatMessage>)> = converted
+            .entries
+            .drain(..)
+            .filter(|_| same_vision)
+            .map(|entry| (Arc::as_ptr(&entry.0).cast(), entry))
+            .collect();
+        let mut history = vec![];
+        let mut entries = vec![];
+        for message in prompt {
+            let messages = match reusable.remove(&Arc::as_ptr(&message.content).cast()) {
+                Some((_, messages)) => messages,
+                None => {
+                    converted.conversions += 1;
+                    message.to_chat_messages(vision)
+                }
+            };
+            entries.push((message.content.clone(), messages.len()));
+            history.extend(messages);
+        }
+        entries.truncate(keep);
+        let lent = Lent {
+            cache: cache.clone(),
+            vision,
+            entries,
+        };
+        (history, lent)
+    }
+
+    pub(crate) fn conversions(&self) -> usize {
+        self.conversions
+    }
+}
+
+impl Lent {
+    /// Caches the front of `history`, which the stream only added to.
+    pub(crate) fn give_back(self, history: Vec<ChatMessage>) {
+        let mut history = history.into_iter();
+        let entries = self
+            .entries
+            .into_iter()
+            .map(|(content, len)| (content, history.by_ref().take(len).collect()))
+            .collect();
+        let mut converted = self.cache.lock().unwrap();
+        converted.vision = self.vision;
+        converted.entries = entries;
+    }
+}
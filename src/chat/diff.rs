@@ -0,0 +1,114 @@
+use crate::chat::{Chat, siblings::SelectionError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffKind {
+    Same,
+    /// Only in the second sibling.
+    Added,
+    /// Only in the first sibling.
+    Removed,
+}
+
+/// A run of words, with the whitespace after them, that the two siblings share or not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffSegment {
+    pub kind: DiffKind,
+    pub text: String,
+}
+
+impl Chat {
+    /// Word level diff from the sibling `a` to the sibling `b` at `depth`, over their cleaned
+    /// text. Consecutive words of the same kind are joined in one segment.
+    pub fn diff_siblings(
+        &self,
+        depth: usize,
+        a: usize,
+        b: usize,
+    ) -> Result<Vec<DiffSegment>, SelectionError> {
+        let root = self.root.lock().unwrap();
+        let level = root.level(depth)?;
+        let len = level.messages.len();
+        let text = |index: usize| match level.messages.get(index) {
+            Some(message) => Ok(message.clean()),
+            None => Err(SelectionError::Index { index, len }),
+        };
+        let (a, b) = (text(a)?, text(b)?);
+        Ok(diff_words(&words(&a), &words(&b)))
+    }
+}
+
+/// Each word with the whitespace that follows it, leading whitespace being a word of its own.
+fn words(text: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = 0;
+    let mut in_space = false;
+    for (i, ch) in text.char_indices() {
+        match (ch.is_whitespace(), in_space) {
+            (true, _) => in_space = true,
+            (false, true) => {
+                words.push(&text[start..i]);
+                start = i;
+                in_space = false;
+            }
+            (false, false) => {}
+        }
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+    words
+}
+
+/// Longest common subsequence of the words, once the common start and end are set aside.
+fn diff_words(a: &[&str], b: &[&str]) -> Vec<DiffSegment> {
+    let prefix = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (middle_a, middle_b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    // lengths[i][j] is the length of the subsequence of middle_a[i..] and middle_b[j..]
+    let width = middle_b.len() + 1;
+    let mut lengths = vec![0u32; (middle_a.len() + 1) * width];
+    for i in (0..middle_a.len()).rev() {
+        for j in (0..middle_b.len()).rev() {
+            lengths[i * width + j] = match middle_a[i] == middle_b[j] {
+                true => lengths[(i + 1) * width + j + 1] + 1,
+                false => lengths[(i + 1) * width + j].max(lengths[i * width + j + 1]),
+            };
+        }
+    }
+
+    let mut segments = vec![];
+    let mut push = |kind: DiffKind, word: &str| match segments.last_mut() {
+        Some(DiffSegment { kind: last, text }) if *last == kind => text.push_str(word),
+        _ => segments.push(DiffSegment {
+            kind,
+            text: word.to_string(),
+        }),
+    };
+    a[..prefix].iter().for_each(|w| push(DiffKind::Same, w));
+    let (mut i, mut j) = (0, 0);
+    while i < middle_a.len() || j < middle_b.len() {
+        if i < middle_a.len() && j < middle_b.len() && middle_a[i] == middle_b[j] {
+            push(DiffKind::Same, middle_a[i]);
+            i += 1;
+            j += 1;
+        } else if i < middle_a.len()
+            && (j == middle_b.len() || lengths[(i + 1) * width + j] >= lengths[i * width + j + 1])
+        {
+            push(DiffKind::Removed, middle_a[i]);
+            i += 1;
+        } else {
+            push(DiffKind::Added, middle_b[j]);
+            j += 1;
+        }
+    }
+    a[a.len() - suffix..]
+        .iter()
+        .for_each(|w| push(DiffKind::Same, w));
+    segments
+}
@@ -0,0 +1,54 @@
+use std::time::SystemTime;
+
+use log::trace;
+
+use crate::chat::{Chat, siblings::SelectionError};
+
+/// Corrections that keep the message, unlike `add_edit` which writes a sibling.
+impl Chat {
+    /// Replaces the text of the message, the previous one is kept in `Message::revisions`.
+    /// Nothing is generated, whoever wrote the message.
+    pub fn edit_in_place(&mut self, id: usize, text: String) -> Result<(), SelectionError> {
+        let text = text.trim().to_string();
+        trace!("Editing message {id} in place");
+        {
+            let mut root = self.root.lock().unwrap();
+            let message = root.find_mut(id).ok_or(SelectionError::Id { id })?;
+            if message.text.trim() == text {
+                return Ok(());
+            }
+            let previous = std::mem::replace(&mut message.text, text);
+            message.invalidate_prompt();
+            message.revisions.push((SystemTime::now(), previous));
+        }
+        self.touch();
+        Ok(())
+    }
+
+    /// The texts the message had before its edits in place, oldest first. `revisions` lists
+    /// the char revisions.
+    pub fn message_revisions(
+        &self,
+        id: usize,
+    ) -> Result<Vec<(SystemTime, String)>, SelectionError> {
+        let mut root = self.root.lock().unwrap();
+        let message = root.find_mut(id).ok_or(SelectionError::Id { id })?;
+        Ok(message.revisions.clone())
+    }
+
+    /// Puts the text of revision `index` back, as an edit in place so the current text becomes
+    /// a revision in turn.
+    pub fn restore_revision(&mut self, id: usize, index: usize) -> Result<(), SelectionError> {
+        let text = {
+            let mut root = self.root.lock().unwrap();
+            let message = root.find_mut(id).ok_or(SelectionError::Id { id })?;
+            let len = message.revisions.len();
+            let (_, text) = message
+                .revisions
+                .get(index)
+                .ok_or(SelectionError::Revision { index, len })?;
+            text.clone()
+        };
+        self.edit_in_place(id, text)
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    fmt::Display,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use llm::error::LLMError;
+use serde_json::Value;
+
+/// Errors delivered through `ChatUpdate::RequestError`, the strings keep the provider's text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatError {
+    Auth(String),
+    RateLimited {
+        retry_after: Option<Duration>,
+        message: String,
+    },
+    Network(String),
+    /// 5xx from the provider or an upstream.
+    Server(String),
+    Provider(String),
+    ContextTooLong(String),
+    /// The provider did not start the stream or stopped sending it, see
+    /// `Settings::connect_timeout_secs` and `request_timeout_secs`.
+    Timeout,
+    Cancelled,
+    /// The app stopped while generating, found when loading the chat.
+    Interrupted,
+}
+
+impl Display for ChatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatError::Auth(message)
+            | ChatError::RateLimited { message, .. }
+            | ChatError::Network(message)
+            | ChatError::Server(message)
+            | ChatError::Provider(message)
+            | ChatError::ContextTooLong(message) => write!(f, "{message}"),
+            ChatError::Timeout => write!(f, "The provider stopped responding"),
+            ChatError::Cancelled => write!(f, "Generation cancelled"),
+            ChatError::Interrupted => write!(f, "Generation interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+impl From<&LLMError> for ChatError {
+    fn from(error: &LLMError) -> Self {
+        let message = error.to_string();
+        match error {
+            LLMError::AuthError(_) => ChatError::Auth(message),
+            LLMError::HttpError(_) => ChatError::Network(message),
+            LLMError::ResponseFormatError {
+                message: status,
+                raw_response,
+            } => {
+                let body: Option<Value> = serde_json::from_str(raw_response).ok();
+                match Self::status_code(status) {
+                    Some(401 | 403) => ChatError::Auth(message),
+                    Some(429) => ChatError::RateLimited {
+                        retry_after: body.as_ref().and_then(Self::retry_after),
+                        message,
+                    },
+                    _ if Self::is_context_error(raw_response) => ChatError::ContextTooLong(message),
+                    Some(500..=599) => ChatError::Server(message),
+                    _ => ChatError::Provider(message),
+                }
+            }
+            LLMError::ProviderError(text) | LLMError::InvalidRequest(text)
+                if Self::is_context_error(text) =>
+            {
+                ChatError::ContextTooLong(message)
+            }
+            _ => ChatError::Provider(message),
+        }
+    }
+}
+
+/// How transient errors are retried before a request fails.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Doubles the base delay on each attempt unless the provider said how long to wait.
+    /// `None` when `error` is not retried or `attempt` retries were already made.
+    pub fn delay(&self, attempt: u32, error: &ChatError) -> Option<Duration> {
+        if !error.is_retryable() || attempt >= self.max_retries {
+            return None;
+        }
+        match error {
+            ChatError::RateLimited {
+                retry_after: Some(retry_after),
+                ..
+            } => Some(*retry_after),
+            _ => Some(self.base_delay.saturating_mul(2u32.saturating_pow(attempt))),
+        }
+    }
+}
+
+impl From<LLMError> for ChatError {
+    fn from(error: LLMError) -> Self {
+        Self::from(&error)
+    }
+}
+
+impl ChatError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ChatError::RateLimited { .. }
+                | ChatError::Network(_)
+                | ChatError::Server(_)
+                | ChatError::Timeout
+        )
+    }
+
+    // `llm` formats them as "<PROVIDER> API returned error status: 429 Too Many Requests"
+    fn status_code(status: &str) -> Option<u16> {
+        let (_, code) = status.rsplit_once("status: ")?;
+        code.get(..3)?.parse().ok()
+    }
+
+    fn is_context_error(text: &str) -> bool {
+        let text = text.to_lowercase();
+        [
+            "context length",
+            "context window",
+            "maximum context",
+            "too many tokens",
+        ]
+        .iter()
+        .any(|pattern| text.contains(pattern))
+    }
+
+    // OpenRouter forwards the rate limit headers in the error metadata
+    fn retry_after(body: &Value) -> Option<Duration> {
+        let headers = body.pointer("/error/metadata/headers")?;
+        let header = |name: &str| {
+            headers.get(name).and_then(|v| match v {
+                Value::String(s) => s.parse::<u64>().ok(),
+                _ => v.as_u64(),
+            })
+        };
+        if let Some(seconds) = header("Retry-After") {
+            return Some(Duration::from_secs(seconds));
+        }
+        let reset = UNIX_EPOCH + Duration::from_millis(header("X-RateLimit-Reset")?);
+        reset.duration_since(SystemTime::now()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(code: &str, body: &str) -> LLMError {
+        LLMError::ResponseFormatError {
+            message: format!("OpenRouter API returned error status: {code}"),
+            raw_response: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn maps_the_llm_errors() {
+        let cases = [
+            (LLMError::AuthError("No key".to_string()), "Auth"),
+            (
+                LLMError::HttpError("Connection reset".to_string()),
+                "Network",
+            ),
+            (status("401 Unauthorized", "{}"), "Auth"),
+            (status("429 Too Many Requests", "{}"), "RateLimited"),
+            (status("502 Bad Gateway", "{}"), "Server"),
+            (
+                status(
+                    "400 Bad Request",
+                    r#"{"error":{"message":"This model's maximum context length is 8192 tokens"}}"#,
+                ),
+                "ContextTooLong",
+            ),
+            (status("400 Bad Request", "{}"), "Provider"),
+            (
+                LLMError::ProviderError("Too many tokens in the prompt".to_string()),
+                "ContextTooLong",
+            ),
+            (
+                LLMError::ProviderError("Model not found".to_string()),
+                "Provider",
+            ),
+        ];
+        for (error, expected) in cases {
+            let mapped = ChatError::from(&error);
+            let variant = format!("{mapped:?}");
+            assert!(variant.starts_with(expected), "{error} mapped to {variant}");
+        }
+    }
+
+    #[test]
+    fn display_keeps_the_provider_text() {
+        let error = LLMError::HttpError("Connection reset".to_string());
+        assert_eq!(ChatError::from(&error).to_string(), error.to_string());
+        assert_eq!(ChatError::Cancelled.to_string(), "Generation cancelled");
+    }
+
+    #[test]
+    fn rate_limits_read_retry_after() {
+        let body = r#"{"error":{"metadata":{"headers":{"Retry-After":"12"}}}}"#;
+        assert_eq!(
+            ChatError::from(&status("429 Too Many Requests", body)),
+            ChatError::RateLimited {
+                retry_after: Some(Duration::from_secs(12)),
+                message: status("429 Too Many Requests", body).to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::{fs, path::Path};
+
+use crate::{
+    chat::Chat,
+    message::{Message, Style},
+};
+
+#[derive(Debug, Clone)]
+pub struct MarkdownOptions {
+    /// Start with the chat title and the model from the settings.
+    pub header: bool,
+    /// Add the unselected siblings of each message as collapsible sections.
+    pub siblings: bool,
+}
+
+impl Default for MarkdownOptions {
+    fn default() -> Self {
+        Self {
+            header: true,
+            siblings: false,
+        }
+    }
+}
+
+impl Chat {
+    pub fn export_markdown(&self) -> String {
+        self.export_markdown_with(&MarkdownOptions::default())
+    }
+
+    pub fn export_markdown_with(&self, options: &MarkdownOptions) -> String {
+        let mut out = String::new();
+        if options.header {
+            out.push_str(&format!("# {}\n\n", self.title()));
+            out.push_str(&format!("*Model: {}*\n\n", self.effective_settings().model));
+        }
+
+        let pinned = self.pinned_messages();
+        if !pinned.is_empty() {
+            out.push_str("## Pinned\n\n");
+            for pin in &pinned {
+                out.push_str(&format!(
+                    "> **{}:** {}\n\n",
+                    self.owner_name(&pin.message),
+                    Self::markdown_body(&pin.message)
+                ));
+            }
+        }
+
+        let mut levels = vec![];
+        self.root.lock().unwrap().get_levels(&mut levels);
+        for (selected, messages) in levels {
+            let message = &messages[selected];
+            out.push_str(&format!(
+                "**{}:** {}\n\n",
+                self.owner_name(message),
+                Self::markdown_body(message)
+            ));
+
+            if options.siblings && messages.len() > 1 {
+                out.push_str(&format!(
+                    "<details>\n<summary>{} other versions</summary>\n\n",
+                    messages.len() - 1
+                ));
+                for (i, sibling) in messages.iter().enumerate() {
+                    if i != selected {
+                        out.push_str(&format!(
+                            "**{}** ({}/{}): {}\n\n",
+                            self.owner_name(sibling),
+                            i + 1,
+                            messages.len(),
+                            Self::markdown_body(sibling)
+                        ));
+                    }
+                }
+                out.push_str("</details>\n\n");
+            }
+        }
+        out
+    }
+
+    pub fn export_markdown_to(
+        &self,
+        path: &Path,
+        options: &MarkdownOptions,
+    ) -> std::io::Result<()> {
+        fs::write(path, self.export_markdown_with(options))
+    }
+
+    fn markdown_body(message: &Message) -> String {
+        let mut paragraphs = vec![];
+        // The lines of a fenced block stay together, each would be a paragraph otherwise
+        let mut block: Option<String> = None;
+        for line in message.spans() {
+            if let [(text, Style::CodeBlock)] = line.as_slice() {
+                match &mut block {
+                    Some(block) => {
+                        block.push('\n');
+                        block.push_str(text);
+                    }
+                    None => block = Some(text.clone()),
+                }
+                continue;
+            }
+            paragraphs.extend(block.take());
+            // Emphasis left open at the end of the line is text in the spans already
+            let paragraph: String = line.into_iter().map(|(text, _)| text).collect();
+            paragraphs.push(paragraph);
+        }
+        paragraphs.extend(block);
+        paragraphs.join("\n\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chat::Node,
+        persona::{Persona, card::Card},
+        settings::Settings,
+    };
+
+    /// Ann greets Luna, who has two versions of her reply, then Ann leaves.
+    fn chat() -> Chat {
+        let levels = [
+            (
+                0,
+                vec![Message::from_user(
+                    "Ann".to_string(),
+                    "Hi *waves*".to_string(),
+                )],
+            ),
+            (
+                1,
+                vec![
+                    Message::from_char(0, "Luna".to_string(), "First try".to_string()),
+                    Message::from_char(0, "Luna".to_string(), "\"Hello\" *smiles*".to_string()),
+                ],
+            ),
+            (
+                0,
+                vec![Message::from_user(
+                    "Ann".to_string(),
+                    "Bye\n\n**soon**".to_string(),
+                )],
+            ),
+        ];
+        let mut node = Node::new();
+        for (selected, messages) in levels.into_iter().rev() {
+            let mut parent = Node::new();
+            for i in 0..messages.len() {
+                match i == selected {
+                    true => parent
+                        .childs
+                        .push(std::mem::replace(&mut node, Node::new())),
+                    false => parent.childs.push(Node::new()),
+                }
+            }
+            parent.messages = messages;
+            parent.selected = selected;
+            node = parent;
+        }
+        let settings = Settings {
+            model: "test/model".to_string(),
+            ..Settings::default()
+        };
+        Chat::from_root(
+            node,
+            Persona::from_card(Card::basic("Ann", "")),
+            Persona::from_card(Card::basic("Luna", "")),
+            settings,
+        )
+    }
+
+    const BODY: &str = "**Ann:** Hi *waves*\n\n\
+        **Luna:** \"Hello\" *smiles*\n\n";
+
+    #[test]
+    fn renders_the_selected_history() {
+        let chat = chat();
+        assert_eq!(
+            chat.export_markdown(),
+            format!(
+                "# Ann's chat with Luna\n\n*Model: test/model*\n\n{BODY}**Ann:** Bye\n\n**soon**\n\n"
+            )
+        );
+
+        let options = MarkdownOptions {
+            header: false,
+            siblings: false,
+        };
+        assert_eq!(
+            chat.export_markdown_with(&options),
+            format!("{BODY}**Ann:** Bye\n\n**soon**\n\n")
+        );
+    }
+
+    #[test]
+    fn siblings_are_collapsible() {
+        let options = MarkdownOptions {
+            header: false,
+            siblings: true,
+        };
+        assert_eq!(
+            chat().export_markdown_with(&options),
+            format!(
+                "{BODY}<details>\n<summary>1 other versions</summary>\n\n\
+                 **Luna** (1/2): First try\n\n</details>\n\n\
+                 **Ann:** Bye\n\n**soon**\n\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::{Arc, Mutex};
+
+use crate::chat::{Chat, Node, siblings::SelectionError};
+
+impl Chat {
+    /// A new chat holding a copy of the selected history down to `depth` included, without the
+    /// other siblings. It keeps the personas, settings, tools, lorebooks and char revisions of
+    /// this one, which is left untouched. The messages get new ids and no pins, so both chats can
+    /// be saved and merged separately.
+    pub fn fork_at(&self, depth: usize) -> Result<Chat, SelectionError> {
+        let root = self.root.lock().unwrap().fork_path(depth)?;
+        let mut fork = Chat::from_root(
+            root,
+            self.personas[0].clone(),
+            self.personas[1].clone(),
+            self.settings.clone(),
+        );
+        fork.dialect = self.dialect.clone();
+        fork.budget.set_limit(self.budget.limit());
+        fork.revisions = self.revisions.clone();
+        fork.active_revision = Arc::new(Mutex::new(*self.active_revision.lock().unwrap()));
+        fork.rng = self.rng;
+        fork.estimator = self.estimator.clone();
+        fork.tools = self.tools.clone();
+        fork.provider_factory = self.provider_factory.clone();
+        fork.authors_note = self.authors_note.clone();
+        fork.lorebooks = self.lorebooks.clone();
+        Ok(fork)
+    }
+}
+
+impl Node {
+    /// One message per level, the selected ones down to `depth`.
+    fn fork_path(&self, depth: usize) -> Result<Node, SelectionError> {
+        // Fails the same way as the other depth based methods
+        self.level(depth)?;
+        let mut levels = vec![];
+        let mut node = self;
+        for _ in 0..=depth {
+            levels.push(node.messages[node.selected].renewed());
+            node = &node.childs[node.selected];
+        }
+        let mut root = Node::new();
+        for message in levels.into_iter().rev() {
+            let mut parent = Node::new();
+            parent.push_sibling(message, root);
+            root = parent;
+        }
+        Ok(root)
+    }
+}
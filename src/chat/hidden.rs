@@ -0,0 +1,20 @@
+use log::trace;
+
+use crate::chat::{Chat, siblings::SelectionError};
+
+impl Chat {
+    /// Leaves the message out of the prompts, and of the lore scan and the context trimming,
+    /// while it stays in the history. The last message of the history is always sent, it is the
+    /// one being generated.
+    pub fn set_hidden(&mut self, id: usize, hidden: bool) -> Result<(), SelectionError> {
+        trace!("Message {id} hidden from the prompt: {hidden}");
+        self.root
+            .lock()
+            .unwrap()
+            .find_mut(id)
+            .ok_or(SelectionError::Id { id })?
+            .hidden_from_prompt = hidden;
+        self.touch();
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use log::{trace, warn};
+
+use crate::{
+    chat::{Chat, ChatUpdate, Node, siblings::SelectionError},
+    message::Message,
+};
+
+/// Counterparts of the depth based methods addressing a message by `Message::id`, which keeps
+/// pointing at the same message when the selection above it changes. The methods acting on the
+/// selected history (`next_by_id`, `edit_by_id`) select the path to the message first.
+impl Chat {
+    /// Depth of the message in the selected history, None when it is not in it.
+    pub fn depth_of(&self, id: usize) -> Option<usize> {
+        self.root.lock().unwrap().depth_of(id)
+    }
+
+    /// Selects the message wherever it is in the tree, and every message leading to it, so the
+    /// history goes through it. Returns its depth.
+    pub fn select_path_to(&mut self, id: usize) -> Result<usize, SelectionError> {
+        let mut root = self.root.lock().unwrap();
+        let path = root.path_to(id).ok_or(SelectionError::Id { id })?;
+        root.select_path(&path);
+        drop(root);
+        self.touch();
+        Self::send_update(&self.tx, ChatUpdate::SelectionChanged);
+        Ok(path.len() - 1)
+    }
+
+    /// `select_path_to`, None when the message is not found.
+    pub fn select_sibling_by_id(&mut self, id: usize) -> Option<usize> {
+        self.select_path_to(id).ok()
+    }
+
+    /// `next` on the message. Like `next` this moves the selection, here first to the message
+    /// wherever it is, so the history goes through it and `ChatUpdate::SelectionChanged` is sent
+    /// even before the swipe. Returns if it was found, the selection being left alone otherwise.
+    pub fn next_by_id(&mut self, id: usize) -> bool {
+        let Some(depth) = self.select_sibling_by_id(id) else {
+            trace!("No message {id}");
+            return false;
+        };
+        self.next(depth);
+        true
+    }
+
+    /// `add_edit` on the message. The selection is first moved to the message wherever it is,
+    /// sending `ChatUpdate::SelectionChanged`, then to the new edit like `add_edit` does. Returns if
+    /// it was found, the selection being left alone otherwise.
+    pub fn edit_by_id(&mut self, id: usize, text: String) -> bool {
+        let Some(depth) = self.select_sibling_by_id(id) else {
+            trace!("No message {id}");
+            return false;
+        };
+        self.add_edit(depth, text);
+        true
+    }
+
+    /// Deletes the message and its replies wherever it is, the selection is left alone. Returns
+    /// how many pinned messages were unpinned, None when the message was not found.
+    pub fn delete_by_id(&mut self, id: usize) -> Option<usize> {
+        trace!("Deleting message {id}");
+        let pinned = self.pins_under(id);
+        if !pinned.is_empty() {
+            warn!(
+                "Deleting message {id} removes {} pinned messages",
+                pinned.len()
+            );
+        }
+        if !self.root.lock().unwrap().delete_id(id) {
+            return None;
+        }
+        self.touch();
+        Some(self.unpin_deleted(&pinned))
+    }
+}
+
+impl Node {
+    fn depth_of(&self, id: usize) -> Option<usize> {
+        let message = self.messages.get(self.selected)?;
+        match message.id() == id {
+            true => Some(0),
+            false => self.childs[self.selected].depth_of(id).map(|d| d + 1),
+        }
+    }
+
+    /// Sibling indices from the root down to the message, its own last.
+    pub(super) fn path_to(&self, id: usize) -> Option<Vec<usize>> {
+        self.messages
+            .iter()
+            .zip(&self.childs)
+            .enumerate()
+            .find_map(|(i, (message, child))| match message.id() == id {
+                true => Some(vec![i]),
+                false => child.path_to(id).map(|mut path| {
+                    path.insert(0, i);
+                    path
+                }),
+            })
+    }
+
+    /// The message at the end of a `path_to` path.
+    pub(super) fn message_at_mut(&mut self, path: &[usize]) -> Option<&mut Message> {
+        let (&index, rest) = path.split_first()?;
+        match rest.is_empty() {
+            true => self.messages.get_mut(index),
+            false => self.childs.get_mut(index)?.message_at_mut(rest),
+        }
+    }
+
+    fn select_path(&mut self, path: &[usize]) {
+        if let Some((&index, rest)) = path.split_first() {
+            self.selected = index;
+            self.childs[index].select_path(rest);
+        }
+    }
+
+    fn delete_id(&mut self, id: usize) -> bool {
+        let Some(index) = self.messages.iter().position(|m| m.id() == id) else {
+            return self.childs.iter_mut().any(|child| child.delete_id(id));
+        };
+        self.messages.remove(index);
+        self.childs.remove(index);
+        if self.selected > index || (self.selected == index && self.selected > 0) {
+            self.selected -= 1;
+        }
+        true
+    }
+}
@@ -0,0 +1,36 @@
+use log::trace;
+
+use crate::chat::{Chat, siblings::SelectionError};
+
+/// Names put on messages to find a branch again, shown by `siblings_at` and
+/// `get_history_structure`.
+impl Chat {
+    /// Replaces the label of the message, an empty one clears it.
+    pub fn set_label(&mut self, id: usize, label: String) -> Result<(), SelectionError> {
+        let label = Some(label.trim().to_string()).filter(|l| !l.is_empty());
+        trace!("Labelling message {id} {label:?}");
+        self.root
+            .lock()
+            .unwrap()
+            .find_mut(id)
+            .ok_or(SelectionError::Id { id })?
+            .label = label;
+        self.touch();
+        Ok(())
+    }
+
+    pub fn clear_label(&mut self, id: usize) -> Result<(), SelectionError> {
+        self.set_label(id, String::new())
+    }
+
+    /// Every labelled message of the tree with its label, depth first with the siblings in order.
+    pub fn bookmarks(&self) -> Vec<(usize, String)> {
+        let mut bookmarks = vec![];
+        self.root.lock().unwrap().for_each_message(&mut |message| {
+            if let Some(label) = &message.label {
+                bookmarks.push((message.id(), label.clone()));
+            }
+        });
+        bookmarks
+    }
+}
@@ -0,0 +1,399 @@
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    chat::{Node, persist::SavedChat, pins::MAX_PINS, revision::SavedRevision},
+    message::Message,
+};
+
+#[derive(Debug, Clone)]
+pub struct MergeConflict {
+    /// Sibling indices from the root of the merged tree down to the conflicting message.
+    pub path: Vec<usize>,
+    pub description: String,
+    pub ours: Option<Message>,
+    pub theirs: Option<Message>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MergeResult {
+    pub merged: SavedChat,
+    /// Conflicting messages are kept in `merged`, these only point at them.
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl MergeResult {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Three-way merge of two saved versions of the same chat.
+///
+/// Messages are matched by creation timestamp. Additions from either side are kept, edits made on
+/// both sides become siblings and deletions are honored unless the other side changed the deleted
+/// subtree, which is reported as a conflict while keeping the data.
+pub fn merge(base: &SavedChat, ours: &SavedChat, theirs: &SavedChat) -> MergeResult {
+    let mut conflicts = vec![];
+    // Both devices order the sides the same, so they merge into the same tree
+    let ours_first = ours.device <= theirs.device;
+    let second_clock = match ours_first {
+        true => theirs.clock,
+        false => ours.clock,
+    };
+    let divergence = Divergence {
+        ours_first,
+        offset: Duration::from_nanos(second_clock + 1),
+    };
+    let root = merge_node(
+        Some(&base.root),
+        &ours.root,
+        &theirs.root,
+        divergence,
+        &mut vec![],
+        &mut conflicts,
+    );
+    let pins = merge_pins(&ours.pins, &theirs.pins, &root);
+    MergeResult {
+        merged: SavedChat {
+            version: ours.version,
+            device: ours.device.clone(),
+            clock: ours.clock.max(theirs.clock) + 1,
+            meta: ours.meta.clone().or(theirs.meta.clone()),
+            root,
+            revisions: merge_revisions(&ours.revisions, &theirs.revisions),
+            rng: ours.rng.or(theirs.rng),
+            pins,
+            authors_note: ours.authors_note.clone().or(theirs.authors_note.clone()),
+        },
+        conflicts,
+    }
+}
+
+fn merge_revisions(ours: &[SavedRevision], theirs: &[SavedRevision]) -> Vec<SavedRevision> {
+    let mut revisions = ours.to_vec();
+    for revision in theirs {
+        if !revisions.iter().any(|r| r.hash() == revision.hash()) {
+            revisions.push(revision.clone());
+        }
+    }
+    revisions
+}
+
+fn merge_pins(ours: &[SystemTime], theirs: &[SystemTime], root: &Node) -> Vec<SystemTime> {
+    let mut pins = ours.to_vec();
+    pins.extend(theirs.iter().filter(|t| !ours.contains(t)));
+    pins.retain(|t| root.find_by_timestamp(*t).is_some());
+    pins.truncate(MAX_PINS);
+    pins
+}
+
+fn position(node: &Node, timestamp: SystemTime) -> Option<usize> {
+    node.messages
+        .iter()
+        .position(|m| m.timestamp() == timestamp)
+}
+
+fn subtree_changed(base: &Node, other: &Node) -> bool {
+    if base.messages.len() != other.messages.len() {
+        return true;
+    }
+    base.messages
+        .iter()
+        .enumerate()
+        .any(|(i, m)| match position(other, m.timestamp()) {
+            Some(o) => {
+                other.messages[o].text != m.text
+                    || subtree_changed(&base.childs[i], &other.childs[o])
+            }
+            None => true,
+        })
+}
+
+/// How a message edited on both sides is split in two siblings.
+#[derive(Debug, Clone, Copy)]
+struct Divergence {
+    /// Our version keeps the message, theirs is the new sibling, otherwise the other way round.
+    ours_first: bool,
+    /// Added to the timestamp of the message for the new sibling, from the clock of its side.
+    offset: Duration,
+}
+
+fn merge_node(
+    base: Option<&Node>,
+    ours: &Node,
+    theirs: &Node,
+    divergence: Divergence,
+    path: &mut Vec<usize>,
+    conflicts: &mut Vec<MergeConflict>,
+) -> Node {
+    let empty = Node::new();
+    let base = base.unwrap_or(&empty);
+    let mut merged = Node::new();
+
+    for (o, message) in ours.messages.iter().enumerate() {
+        let timestamp = message.timestamp();
+        let b = position(base, timestamp);
+        match (b, position(theirs, timestamp)) {
+            (_, Some(t)) => {
+                let their_message = &theirs.messages[t];
+                let base_message = b.map(|b| &base.messages[b]);
+                let theirs_unchanged = base_message.is_some_and(|m| m.text == their_message.text);
+                let ours_unchanged = base_message.is_some_and(|m| m.text == message.text);
+                if message.text != their_message.text && !theirs_unchanged && !ours_unchanged {
+                    // Divergent edits, each version keeps its replies
+                    let (first, first_child, second, second_child) = match divergence.ours_first {
+                        true => (message, &ours.childs[o], their_message, &theirs.childs[t]),
+                        false => (their_message, &theirs.childs[t], message, &ours.childs[o]),
+                    };
+                    merged.push_sibling(first.clone(), first_child.clone());
+                    let mut edit = second.clone();
+                    let mut timestamp = timestamp + divergence.offset;
+                    while [ours, theirs, &merged]
+                        .iter()
+                        .any(|node| position(node, timestamp).is_some())
+                    {
+                        timestamp += Duration::from_nanos(1);
+                    }
+                    edit.set_timestamp(timestamp);
+                    merged.push_sibling(edit, second_child.clone());
+                    continue;
+                }
+
+                path.push(merged.messages.len());
+                let child = merge_node(
+                    b.map(|b| &base.childs[b]),
+                    &ours.childs[o],
+                    &theirs.childs[t],
+                    divergence,
+                    path,
+                    conflicts,
+                );
+                path.pop();
+                match message.text == their_message.text || theirs_unchanged {
+                    true => merged.push_sibling(message.clone(), child),
+                    false => merged.push_sibling(their_message.clone(), child),
+                }
+            }
+            (Some(b), None) => {
+                let unchanged = base.messages[b].text == message.text
+                    && !subtree_changed(&base.childs[b], &ours.childs[o]);
+                if !unchanged {
+                    path.push(merged.messages.len());
+                    conflicts.push(MergeConflict {
+                        path: path.clone(),
+                        description: "Deleted on their side but changed on ours".to_string(),
+                        ours: Some(message.clone()),
+                        theirs: None,
+                    });
+                    path.pop();
+                    merged.push_sibling(message.clone(), ours.childs[o].clone());
+                }
+            }
+            (None, None) => merged.push_sibling(message.clone(), ours.childs[o].clone()),
+        }
+    }
+
+    for (t, message) in theirs.messages.iter().enumerate() {
+        let timestamp = message.timestamp();
+        if position(ours, timestamp).is_some() {
+            continue;
+        }
+        match position(base, timestamp) {
+            None => merged.push_sibling(message.clone(), theirs.childs[t].clone()),
+            Some(b) => {
+                let unchanged = base.messages[b].text == message.text
+                    && !subtree_changed(&base.childs[b], &theirs.childs[t]);
+                if !unchanged {
+                    path.push(merged.messages.len());
+                    conflicts.push(MergeConflict {
+                        path: path.clone(),
+                        description: "Deleted on our side but changed on theirs".to_string(),
+                        ours: None,
+                        theirs: Some(message.clone()),
+                    });
+                    path.pop();
+                    merged.push_sibling(message.clone(), theirs.childs[t].clone());
+                }
+            }
+        }
+    }
+
+    // Selection prefers ours
+    let selected = ours
+        .messages
+        .get(ours.selected)
+        .and_then(|m| position(&merged, m.timestamp()))
+        .or_else(|| {
+            theirs
+                .messages
+                .get(theirs.selected)
+                .and_then(|m| position(&merged, m.timestamp()))
+        });
+    merged.selected = selected.unwrap_or(0);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chat::Chat, persona::Persona, settings::Settings};
+
+    fn saved(device: &str, root: Node) -> SavedChat {
+        let chat = Chat::with_personas(
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        let mut saved = chat.to_saved();
+        saved.device = device.to_string();
+        saved.root = root;
+        saved
+    }
+
+    /// Both sides start from "Hi" answered by "Hello".
+    fn sides() -> (SavedChat, SavedChat, SavedChat) {
+        let mut root = Node::new();
+        root.push(Message::from_user("Ann".to_string(), "Hi".to_string()));
+        root.push(Message::from_char(
+            0,
+            "Luna".to_string(),
+            "Hello".to_string(),
+        ));
+        (
+            saved("base", root.clone()),
+            saved("desktop", root.clone()),
+            saved("laptop", root),
+        )
+    }
+
+    /// Every message with its depth and timestamp, in a stable order.
+    fn flatten(node: &Node) -> Vec<(usize, SystemTime, String)> {
+        fn walk(node: &Node, depth: usize, out: &mut Vec<(usize, SystemTime, String)>) {
+            for (message, child) in node.messages.iter().zip(&node.childs) {
+                out.push((depth, message.timestamp(), message.text.trim().to_string()));
+                walk(child, depth + 1, out);
+            }
+        }
+        let mut out = vec![];
+        walk(node, 0, &mut out);
+        out.sort();
+        out
+    }
+
+    fn count(node: &Node, text: &str) -> usize {
+        flatten(node).iter().filter(|(_, _, t)| t == text).count()
+    }
+
+    #[test]
+    fn additions_of_both_sides_are_kept_once() {
+        let (base, mut ours, mut theirs) = sides();
+        ours.root.push(Message::from_user(
+            "Ann".to_string(),
+            "From the desktop".to_string(),
+        ));
+        theirs.root.childs[0].push_sibling(
+            Message::from_char(0, "Luna".to_string(), "Another hello".to_string()),
+            Node::new(),
+        );
+
+        let result = merge(&base, &ours, &theirs);
+        assert!(result.is_clean());
+        for text in ["Hi", "Hello", "From the desktop", "Another hello"] {
+            assert_eq!(count(&result.merged.root, text), 1, "{text}");
+        }
+        assert_eq!(result.merged.root.childs[0].messages.len(), 2);
+    }
+
+    #[test]
+    fn one_sided_edits_are_applied() {
+        let (base, ours, mut theirs) = sides();
+        theirs.root.childs[0].messages[0].text = "Hello there".to_string();
+
+        let result = merge(&base, &ours, &theirs);
+        assert!(result.is_clean());
+        assert_eq!(count(&result.merged.root, "Hello there"), 1);
+        assert_eq!(count(&result.merged.root, "Hello"), 0);
+    }
+
+    #[test]
+    fn divergent_edits_become_siblings_with_their_replies() {
+        let (base, mut ours, mut theirs) = sides();
+        ours.root.messages[0].text = "Hi from the desktop".to_string();
+        theirs.root.messages[0].text = "Hi from the laptop".to_string();
+        theirs.root.push(Message::from_user(
+            "Ann".to_string(),
+            "Still here?".to_string(),
+        ));
+
+        let result = merge(&base, &ours, &theirs);
+        assert!(result.is_clean());
+        let root = &result.merged.root;
+        assert_eq!(root.messages.len(), 2);
+        let laptop = root
+            .messages
+            .iter()
+            .position(|m| m.text == "Hi from the laptop")
+            .unwrap();
+        assert_eq!(count(&root.childs[laptop], "Still here?"), 1);
+        assert_eq!(count(&root.childs[1 - laptop], "Still here?"), 0);
+        assert_eq!(count(root, "Hello"), 2);
+        assert_ne!(root.messages[0].timestamp(), root.messages[1].timestamp());
+    }
+
+    #[test]
+    fn divergent_edits_merge_the_same_on_both_devices() {
+        let (base, mut ours, mut theirs) = sides();
+        ours.root.messages[0].text = "Desktop".to_string();
+        ours.clock = 4;
+        theirs.root.messages[0].text = "Laptop".to_string();
+        theirs.clock = 9;
+
+        let on_desktop = merge(&base, &ours, &theirs);
+        let on_laptop = merge(&base, &theirs, &ours);
+        assert_eq!(
+            flatten(&on_desktop.merged.root),
+            flatten(&on_laptop.merged.root)
+        );
+        assert_eq!(on_desktop.merged.clock, 10);
+    }
+
+    #[test]
+    fn selection_prefers_ours() {
+        let (base, mut ours, mut theirs) = sides();
+        for (side, text) in [(&mut ours, "Ours"), (&mut theirs, "Theirs")] {
+            side.root.childs[0].push_sibling(
+                Message::from_char(0, "Luna".to_string(), text.to_string()),
+                Node::new(),
+            );
+            side.root.childs[0].selected = 1;
+        }
+
+        let result = merge(&base, &ours, &theirs);
+        let level = &result.merged.root.childs[0];
+        assert_eq!(level.messages[level.selected].text.trim(), "Ours");
+    }
+
+    #[test]
+    fn deleting_a_changed_message_is_a_conflict() {
+        let (base, mut ours, mut theirs) = sides();
+        ours.root.childs[0].messages[0].text = "Hello, edited".to_string();
+        theirs.root.childs[0] = Node::new();
+
+        let result = merge(&base, &ours, &theirs);
+        assert_eq!(result.conflicts.len(), 1);
+        let conflict = &result.conflicts[0];
+        assert_eq!(conflict.path, [0, 0]);
+        assert!(conflict.theirs.is_none());
+        assert_eq!(count(&result.merged.root, "Hello, edited"), 1);
+    }
+
+    #[test]
+    fn deleting_an_unchanged_message_is_honored() {
+        let (base, ours, mut theirs) = sides();
+        theirs.root.childs[0] = Node::new();
+
+        let result = merge(&base, &ours, &theirs);
+        assert!(result.is_clean());
+        assert_eq!(count(&result.merged.root, "Hello"), 0);
+    }
+}
@@ -0,0 +1,111 @@
+use std::{fs::File, io::BufReader, path::Path, time::SystemTime};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    chat::{Chat, Node, persist::SavedChat},
+    message::OwnerType,
+};
+
+/// What a list of the saved chats shows. It is kept apart from the tree, so reading it never
+/// waits for a generation, and written ahead of it in the save file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChatMeta {
+    /// Generated with the chat, saves and loads keep it.
+    pub id: Uuid,
+    pub created_at: SystemTime,
+    /// Last time the tree changed.
+    pub last_message_at: SystemTime,
+    /// Every message of the tree, the other siblings included.
+    pub message_count: usize,
+    /// Names of the users and chars of the chat, first appearance first.
+    pub personas: Vec<String>,
+}
+
+impl ChatMeta {
+    pub(crate) fn new(root: &Node, personas: &[&str]) -> Self {
+        let now = SystemTime::now();
+        let mut meta = Self {
+            id: Uuid::new_v4(),
+            created_at: now,
+            last_message_at: now,
+            message_count: root.count_messages(),
+            personas: vec![],
+        };
+        for name in personas {
+            meta.add_persona(name);
+        }
+        meta
+    }
+
+    /// For a chat saved before it had metadata, dated by its oldest message and with the
+    /// personas who wrote in it after `personas`.
+    pub(crate) fn of_tree(root: &Node, personas: &[&str]) -> Self {
+        let mut meta = Self::new(root, personas);
+        let (mut oldest, mut newest) = (None, None);
+        root.for_each_message(&mut |message| {
+            if !matches!(message.owner, OwnerType::System) {
+                meta.add_persona(&message.owner_name);
+            }
+            let time = message.timestamp();
+            oldest = Some(oldest.map_or(time, |o: SystemTime| o.min(time)));
+            newest = newest.max(Some(time));
+        });
+        meta.created_at = oldest.unwrap_or(meta.created_at);
+        meta.last_message_at = newest.unwrap_or(meta.last_message_at);
+        meta
+    }
+
+    pub(crate) fn add_persona(&mut self, name: &str) {
+        if !self.personas.iter().any(|p| p == name) {
+            self.personas.push(name.to_string());
+        }
+    }
+
+    /// Reads only the header of a save file. Files written before the header are loaded whole,
+    /// and keep the id their name has so `ChatStore::load` finds them.
+    pub fn read(path: &Path) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let first = serde_json::Deserializer::from_reader(reader)
+            .into_iter::<serde_json::Value>()
+            .next()
+            .ok_or(anyhow!("Empty chat file {:?}", path))??;
+        match first.get("root") {
+            None => Ok(serde_json::from_value(first)?),
+            Some(_) => {
+                let saved: SavedChat = serde_json::from_value(first)?;
+                let mut meta = ChatMeta::of_tree(&saved.root, &[]);
+                if let Some(id) = path.file_stem().and_then(|s| s.to_str()?.parse().ok()) {
+                    meta.id = id;
+                }
+                Ok(meta)
+            }
+        }
+    }
+}
+
+impl Chat {
+    /// Copies the metadata without locking the tree.
+    pub fn meta(&self) -> ChatMeta {
+        self.meta.lock().unwrap().clone()
+    }
+
+    /// Records a change of the tree.
+    pub(crate) fn touch(&self) {
+        let message_count = self.root.lock().unwrap().count_messages();
+        let mut meta = self.meta.lock().unwrap();
+        meta.message_count = message_count;
+        meta.last_message_at = SystemTime::now();
+        drop(meta);
+        self.schedule_autosave();
+    }
+}
+
+impl Node {
+    /// Messages in the subtree, the siblings off the selected path included.
+    pub(crate) fn count_messages(&self) -> usize {
+        self.messages.len() + self.childs.iter().map(Node::count_messages).sum::<usize>()
+    }
+}
@@ -0,0 +1,2053 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex, atomic::AtomicU64},
+    time::{Duration, SystemTime},
+};
+
+use image::{ImageBuffer, Rgba};
+use llm::{
+    LLMProvider,
+    builder::{LLMBackend, LLMBuilder},
+    chat::ChatRole,
+    error::LLMError,
+};
+use log::{error, trace, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use crate::{
+    budget::{EVICTION_ORDER, MemoryBudget},
+    chat::{
+        autosave::Autosave,
+        converted::ConvertedHistory,
+        error::{ChatError, RetryPolicy},
+        meta::ChatMeta,
+        pins::Pins,
+        rng::ChatRng,
+        stream::ReplyStream,
+    },
+    clock::{Clock, SystemClock},
+    dialects::{self, Dialect, ExamplePlacement},
+    lorebook::{self, Lorebook},
+    message::{
+        Attachment, FinishReason, GenerationInfo, Message, MessageStatus, NARRATOR, OwnerType,
+        PromptMessage, RevisionTag, RoutingInfo, TokenUsage,
+    },
+    models,
+    persona::{
+        Persona,
+        avatar::{AvatarStyle, RawImage},
+        card::Entry,
+        schedule::Availability,
+    },
+    prompt::PromptBuilder,
+    settings::{CUSTOM_BACKEND, OPENROUTER, Settings, SettingsError},
+    tokens::{HeuristicEstimator, MESSAGE_OVERHEAD, TokenEstimator},
+    tools::ToolSpec,
+};
+
+pub mod autosave;
+mod converted;
+pub mod diff;
+pub mod edits;
+pub mod error;
+pub mod export;
+pub mod fork;
+pub mod hidden;
+pub mod ids;
+pub mod labels;
+pub mod merge;
+pub mod meta;
+pub mod openai;
+pub mod persist;
+pub mod pins;
+pub mod preview;
+pub mod prune;
+pub mod repair;
+pub mod revision;
+pub mod rng;
+pub mod search;
+pub mod siblings;
+pub mod sillytavern;
+pub mod stats;
+pub mod store;
+pub mod stream;
+pub mod tree;
+pub mod usage;
+
+pub use merge::merge;
+
+/// Updates a subscriber can fall behind by before it loses the oldest.
+pub const UPDATE_CAPACITY: usize = 64;
+
+#[derive(Clone)]
+pub enum ChatUpdate {
+    RequestSent,
+    RequestOk,
+    RequestError(ChatError),
+    StreamUpdate,
+    StreamFinished {
+        reason: FinishReason,
+        /// Also kept in the metadata of the message.
+        usage: TokenUsage,
+    },
+    BuffersTrimmed {
+        freed_bytes: usize,
+    },
+    /// The oldest messages did not fit in `Settings::context_limit` and were left out of the request.
+    ContextTrimmed {
+        dropped: usize,
+    },
+    ReplyScheduled {
+        fire_at: SystemTime,
+    },
+    /// The model called a tool with these arguments, the reply continues once it returns.
+    ToolCalled {
+        name: String,
+        args: serde_json::Value,
+    },
+    /// A message the model is about to write was added outside of the user/char turn order.
+    MessageCreated {
+        owner: OwnerType,
+    },
+    PinsChanged,
+    /// Another sibling was selected by `Chat::select_path_to` or `select_sibling`.
+    SelectionChanged,
+    /// The chat was loaded with these messages left generating, see `Chat::recovered`.
+    Recovered {
+        ids: Vec<usize>,
+    },
+    /// The autosave wrote the chat, see `Chat::enable_autosave`.
+    Saved,
+    /// The tree was replaced by the greetings, see `Chat::reset`.
+    Reset,
+    /// The request failed with a transient error and is sent again, `attempt` starts at 1.
+    Retrying {
+        attempt: u32,
+    },
+    /// The result is fed back to the model, which continues the reply.
+    ToolCallFinished {
+        name: String,
+        is_error: bool,
+    },
+}
+
+/// What is actually sent to the provider for one generation.
+#[derive(Debug, Clone)]
+pub struct RequestSnapshot {
+    pub model: String,
+    pub system: Option<String>,
+    pub messages: Vec<PromptMessage>,
+    pub char_revision: RevisionTag,
+}
+
+impl RequestSnapshot {
+    fn estimated_bytes(&self) -> usize {
+        let messages: usize = self.messages.iter().map(|m| m.content.len()).sum();
+        self.model.len() + self.system.as_ref().map_or(0, |s| s.len()) + messages
+    }
+}
+
+const MAX_SNAPSHOTS: usize = 16;
+
+/// One depth of the selected history, see `Chat::get_history_structure`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryLevel {
+    /// Of the selected message among its siblings, starting at 1.
+    pub position: usize,
+    pub siblings: usize,
+    /// Of the selected message.
+    pub label: Option<String>,
+}
+
+/// What a generation writes into the last message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Generation {
+    /// Fills the empty char message, which is left out of the prompt.
+    Reply,
+    /// Appends to the char message, which is sent as the start of the reply.
+    Continue,
+    /// Fills the empty user message in the user persona's voice.
+    Impersonate,
+}
+
+type BuildProvider =
+    dyn Fn(&Settings, Option<&str>) -> Result<Box<dyn LLMProvider>, LLMError> + Send + Sync;
+
+/// Builds the provider of each generation in place of the OpenRouter one.
+#[derive(Clone)]
+struct ProviderFactory(Arc<BuildProvider>);
+
+impl fmt::Debug for ProviderFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProviderFactory").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub struct Chat {
+    root: Arc<Mutex<Node>>,
+    personas: Vec<Persona>,
+    settings: Settings,
+    dialect: Option<Dialect>,
+    snapshots: VecDeque<RequestSnapshot>,
+    /// The `llm` messages of the last request, given back by its stream.
+    converted: Arc<Mutex<ConvertedHistory>>,
+    budget: MemoryBudget,
+    /// Shared with the autosave task, which bumps it on each save.
+    clock: Arc<AtomicU64>,
+    revisions: Vec<revision::CharRevision>,
+    active_revision: Arc<Mutex<Option<u64>>>,
+    rng: ChatRng,
+    estimator: Arc<dyn TokenEstimator>,
+    /// What the availability schedules are evaluated against.
+    wall_clock: Arc<dyn Clock>,
+    tools: Vec<Arc<ToolSpec>>,
+    provider_factory: Option<ProviderFactory>,
+    pins: Pins,
+    authors_note: Option<String>,
+    /// Lorebooks scanned along with the one of the char card.
+    lorebooks: Vec<Lorebook>,
+    tx: broadcast::Sender<ChatUpdate>,
+    /// Task of the last generation, and the id of the message it writes.
+    generation: Option<(JoinHandle<()>, usize)>,
+    meta: Arc<Mutex<ChatMeta>>,
+    autosave: Option<Autosave>,
+    /// Messages found generating when loaded, see `Chat::recovered`.
+    recovered: Vec<usize>,
+}
+
+impl Chat {
+    pub fn with_personas(user: Persona, char: Persona, settings: Settings) -> Self {
+        let root = Self::greetings(&user, &char);
+        Self::from_root(root, user, char, settings)
+    }
+
+    /// A tree of the greetings of `char`, one sibling each.
+    fn greetings(user: &Persona, char: &Persona) -> Node {
+        let mut root = Node::new();
+        if let Some(greetings) = char.greetings(Some(user.name())) {
+            for greeting in greetings {
+                root.messages
+                    .push(Message::from_char(0, char.name().to_string(), greeting));
+                root.childs.push(Node::new());
+            }
+        }
+        root
+    }
+
+    /// Starts over from the greetings, cancelling the generation first. Everything but the tree
+    /// and the pins is kept, the subscribers included. The greetings get new ids, ids are never
+    /// reused.
+    pub fn reset(&mut self) {
+        trace!("Resetting the chat");
+        self.cancel();
+        *self.root.lock().unwrap() = Self::greetings(&self.personas[0], &self.personas[1]);
+        if !self.pins.is_empty() {
+            self.pins.clear();
+            Self::send_update(&self.tx, ChatUpdate::PinsChanged);
+        }
+        self.touch();
+        Self::send_update(&self.tx, ChatUpdate::Reset);
+    }
+
+    /// Stops the generation in flight or scheduled, the message keeps the text streamed so far.
+    /// Returns false when there was none.
+    pub fn cancel(&mut self) -> bool {
+        self.abort_generation().is_some()
+    }
+
+    /// `cancel`, then waits for the task of the generation to exit.
+    pub async fn stop(&mut self) {
+        if let Some(task) = self.abort_generation() {
+            let _ = task.await;
+        }
+    }
+
+    fn abort_generation(&mut self) -> Option<JoinHandle<()>> {
+        let (task, target) = self.generation.take()?;
+        if task.is_finished() {
+            return None;
+        }
+        trace!("Cancelling the generation");
+        task.abort();
+        let error = ChatError::Cancelled;
+        Self::set_status(
+            &self.root,
+            target,
+            MessageStatus::Errored(error.to_string()),
+        );
+        self.touch();
+        Self::send_update(&self.tx, ChatUpdate::RequestError(error));
+        Some(task)
+    }
+
+    fn from_root(root: Node, user: Persona, char: Persona, settings: Settings) -> Self {
+        let meta = ChatMeta::new(&root, &[user.name(), char.name()]);
+        Chat {
+            root: Arc::new(Mutex::new(root)),
+            personas: vec![user, char],
+            rng: ChatRng::from_seed(settings.seed),
+            estimator: Arc::new(HeuristicEstimator),
+            wall_clock: Arc::new(SystemClock),
+            tools: vec![],
+            provider_factory: None,
+            pins: Pins::default(),
+            authors_note: None,
+            lorebooks: vec![],
+            settings,
+            dialect: None,
+            snapshots: VecDeque::new(),
+            converted: Default::default(),
+            budget: MemoryBudget::default(),
+            clock: Arc::new(AtomicU64::new(0)),
+            revisions: vec![],
+            active_revision: Arc::new(Mutex::new(None)),
+            tx: broadcast::channel(UPDATE_CAPACITY).0,
+            generation: None,
+            meta: Arc::new(Mutex::new(meta)),
+            autosave: None,
+            recovered: vec![],
+        }
+    }
+
+    /// Sends the updates to the subscribers of `tx` instead, so they outlive the chat. A chat
+    /// loaded with interrupted messages announces them to these with `ChatUpdate::Recovered`.
+    pub fn set_tx(&mut self, tx: broadcast::Sender<ChatUpdate>) {
+        self.tx = tx;
+        if !self.recovered.is_empty() {
+            let ids = self.recovered.clone();
+            Self::send_update(&self.tx, ChatUpdate::Recovered { ids });
+        }
+    }
+
+    /// Ids of the messages that were generating when the chat was saved, now errored when
+    /// empty or completed with `FinishReason::Length`, to offer to regenerate or continue them.
+    pub fn recovered(&self) -> &[usize] {
+        &self.recovered
+    }
+
+    /// If a generation is in flight or scheduled.
+    pub fn is_generating(&self) -> bool {
+        self.generation
+            .as_ref()
+            .is_some_and(|(task, _)| !task.is_finished())
+    }
+
+    /// A new listener, getting every update sent from now on. One that falls more than
+    /// `UPDATE_CAPACITY` updates behind loses the oldest, the generation never waits for it.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChatUpdate> {
+        self.tx.subscribe()
+    }
+
+    pub fn user(&self) -> Persona {
+        self.personas[0].clone()
+    }
+
+    pub fn char(&self) -> Persona {
+        self.personas[1].clone()
+    }
+
+    /// Plays as `user` from now on, marking it as the most recently used. The messages already
+    /// written keep the previous name, the generation in flight is left alone.
+    pub fn set_user(&mut self, mut user: Persona) {
+        trace!("Playing as {}", user.name());
+        user.set_modified_time();
+        self.meta.lock().unwrap().add_persona(user.name());
+        self.personas[0] = user;
+    }
+
+    /// Replaces the char, with an edited version of its card for instance, keeping the tree. The
+    /// next generations use its prompt and avatar, the messages already written are left as they
+    /// are, tagged with the revision of the previous card. An active revision is reverted.
+    pub fn set_char(&mut self, char: Persona) {
+        trace!("Replacing the char with {}", char.name());
+        self.meta.lock().unwrap().add_persona(char.name());
+        self.personas[1] = char;
+        *self.active_revision.lock().unwrap() = None;
+    }
+
+    pub fn title(&self) -> String {
+        format!(
+            "{}'s chat with {}",
+            self.personas[0].name(),
+            self.personas[1].name()
+        )
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn rng_state(&self) -> ChatRng {
+        self.rng
+    }
+
+    /// Every randomness consumer of the chat draws from here so seeded sessions replay identically.
+    pub fn rng(&mut self) -> &mut ChatRng {
+        &mut self.rng
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = ChatRng::new(seed);
+    }
+
+    /// Makes a tool available to the model in the next generations, replacing one with the same name.
+    pub fn register_tool(&mut self, tool: ToolSpec) {
+        self.tools.retain(|t| t.name != tool.name);
+        self.tools.push(Arc::new(tool));
+    }
+
+    /// Replaces the OpenRouter provider, for other backends and for tests. `factory` gets the
+    /// effective settings and the system prompt, the registered tools and the seed are only
+    /// sent by the OpenRouter provider.
+    pub fn set_provider_factory(
+        &mut self,
+        factory: impl Fn(&Settings, Option<&str>) -> Result<Box<dyn LLMProvider>, LLMError>
+        + Send
+        + Sync
+        + 'static,
+    ) {
+        self.provider_factory = Some(ProviderFactory(Arc::new(factory)));
+    }
+
+    pub fn authors_note(&self) -> Option<&str> {
+        self.authors_note.as_deref()
+    }
+
+    /// Note added at the end of the system prompt, see `PromptSection::AuthorsNote`.
+    pub fn set_authors_note(&mut self, note: Option<String>) {
+        self.authors_note = note.filter(|n| !n.trim().is_empty());
+        self.schedule_autosave();
+    }
+
+    pub fn lorebooks(&self) -> &[Lorebook] {
+        &self.lorebooks
+    }
+
+    /// Adds the entries of `lorebook` to those of the char book, replacing a book with the same name.
+    pub fn attach_lorebook(&mut self, lorebook: Lorebook) {
+        self.lorebooks.retain(|l| l.name != lorebook.name);
+        self.lorebooks.push(lorebook);
+    }
+
+    pub fn detach_lorebook(&mut self, name: &str) {
+        self.lorebooks.retain(|l| l.name != name);
+    }
+
+    pub fn set_token_estimator(&mut self, estimator: Arc<dyn TokenEstimator>) {
+        self.estimator = estimator;
+    }
+
+    /// Replaces the system time the availability of the char is checked at.
+    pub fn set_wall_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.wall_clock = clock;
+    }
+
+    /// Tokens the next request takes, before trimming it to `Settings::context_limit`.
+    pub fn prompt_token_estimate(&self) -> usize {
+        let (request, _) = self.build_request(Generation::Reply, None, false);
+        self.estimator
+            .estimate_prompt(request.system.as_deref(), &request.messages)
+    }
+
+    /// The settings with the overrides of the char on top, what the requests are made with.
+    pub fn effective_settings(&self) -> Settings {
+        let mut settings = self.settings.clone();
+        self.active_char().settings_overrides().apply(&mut settings);
+        settings
+    }
+
+    /// Replaces and saves the settings, unless `Settings::validate` rejects them.
+    pub fn set_settings(&mut self, settings: Settings) -> Result<(), Vec<SettingsError>> {
+        settings.validate()?;
+        trace!("Settings changed");
+        self.settings = settings;
+        let _ = self.settings.save();
+        Ok(())
+    }
+
+    /// Replaces the settings without saving them, for settings read back from the file.
+    pub(crate) fn reload_settings(&mut self, settings: Settings) {
+        trace!("Settings reloaded");
+        self.settings = settings;
+    }
+
+    /// The dialect used for requests, either the per-chat override or the one resolved from the model.
+    pub fn dialect(&self) -> Dialect {
+        match &self.dialect {
+            Some(dialect) => dialect.clone(),
+            None => dialects::resolve(
+                &self.effective_settings().model,
+                &self.settings.dialect_rules,
+            ),
+        }
+    }
+
+    /// If the model takes the reasoning settings, they are not sent otherwise.
+    pub fn supports_reasoning(&self) -> bool {
+        self.dialect().reasoning
+    }
+
+    pub fn set_dialect(&mut self, dialect: Option<Dialect>) {
+        self.dialect = dialect;
+    }
+
+    pub fn last_request(&self) -> Option<&RequestSnapshot> {
+        self.snapshots.back()
+    }
+
+    pub fn request_snapshots(&self) -> impl Iterator<Item = &RequestSnapshot> {
+        self.snapshots.iter()
+    }
+
+    /// History messages copied into `llm` messages by the requests so far. A request reuses
+    /// those of the one before whose stream ended.
+    pub fn converted_messages(&self) -> usize {
+        self.converted.lock().unwrap().conversions()
+    }
+
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.budget.set_limit(bytes);
+        self.enforce_budget();
+    }
+
+    pub fn memory_usage(&self) -> BTreeMap<&'static str, usize> {
+        self.budget.usage()
+    }
+
+    fn push_snapshot(&mut self, request: RequestSnapshot) {
+        self.snapshots.push_back(request);
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        self.record_snapshots();
+        self.enforce_budget();
+    }
+
+    fn record_snapshots(&mut self) {
+        let bytes = self.snapshots.iter().map(|s| s.estimated_bytes()).sum();
+        self.budget.record("snapshots", bytes);
+    }
+
+    // Only ever drops auxiliary buffers, the message tree is never touched
+    fn enforce_budget(&mut self) {
+        let mut freed_bytes = 0;
+        for buffer in EVICTION_ORDER {
+            if self.budget.excess() == 0 {
+                break;
+            }
+            let before = self.budget.total();
+            if buffer == "snapshots" {
+                while self.budget.excess() > 0
+                    && let Some(snapshot) = self.snapshots.pop_front()
+                {
+                    trace!(
+                        "Evicting request snapshot of {} bytes",
+                        snapshot.estimated_bytes()
+                    );
+                    self.record_snapshots();
+                }
+            }
+            freed_bytes += before - self.budget.total();
+        }
+        if freed_bytes > 0 {
+            Self::send_update(&self.tx, ChatUpdate::BuffersTrimmed { freed_bytes });
+        }
+    }
+
+    /// `NARRATOR` for the system messages.
+    pub fn owner_name(&self, message: &Message) -> &str {
+        match self.personas.get(usize::from(message.owner)) {
+            Some(persona) => persona.name(),
+            None => NARRATOR,
+        }
+    }
+
+    /// None for the system messages.
+    pub fn message_image(&self, message: &Message) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        self.personas.get(usize::from(message.owner))?.image()
+    }
+
+    pub fn raw_images(&self, style: AvatarStyle) -> Vec<Option<RawImage>> {
+        let mut raw_images = vec![];
+        for p in &self.personas {
+            raw_images.push(p.raw_image(style))
+        }
+        raw_images
+    }
+
+    pub fn raw_image_for(&self, owner: OwnerType, style: AvatarStyle) -> Option<RawImage> {
+        self.personas.get(usize::from(owner))?.raw_image(style)
+    }
+
+    pub fn add_user_message(&mut self, text: String) {
+        self.add_user_message_with_images(text, vec![]);
+    }
+
+    /// Adds narration at the end of the history, sent to the model as such, without a reply.
+    pub fn add_system_message(&mut self, text: String) {
+        let text = text.trim().to_string();
+        if text.is_empty() {
+            return;
+        }
+        trace!("Adding system Message");
+        self.root.lock().unwrap().push(Message::from_system(text));
+        self.touch();
+        let owner = OwnerType::System;
+        Self::send_update(&self.tx, ChatUpdate::MessageCreated { owner });
+    }
+
+    /// Sends images along with the text, to models whose dialect has `vision`.
+    pub fn add_user_message_with_images(&mut self, text: String, images: Vec<Attachment>) {
+        let text = text.trim().to_string();
+        if !text.is_empty() || !images.is_empty() {
+            trace!("Adding user Message");
+            let mut message = Message::from_user(self.personas[0].name().to_string(), text);
+            message.attachments = images;
+            self.root.lock().unwrap().push(message);
+        }
+
+        // Response from the llm
+        trace!("Adding char response");
+        self.root.lock().unwrap().push(Message::empty_from_char(
+            0,
+            self.personas[1].name().to_string(),
+        ));
+        self.touch();
+
+        let now = self.wall_clock.now();
+        let availability = match self.settings.presence_schedules {
+            true => self.personas[1].availability(now),
+            false => Availability::Available,
+        };
+        match availability {
+            Availability::Available => self.generate(),
+            Availability::DelayedReply { fire_at, situation } => {
+                trace!(
+                    "{} is {situation}, reply scheduled",
+                    self.personas[1].name()
+                );
+                Self::send_update(&self.tx, ChatUpdate::ReplyScheduled { fire_at });
+                let delay = fire_at.duration_since(now).unwrap_or_default();
+                self.generate_with(Generation::Reply, Some(delay), None, None);
+            }
+            Availability::AwayMessage { situation } => {
+                let nudge = format!(
+                    "{} is currently {situation} and can only answer with a short message.",
+                    self.personas[1].name()
+                );
+                self.generate_with(Generation::Reply, None, Some(nudge), None);
+            }
+        }
+    }
+
+    pub fn next(&mut self, depth: usize) {
+        trace!("Next depth {depth}");
+        let mut root = self.root.lock().unwrap();
+        // Only the model writes new siblings, there is nothing to generate narration with
+        let is_system = root
+            .selected_message_mut(depth)
+            .is_some_and(|m| matches!(m.owner, OwnerType::System));
+        if is_system
+            && root
+                .level(depth)
+                .is_ok_and(|l| l.selected + 1 >= l.messages.len())
+        {
+            return;
+        }
+        let pushed = root.next(depth);
+        drop(root);
+        self.touch();
+        if pushed {
+            trace!("Adding char response");
+            self.generate();
+        }
+    }
+
+    pub fn previous(&mut self, depth: usize) {
+        trace!("Next depth {depth}");
+        self.root.lock().unwrap().previous(depth);
+        self.touch();
+    }
+
+    pub fn add_edit(&mut self, depth: usize, text: String) {
+        let text = text.trim().to_string();
+        trace!("Adding new edit depth {depth}");
+        let added_response =
+            self.root
+                .lock()
+                .unwrap()
+                .add_edit(depth, self.personas[1].name().to_string(), text);
+        self.touch();
+        if added_response {
+            self.generate();
+        }
+    }
+
+    /// Adds a new sibling at `depth` generated with `seed`, to reproduce a swipe recorded in the
+    /// metadata of another message.
+    pub fn next_with_seed(&mut self, depth: usize, seed: u64) {
+        trace!("New sibling at depth {depth} with seed {seed}");
+        let mut root = self.root.lock().unwrap();
+        let is_char = root
+            .selected_message_mut(depth)
+            .is_some_and(|m| matches!(m.owner, OwnerType::Char(_)));
+        let pushed = is_char && root.push_brother(depth);
+        drop(root);
+        if pushed {
+            self.touch();
+            self.generate_with(Generation::Reply, None, None, Some(seed));
+        }
+    }
+
+    /// Rerolls the char message at `depth` into the same message instead of a new sibling, from
+    /// the history before it. The messages after it are kept as they are. User messages are left
+    /// untouched, and nothing is done while a generation is running.
+    pub fn regenerate(&mut self, depth: usize) {
+        if self.is_generating() {
+            warn!("A generation is running, not regenerating depth {depth}");
+            return;
+        }
+        match self.root.lock().unwrap().selected_message_mut(depth) {
+            Some(message) if matches!(message.owner, OwnerType::Char(_)) => {
+                trace!("Regenerating depth {depth} in place");
+                message.clear();
+            }
+            _ => {
+                trace!("No char message to regenerate at depth {depth}");
+                return;
+            }
+        }
+        self.touch();
+        self.generate_at(Some(depth), Generation::Reply, None, None, None);
+    }
+
+    /// Regenerates the most recent char message of the selected path that failed or came back
+    /// empty, into the same message from the history before it, whatever follows it.
+    pub fn retry_last(&mut self) {
+        if self.is_generating() {
+            warn!("A generation is running, not retrying");
+            return;
+        }
+        let failed = self.get_history().iter().rposition(|m| {
+            matches!(m.owner, OwnerType::Char(_))
+                && match &m.status {
+                    MessageStatus::Errored(_) => true,
+                    MessageStatus::Complete => m.text.trim().is_empty(),
+                    MessageStatus::Pending | MessageStatus::Streaming => false,
+                }
+        });
+        match failed {
+            Some(depth) => {
+                trace!("Retrying depth {depth}");
+                if let Some(message) = self.root.lock().unwrap().selected_message_mut(depth) {
+                    message.clear();
+                }
+                self.touch();
+                self.generate_at(Some(depth), Generation::Reply, None, None, None);
+            }
+            None => warn!("No failed generation to retry"),
+        }
+    }
+
+    /// Returns how many pinned messages were unpinned because they were deleted.
+    pub fn delete(&mut self, depth: usize) -> usize {
+        trace!("Deleting depth {depth}");
+        let id = self
+            .root
+            .lock()
+            .unwrap()
+            .level(depth)
+            .ok()
+            .map(|level| level.messages[level.selected].id());
+        let pinned = id.map(|id| self.pins_under(id)).unwrap_or_default();
+        if !pinned.is_empty() {
+            warn!(
+                "Deleting depth {depth} removes {} pinned messages",
+                pinned.len()
+            );
+        }
+        self.root.lock().unwrap().delete(depth);
+        self.touch();
+        self.unpin_deleted(&pinned)
+    }
+
+    /// Asks the model to continue the last char message, e.g. after it was cut by `max_tokens`.
+    /// The new tokens are appended to the same message.
+    pub fn continue_last(&mut self) {
+        let continuable = self
+            .root
+            .lock()
+            .unwrap()
+            .last_message_mut()
+            .is_some_and(|m| matches!(m.owner, OwnerType::Char(_)) && !m.text.is_empty());
+        if !continuable {
+            trace!("Nothing to continue");
+            return;
+        }
+        let nudge = format!(
+            "Continue {}'s last message from where it stopped, without repeating it.",
+            self.personas[1].name()
+        );
+        self.generate_with(Generation::Continue, None, Some(nudge), None);
+    }
+
+    /// Writes the user's next message in the user persona's voice, without a char reply after it.
+    pub fn impersonate(&mut self) {
+        trace!("Impersonating user");
+        self.root.lock().unwrap().push(Message::empty_from_user(
+            self.personas[0].name().to_string(),
+        ));
+        self.touch();
+        let owner = OwnerType::User;
+        Self::send_update(&self.tx, ChatUpdate::MessageCreated { owner });
+        self.generate_with(Generation::Impersonate, None, None, None);
+    }
+
+    fn generate(&mut self) {
+        self.generate_with(Generation::Reply, None, None, None);
+    }
+
+    fn generate_with(
+        &mut self,
+        generation: Generation,
+        delay: Option<Duration>,
+        nudge: Option<String>,
+        seed: Option<u64>,
+    ) {
+        self.generate_at(None, generation, delay, nudge, seed);
+    }
+
+    /// Generates into the selected message at `depth` from the history before it, into the last
+    /// message without one. A generation still running is cancelled first, there is a single
+    /// task writing to the tree.
+    fn generate_at(
+        &mut self,
+        depth: Option<usize>,
+        generation: Generation,
+        delay: Option<Duration>,
+        nudge: Option<String>,
+        seed: Option<u64>,
+    ) {
+        if self.abort_generation().is_some() {
+            warn!("Cancelled the running generation for a new one");
+        }
+        // Drawn from the chat randomness, a seeded session replays identically while its rerolls
+        // still differ
+        let seed = match (seed, self.settings.seed) {
+            (Some(seed), _) => Some(seed),
+            (None, Some(_)) => Some(self.rng.next_u64()),
+            (None, None) => None,
+        };
+        let history = self.request_history_to(generation, depth);
+        let (request, dropped) =
+            self.build_request_for(generation, nudge.as_deref(), true, history);
+        let settings = self.effective_settings();
+        let info = GenerationInfo {
+            model: request.model.clone(),
+            temperature: settings.temperature,
+            max_tokens: settings.max_tokens,
+            seed,
+            backend: match self.provider_factory {
+                Some(_) => CUSTOM_BACKEND,
+                None => OPENROUTER,
+            }
+            .to_string(),
+        };
+        if dropped > 0 {
+            Self::send_update(&self.tx, ChatUpdate::ContextTrimmed { dropped });
+        }
+        // Captured now so navigating while streaming does not redirect the tokens
+        let target = {
+            let mut root = self.root.lock().unwrap();
+            let message = match depth {
+                Some(depth) => root.selected_message_mut(depth),
+                None => root.last_message_mut(),
+            };
+            let Some(message) = message else {
+                trace!("No message to generate into");
+                return;
+            };
+            if generation != Generation::Impersonate {
+                message.metadata.revision = Some(request.char_revision.clone());
+                message.metadata.generation = Some(info);
+            }
+            message.status = MessageStatus::Pending;
+            message.metadata.seed = seed;
+            message.id()
+        };
+        // Initialize and configure the LLM client with streaming enabled
+        let llm = match self.llm(request.system.clone(), seed) {
+            Ok(llm) => llm,
+            Err(e) => {
+                // The empty char message stays in place so the generation can be retried
+                error!("Failed to build LLM: {e}");
+                Self::set_status(&self.root, target, MessageStatus::Errored(e.to_string()));
+                Self::send_update(&self.tx, ChatUpdate::RequestError(ChatError::from(&e)));
+                self.push_snapshot(request);
+                return;
+            }
+        };
+        // `llm` wants owned strings, only the messages not in the last request are copied. A
+        // continued target is replaced by the stream, so not cached again.
+        let keep = request.messages.len() - usize::from(generation == Generation::Continue);
+        let (history, lent) = ConvertedHistory::lend(
+            &self.converted,
+            &request.messages,
+            self.dialect().vision,
+            keep,
+        );
+        let pricing = models::cached_pricing(&request.model);
+        self.push_snapshot(request);
+        let retry = RetryPolicy {
+            max_retries: self.settings.max_retries,
+            base_delay: Duration::from_millis(self.settings.retry_base_delay_ms),
+        };
+        let stream = ReplyStream {
+            root: self.root.clone(),
+            meta: self.meta.clone(),
+            autosave: self.autosave.as_ref().map(|a| a.tx.clone()),
+            target,
+            tx: self.tx.clone(),
+            llm,
+            history,
+            lent: Some(lent),
+            tools: self.tools.clone(),
+            retry,
+            max_tokens: settings.max_tokens,
+            auto_continue: self.settings.auto_continue,
+            stop_sequences: self.settings.stop_sequences.clone(),
+            pricing,
+            connect_timeout: Some(Duration::from_secs(self.settings.connect_timeout_secs))
+                .filter(|t| !t.is_zero()),
+            update_interval: Some(Duration::from_millis(
+                self.settings.stream_update_interval_ms,
+            ))
+            .filter(|t| !t.is_zero()),
+            stall_timeout: Some(Duration::from_secs(self.settings.request_timeout_secs))
+                .filter(|t| !t.is_zero()),
+            continuing: generation == Generation::Continue,
+            path: Default::default(),
+        };
+        let task = tokio::spawn(async move {
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            Chat::send_update(&stream.tx, ChatUpdate::RequestSent);
+            stream.run().await;
+        });
+        self.generation = Some((task, target));
+    }
+
+    fn set_status(root: &Mutex<Node>, target: usize, status: MessageStatus) {
+        if let Some(message) = root.lock().unwrap().find_mut(target) {
+            message.status = status;
+        }
+    }
+
+    // Successful stream chunks are reduced to their text by `llm`, only error bodies keep the metadata
+    fn error_routing(error: &LLMError) -> Option<RoutingInfo> {
+        match error {
+            LLMError::ResponseFormatError { raw_response, .. } => {
+                let value = serde_json::from_str(raw_response).ok()?;
+                RoutingInfo::from_openrouter_json(&value)
+            }
+            _ => None,
+        }
+    }
+
+    /// Fails only when nobody listens.
+    fn send_update(tx: &broadcast::Sender<ChatUpdate>, cu: ChatUpdate) {
+        let _ = tx.send(cu);
+    }
+
+    pub fn get_history(&self) -> Vec<Message> {
+        let mut history = vec![];
+        self.root.lock().unwrap().get_history(&mut history);
+        history
+    }
+
+    pub fn get_history_structure(&self) -> Vec<HistoryLevel> {
+        let mut structure = vec![];
+        self.root
+            .lock()
+            .unwrap()
+            .get_history_structure(&mut structure);
+        structure
+    }
+
+    /// The selected history as sent, without the message a reply replaces.
+    fn request_history(&self, generation: Generation) -> Vec<PromptMessage> {
+        self.request_history_to(generation, None)
+    }
+
+    /// The selected history up to the message at `depth`, or to the end.
+    fn request_history_to(
+        &self,
+        generation: Generation,
+        depth: Option<usize>,
+    ) -> Vec<PromptMessage> {
+        let mut history = vec![];
+        let levels = depth.map_or(usize::MAX, |d| d + 1);
+        let prefix = self.dialect().assistant_prefix(self.active_char().name());
+        self.root
+            .lock()
+            .unwrap()
+            .prompt_history(levels, &prefix, &mut history);
+        if generation != Generation::Continue {
+            history.pop();
+        }
+        history
+    }
+
+    /// Entries of the char book and the attached lorebooks that `history` triggers.
+    fn triggered_lore(&self, history: &[PromptMessage]) -> Vec<&Entry> {
+        let books: Vec<_> = self
+            .active_char()
+            .data
+            .character_book
+            .iter()
+            .chain(self.lorebooks.iter().map(|l| &l.book))
+            .collect();
+        lorebook::activate(&books, history, self.estimator.as_ref())
+    }
+
+    /// Builds the request for the selected history, returning how many messages were trimmed.
+    fn build_request(
+        &self,
+        generation: Generation,
+        nudge: Option<&str>,
+        trim: bool,
+    ) -> (RequestSnapshot, usize) {
+        self.build_request_for(generation, nudge, trim, self.request_history(generation))
+    }
+
+    /// `build_request` for another history, the lore being triggered by it.
+    fn build_request_for(
+        &self,
+        generation: Generation,
+        nudge: Option<&str>,
+        trim: bool,
+        mut history: Vec<PromptMessage>,
+    ) -> (RequestSnapshot, usize) {
+        let dialect = self.dialect();
+        let user_name = self.personas[0].name();
+        let char = self.active_char();
+
+        let lore = self.triggered_lore(&history);
+
+        let placement = self.settings.example_placement.unwrap_or(dialect.examples);
+        let examples = match placement {
+            ExamplePlacement::System => vec![],
+            ExamplePlacement::Turns => char.example_dialogues(Some(user_name)),
+        };
+        let prompt = PromptBuilder::new(char, &self.settings.prompt_order)
+            .partner(user_name)
+            .examples(examples.is_empty())
+            .user_persona(&self.personas[0])
+            .lorebook(&lore)
+            .authors_note(self.authors_note.as_deref())
+            .build();
+        let mut system = match generation {
+            Generation::Reply | Generation::Continue => format!(
+                "Write a story between {} and {}. Do not speak or impersonate {}.\n{}\nStory start:\n",
+                user_name,
+                char.name(),
+                user_name,
+                prompt
+            ),
+            Generation::Impersonate => format!(
+                "Write {}'s next message in a story between {} and {}. Write only as {}, do not speak for {}.\n{}\nStory start:\n",
+                user_name,
+                user_name,
+                char.name(),
+                user_name,
+                char.name(),
+                prompt
+            ),
+        };
+        if let Some(nudge) = nudge {
+            system.push_str(nudge);
+            system.push('\n');
+        }
+
+        let mut messages = vec![];
+        for (owner, text) in examples.into_iter().flatten() {
+            let role = match owner {
+                OwnerType::User | OwnerType::System => ChatRole::User,
+                OwnerType::Char(_) => ChatRole::Assistant,
+            };
+            messages.push(PromptMessage::new(role, &text));
+        }
+        messages.append(&mut history);
+
+        let mut request = RequestSnapshot {
+            model: self.effective_settings().model,
+            system: Some(system),
+            messages,
+            char_revision: self.active_revision_tag(),
+        };
+        // Trimmed before the dialect so a system prompt merged into a message is never dropped
+        let dropped = match trim {
+            true => self.trim_to_context(&mut request),
+            false => 0,
+        };
+        dialect.apply(&mut request, char.name());
+        (request, dropped)
+    }
+
+    /// Drops the oldest messages until the request fits in the context, keeping the system prompt
+    /// and the latest message. Returns how many were dropped.
+    fn trim_to_context(&self, request: &mut RequestSnapshot) -> usize {
+        let Some(limit) = self.settings.context_limit else {
+            return 0;
+        };
+        // The reply has to fit too
+        let limit = limit.saturating_sub(self.effective_settings().max_tokens as usize);
+        let tokens: Vec<usize> = request
+            .messages
+            .iter()
+            .map(|m| self.estimator.estimate(&m.content) + MESSAGE_OVERHEAD)
+            .collect();
+        let system = self
+            .estimator
+            .estimate_prompt(request.system.as_deref(), &[]);
+        let mut total = system + tokens.iter().sum::<usize>();
+
+        let mut dropped = 0;
+        while total > limit && dropped + 1 < tokens.len() {
+            total -= tokens[dropped];
+            dropped += 1;
+        }
+        if dropped > 0 {
+            trace!("Dropped {dropped} messages to fit {limit} tokens");
+            request.messages.drain(..dropped);
+        }
+        dropped
+    }
+
+    fn llm(
+        &self,
+        system: Option<String>,
+        seed: Option<u64>,
+    ) -> Result<Box<dyn LLMProvider>, LLMError> {
+        let settings = self.effective_settings();
+        if let Some(ProviderFactory(factory)) = &self.provider_factory {
+            return factory(&settings, system.as_deref());
+        }
+        let api_key = settings.resolve_api_key(OPENROUTER).ok_or_else(|| {
+            LLMError::AuthError(format!(
+                "No API key for {OPENROUTER}, set it in keys or OPENROUTER_API_KEY"
+            ))
+        })?;
+        let mut builder = LLMBuilder::new()
+            .backend(LLMBackend::OpenRouter)
+            .api_key(api_key)
+            .model(settings.model.clone())
+            .temperature(settings.temperature)
+            .max_tokens(settings.max_tokens);
+        if let Some(system) = system {
+            builder = builder.system(system);
+        }
+        if let Some(top_p) = settings.top_p() {
+            builder = builder.top_p(top_p);
+        }
+        let extra_body = self.extra_body(&settings, seed);
+        if !extra_body.is_empty() {
+            builder = builder.extra_body(extra_body);
+        }
+        if !self.dialect().no_tools {
+            for tool in &self.tools {
+                builder = builder.function(tool.to_function());
+            }
+        }
+        builder.build()
+    }
+
+    /// The OpenRouter options the builder has no method for.
+    fn extra_body(
+        &self,
+        settings: &Settings,
+        seed: Option<u64>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let mut extra_body = settings.sampler_body();
+        if let Some(seed) = seed {
+            extra_body.insert("seed".to_string(), seed.into());
+        }
+        if self.supports_reasoning()
+            && let Some(reasoning) = settings.reasoning_body()
+        {
+            extra_body.insert("reasoning".to_string(), reasoning);
+        }
+        if let Some(prefs) = &settings.provider_preferences
+            && let serde_json::Value::Object(prefs) = prefs.to_openrouter_json()
+        {
+            extra_body.extend(prefs);
+        }
+        extra_body
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Node {
+    messages: Vec<Message>,
+    childs: Vec<Node>,
+    selected: usize,
+}
+
+impl Node {
+    fn new() -> Self {
+        Node {
+            messages: vec![],
+            childs: vec![],
+            selected: 0,
+        }
+    }
+
+    fn push_sibling(&mut self, message: Message, child: Node) {
+        self.messages.push(message);
+        self.childs.push(child);
+    }
+
+    fn push(&mut self, message: Message) {
+        match self.childs.is_empty() {
+            true => {
+                self.messages.push(message);
+                self.childs.push(Node::new());
+            }
+            false => self.childs[self.selected].push(message),
+        }
+    }
+
+    /// The message with `id` wherever it is, regardless of the selection.
+    fn find_mut(&mut self, id: usize) -> Option<&mut Message> {
+        self.messages
+            .iter_mut()
+            .zip(&mut self.childs)
+            .find_map(|(message, child)| match message.id() == id {
+                true => Some(message),
+                false => child.find_mut(id),
+            })
+    }
+
+    fn selected_message_mut(&mut self, depth: usize) -> Option<&mut Message> {
+        if self.messages.is_empty() {
+            return None;
+        }
+
+        match depth == 0 {
+            true => Some(&mut self.messages[self.selected]),
+            false => self.childs[self.selected].selected_message_mut(depth - 1),
+        }
+    }
+
+    fn last_message_mut(&mut self) -> Option<&mut Message> {
+        if self.messages.is_empty() {
+            return None;
+        }
+
+        match self.childs[self.selected].childs.is_empty() {
+            true => Some(&mut self.messages[self.selected]),
+            false => self.childs[self.selected].last_message_mut(),
+        }
+    }
+
+    /// The messages of the first `levels` depths, without the hidden ones but the last one.
+    fn prompt_history(&mut self, levels: usize, prefix: &str, history: &mut Vec<PromptMessage>) {
+        if !self.messages.is_empty() && levels > 0 {
+            let child = &mut self.childs[self.selected];
+            let message = &mut self.messages[self.selected];
+            if !message.hidden_from_prompt || child.messages.is_empty() || levels == 1 {
+                history.push(message.prompt_message(prefix));
+            }
+            child.prompt_history(levels - 1, prefix, history);
+        }
+    }
+
+    pub fn get_history(&self, history: &mut Vec<Message>) {
+        if !self.messages.is_empty() {
+            history.push(self.messages[self.selected].clone());
+            self.childs[self.selected].get_history(history);
+        }
+    }
+
+    fn get_levels(&self, levels: &mut Vec<(usize, Vec<Message>)>) {
+        if !self.messages.is_empty() {
+            levels.push((self.selected, self.messages.clone()));
+            self.childs[self.selected].get_levels(levels);
+        }
+    }
+
+    pub fn get_history_structure(&self, structure: &mut Vec<HistoryLevel>) {
+        if !self.messages.is_empty() {
+            structure.push(HistoryLevel {
+                position: self.selected + 1,
+                siblings: self.messages.len(),
+                label: self.messages[self.selected].label.clone(),
+            });
+            self.childs[self.selected].get_history_structure(structure);
+        }
+    }
+
+    fn previous(&mut self, depth: usize) {
+        match depth == 0 {
+            true => {
+                if self.selected > 0 {
+                    self.selected -= 1
+                }
+            }
+            false => self.childs[self.selected].previous(depth - 1),
+        }
+    }
+
+    fn next(&mut self, depth: usize) -> bool {
+        match depth == 0 {
+            true => match self.selected + 1 >= self.messages.len() {
+                true => {
+                    self.messages
+                        .push(self.messages[self.selected].create_brother());
+                    self.childs.push(Node::new());
+                    self.selected += 1;
+                    true
+                }
+                false => {
+                    self.selected += 1;
+                    false
+                }
+            },
+            false => self.childs[self.selected].next(depth - 1),
+        }
+    }
+
+    /// Adds an empty sibling after the others at `depth` and selects it.
+    fn push_brother(&mut self, depth: usize) -> bool {
+        if self.messages.is_empty() {
+            return false;
+        }
+
+        match depth == 0 {
+            true => {
+                self.messages
+                    .push(self.messages[self.selected].create_brother());
+                self.childs.push(Node::new());
+                self.selected = self.messages.len() - 1;
+                true
+            }
+            false => self.childs[self.selected].push_brother(depth - 1),
+        }
+    }
+
+    pub fn add_edit(&mut self, depth: usize, responder_name: String, text: String) -> bool {
+        match depth == 0 {
+            true => {
+                let mut added_response = false;
+                let mut edit = self.messages[self.selected].create_brother();
+                edit.text = text;
+                self.messages.push(edit);
+                let mut new_node = Node::new();
+                if let OwnerType::User = self.messages[self.selected].owner {
+                    new_node
+                        .messages
+                        .push(Message::empty_from_char(0, responder_name));
+                    new_node.childs.push(Node::new());
+                    added_response = true;
+                }
+                self.childs.push(new_node);
+                self.selected = self.messages.len() - 1;
+                added_response
+            }
+            false => self.childs[self.selected].add_edit(depth - 1, responder_name, text),
+        }
+    }
+
+    fn delete(&mut self, depth: usize) {
+        match depth == 0 {
+            true => {
+                self.messages.remove(self.selected);
+                self.childs.remove(self.selected);
+                if self.selected > 0 {
+                    self.selected -= 1
+                }
+            }
+            false => self.childs[self.selected].delete(depth - 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        chat::preview::{PromptPreview, PromptRole},
+        persona::{builder::CardBuilder, card::Card},
+        settings::ProviderPrefs,
+        testing::{MockProvider, TempDir, TestClock, generation_end},
+    };
+
+    fn chat() -> Chat {
+        Chat::with_personas(
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        )
+    }
+
+    fn mock(chat: &mut Chat, tokens: &'static [&'static str]) {
+        chat.set_provider_factory(move |_, _| Ok(Box::new(MockProvider::new(tokens))));
+    }
+
+    /// The system prompt of the last request, which the dialect can merge into the messages.
+    fn prompt(chat: &Chat) -> String {
+        let request = chat.last_request().unwrap();
+        let messages = request.messages.iter().map(|m| &*m.content);
+        request
+            .system
+            .as_deref()
+            .into_iter()
+            .chain(messages)
+            .collect()
+    }
+
+    /// Sends `text` and waits for the reply.
+    async fn exchange(chat: &mut Chat, text: &str) {
+        let mut rx = chat.subscribe();
+        chat.add_user_message(text.to_string());
+        assert!(matches!(
+            generation_end(&mut rx).await,
+            ChatUpdate::StreamFinished { .. }
+        ));
+        stream_ended(chat).await;
+    }
+
+    /// The end update is sent before the stream gives its history back.
+    async fn stream_ended(chat: &Chat) {
+        while chat.is_generating() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// A chat whose requests are its history as is, the default model merging the system prompt
+    /// into the first message.
+    fn plain_chat() -> Chat {
+        let mut chat = chat();
+        chat.set_dialect(Some(Dialect::default()));
+        chat
+    }
+
+    /// The request contents, to compare by address with the ones of another request.
+    fn contents(chat: &Chat) -> Vec<Arc<str>> {
+        let request = chat.last_request().unwrap();
+        request.messages.iter().map(|m| m.content.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn continue_appends_to_the_same_message() {
+        let mut chat = chat();
+        mock(&mut chat, &["Once upon"]);
+        exchange(&mut chat, "Tell me a story").await;
+        let before = chat.get_history();
+        let id = before.last().unwrap().id();
+
+        mock(&mut chat, &[" a time"]);
+        let mut rx = chat.subscribe();
+        chat.continue_last();
+        generation_end(&mut rx).await;
+
+        let after = chat.get_history();
+        assert_eq!(after.len(), before.len());
+        assert_eq!(after.last().unwrap().id(), id);
+        assert_eq!(after.last().unwrap().text.trim(), "Once upon a time");
+        assert_eq!(chat.get_history_structure().last().unwrap().siblings, 1);
+        // The partial message is sent for the model to continue
+        let last = chat
+            .last_request()
+            .unwrap()
+            .messages
+            .last()
+            .unwrap()
+            .clone();
+        assert_eq!(last.role, ChatRole::Assistant);
+        assert_eq!(last.content.trim(), "Once upon");
+    }
+
+    #[tokio::test]
+    async fn nothing_to_continue_after_a_user_message() {
+        let mut chat = chat();
+        chat.root
+            .lock()
+            .unwrap()
+            .push(Message::from_user("User".to_string(), "Hi".to_string()));
+        let mut rx = chat.subscribe();
+        chat.continue_last();
+        assert!(rx.try_recv().is_err());
+        assert!(!chat.is_generating());
+    }
+
+    #[tokio::test]
+    async fn impersonation_writes_the_user_turn() {
+        let mut chat = chat();
+        mock(&mut chat, &["Hello"]);
+        exchange(&mut chat, "Hi").await;
+
+        mock(&mut chat, &["I wave"]);
+        let mut rx = chat.subscribe();
+        chat.impersonate();
+        assert!(matches!(
+            rx.recv().await,
+            Ok(ChatUpdate::MessageCreated {
+                owner: OwnerType::User
+            })
+        ));
+        generation_end(&mut rx).await;
+
+        let history = chat.get_history();
+        assert_eq!(history.len(), 3);
+        let impersonated = history.last().unwrap();
+        assert!(matches!(impersonated.owner, OwnerType::User));
+        assert_eq!(impersonated.text.trim(), "I wave");
+        assert!(prompt(&chat).contains("Write User's next message"));
+        assert!(!chat.is_generating());
+
+        mock(&mut chat, &["Hello again"]);
+        chat.add_edit(2, "I wave back".to_string());
+        generation_end(&mut rx).await;
+        let history = chat.get_history();
+        assert_eq!(history[2].text, "I wave back");
+        assert_eq!(chat.get_history_structure()[2].siblings, 2);
+        assert_eq!(history[3].text.trim(), "Hello again");
+    }
+
+    #[tokio::test]
+    async fn regenerate_rewrites_the_last_reply_in_place() {
+        let mut chat = chat();
+        mock(&mut chat, &["First"]);
+        exchange(&mut chat, "Hi").await;
+        let id = chat.get_history()[1].id();
+
+        mock(&mut chat, &["Second"]);
+        let mut rx = chat.subscribe();
+        chat.regenerate(1);
+        generation_end(&mut rx).await;
+        let history = chat.get_history();
+        assert_eq!(history[1].id(), id);
+        assert_eq!(history[1].text.trim(), "Second");
+        assert_eq!(chat.get_history_structure()[1].siblings, 1);
+    }
+
+    #[tokio::test]
+    async fn regenerate_leaves_user_messages_and_rewrites_earlier_replies() {
+        let mut chat = chat();
+        mock(&mut chat, &["Reply"]);
+        exchange(&mut chat, "One").await;
+        exchange(&mut chat, "Two").await;
+        let id = chat.get_history()[1].id();
+
+        let mut rx = chat.subscribe();
+        chat.regenerate(2);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(chat.get_history()[2].text.trim(), "Two");
+
+        mock(&mut chat, &["Again"]);
+        chat.regenerate(1);
+        generation_end(&mut rx).await;
+        let history = chat.get_history();
+        assert_eq!(history[1].id(), id);
+        assert_eq!(history[1].text.trim(), "Again");
+        assert_eq!(chat.get_history_structure()[1].siblings, 1);
+        // What follows is kept, and left out of the request
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[2].text.trim(), "Two");
+        let request = chat.last_request().unwrap();
+        assert_eq!(request.messages.len(), 1);
+        assert!(request.messages[0].content.ends_with("One\n"));
+    }
+
+    #[tokio::test]
+    async fn requests_convert_only_the_new_messages() {
+        let mut chat = plain_chat();
+        mock(&mut chat, &["Reply"]);
+        exchange(&mut chat, "Turn 0").await;
+        for turn in 1..6 {
+            let before = contents(&chat);
+            let converted = chat.converted_messages();
+            exchange(&mut chat, &format!("Turn {turn}")).await;
+            // The last reply and the new user message
+            assert_eq!(chat.converted_messages() - converted, 2);
+            let after = contents(&chat);
+            assert_eq!(after.len(), before.len() + 2);
+            assert!(before.iter().zip(&after).all(|(a, b)| Arc::ptr_eq(a, b)));
+        }
+    }
+
+    #[tokio::test]
+    async fn edited_messages_are_converted_again() {
+        let mut chat = plain_chat();
+        mock(&mut chat, &["Reply"]);
+        exchange(&mut chat, "One").await;
+        exchange(&mut chat, "Two").await;
+        let before = contents(&chat);
+        let converted = chat.converted_messages();
+
+        let id = chat.get_history()[0].id();
+        chat.edit_in_place(id, "One, edited".to_string()).unwrap();
+        exchange(&mut chat, "Three").await;
+        assert_eq!(chat.converted_messages() - converted, 3);
+        let after = contents(&chat);
+        assert_eq!(after[0].trim(), "One, edited");
+        assert!(!Arc::ptr_eq(&before[0], &after[0]));
+        assert!(
+            before[1..]
+                .iter()
+                .zip(&after[1..])
+                .all(|(a, b)| Arc::ptr_eq(a, b))
+        );
+    }
+
+    #[tokio::test]
+    async fn regenerated_replies_are_sent_as_rewritten() {
+        let mut chat = plain_chat();
+        mock(&mut chat, &["First"]);
+        exchange(&mut chat, "Hi").await;
+        let converted = chat.converted_messages();
+
+        mock(&mut chat, &["Second"]);
+        let mut rx = chat.subscribe();
+        chat.regenerate(1);
+        generation_end(&mut rx).await;
+        stream_ended(&chat).await;
+        // The same history as the request before
+        assert_eq!(chat.converted_messages(), converted);
+
+        exchange(&mut chat, "Again").await;
+        assert_eq!(chat.converted_messages() - converted, 2);
+        assert_eq!(contents(&chat)[1].trim(), "Second");
+    }
+
+    #[tokio::test]
+    async fn renaming_the_char_renames_the_prefixed_replies() {
+        let mut chat = chat();
+        chat.set_dialect(Some(Dialect {
+            assistant_name_prefix: true,
+            ..Default::default()
+        }));
+        mock(&mut chat, &["Reply"]);
+        exchange(&mut chat, "One").await;
+        exchange(&mut chat, "Two").await;
+        let name = chat.char().name().to_string();
+        assert!(contents(&chat)[1].starts_with(&format!("{name}: ")));
+
+        chat.set_char(
+            CardBuilder::from_persona(&chat.char())
+                .name("Renamed")
+                .build(),
+        );
+        let converted = chat.converted_messages();
+        exchange(&mut chat, "Three").await;
+        let after = contents(&chat);
+        for reply in [&after[1], &after[3]] {
+            assert!(reply.starts_with("Renamed: ") && reply.trim().ends_with("Reply"));
+        }
+        // Both replies and the new user message
+        assert_eq!(chat.converted_messages() - converted, 3);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_regenerated_while_generating() {
+        let mut chat = chat();
+        mock(&mut chat, &["Reply"]);
+        exchange(&mut chat, "Hi").await;
+        chat.set_provider_factory(|_, _| {
+            Ok(Box::new(
+                MockProvider::new(&["Slow", " reply"]).delay(Duration::from_millis(50)),
+            ))
+        });
+        let mut rx = chat.subscribe();
+        chat.next(1);
+        let target = chat.get_history()[1].id();
+
+        chat.regenerate(1);
+        chat.retry_last();
+        generation_end(&mut rx).await;
+        let history = chat.get_history();
+        assert_eq!(history[1].id(), target);
+        assert_eq!(history[1].text.trim(), "Slow reply");
+        assert_eq!(chat.get_history_structure()[1].siblings, 2);
+    }
+
+    #[tokio::test]
+    async fn a_new_generation_cancels_the_running_one() {
+        let mut chat = chat();
+        chat.set_provider_factory(|_, _| {
+            Ok(Box::new(
+                MockProvider::new(&["Slow", " reply"]).delay(Duration::from_millis(50)),
+            ))
+        });
+        let mut rx = chat.subscribe();
+        chat.add_user_message("One".to_string());
+        let cancelled = chat.get_history()[1].id();
+        mock(&mut chat, &["Fast"]);
+        chat.add_user_message("Two".to_string());
+
+        assert!(matches!(
+            generation_end(&mut rx).await,
+            ChatUpdate::RequestError(ChatError::Cancelled)
+        ));
+        assert!(matches!(
+            generation_end(&mut rx).await,
+            ChatUpdate::StreamFinished { .. }
+        ));
+        let history = chat.get_history();
+        assert_eq!(history[1].id(), cancelled);
+        assert!(matches!(history[1].status, MessageStatus::Errored(_)));
+        assert_eq!(history[3].text.trim(), "Fast");
+        assert!(!chat.is_generating());
+    }
+
+    fn chat_with_examples(examples: &str, placement: ExamplePlacement) -> PromptPreview {
+        let mut card = Card::basic("Luna", "A moon spirit.");
+        card.data.mes_example = examples.to_string();
+        let settings = Settings {
+            example_placement: Some(placement),
+            ..Settings::default()
+        };
+        let chat = Chat::with_personas(Persona::default_user(), Persona::from_card(card), settings);
+        chat.build_prompt_preview()
+    }
+
+    #[test]
+    fn examples_are_sent_as_turns() {
+        let preview = chat_with_examples(
+            "<START>\n{{user}}: Hi\n{{char}}: Hello",
+            ExamplePlacement::Turns,
+        );
+        let segments: Vec<(PromptRole, &str)> = preview
+            .segments
+            .iter()
+            .map(|s| (s.role, s.content.as_str()))
+            .collect();
+        // The dialect may merge the system prompt into the first turn
+        let [.., (PromptRole::User, hi), (PromptRole::Assistant, "Hello")] = segments[..] else {
+            panic!("Example turns expected in {segments:?}");
+        };
+        assert!(hi.ends_with("Hi"));
+        assert!(segments.iter().all(|(_, s)| !s.contains("User: Hi")));
+    }
+
+    #[test]
+    fn examples_stay_in_the_system_prompt() {
+        let examples = "<START>\n{{user}}: Hi\n{{char}}: Hello";
+        let preview = chat_with_examples(examples, ExamplePlacement::System);
+        assert_eq!(preview.segments.len(), 1);
+        assert!(
+            preview.segments[0]
+                .content
+                .contains("User: Hi\nLuna: Hello")
+        );
+
+        // Without conversations to split there are no turns
+        let preview = chat_with_examples("Luna: Hello", ExamplePlacement::Turns);
+        assert_eq!(preview.segments.len(), 1);
+        assert!(preview.segments[0].content.contains("Luna: Hello"));
+    }
+
+    #[tokio::test]
+    async fn the_factory_gets_the_settings_and_system_prompt() {
+        let mut chat = chat();
+        chat.settings.model = "mock/model".to_string();
+        let calls = Arc::new(Mutex::new(vec![]));
+        let seen = calls.clone();
+        chat.set_provider_factory(move |settings, system| {
+            let call = (settings.model.clone(), system.map(str::to_string));
+            seen.lock().unwrap().push(call);
+            Ok(Box::new(MockProvider::new(&["Hello ", "there"])))
+        });
+        exchange(&mut chat, "Hi").await;
+        exchange(&mut chat, "Again").await;
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        let request = chat.last_request().unwrap();
+        assert_eq!(calls[1], ("mock/model".to_string(), request.system.clone()));
+        let history = chat.get_history();
+        assert_eq!(history.last().unwrap().text.trim(), "Hello there");
+    }
+
+    /// Saves the chat once the stream sent `tokens` tokens, and loads it back.
+    async fn saved_mid_stream(tokens: usize) -> (Chat, usize) {
+        let dir = TempDir::new("mid-stream");
+        let path = dir.path().join("chat.json");
+        let mut chat = chat();
+        let provider = MockProvider::new(&["Once ", "upon ", "a time"]).stall_after(tokens);
+        chat.set_provider_factory(move |_, _| Ok(Box::new(provider.clone())));
+        let mut rx = chat.subscribe();
+        chat.add_user_message("Tell me a story".to_string());
+        let sent = ["", "Once", "Once upon"][tokens];
+        let streamed = async {
+            while !matches!(rx.recv().await, Ok(ChatUpdate::RequestOk)) {}
+            while chat.get_history().last().unwrap().text.trim() != sent {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), streamed)
+            .await
+            .expect("The tokens were not streamed");
+        assert!(chat.is_generating());
+        chat.save_to(&path).unwrap();
+        let id = chat.get_history().last().unwrap().id();
+        chat.stop().await;
+
+        let (loaded, _) = Chat::load_from(
+            &path,
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        )
+        .unwrap();
+        (loaded, id)
+    }
+
+    #[tokio::test]
+    async fn messages_saved_mid_stream_are_recovered() {
+        let (mut chat, id) = saved_mid_stream(2).await;
+        assert!(!chat.is_generating());
+        assert_eq!(chat.recovered(), [id]);
+        let message = chat.get_history().pop().unwrap();
+        assert_eq!(message.id(), id);
+        assert_eq!(message.text.trim(), "Once upon");
+        assert!(matches!(message.status, MessageStatus::Complete));
+        assert!(matches!(message.finish_reason, Some(FinishReason::Length)));
+
+        // Announced to the listeners the chat is handed to
+        let (tx, mut rx) = broadcast::channel(UPDATE_CAPACITY);
+        chat.set_tx(tx);
+        assert!(matches!(rx.try_recv(), Ok(ChatUpdate::Recovered { ids }) if ids == [id]));
+    }
+
+    #[tokio::test]
+    async fn empty_messages_saved_mid_stream_are_errored() {
+        let (chat, id) = saved_mid_stream(0).await;
+        assert!(!chat.is_generating());
+        assert_eq!(chat.recovered(), [id]);
+        let message = chat.get_history().pop().unwrap();
+        assert!(matches!(message.status, MessageStatus::Errored(_)));
+    }
+
+    /// Luna sleeps from 22:00 to 07:00 UTC and works from 09:00 to 17:00, `time` is RFC 3339.
+    fn scheduled_chat(settings: Settings, time: &str) -> (Chat, Arc<TestClock>) {
+        let mut card = Card::basic("Luna", "");
+        let window = |start: &str, end: &str, mode: &str, situation: &str| json!({ "start": start, "end": end, "mode": mode, "situation": situation });
+        card.data.extensions.insert(
+            "libmoon".to_string(),
+            json!({ "schedule": {
+                "timezone": "UTC",
+                "windows": [
+                    window("22:00", "07:00", "delayed_reply", "asleep"),
+                    window("09:00", "17:00", "away_message", "at work"),
+                ],
+            }}),
+        );
+        let mut chat =
+            Chat::with_personas(Persona::default_user(), Persona::from_card(card), settings);
+        let clock = Arc::new(TestClock::new(at(time)));
+        chat.set_wall_clock(clock.clone());
+        mock(&mut chat, &["Hi"]);
+        (chat, clock)
+    }
+
+    fn at(time: &str) -> SystemTime {
+        SystemTime::from(time.parse::<jiff::Timestamp>().unwrap())
+    }
+
+    const NUDGE: &str = "Luna is currently at work and can only answer with a short message.";
+
+    #[tokio::test]
+    async fn replies_wait_for_the_end_of_the_window() {
+        let (mut chat, clock) = scheduled_chat(Settings::default(), "2024-05-01T23:00:00Z");
+        let mut rx = chat.subscribe();
+        chat.add_user_message("Are you awake?".to_string());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ChatUpdate::ReplyScheduled { fire_at }) if fire_at == at("2024-05-02T07:00:00Z")
+        ));
+        assert!(chat.is_generating());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(
+            rx.try_recv().is_err(),
+            "Nothing is sent before the window ends"
+        );
+        chat.stop().await;
+
+        // Once the window ended the reply comes at once
+        clock.set(at("2024-05-02T07:00:00Z"));
+        exchange(&mut chat, "Good morning").await;
+        assert_eq!(chat.get_history().last().unwrap().text.trim(), "Hi");
+    }
+
+    #[tokio::test]
+    async fn away_replies_get_a_nudge() {
+        let (mut chat, clock) = scheduled_chat(Settings::default(), "2024-05-01T16:59:00Z");
+        exchange(&mut chat, "Busy?").await;
+        assert!(prompt(&chat).contains(NUDGE));
+
+        clock.advance(Duration::from_secs(60));
+        exchange(&mut chat, "Still busy?").await;
+        assert!(!prompt(&chat).contains(NUDGE));
+    }
+
+    #[tokio::test]
+    async fn schedules_can_be_turned_off() {
+        let settings = Settings {
+            presence_schedules: false,
+            ..Settings::default()
+        };
+        let (mut chat, _) = scheduled_chat(settings.clone(), "2024-05-01T10:00:00Z");
+        exchange(&mut chat, "Busy?").await;
+        assert!(!prompt(&chat).contains(NUDGE));
+
+        let (mut chat, _) = scheduled_chat(settings, "2024-05-01T23:00:00Z");
+        let mut rx = chat.subscribe();
+        exchange(&mut chat, "Are you awake?").await;
+        let scheduled = std::iter::from_fn(|| rx.try_recv().ok())
+            .any(|u| matches!(u, ChatUpdate::ReplyScheduled { .. }));
+        assert!(!scheduled);
+    }
+
+    #[tokio::test]
+    async fn provider_errors_leave_a_retryable_message() {
+        let mut chat = chat();
+        chat.set_provider_factory(|_, _| Err(LLMError::InvalidRequest("No model".to_string())));
+        let mut rx = chat.subscribe();
+        chat.add_user_message("Hi".to_string());
+
+        assert!(matches!(
+            generation_end(&mut rx).await,
+            ChatUpdate::RequestError(ChatError::Provider(_))
+        ));
+        assert!(!chat.is_generating());
+        let history = chat.get_history();
+        let failed = history.last().unwrap();
+        assert!(matches!(failed.owner, OwnerType::Char(_)));
+        assert!(matches!(failed.status, MessageStatus::Errored(_)));
+        assert!(failed.text.trim().is_empty());
+
+        chat.set_provider_factory(|_, _| Ok(Box::new(MockProvider::new(&["Hello"]))));
+        chat.retry_last();
+        assert!(matches!(
+            generation_end(&mut rx).await,
+            ChatUpdate::StreamFinished { .. }
+        ));
+        let retried = chat.get_history();
+        assert_eq!(retried.len(), history.len());
+        assert_eq!(retried.last().unwrap().id(), failed.id());
+        assert_eq!(retried.last().unwrap().text.trim(), "Hello");
+    }
+
+    fn snapshot(bytes: usize) -> RequestSnapshot {
+        RequestSnapshot {
+            model: String::new(),
+            system: None,
+            messages: vec![PromptMessage::new(ChatRole::User, &"x".repeat(bytes))],
+            char_revision: RevisionTag {
+                hash: 0,
+                label: String::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn snapshots_are_evicted_oldest_first() {
+        let mut chat = chat();
+        let mut rx = chat.subscribe();
+        chat.set_memory_budget(10_000);
+        for bytes in [1000, 2000, 3000, 4000] {
+            chat.push_snapshot(snapshot(bytes));
+        }
+        assert_eq!(chat.memory_usage().get("snapshots"), Some(&10_000));
+        assert!(rx.try_recv().is_err());
+
+        chat.set_memory_budget(7500);
+        let sizes: Vec<usize> = chat
+            .request_snapshots()
+            .map(|s| s.estimated_bytes())
+            .collect();
+        assert_eq!(sizes, [3000, 4000]);
+        assert_eq!(chat.memory_usage().get("snapshots"), Some(&7000));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ChatUpdate::BuffersTrimmed { freed_bytes: 3000 })
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn snapshot_accounting_follows_their_content() {
+        let mut chat = chat();
+        let mut snapshot = snapshot(500);
+        snapshot.model = "model".to_string();
+        snapshot.system = Some("system".to_string());
+        let content: usize = snapshot.model.len() + 6 + 500;
+        chat.push_snapshot(snapshot);
+        let estimate = chat.memory_usage()["snapshots"];
+        assert!(estimate.abs_diff(content) <= content / 10);
+    }
+
+    #[test]
+    fn eviction_keeps_the_tree() {
+        let mut chat = chat();
+        chat.root
+            .lock()
+            .unwrap()
+            .push(Message::from_user("User".to_string(), "Hi".to_string()));
+        let history = chat.get_history().len();
+        chat.push_snapshot(snapshot(100));
+        chat.set_memory_budget(0);
+        assert_eq!(chat.request_snapshots().count(), 0);
+        assert_eq!(chat.get_history().len(), history);
+    }
+
+    #[test]
+    fn provider_preferences_are_in_the_request() {
+        let mut chat = chat();
+        assert!(
+            !chat
+                .extra_body(&chat.settings, None)
+                .contains_key("provider")
+        );
+
+        chat.settings.provider_preferences = Some(ProviderPrefs {
+            order: vec!["Together".to_string()],
+            deny: vec!["DeepInfra".to_string()],
+            ..Default::default()
+        });
+        let body = chat.extra_body(&chat.settings, Some(7));
+        assert_eq!(
+            body.get("provider"),
+            Some(&json!({ "order": ["Together"], "ignore": ["DeepInfra"] }))
+        );
+        assert_eq!(body.get("seed"), Some(&json!(7)));
+    }
+
+    #[test]
+    fn routing_is_read_from_error_bodies() {
+        let error = LLMError::ResponseFormatError {
+            message: "Moderation".to_string(),
+            raw_response: json!({
+                "error": {
+                    "code": 403,
+                    "message": "Input flagged",
+                    "metadata": { "provider_name": "OpenAI", "reasons": ["violence"] }
+                }
+            })
+            .to_string(),
+        };
+        let routing = Chat::error_routing(&error).unwrap();
+        assert_eq!(routing.provider_name.as_deref(), Some("OpenAI"));
+        assert_eq!(routing.moderation_flags, ["violence"]);
+
+        assert!(Chat::error_routing(&LLMError::HttpError("down".to_string())).is_none());
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use llm::chat::ChatRole;
+use serde_json::{Map, Value, json};
+
+use crate::{
+    chat::{Chat, Generation, Node},
+    message::{Message, NARRATOR, OwnerType, PromptMessage},
+};
+
+#[derive(Debug, Clone)]
+pub struct OpenAiOptions {
+    /// Start with the system prompt, when the dialect keeps it apart.
+    pub include_system: bool,
+    /// The text of the messages after `Message::clean`, the raw text otherwise.
+    pub clean: bool,
+}
+
+impl Default for OpenAiOptions {
+    fn default() -> Self {
+        Self {
+            include_system: true,
+            clean: false,
+        }
+    }
+}
+
+/// Conversations as the `[{"role": ..., "content": ...}]` arrays of the OpenAI chat format,
+/// built like the requests so they match what the model is sent. The whole history is kept,
+/// `Settings::context_limit` does not apply, and the attachments are left out.
+impl Chat {
+    pub fn export_openai_json(&self, include_system: bool) -> String {
+        self.export_openai_json_with(&OpenAiOptions {
+            include_system,
+            ..OpenAiOptions::default()
+        })
+    }
+
+    /// The selected history.
+    pub fn export_openai_json_with(&self, options: &OpenAiOptions) -> String {
+        let mut messages = self.get_history();
+        let last = messages.len().saturating_sub(1);
+        let history = messages
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, m)| !m.hidden_from_prompt || *i == last)
+            .map(|(_, m)| Self::openai_prompt_message(m, options.clean))
+            .collect();
+        let messages = self.openai_messages(history, options);
+        serde_json::to_string_pretty(&messages).unwrap_or_default()
+    }
+
+    /// Every branch of the tree, from the first message to one without replies, keyed by the
+    /// index of its message among the siblings at each depth, as in "0.2.1". Branches come in
+    /// the order of these indices.
+    pub fn export_openai_branches(&self, options: &OpenAiOptions) -> String {
+        let mut branches = vec![];
+        self.root
+            .lock()
+            .unwrap()
+            .branches(options.clean, &mut vec![], &mut vec![], &mut branches);
+        let branches: Map<String, Value> = branches
+            .into_iter()
+            .map(|(path, history)| {
+                let key = path
+                    .iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(".");
+                (key, Value::Array(self.openai_messages(history, options)))
+            })
+            .collect();
+        serde_json::to_string_pretty(&branches).unwrap_or_default()
+    }
+
+    fn openai_messages(&self, history: Vec<PromptMessage>, options: &OpenAiOptions) -> Vec<Value> {
+        // Keeps the last message, which a reply would be written into
+        let (request, _) = self.build_request_for(Generation::Continue, None, false, history);
+        let system = request
+            .system
+            .filter(|_| options.include_system)
+            .map(|content| json!({ "role": "system", "content": content }));
+        let messages = request.messages.iter().map(|m| {
+            let role = match m.role {
+                ChatRole::User => "user",
+                ChatRole::Assistant => "assistant",
+            };
+            json!({ "role": role, "content": &*m.content })
+        });
+        system.into_iter().chain(messages).collect()
+    }
+
+    fn openai_prompt_message(message: &mut Message, clean: bool) -> PromptMessage {
+        let mut prompt = message.prompt_message("");
+        if clean {
+            prompt.content = match message.owner {
+                OwnerType::System => Arc::from(format!("[{NARRATOR}: {}]", message.clean())),
+                _ => Arc::from(message.clean()),
+            };
+        }
+        prompt
+    }
+}
+
+impl Node {
+    fn branches(
+        &mut self,
+        clean: bool,
+        path: &mut Vec<usize>,
+        history: &mut Vec<PromptMessage>,
+        branches: &mut Vec<(Vec<usize>, Vec<PromptMessage>)>,
+    ) {
+        for (i, (message, child)) in self.messages.iter_mut().zip(&mut self.childs).enumerate() {
+            // Like the requests, a hidden message is sent only when it ends the branch
+            let leaf = child.messages.is_empty();
+            let sent = !message.hidden_from_prompt || leaf;
+            path.push(i);
+            if sent {
+                history.push(Chat::openai_prompt_message(message, clean));
+            }
+            match leaf {
+                true => branches.push((path.clone(), history.clone())),
+                false => child.branches(clean, path, history, branches),
+            }
+            path.pop();
+            if sent {
+                history.pop();
+            }
+        }
+    }
+}
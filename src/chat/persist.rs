@@ -0,0 +1,168 @@
+use std::{fs, io::Write, path::Path, sync::atomic::Ordering, time::SystemTime};
+
+use anyhow::{Result, anyhow};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    chat::{
+        Chat, Node, meta::ChatMeta, repair::RepairReport, revision::SavedRevision, rng::ChatRng,
+    },
+    persona::Persona,
+    settings::Settings,
+};
+
+/// 2 puts the `ChatMeta` on a line of its own ahead of the tree.
+pub const SAVE_VERSION: u32 = 2;
+
+/// On-disk form of a chat tree.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SavedChat {
+    pub version: u32,
+    /// Device that last wrote the file.
+    pub device: String,
+    /// Lamport clock, bumped on every save and past both sides on merge.
+    pub clock: u64,
+    /// Written as the header, None for files older than it.
+    #[serde(skip)]
+    pub meta: Option<ChatMeta>,
+    pub(crate) root: Node,
+    #[serde(default)]
+    pub(crate) revisions: Vec<SavedRevision>,
+    #[serde(default)]
+    pub(crate) rng: Option<ChatRng>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) pins: Vec<SystemTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) authors_note: Option<String>,
+}
+
+impl SavedChat {
+    pub fn load(path: &Path) -> Result<Self> {
+        let (header, tree) = Self::split(&fs::read(path)?)?;
+        let mut saved: SavedChat = serde_json::from_value(tree)?;
+        saved.meta = header.map(serde_json::from_value).transpose()?;
+        Ok(saved)
+    }
+
+    /// The header and the tree of a save file, which has no header before version 2.
+    pub(crate) fn split(content: &[u8]) -> Result<(Option<Value>, Value)> {
+        let mut values = serde_json::Deserializer::from_slice(content).into_iter::<Value>();
+        let first = values.next().ok_or(anyhow!("Empty chat file"))??;
+        match values.next() {
+            Some(tree) => Ok((Some(first), tree?)),
+            None => Ok((None, first)),
+        }
+    }
+
+    /// Written next to `path` then moved over it, a crash while saving leaves the previous save.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = fs::File::create(&tmp)?;
+        if let Some(meta) = &self.meta {
+            writeln!(file, "{}", serde_json::to_string(meta)?)?;
+        }
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+impl Chat {
+    pub fn to_saved(&self) -> SavedChat {
+        SavedChat {
+            meta: Some(self.meta()),
+            root: self.root.lock().unwrap().clone(),
+            ..self.saved_parts()
+        }
+    }
+
+    /// `to_saved` without the tree and the metadata.
+    pub(crate) fn saved_parts(&self) -> SavedChat {
+        SavedChat {
+            version: SAVE_VERSION,
+            device: self.settings.device_id.clone(),
+            clock: self.clock.load(Ordering::Relaxed),
+            meta: None,
+            root: Node::new(),
+            revisions: self.saved_revisions(),
+            rng: Some(self.rng),
+            pins: self.pins.timestamps().to_vec(),
+            authors_note: self.authors_note.clone(),
+        }
+    }
+
+    /// The save is repaired first, see `SavedChat::load_repaired`. Messages sharing a creation
+    /// timestamp, from a merge or a hand edit, get distinct ones so their ids stay unique.
+    pub fn from_saved(
+        saved: SavedChat,
+        user: Persona,
+        char: Persona,
+        settings: Settings,
+    ) -> (Self, RepairReport) {
+        let mut report = RepairReport::default();
+        let chat = Self::from_repaired(saved, &mut report, user, char, settings);
+        (chat, report)
+    }
+
+    fn from_repaired(
+        mut saved: SavedChat,
+        report: &mut RepairReport,
+        user: Persona,
+        char: Persona,
+        settings: Settings,
+    ) -> Self {
+        saved.repair(report);
+        let recovered = saved.root.recover_interrupted();
+        if !recovered.is_empty() {
+            warn!(
+                "{} messages were interrupted while generating",
+                recovered.len()
+            );
+        }
+        let names = [user.name(), char.name()];
+        let mut meta = match saved.meta {
+            Some(meta) => meta,
+            None => ChatMeta::of_tree(&saved.root, &names),
+        };
+        for name in names {
+            meta.add_persona(name);
+        }
+        let mut chat = Self::from_root(saved.root, user, char, settings);
+        meta.message_count = chat.meta().message_count;
+        *chat.meta.lock().unwrap() = meta;
+        chat.clock.store(saved.clock, Ordering::Relaxed);
+        chat.restore_revisions(saved.revisions);
+        chat.pins = saved.pins.into();
+        chat.authors_note = saved.authors_note;
+        chat.recovered = recovered;
+        if let Some(rng) = saved.rng {
+            chat.rng = rng;
+        }
+        chat
+    }
+
+    pub fn save_to(&mut self, path: &Path) -> Result<()> {
+        self.clock.fetch_add(1, Ordering::Relaxed);
+        self.to_saved().save(path)
+    }
+
+    /// Loads a possibly damaged save, with what was repaired to load it. Only unreadable JSON
+    /// is an error.
+    pub fn load_from(
+        path: &Path,
+        user: Persona,
+        char: Persona,
+        settings: Settings,
+    ) -> Result<(Self, RepairReport)> {
+        let (saved, mut report) = SavedChat::load_repaired(path)?;
+        let chat = Self::from_repaired(saved, &mut report, user, char, settings);
+        Ok((chat, report))
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::SystemTime,
+};
+
+use log::warn;
+
+use crate::{
+    chat::{Chat, ChatUpdate, Node},
+    message::{Message, timestamp_id},
+};
+
+pub const MAX_PINS: usize = 32;
+
+/// A pinned message with where it is, see `Chat::pinned_messages`.
+#[derive(Debug, Clone)]
+pub struct PinnedMessage {
+    pub id: usize,
+    pub message: Message,
+    /// `Chat::select_path_to` brings an off path pin into the history.
+    pub on_selected_path: bool,
+}
+
+/// The pinned messages by creation time in pinning order, as saved, indexed by id.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pins {
+    order: Vec<SystemTime>,
+    ids: HashSet<usize>,
+}
+
+impl From<Vec<SystemTime>> for Pins {
+    fn from(order: Vec<SystemTime>) -> Self {
+        let ids = order.iter().map(|t| timestamp_id(*t)).collect();
+        Self { order, ids }
+    }
+}
+
+impl Pins {
+    pub(crate) fn contains(&self, id: usize) -> bool {
+        self.ids.contains(&id)
+    }
+
+    pub(crate) fn timestamps(&self) -> &[SystemTime] {
+        &self.order
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.order.clear();
+        self.ids.clear();
+    }
+
+    fn insert(&mut self, timestamp: SystemTime) {
+        if self.ids.insert(timestamp_id(timestamp)) {
+            self.order.push(timestamp);
+        }
+    }
+
+    /// Returns how many of `ids` were pinned.
+    fn remove(&mut self, ids: &HashSet<usize>) -> usize {
+        let len = self.order.len();
+        self.order.retain(|t| !ids.contains(&timestamp_id(*t)));
+        self.ids.retain(|id| !ids.contains(id));
+        len - self.order.len()
+    }
+}
+
+impl Chat {
+    /// Pins a message from any branch. Returns false when it is not in the chat or `MAX_PINS` is reached.
+    pub fn pin_message(&mut self, id: usize) -> bool {
+        let Some(timestamp) = self.root.lock().unwrap().find(id).map(|m| m.timestamp()) else {
+            return false;
+        };
+        if self.pins.contains(id) {
+            return true;
+        }
+        if self.pins.len() >= MAX_PINS {
+            warn!("Cannot pin more than {MAX_PINS} messages");
+            return false;
+        }
+        self.pins.insert(timestamp);
+        self.pins_changed();
+        true
+    }
+
+    pub fn unpin_message(&mut self, id: usize) -> bool {
+        let unpinned = self.pins.remove(&HashSet::from([id])) > 0;
+        if unpinned {
+            self.pins_changed();
+        }
+        unpinned
+    }
+
+    /// Pinned messages in pinning order, wherever they are in the tree.
+    pub fn pinned_messages(&self) -> Vec<PinnedMessage> {
+        let mut found = HashMap::new();
+        self.root
+            .lock()
+            .unwrap()
+            .collect_pinned(&self.pins, true, &mut found);
+        self.pins
+            .timestamps()
+            .iter()
+            .filter_map(|t| found.remove(&timestamp_id(*t)))
+            .collect()
+    }
+
+    pub fn is_pinned(&self, message: &Message) -> bool {
+        self.pins.contains(message.id())
+    }
+
+    /// Ids of the pinned messages among the message and its replies, to unpin with
+    /// `unpin_deleted` once it is deleted.
+    pub(crate) fn pins_under(&self, id: usize) -> HashSet<usize> {
+        let root = self.root.lock().unwrap();
+        let Some((message, replies)) = root.subtree(id) else {
+            return HashSet::new();
+        };
+        let mut found = HashMap::new();
+        replies.collect_pinned(&self.pins, false, &mut found);
+        let mut ids: HashSet<usize> = found.into_keys().collect();
+        if self.pins.contains(message.id()) {
+            ids.insert(message.id());
+        }
+        ids
+    }
+
+    /// Drops the pins of the deleted messages `ids`, returning how many were dropped.
+    pub(crate) fn unpin_deleted(&mut self, ids: &HashSet<usize>) -> usize {
+        let dropped = self.pins.remove(ids);
+        if dropped > 0 {
+            warn!("Unpinned {dropped} deleted messages");
+            self.pins_changed();
+        }
+        dropped
+    }
+
+    fn pins_changed(&self) {
+        self.schedule_autosave();
+        Self::send_update(&self.tx, ChatUpdate::PinsChanged);
+    }
+}
+
+impl Node {
+    pub(crate) fn find(&self, id: usize) -> Option<&Message> {
+        self.find_where(&|m| m.id() == id)
+    }
+
+    pub(crate) fn find_by_timestamp(&self, timestamp: SystemTime) -> Option<&Message> {
+        self.find_where(&|m| m.timestamp() == timestamp)
+    }
+
+    fn find_where(&self, predicate: &dyn Fn(&Message) -> bool) -> Option<&Message> {
+        self.messages
+            .iter()
+            .zip(&self.childs)
+            .find_map(|(message, child)| match predicate(message) {
+                true => Some(message),
+                false => child.find_where(predicate),
+            })
+    }
+
+    /// The message with its replies.
+    fn subtree(&self, id: usize) -> Option<(&Message, &Node)> {
+        self.messages
+            .iter()
+            .zip(&self.childs)
+            .find_map(|(message, child)| match message.id() == id {
+                true => Some((message, child)),
+                false => child.subtree(id),
+            })
+    }
+
+    /// The pinned messages of the tree by id, in one walk.
+    fn collect_pinned(
+        &self,
+        pins: &Pins,
+        on_path: bool,
+        found: &mut HashMap<usize, PinnedMessage>,
+    ) {
+        for (i, (message, child)) in self.messages.iter().zip(&self.childs).enumerate() {
+            let on_path = on_path && i == self.selected;
+            let id = message.id();
+            if pins.contains(id) {
+                found.insert(
+                    id,
+                    PinnedMessage {
+                        id,
+                        message: message.clone(),
+                        on_selected_path: on_path,
+                    },
+                );
+            }
+            child.collect_pinned(pins, on_path, found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        persona::Persona,
+        settings::Settings,
+        testing::{MockProvider, generation_end},
+    };
+
+    async fn chat_with_swipes() -> Chat {
+        let mut chat = Chat::with_personas(
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        chat.set_provider_factory(|_, _| Ok(Box::new(MockProvider::new(&["Reply"]))));
+        let mut rx = chat.subscribe();
+        chat.add_user_message("Hi".to_string());
+        generation_end(&mut rx).await;
+        chat.next(1);
+        generation_end(&mut rx).await;
+        chat
+    }
+
+    #[tokio::test]
+    async fn messages_off_the_selected_branch_can_be_pinned() {
+        let mut chat = chat_with_swipes().await;
+        chat.previous(1);
+        let first = chat.get_history()[1].id();
+        chat.next(1);
+        let second = chat.get_history()[1].id();
+
+        assert!(chat.pin_message(first));
+        assert!(chat.pin_message(second));
+        // Pinning twice keeps one pin
+        assert!(chat.pin_message(first));
+        assert!(!chat.pin_message(12345));
+
+        let pinned = chat.pinned_messages();
+        let pins: Vec<_> = pinned.iter().map(|p| (p.id, p.on_selected_path)).collect();
+        assert_eq!(pins, [(first, false), (second, true)]);
+        assert_eq!(pinned[0].message.id(), first);
+
+        assert!(chat.unpin_message(first));
+        assert!(!chat.unpin_message(first));
+        assert_eq!(chat.pinned_messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_pinned_message_unpins_it() {
+        let mut chat = chat_with_swipes().await;
+        let reply = chat.get_history()[1].id();
+        let user = chat.get_history()[0].id();
+        chat.pin_message(user);
+        chat.pin_message(reply);
+
+        let mut rx = chat.subscribe();
+        assert_eq!(chat.delete(1), 1);
+        assert!(matches!(rx.try_recv(), Ok(ChatUpdate::PinsChanged)));
+        let pinned: Vec<_> = chat.pinned_messages().iter().map(|p| p.id).collect();
+        assert_eq!(pinned, [user]);
+
+        // With its replies
+        assert_eq!(chat.delete_by_id(user), Some(1));
+        assert!(chat.pinned_messages().is_empty());
+        assert_eq!(chat.delete_by_id(user), None);
+    }
+
+    #[test]
+    fn saved_pins_are_indexed_by_id() {
+        let message = Message::from_user("User".to_string(), "Hi".to_string());
+        let pins = Pins::from(vec![message.timestamp()]);
+        assert!(pins.contains(message.id()));
+        assert_eq!(pins.timestamps(), [message.timestamp()]);
+    }
+}
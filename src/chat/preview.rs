@@ -0,0 +1,80 @@
+use llm::chat::ChatRole;
+
+use crate::{
+    chat::{Chat, Generation},
+    lorebook::LorePosition,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptRole {
+    System,
+    User,
+    Assistant,
+}
+
+/// One message of the prompt as the provider receives it.
+#[derive(Debug, Clone)]
+pub struct PromptSegment {
+    pub role: PromptRole,
+    pub content: String,
+}
+
+/// A lore entry the history triggered, and where in the system prompt it was put.
+#[derive(Debug, Clone)]
+pub struct PreviewLore {
+    pub name: Option<String>,
+    pub position: LorePosition,
+    pub content: String,
+}
+
+/// The prompt the next generation would send, built without calling the provider.
+#[derive(Debug, Clone)]
+pub struct PromptPreview {
+    pub model: String,
+    /// The system prompt first when the dialect keeps one, then the history.
+    pub segments: Vec<PromptSegment>,
+    /// Oldest messages left out to fit `Settings::context_limit`.
+    pub dropped: usize,
+    pub estimated_tokens: usize,
+    /// Triggered lore in insertion order.
+    pub lore: Vec<PreviewLore>,
+}
+
+impl Chat {
+    /// Assembles the prompt exactly like a reply generation does. The last message is left out
+    /// as the reply it would be replaced by.
+    pub fn build_prompt_preview(&self) -> PromptPreview {
+        let (request, dropped) = self.build_request(Generation::Reply, None, true);
+        let estimated_tokens = self
+            .estimator
+            .estimate_prompt(request.system.as_deref(), &request.messages);
+        let lore = self
+            .triggered_lore(&self.request_history(Generation::Reply))
+            .into_iter()
+            .map(|e| PreviewLore {
+                name: e.name.clone().or_else(|| e.comment.clone()),
+                position: LorePosition::of(e),
+                content: e.content.clone(),
+            })
+            .collect();
+
+        let system = request.system.map(|content| PromptSegment {
+            role: PromptRole::System,
+            content,
+        });
+        let messages = request.messages.iter().map(|m| PromptSegment {
+            role: match m.role {
+                ChatRole::User => PromptRole::User,
+                ChatRole::Assistant => PromptRole::Assistant,
+            },
+            content: m.content.to_string(),
+        });
+        PromptPreview {
+            model: request.model,
+            segments: system.into_iter().chain(messages).collect(),
+            dropped,
+            estimated_tokens,
+            lore,
+        }
+    }
+}
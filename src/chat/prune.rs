@@ -0,0 +1,211 @@
+use log::trace;
+
+use crate::chat::{Chat, Node, pins::Pins, siblings::SelectionError};
+
+/// Why a sibling off the selected history was not pruned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeptReason {
+    Pinned,
+    /// A message among its replies is pinned, the first one found.
+    PinnedReply {
+        id: usize,
+    },
+}
+
+/// What `Chat::prune_unselected` or `prune_at` did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PruneReport {
+    /// Messages removed, replies of the removed siblings included.
+    pub removed: usize,
+    /// Siblings left off the selected history, by id, in tree order.
+    pub kept: Vec<(usize, KeptReason)>,
+}
+
+/// Why `prune_level` keeps a sibling.
+enum Keep {
+    Selected,
+    Because(KeptReason),
+}
+
+/// Removing the alternatives that were not kept. A sibling with a pinned message in its replies
+/// is kept as well, unpin it first to prune it.
+impl Chat {
+    /// Removes every sibling off the selected history.
+    pub fn prune_unselected(&mut self) -> PruneReport {
+        let mut report = PruneReport::default();
+        self.root
+            .lock()
+            .unwrap()
+            .prune_path(&self.pins, &mut report);
+        self.touch();
+        trace!(
+            "Pruned {} messages, kept {} siblings",
+            report.removed,
+            report.kept.len()
+        );
+        report
+    }
+
+    /// Removes the siblings of the selected message at `depth` only.
+    pub fn prune_at(&mut self, depth: usize) -> Result<PruneReport, SelectionError> {
+        let mut report = PruneReport::default();
+        let mut root = self.root.lock().unwrap();
+        root.level_mut(depth)?.prune_level(&self.pins, &mut report);
+        drop(root);
+        self.touch();
+        trace!("Pruned {} messages at depth {depth}", report.removed);
+        Ok(report)
+    }
+
+    /// What `prune_unselected` would remove, without removing anything.
+    pub fn count_prunable(&self) -> usize {
+        self.root.lock().unwrap().count_path(&self.pins)
+    }
+}
+
+impl Node {
+    fn prune_path(&mut self, pins: &Pins, report: &mut PruneReport) {
+        if self.messages.is_empty() {
+            return;
+        }
+        self.prune_level(pins, report);
+        self.childs[self.selected].prune_path(pins, report);
+    }
+
+    fn count_path(&self, pins: &Pins) -> usize {
+        if self.messages.is_empty() {
+            return 0;
+        }
+        self.count_level(pins) + self.childs[self.selected].count_path(pins)
+    }
+
+    /// Removes the siblings of the selected message, adding them to `report`.
+    fn prune_level(&mut self, pins: &Pins, report: &mut PruneReport) {
+        let keep = self.kept(pins);
+        self.selected = keep[..self.selected].iter().filter(|k| k.is_some()).count();
+        let siblings = std::mem::take(&mut self.messages)
+            .into_iter()
+            .zip(std::mem::take(&mut self.childs));
+        for ((message, child), keep) in siblings.zip(keep) {
+            match keep {
+                Some(Keep::Selected) => (),
+                Some(Keep::Because(reason)) => report.kept.push((message.id(), reason)),
+                None => {
+                    report.removed += 1 + child.count_messages();
+                    continue;
+                }
+            }
+            self.push_sibling(message, child);
+        }
+    }
+
+    fn count_level(&self, pins: &Pins) -> usize {
+        self.kept(pins)
+            .iter()
+            .zip(&self.childs)
+            .filter(|(keep, _)| keep.is_none())
+            .map(|(_, child)| 1 + child.count_messages())
+            .sum()
+    }
+
+    /// If each sibling stays and why, None for those pruned.
+    fn kept(&self, pins: &Pins) -> Vec<Option<Keep>> {
+        self.messages
+            .iter()
+            .zip(&self.childs)
+            .enumerate()
+            .map(|(i, (message, child))| match i == self.selected {
+                true => Some(Keep::Selected),
+                false if pins.contains(message.id()) => Some(Keep::Because(KeptReason::Pinned)),
+                false => child
+                    .first_pin(pins)
+                    .map(|id| Keep::Because(KeptReason::PinnedReply { id })),
+            })
+            .collect()
+    }
+
+    fn first_pin(&self, pins: &Pins) -> Option<usize> {
+        self.messages
+            .iter()
+            .zip(&self.childs)
+            .find_map(|(message, child)| match pins.contains(message.id()) {
+                true => Some(message.id()),
+                false => child.first_pin(pins),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        persona::Persona,
+        settings::Settings,
+        testing::{MockProvider, generation_end},
+    };
+
+    /// "Hi" with three replies, the first one answered by "More" and its own reply, the last
+    /// one selected.
+    async fn three_replies() -> (Chat, [usize; 3], usize) {
+        let mut chat = Chat::with_personas(
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        chat.set_provider_factory(|_, _| Ok(Box::new(MockProvider::new(&["Reply"]))));
+        let mut rx = chat.subscribe();
+        chat.add_user_message("Hi".to_string());
+        generation_end(&mut rx).await;
+        chat.add_user_message("More".to_string());
+        generation_end(&mut rx).await;
+        let more = chat.get_history()[2].id();
+        let mut replies = [0; 3];
+        replies[0] = chat.get_history()[1].id();
+        for reply in &mut replies[1..] {
+            chat.next(1);
+            generation_end(&mut rx).await;
+            *reply = chat.get_history()[1].id();
+        }
+        (chat, replies, more)
+    }
+
+    #[tokio::test]
+    async fn subtrees_with_a_pin_are_kept() {
+        let (mut chat, [first, second, third], more) = three_replies().await;
+        chat.pin_message(more);
+        assert_eq!(chat.count_prunable(), 1);
+
+        let report = chat.prune_unselected();
+        assert_eq!(report.removed, 1);
+        assert_eq!(report.kept, [(first, KeptReason::PinnedReply { id: more })]);
+        let structure = chat.get_history_structure();
+        assert_eq!((structure[1].position, structure[1].siblings), (2, 2));
+        assert_eq!(chat.get_history()[1].id(), third);
+        assert!(chat.root.lock().unwrap().find(second).is_none());
+        assert!(chat.root.lock().unwrap().find(more).is_some());
+    }
+
+    #[tokio::test]
+    async fn pinned_siblings_are_kept() {
+        let (mut chat, [first, second, _], more) = three_replies().await;
+        chat.pin_message(second);
+
+        let report = chat.prune_at(1).unwrap();
+        // The first reply goes with "More" and its reply
+        assert_eq!(report.removed, 3);
+        assert_eq!(report.kept, [(second, KeptReason::Pinned)]);
+        assert!(chat.root.lock().unwrap().find(first).is_none());
+        assert!(chat.root.lock().unwrap().find(more).is_none());
+        assert!(chat.prune_at(5).is_err());
+    }
+
+    #[tokio::test]
+    async fn unpinned_siblings_are_all_pruned() {
+        let (mut chat, _, _) = three_replies().await;
+        let report = chat.prune_unselected();
+        assert_eq!(report.removed, 4);
+        assert!(report.kept.is_empty());
+        assert_eq!(chat.get_history_structure()[1].siblings, 1);
+        assert_eq!(chat.count_prunable(), 0);
+    }
+}
@@ -0,0 +1,508 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    chat::{
+        Chat, Node,
+        error::ChatError,
+        persist::{SAVE_VERSION, SavedChat},
+    },
+    message::{FinishReason, Message, MessageStatus, OwnerType},
+};
+
+/// What was changed to make a damaged save loadable.
+#[derive(Debug, Clone, Default)]
+pub struct RepairReport {
+    /// Of the save, None for a chat given as a `SavedChat`.
+    pub path: Option<PathBuf>,
+    pub actions: Vec<String>,
+}
+
+impl RepairReport {
+    fn new(path: &Path) -> Self {
+        Self {
+            path: Some(path.to_path_buf()),
+            actions: vec![],
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    fn push(&mut self, action: String) {
+        match &self.path {
+            Some(path) => warn!("Repairing {path:?}: {action}"),
+            None => warn!("Repairing the chat: {action}"),
+        }
+        self.actions.push(action);
+    }
+}
+
+impl SavedChat {
+    /// Loads a save, repairing what can be repaired. Only unreadable JSON is an error.
+    /// Nothing is written, see `Chat::save_repaired`. The tree is repaired by `Chat::from_saved`.
+    pub fn load_repaired(path: &Path) -> Result<(Self, RepairReport)> {
+        let (header, value) = Self::split(&fs::read(path)?)?;
+        let mut report = RepairReport::new(path);
+        let mut saved = match serde_json::from_value::<Self>(value.clone()) {
+            Ok(saved) => saved,
+            Err(e) => {
+                report.push(format!("Rebuilt from its readable parts ({e})"));
+                Self::from_value_lenient(&value, &mut report)?
+            }
+        };
+        // Rebuilt from the tree by `Chat::from_saved` when unreadable
+        saved.meta = header.and_then(|header| match serde_json::from_value(header) {
+            Ok(meta) => Some(meta),
+            Err(e) => {
+                report.push(format!("Dropped the unreadable header ({e})"));
+                None
+            }
+        });
+        Ok((saved, report))
+    }
+
+    /// Makes the tree consistent, whatever wrote it.
+    pub(crate) fn repair(&mut self, report: &mut RepairReport) {
+        self.root.repair(&mut vec![], report);
+        self.dedup_timestamps(report);
+
+        let len = self.pins.len();
+        let root = &self.root;
+        self.pins.retain(|t| root.find_by_timestamp(*t).is_some());
+        if self.pins.len() != len {
+            report.push(format!(
+                "Dropped {} pins of missing messages",
+                len - self.pins.len()
+            ));
+        }
+    }
+
+    fn from_value_lenient(value: &Value, report: &mut RepairReport) -> Result<Self> {
+        let root = value
+            .get("root")
+            .ok_or_else(|| anyhow!("No chat tree in the save"))?;
+        Ok(Self {
+            version: field(value, "version", report).unwrap_or(SAVE_VERSION),
+            device: field(value, "device", report).unwrap_or_default(),
+            clock: field(value, "clock", report).unwrap_or_default(),
+            meta: None,
+            root: Node::from_value_lenient(root, &mut vec![], report),
+            revisions: field(value, "revisions", report).unwrap_or_default(),
+            rng: field(value, "rng", report).unwrap_or_default(),
+            pins: field(value, "pins", report).unwrap_or_default(),
+            authors_note: field(value, "authors_note", report).unwrap_or_default(),
+        })
+    }
+
+    fn dedup_timestamps(&mut self, report: &mut RepairReport) {
+        let moved = self.root.dedup_ids();
+        if moved > 0 {
+            report.push(format!("Reassigned {moved} duplicate message ids"));
+        }
+    }
+}
+
+/// A missing field is not worth reporting, an unreadable one is.
+fn field<T: DeserializeOwned>(value: &Value, key: &str, report: &mut RepairReport) -> Option<T> {
+    let field = value.get(key)?;
+    match serde_json::from_value(field.clone()) {
+        Ok(field) => Some(field),
+        Err(e) => {
+            report.push(format!("Dropped unreadable {key} ({e})"));
+            None
+        }
+    }
+}
+
+impl Node {
+    fn from_value_lenient(value: &Value, path: &mut Vec<usize>, report: &mut RepairReport) -> Self {
+        let mut node = Node::new();
+        let empty = vec![];
+        let messages = value
+            .get("messages")
+            .and_then(|m| m.as_array())
+            .unwrap_or(&empty);
+        let childs = value
+            .get("childs")
+            .and_then(|c| c.as_array())
+            .unwrap_or(&empty);
+        for (i, message) in messages.iter().enumerate() {
+            let Some(child) = childs.get(i) else {
+                break;
+            };
+            match serde_json::from_value::<Message>(message.clone()) {
+                Ok(message) => {
+                    path.push(node.messages.len());
+                    let child = Self::from_value_lenient(child, path, report);
+                    path.pop();
+                    node.messages.push(message);
+                    node.childs.push(child);
+                }
+                Err(e) => report.push(format!(
+                    "Dropped the unreadable message at {:?} and its replies ({e})",
+                    [path.as_slice(), &[i]].concat()
+                )),
+            }
+        }
+        if messages.len() != childs.len() {
+            report.push(format!(
+                "Truncated {} messages and {} branches at {path:?} to the shorter length",
+                messages.len(),
+                childs.len()
+            ));
+        }
+        node.selected = value
+            .get("selected")
+            .and_then(|s| s.as_u64())
+            .unwrap_or_default() as usize;
+        node
+    }
+
+    fn repair(&mut self, path: &mut Vec<usize>, report: &mut RepairReport) {
+        let len = self.messages.len().min(self.childs.len());
+        if self.messages.len() != self.childs.len() {
+            report.push(format!(
+                "Truncated {} messages and {} branches at {path:?} to the shorter length",
+                self.messages.len(),
+                self.childs.len()
+            ));
+            self.messages.truncate(len);
+            self.childs.truncate(len);
+        }
+        if self.selected >= len.max(1) {
+            let selected = len.saturating_sub(1);
+            report.push(format!(
+                "Selection {} at {path:?} out of range, clamped to {selected}",
+                self.selected
+            ));
+            self.selected = selected;
+        }
+        for (i, message) in self.messages.iter_mut().enumerate() {
+            // Chats have a single char
+            if let OwnerType::Char(char) = message.owner
+                && char != 0
+            {
+                report.push(format!(
+                    "Unknown char {char} at {:?}, reassigned to the chat's char",
+                    [path.as_slice(), &[i]].concat()
+                ));
+                message.owner = OwnerType::Char(0);
+            }
+        }
+        for (i, child) in self.childs.iter_mut().enumerate() {
+            path.push(i);
+            child.repair(path, report);
+            path.pop();
+        }
+    }
+
+    /// Message ids are derived from creation timestamps, duplicates are moved by a nanosecond.
+    /// Returns how many were moved.
+    pub(crate) fn dedup_ids(&mut self) -> usize {
+        let mut seen = HashSet::new();
+        let mut moved = 0;
+        self.for_each_message_mut(&mut |message| {
+            let mut timestamp = message.timestamp();
+            if seen.contains(&timestamp) {
+                while seen.contains(&timestamp) {
+                    timestamp += Duration::from_nanos(1);
+                }
+                message.set_timestamp(timestamp);
+                moved += 1;
+            }
+            seen.insert(timestamp);
+        });
+        moved
+    }
+
+    /// Messages saved while generating can not be generating anymore. Those with text are
+    /// completed as cut by the length, to be continued, the empty ones as errored, to be retried.
+    /// Returns their ids.
+    pub(crate) fn recover_interrupted(&mut self) -> Vec<usize> {
+        let mut ids = vec![];
+        self.for_each_message_mut(&mut |message| {
+            if !matches!(
+                message.status,
+                MessageStatus::Pending | MessageStatus::Streaming
+            ) {
+                return;
+            }
+            match message.text.trim().is_empty() {
+                true => message.status = MessageStatus::Errored(ChatError::Interrupted.to_string()),
+                false => {
+                    message.status = MessageStatus::Complete;
+                    message.finish_reason = Some(FinishReason::Length);
+                }
+            }
+            ids.push(message.id());
+        });
+        ids
+    }
+
+    fn for_each_message_mut(&mut self, f: &mut dyn FnMut(&mut Message)) {
+        for (message, child) in self.messages.iter_mut().zip(&mut self.childs) {
+            f(message);
+            child.for_each_message_mut(f);
+        }
+    }
+}
+
+impl Chat {
+    /// Writes the repaired chat over the damaged file, which is kept next to it as `.corrupt`.
+    pub fn save_repaired(&mut self, report: &RepairReport) -> Result<()> {
+        let path = report
+            .path
+            .as_ref()
+            .ok_or_else(|| anyhow!("The repaired chat was not read from a file"))?;
+        if !report.is_clean() {
+            let mut corrupt = path.clone().into_os_string();
+            corrupt.push(".corrupt");
+            fs::copy(path, corrupt)?;
+        }
+        self.save_to(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        chat::{rng::ChatRng, store::ChatStore},
+        persona::Persona,
+        settings::Settings,
+        testing::TempDir,
+    };
+
+    /// "Hi" answered by "A", itself answered by "More", and by the selected "B".
+    fn tree() -> Node {
+        let mut more = Node::new();
+        more.push_sibling(
+            Message::from_user("User".to_string(), "More".to_string()),
+            Node::new(),
+        );
+        let mut replies = Node::new();
+        replies.push_sibling(
+            Message::from_char(0, "Luna".to_string(), "A".to_string()),
+            more,
+        );
+        replies.push_sibling(
+            Message::from_char(0, "Luna".to_string(), "B".to_string()),
+            Node::new(),
+        );
+        replies.selected = 1;
+        let mut root = Node::new();
+        root.push_sibling(
+            Message::from_user("User".to_string(), "Hi".to_string()),
+            replies,
+        );
+        root
+    }
+
+    fn load(path: &Path) -> Result<(Chat, RepairReport)> {
+        Chat::load_from(
+            path,
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        )
+    }
+
+    /// A saved chat with a pin, its header and its tree.
+    fn save(dir: &TempDir) -> (PathBuf, Value, Value) {
+        let mut chat = Chat::from_root(
+            tree(),
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        chat.pin_message(chat.get_history()[0].id());
+        let path = dir.path().join("chat.json");
+        chat.save_to(&path).unwrap();
+        let (header, tree) = SavedChat::split(&fs::read(&path).unwrap()).unwrap();
+        (path, header.unwrap(), tree)
+    }
+
+    fn write(path: &Path, header: &Value, tree: &Value) {
+        fs::write(path, format!("{header}\n{tree}")).unwrap();
+    }
+
+    fn texts(chat: &Chat) -> Vec<String> {
+        let history = chat.get_history();
+        history.iter().map(|m| m.text.trim().to_string()).collect()
+    }
+
+    #[test]
+    fn clean_saves_load_as_saved() {
+        let dir = TempDir::new("repair-clean");
+        let (path, _, _) = save(&dir);
+        let (chat, report) = load(&path).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.path.as_deref(), Some(path.as_path()));
+        assert_eq!(texts(&chat), ["Hi", "B"]);
+        assert_eq!(chat.pinned_messages().len(), 1);
+    }
+
+    #[test]
+    fn inconsistent_trees_are_repaired() {
+        let dir = TempDir::new("repair-tree");
+        let (path, header, mut tree) = save(&dir);
+        let replies = &mut tree["root"]["childs"][0];
+        replies["selected"] = json!(7);
+        replies["messages"][0]["owner"] = json!({ "Char": 3 });
+        // A branch without its message
+        replies["childs"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({ "messages": [], "childs": [], "selected": 0 }));
+        write(&path, &header, &tree);
+
+        let (chat, report) = load(&path).unwrap();
+        assert_eq!(report.actions.len(), 3, "{:?}", report.actions);
+        assert!(report.actions[0].starts_with("Truncated 2 messages and 3 branches at [0]"));
+        assert!(report.actions[1].starts_with("Selection 7 at [0] out of range"));
+        assert!(report.actions[2].starts_with("Unknown char 3 at [0, 0]"));
+        assert_eq!(texts(&chat), ["Hi", "B"]);
+        assert!(matches!(chat.get_history()[1].owner, OwnerType::Char(0)));
+    }
+
+    #[test]
+    fn unreadable_parts_are_dropped() {
+        let dir = TempDir::new("repair-parts");
+        let (path, _, mut tree) = save(&dir);
+        // "A" with "More", the pinned message stays
+        tree["root"]["childs"][0]["messages"][0]["timestamp"] = json!("yesterday");
+        tree["rng"] = json!("random");
+        write(&path, &json!({ "id": 5 }), &tree);
+
+        let (chat, report) = load(&path).unwrap();
+        let actions = report.actions.join("\n");
+        assert!(actions.contains("Rebuilt from its readable parts"));
+        assert!(actions.contains("Dropped the unreadable message at [0, 0] and its replies"));
+        assert!(actions.contains("Dropped unreadable rng"));
+        assert!(actions.contains("Dropped the unreadable header"));
+        assert_eq!(chat.get_history_structure()[1].siblings, 1);
+        assert_eq!(texts(&chat), ["Hi", "B"]);
+        assert_eq!(chat.pinned_messages().len(), 1);
+    }
+
+    #[test]
+    fn duplicate_ids_and_stale_pins_are_fixed() {
+        let dir = TempDir::new("repair-ids");
+        let (path, header, mut tree) = save(&dir);
+        let timestamp = tree["root"]["messages"][0]["timestamp"].clone();
+        tree["root"]["childs"][0]["messages"][1]["timestamp"] = timestamp;
+        tree["pins"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({ "secs_since_epoch": 1, "nanos_since_epoch": 0 }));
+        write(&path, &header, &tree);
+
+        let (chat, report) = load(&path).unwrap();
+        assert_eq!(
+            report.actions,
+            [
+                "Reassigned 1 duplicate message ids",
+                "Dropped 1 pins of missing messages"
+            ]
+        );
+        let history = chat.get_history();
+        assert_ne!(history[0].id(), history[1].id());
+        assert_eq!(chat.pinned_messages().len(), 1);
+    }
+
+    #[test]
+    fn saves_given_in_memory_are_repaired_too() {
+        let mut saved = Chat::from_root(
+            tree(),
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        )
+        .to_saved();
+        saved.root.childs[0].selected = 4;
+        let (mut chat, report) = Chat::from_saved(
+            saved,
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        assert_eq!(report.path, None);
+        assert_eq!(report.actions.len(), 1);
+        assert_eq!(texts(&chat), ["Hi", "B"]);
+        assert!(chat.save_repaired(&report).is_err());
+    }
+
+    #[test]
+    fn repaired_saves_keep_the_damaged_file() {
+        let dir = TempDir::new("repair-store");
+        let store = ChatStore::new(dir.path().to_path_buf());
+        let (path, header, mut tree) = save(&dir);
+        tree["root"]["selected"] = json!(2);
+        let id = uuid::Uuid::new_v4();
+        let stored = store.path(id);
+        write(&stored, &header, &tree);
+        fs::remove_file(path).unwrap();
+
+        let (mut chat, report) = store
+            .load(
+                id,
+                Persona::default_user(),
+                Persona::default_char(),
+                Settings::default(),
+            )
+            .unwrap();
+        assert_eq!(report.actions.len(), 1);
+        chat.save_repaired(&report).unwrap();
+        let mut corrupt = stored.clone().into_os_string();
+        corrupt.push(".corrupt");
+        let corrupt = SavedChat::split(&fs::read(corrupt).unwrap()).unwrap().1;
+        assert_eq!(corrupt["root"]["selected"], 2);
+        assert!(load(&stored).unwrap().1.is_clean());
+    }
+
+    /// Whatever the damage, loading fails or gives a chat that can be used.
+    fn load_damaged(path: &Path, content: &[u8]) {
+        fs::write(path, content).unwrap();
+        if let Ok((chat, _)) = load(path) {
+            chat.get_history_structure();
+            chat.pinned_messages();
+            chat.export_markdown();
+            for depth in 0..chat.get_history().len() {
+                chat.siblings_at(depth).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_saves_never_panic() {
+        let dir = TempDir::new("repair-fuzz");
+        let (path, _, _) = save(&dir);
+        let content = fs::read(&path).unwrap();
+        let damaged = dir.path().join("damaged.json");
+        for len in 0..content.len() {
+            load_damaged(&damaged, &content[..len]);
+        }
+        let mut rng = ChatRng::new(7);
+        for _ in 0..2000 {
+            let mut flipped = content.clone();
+            for _ in 0..1 + rng.below(3) {
+                let byte = rng.below(flipped.len());
+                flipped[byte] ^= 1 << rng.below(8);
+            }
+            load_damaged(&damaged, &flipped);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chat::{Chat, Node},
+    message::RevisionTag,
+    persona::{Persona, card::Card},
+};
+
+#[derive(Debug, Clone)]
+pub(crate) struct CharRevision {
+    pub(crate) tag: RevisionTag,
+    pub(crate) persona: Persona,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct SavedRevision {
+    tag: RevisionTag,
+    card: Card,
+}
+
+impl SavedRevision {
+    pub(crate) fn hash(&self) -> u64 {
+        self.tag.hash
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RevisionInfo {
+    pub tag: RevisionTag,
+    pub messages: usize,
+    pub active: bool,
+}
+
+/// Returned by `Chat::with_char_revision`, reverting switches the chat back to the original card.
+#[derive(Debug, Clone)]
+pub struct RevisionHandle {
+    hash: u64,
+    active: Arc<Mutex<Option<u64>>>,
+}
+
+impl RevisionHandle {
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self.active.lock().unwrap() == Some(self.hash)
+    }
+
+    pub fn revert(&self) {
+        let mut active = self.active.lock().unwrap();
+        if *active == Some(self.hash) {
+            *active = None;
+        }
+    }
+}
+
+impl Chat {
+    /// Generates with `persona`'s prompt from now on, keeping the original char attached to the chat.
+    pub fn with_char_revision(&mut self, persona: Persona) -> RevisionHandle {
+        let hash = persona.content_hash();
+        if !self.revisions.iter().any(|r| r.tag.hash == hash) {
+            let label = format!("Revision {}", self.revisions.len() + 1);
+            self.revisions.push(CharRevision {
+                tag: RevisionTag { hash, label },
+                persona,
+            });
+        }
+        *self.active_revision.lock().unwrap() = Some(hash);
+        RevisionHandle {
+            hash,
+            active: self.active_revision.clone(),
+        }
+    }
+
+    pub fn revisions(&self) -> Vec<RevisionInfo> {
+        let mut counts = vec![];
+        self.root.lock().unwrap().revision_counts(&mut counts);
+        let count = |hash: u64| {
+            counts
+                .iter()
+                .find(|(h, _)| *h == hash)
+                .map_or(0, |(_, c)| *c)
+        };
+
+        let active = *self.active_revision.lock().unwrap();
+        let original = self.original_revision_tag();
+        let mut revisions = vec![RevisionInfo {
+            messages: count(original.hash),
+            active: active.is_none(),
+            tag: original,
+        }];
+        for revision in &self.revisions {
+            revisions.push(RevisionInfo {
+                tag: revision.tag.clone(),
+                messages: count(revision.tag.hash),
+                active: active == Some(revision.tag.hash),
+            });
+        }
+        revisions
+    }
+
+    /// The char persona used for generation, a revision if one is active.
+    pub(crate) fn active_char(&self) -> &Persona {
+        let active = *self.active_revision.lock().unwrap();
+        active
+            .and_then(|hash| self.revisions.iter().find(|r| r.tag.hash == hash))
+            .map_or(&self.personas[1], |r| &r.persona)
+    }
+
+    pub(crate) fn active_revision_tag(&self) -> RevisionTag {
+        let active = *self.active_revision.lock().unwrap();
+        active
+            .and_then(|hash| self.revisions.iter().find(|r| r.tag.hash == hash))
+            .map_or_else(|| self.original_revision_tag(), |r| r.tag.clone())
+    }
+
+    fn original_revision_tag(&self) -> RevisionTag {
+        RevisionTag {
+            hash: self.personas[1].content_hash(),
+            label: "Original".to_string(),
+        }
+    }
+
+    pub(crate) fn saved_revisions(&self) -> Vec<SavedRevision> {
+        self.revisions
+            .iter()
+            .map(|r| SavedRevision {
+                tag: r.tag.clone(),
+                card: (*r.persona).clone(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn restore_revisions(&mut self, saved: Vec<SavedRevision>) {
+        self.revisions = saved
+            .into_iter()
+            .map(|r| CharRevision {
+                tag: r.tag,
+                persona: Persona::from_card(r.card),
+            })
+            .collect();
+    }
+}
+
+impl Node {
+    fn revision_counts(&self, counts: &mut Vec<(u64, usize)>) {
+        for (message, child) in self.messages.iter().zip(&self.childs) {
+            if let Some(tag) = &message.metadata.revision {
+                match counts.iter_mut().find(|(h, _)| *h == tag.hash) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((tag.hash, 1)),
+                }
+            }
+            child.revision_counts(counts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        settings::Settings,
+        testing::{MockProvider, generation_end},
+    };
+
+    fn revised() -> Persona {
+        Persona::from_card(Card::basic("Luna", "You are Luna, revised and terse."))
+    }
+
+    /// The system prompt, which the dialect can merge into the messages.
+    fn prompt(chat: &Chat) -> String {
+        let request = chat.last_request().unwrap();
+        let messages = request.messages.iter().map(|m| &*m.content);
+        request
+            .system
+            .as_deref()
+            .into_iter()
+            .chain(messages)
+            .collect()
+    }
+
+    async fn reply(chat: &mut Chat, text: &str) -> RevisionTag {
+        let mut rx = chat.subscribe();
+        chat.add_user_message(text.to_string());
+        generation_end(&mut rx).await;
+        let history = chat.get_history();
+        history.last().unwrap().metadata.revision.clone().unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_record_the_revision_they_were_generated_with() {
+        let mut chat = Chat::with_personas(
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        chat.set_provider_factory(|_, _| Ok(Box::new(MockProvider::new(&["Hi"]))));
+
+        let original = reply(&mut chat, "One").await;
+        assert_eq!(original.label, "Original");
+        assert!(!prompt(&chat).contains("revised"));
+
+        let handle = chat.with_char_revision(revised());
+        assert!(handle.is_active());
+        let revision = reply(&mut chat, "Two").await;
+        assert_eq!(revision.hash, revised().content_hash());
+        assert_eq!(revision.label, "Revision 1");
+        assert_eq!(chat.last_request().unwrap().char_revision, revision);
+        assert!(prompt(&chat).contains("revised and terse"));
+
+        handle.revert();
+        assert!(!handle.is_active());
+        assert_eq!(reply(&mut chat, "Three").await, original);
+
+        let revisions = chat.revisions();
+        assert_eq!(revisions.len(), 2);
+        assert_eq!((revisions[0].messages, revisions[0].active), (2, true));
+        assert_eq!((revisions[1].messages, revisions[1].active), (1, false));
+    }
+
+    #[tokio::test]
+    async fn revisions_survive_a_reload() {
+        let mut chat = Chat::with_personas(
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        chat.set_provider_factory(|_, _| Ok(Box::new(MockProvider::new(&["Hi"]))));
+        chat.with_char_revision(revised());
+        let tag = reply(&mut chat, "One").await;
+
+        let (reloaded, report) = Chat::from_saved(
+            chat.to_saved(),
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        assert!(report.is_clean());
+        let revisions = reloaded.revisions();
+        assert_eq!(revisions[1].tag, tag);
+        assert_eq!(revisions[1].messages, 1);
+        assert_eq!(
+            reloaded.get_history().last().unwrap().metadata.revision,
+            Some(tag)
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Counter-based generator (SplitMix64), its whole state is the seed and how many values were
+/// drawn, so it can be saved with the chat and resumed exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ChatRng {
+    pub seed: u64,
+    pub counter: u64,
+}
+
+impl ChatRng {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, counter: 0 }
+    }
+
+    pub fn from_entropy() -> Self {
+        let (high, low) = Uuid::new_v4().as_u64_pair();
+        Self::new(high ^ low)
+    }
+
+    /// Seeded from `seed` when set, from entropy otherwise.
+    pub fn from_seed(seed: Option<u64>) -> Self {
+        seed.map_or_else(Self::from_entropy, Self::new)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.counter = self.counter.wrapping_add(1);
+        let mut z = self
+            .seed
+            .wrapping_add(self.counter.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in `[0, bound)`, 0 when `bound` is 0.
+    pub fn below(&mut self, bound: usize) -> usize {
+        match bound {
+            0 => 0,
+            _ => (self.next_f64() * bound as f64) as usize,
+        }
+    }
+
+    pub fn range_f32(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.next_f64() as f32
+    }
+
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        items.get(self.below(items.len()))
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chat::Chat,
+        persona::Persona,
+        settings::Settings,
+        testing::{MockProvider, generation_end},
+    };
+
+    #[test]
+    fn same_seed_same_values() {
+        let (mut a, mut b) = (ChatRng::new(7), ChatRng::new(7));
+        let values: Vec<u64> = (0..8).map(|_| a.next_u64()).collect();
+        assert_eq!(values, (0..8).map(|_| b.next_u64()).collect::<Vec<_>>());
+        assert_ne!(
+            values,
+            (0..8)
+                .map(|_| ChatRng::new(8).next_u64())
+                .collect::<Vec<_>>()
+        );
+
+        // The state is all it takes to continue the sequence
+        let mut resumed = ChatRng {
+            seed: 7,
+            counter: 8,
+        };
+        assert_eq!(resumed.next_u64(), a.next_u64());
+    }
+
+    #[test]
+    fn draws_stay_in_bounds() {
+        let mut rng = ChatRng::new(1);
+        for _ in 0..1000 {
+            assert!(rng.below(3) < 3);
+            assert!((0.0..1.0).contains(&rng.next_f64()));
+            assert!((0.5..1.5).contains(&rng.range_f32(0.5, 1.5)));
+        }
+        assert_eq!(rng.below(0), 0);
+        assert_eq!(rng.choose::<u8>(&[]), None);
+
+        let mut items: Vec<usize> = (0..10).collect();
+        rng.shuffle(&mut items);
+        items.sort();
+        assert_eq!(items, (0..10).collect::<Vec<_>>());
+    }
+
+    fn seeded_chat() -> Chat {
+        let mut chat = Chat::with_personas(
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings {
+                seed: Some(42),
+                ..Settings::default()
+            },
+        );
+        chat.set_provider_factory(|_, _| Ok(Box::new(MockProvider::new(&["Hi"]))));
+        chat
+    }
+
+    /// Each message, then a reroll of its reply, returns the seeds sent with the replies.
+    async fn script(chat: &mut Chat, messages: &[&str]) -> Vec<u64> {
+        let mut rx = chat.subscribe();
+        let mut seeds = vec![];
+        for message in messages {
+            chat.add_user_message(message.to_string());
+            generation_end(&mut rx).await;
+            let depth = chat.get_history().len() - 1;
+            chat.next(depth);
+            generation_end(&mut rx).await;
+            seeds.push(chat.get_history()[depth].metadata.seed.unwrap());
+        }
+        seeds
+    }
+
+    #[tokio::test]
+    async fn seeded_sessions_replay_identically() {
+        let mut first = seeded_chat();
+        let seeds = script(&mut first, &["One", "Two"]).await;
+        let mut second = seeded_chat();
+        assert_eq!(script(&mut second, &["One", "Two"]).await, seeds);
+        assert_eq!(first.rng_state(), second.rng_state());
+
+        // A reroll is not sent the seed of the reply it replaces
+        let history = first.get_history();
+        let depth = history.len() - 1;
+        first.previous(depth);
+        let replaced = first.get_history()[depth].metadata.seed.unwrap();
+        assert_ne!(replaced, seeds[1]);
+    }
+
+    #[tokio::test]
+    async fn the_rng_resumes_after_a_reload() {
+        let mut uninterrupted = seeded_chat();
+        let expected = script(&mut uninterrupted, &["One", "Two", "Three"]).await;
+
+        let mut chat = seeded_chat();
+        let mut seeds = script(&mut chat, &["One"]).await;
+        let settings = Settings {
+            seed: Some(42),
+            ..Settings::default()
+        };
+        let (mut reloaded, _) = Chat::from_saved(
+            chat.to_saved(),
+            Persona::default_user(),
+            Persona::default_char(),
+            settings,
+        );
+        assert_eq!(reloaded.rng_state(), chat.rng_state());
+        reloaded.set_provider_factory(|_, _| Ok(Box::new(MockProvider::new(&["Hi"]))));
+        seeds.extend(script(&mut reloaded, &["Two", "Three"]).await);
+        assert_eq!(seeds, expected);
+    }
+}
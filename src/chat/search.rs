@@ -0,0 +1,92 @@
+use std::ops::Range;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    chat::{Chat, Node},
+    message::OwnerType,
+};
+
+/// Text kept on each side of a match in `SearchHit::snippet`.
+pub const SNIPPET_CONTEXT: usize = 40;
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchOpts {
+    pub case_sensitive: bool,
+    /// The query is a regex, the literal text otherwise.
+    pub regex: bool,
+    /// Only the messages of the selected history.
+    pub selected_only: bool,
+}
+
+/// One match of `Chat::search`, a message matching several times gives several hits.
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub id: usize,
+    pub owner: OwnerType,
+    /// The match with up to `SNIPPET_CONTEXT` characters around it.
+    pub snippet: String,
+    /// Bytes of the match in `snippet`.
+    pub highlight: Range<usize>,
+    /// `Chat::select_path_to` brings an off path hit into the history.
+    pub on_selected_path: bool,
+}
+
+impl Chat {
+    /// Matches of `query` in the texts of the whole tree, depth first with the siblings in
+    /// order. Fails only on an invalid regex.
+    pub fn search(&self, query: &str, opts: &SearchOpts) -> Result<Vec<SearchHit>, regex::Error> {
+        let mut hits = vec![];
+        if query.is_empty() {
+            return Ok(hits);
+        }
+        let pattern = match opts.regex {
+            true => query.to_string(),
+            false => regex::escape(query),
+        };
+        let re = RegexBuilder::new(&pattern)
+            .case_insensitive(!opts.case_sensitive)
+            .build()?;
+        self.root
+            .lock()
+            .unwrap()
+            .search(&re, true, opts.selected_only, &mut hits);
+        Ok(hits)
+    }
+}
+
+impl Node {
+    fn search(&self, re: &Regex, on_path: bool, selected_only: bool, hits: &mut Vec<SearchHit>) {
+        for (i, (message, child)) in self.messages.iter().zip(&self.childs).enumerate() {
+            let on_selected_path = on_path && i == self.selected;
+            if selected_only && !on_selected_path {
+                continue;
+            }
+            for found in re.find_iter(&message.text).filter(|m| !m.is_empty()) {
+                let (snippet, highlight) = snippet(&message.text, found.range());
+                hits.push(SearchHit {
+                    id: message.id(),
+                    owner: message.owner,
+                    snippet,
+                    highlight,
+                    on_selected_path,
+                });
+            }
+            child.search(re, on_selected_path, selected_only, hits);
+        }
+    }
+}
+
+fn snippet(text: &str, found: Range<usize>) -> (String, Range<usize>) {
+    let start = text[..found.start]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT - 1)
+        .map_or(0, |(i, _)| i);
+    let end = text[found.end..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT)
+        .map_or(text.len(), |(i, _)| found.end + i);
+    let highlight = found.start - start..found.end - start;
+    (text[start..end].to_string(), highlight)
+}
@@ -0,0 +1,114 @@
+use std::fmt::Display;
+
+use crate::{
+    chat::{Chat, ChatUpdate, Node},
+    message::MessageStatus,
+};
+
+/// Characters of a sibling shown in `SiblingInfo::preview`.
+pub const PREVIEW_CHARS: usize = 80;
+
+/// One of the alternatives at a depth of the selected history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SiblingInfo {
+    pub id: usize,
+    /// The start of the text, at most `PREVIEW_CHARS` characters.
+    pub preview: String,
+    pub is_selected: bool,
+    pub status: MessageStatus,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionError {
+    /// The selected history has only `len` messages.
+    Depth { depth: usize, len: usize },
+    /// There are only `len` siblings at this depth.
+    Index { index: usize, len: usize },
+    /// No message of the tree has this id.
+    Id { id: usize },
+    /// The message has only `len` revisions.
+    Revision { index: usize, len: usize },
+}
+
+impl Display for SelectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectionError::Depth { depth, len } => {
+                write!(f, "No message at depth {depth}, the history has {len}")
+            }
+            SelectionError::Index { index, len } => {
+                write!(f, "No sibling {index}, there are {len}")
+            }
+            SelectionError::Id { id } => write!(f, "No message {id}"),
+            SelectionError::Revision { index, len } => {
+                write!(f, "No revision {index}, there are {len}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SelectionError {}
+
+impl Chat {
+    /// Every alternative at `depth`, in order, for a swipe preview.
+    pub fn siblings_at(&self, depth: usize) -> Result<Vec<SiblingInfo>, SelectionError> {
+        let root = self.root.lock().unwrap();
+        let level = root.level(depth)?;
+        Ok(level
+            .messages
+            .iter()
+            .enumerate()
+            .map(|(i, message)| SiblingInfo {
+                id: message.id(),
+                preview: message.text.trim().chars().take(PREVIEW_CHARS).collect(),
+                is_selected: i == level.selected,
+                status: message.status.clone(),
+                label: message.label.clone(),
+            })
+            .collect())
+    }
+
+    /// Selects the sibling `index` at `depth` directly, the messages below follow its own
+    /// selection.
+    pub fn select_sibling(&mut self, depth: usize, index: usize) -> Result<(), SelectionError> {
+        let mut root = self.root.lock().unwrap();
+        let level = root.level_mut(depth)?;
+        let len = level.messages.len();
+        if index >= len {
+            return Err(SelectionError::Index { index, len });
+        }
+        level.selected = index;
+        drop(root);
+        self.touch();
+        Self::send_update(&self.tx, ChatUpdate::SelectionChanged);
+        Ok(())
+    }
+}
+
+impl Node {
+    /// The node holding the siblings at `depth` of the selected history.
+    pub(crate) fn level(&self, depth: usize) -> Result<&Node, SelectionError> {
+        let mut node = self;
+        for len in 0..depth {
+            if node.messages.is_empty() {
+                return Err(SelectionError::Depth { depth, len });
+            }
+            node = &node.childs[node.selected];
+        }
+        match node.messages.is_empty() {
+            true => Err(SelectionError::Depth { depth, len: depth }),
+            false => Ok(node),
+        }
+    }
+
+    pub(crate) fn level_mut(&mut self, depth: usize) -> Result<&mut Node, SelectionError> {
+        // Checked first so the walk below can not fail
+        self.level(depth)?;
+        let mut node = self;
+        for _ in 0..depth {
+            node = &mut node.childs[node.selected];
+        }
+        Ok(node)
+    }
+}
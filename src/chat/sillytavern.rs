@@ -0,0 +1,280 @@
+use std::{fs, path::Path, time::SystemTime};
+
+use anyhow::{Context, Result};
+use jiff::{Timestamp, tz::TimeZone};
+use log::trace;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    chat::{Chat, Node},
+    message::{Message, NARRATOR, OwnerType},
+    persona::Persona,
+    settings::Settings,
+};
+
+#[derive(Debug, Deserialize)]
+struct StMessage {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    is_user: bool,
+    #[serde(default)]
+    is_system: bool,
+    #[serde(default)]
+    mes: String,
+    #[serde(default)]
+    swipes: Option<Vec<String>>,
+    #[serde(default)]
+    swipe_id: Option<usize>,
+}
+
+/// The first line of a chat log.
+#[derive(Serialize)]
+struct StHeader<'a> {
+    user_name: &'a str,
+    character_name: &'a str,
+    create_date: String,
+    chat_metadata: Map<String, Value>,
+}
+
+#[derive(Serialize)]
+struct StExportMessage<'a> {
+    name: &'a str,
+    is_user: bool,
+    is_system: bool,
+    send_date: String,
+    mes: &'a str,
+    swipes: Vec<&'a str>,
+    swipe_id: usize,
+    extra: Map<String, Value>,
+}
+
+impl Chat {
+    /// Imports a SillyTavern chat log, mapping each message's swipes onto sibling messages.
+    pub fn import_sillytavern(
+        path: &Path,
+        user: Persona,
+        char: Persona,
+        settings: Settings,
+    ) -> Result<Chat> {
+        let content = fs::read_to_string(path)?;
+        let mut levels = vec![];
+        // The first line only holds the chat metadata
+        for (i, line) in content.lines().enumerate().skip(1) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let st: StMessage = serde_json::from_str(line)
+                .with_context(|| format!("Invalid message on line {} of {:?}", i + 1, path))?;
+
+            let owner_name = match (&st.name, st.is_user) {
+                _ if st.is_system => NARRATOR.to_string(),
+                (Some(name), _) => name.clone(),
+                (None, true) => user.name().to_string(),
+                (None, false) => char.name().to_string(),
+            };
+            let texts = match st.swipes {
+                Some(swipes) if !swipes.is_empty() => swipes,
+                _ => vec![st.mes],
+            };
+            let selected = st.swipe_id.unwrap_or(0).min(texts.len() - 1);
+            let messages: Vec<Message> = texts
+                .into_iter()
+                .map(|text| match (st.is_system, st.is_user) {
+                    (true, _) => Message::from_system(text),
+                    (false, true) => Message::from_user(owner_name.clone(), text),
+                    (false, false) => Message::from_char(0, owner_name.clone(), text),
+                })
+                .collect();
+            levels.push((selected, messages));
+        }
+        trace!("Imported {} messages from {:?}", levels.len(), path);
+
+        let mut node = Node::new();
+        for (selected, messages) in levels.into_iter().rev() {
+            let mut parent = Node::new();
+            for i in 0..messages.len() {
+                match i == selected {
+                    true => parent
+                        .childs
+                        .push(std::mem::replace(&mut node, Node::new())),
+                    false => parent.childs.push(Node::new()),
+                }
+            }
+            parent.messages = messages;
+            parent.selected = selected;
+            node = parent;
+        }
+        Ok(Chat::from_root(node, user, char, settings))
+    }
+
+    /// Writes the selected history as a SillyTavern chat log, the siblings of each message
+    /// being its swipes. The replies of the other siblings have no place in it and are left out.
+    pub fn export_sillytavern(&self, path: &Path) -> Result<()> {
+        let mut levels = vec![];
+        self.root.lock().unwrap().get_levels(&mut levels);
+        let created = levels
+            .first()
+            .map(|(selected, messages)| messages[*selected].timestamp())
+            .unwrap_or_else(SystemTime::now);
+        let header = StHeader {
+            user_name: self.personas[0].name(),
+            character_name: self.personas[1].name(),
+            create_date: st_date(created, "%Y-%m-%d@%Hh%Mm%Ss"),
+            chat_metadata: Map::new(),
+        };
+        let mut lines = vec![serde_json::to_string(&header)?];
+        for (selected, messages) in &levels {
+            let message = &messages[*selected];
+            lines.push(serde_json::to_string(&StExportMessage {
+                name: &message.owner_name,
+                is_user: matches!(message.owner, OwnerType::User),
+                is_system: matches!(message.owner, OwnerType::System),
+                send_date: st_date(message.timestamp(), "%B %-d, %Y %-I:%M%P"),
+                mes: st_text(message),
+                swipes: messages.iter().map(st_text).collect(),
+                swipe_id: *selected,
+                extra: Map::new(),
+            })?);
+        }
+        fs::write(path, lines.join("\n") + "\n")?;
+        trace!("Exported {} messages to {:?}", levels.len(), path);
+        Ok(())
+    }
+}
+
+/// Without the line break the messages end with, which an import adds back.
+fn st_text(message: &Message) -> &str {
+    message.text.strip_suffix('\n').unwrap_or(&message.text)
+}
+
+/// In the local time, like SillyTavern writes them.
+fn st_date(time: SystemTime, format: &str) -> String {
+    Timestamp::try_from(time)
+        .map(|t| t.to_zoned(TimeZone::system()).strftime(format).to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chat::HistoryLevel, testing::TempDir};
+
+    const LOG: &str = r#"{"user_name":"Ann","character_name":"Luna","create_date":"2024-05-01@12h00m00s","chat_metadata":{}}
+{"name":"Ann","is_user":true,"is_system":false,"send_date":"May 1, 2024 12:00pm","mes":"Hello","extra":{}}
+{"name":"Luna","is_user":false,"mes":"Second","swipes":["First","Second","Third"],"swipe_id":1,"gen_started":"2024-05-01T12:00:01Z"}
+
+{"name":"Ann","is_user":true,"mes":"How are you?","force_avatar":"ann.png"}
+{"name":"Luna","is_user":false,"mes":"Fine","swipes":["Fine"],"swipe_id":7}
+"#;
+
+    fn import(dir: &TempDir, log: &str) -> Result<Chat> {
+        let path = dir.path().join("chat.jsonl");
+        fs::write(&path, log).unwrap();
+        Chat::import_sillytavern(
+            &path,
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        )
+    }
+
+    #[test]
+    fn swipes_become_siblings() {
+        let dir = TempDir::new("st-import");
+        let chat = import(&dir, LOG).unwrap();
+
+        let history: Vec<(String, String)> = chat
+            .get_history()
+            .iter()
+            .map(|m| (m.owner_name.clone(), m.text.trim().to_string()))
+            .collect();
+        let expected = [
+            ("Ann", "Hello"),
+            ("Luna", "Second"),
+            ("Ann", "How are you?"),
+            ("Luna", "Fine"),
+        ];
+        assert_eq!(
+            history,
+            expected.map(|(name, text)| (name.to_string(), text.to_string()))
+        );
+        assert!(matches!(chat.get_history()[0].owner, OwnerType::User));
+        assert!(matches!(chat.get_history()[1].owner, OwnerType::Char(0)));
+
+        let structure: Vec<(usize, usize)> = chat
+            .get_history_structure()
+            .iter()
+            .map(
+                |HistoryLevel {
+                     position, siblings, ..
+                 }| (*position, *siblings),
+            )
+            .collect();
+        assert_eq!(structure, [(1, 1), (2, 3), (1, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn other_swipes_have_no_replies() {
+        let dir = TempDir::new("st-swipes");
+        let mut chat = import(&dir, LOG).unwrap();
+        chat.previous(1);
+        let history = chat.get_history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].text.trim(), "First");
+    }
+
+    #[test]
+    fn invalid_lines_are_errors() {
+        let dir = TempDir::new("st-invalid");
+        let log = "{}\n{\"mes\": 3}\n";
+        assert!(import(&dir, log).is_err());
+    }
+
+    /// Owners, texts and the position and count of the siblings of the selected history.
+    fn summary(chat: &Chat) -> Vec<(String, bool, String, usize, usize)> {
+        let structure = chat.get_history_structure();
+        chat.get_history()
+            .iter()
+            .zip(structure)
+            .map(|(m, level)| {
+                let is_user = matches!(m.owner, OwnerType::User);
+                let text = m.text.trim().to_string();
+                (
+                    m.owner_name.clone(),
+                    is_user,
+                    text,
+                    level.position,
+                    level.siblings,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exports_are_imported_back() {
+        let dir = TempDir::new("st-round-trip");
+        let mut chat = import(&dir, LOG).unwrap();
+        chat.add_edit(3, "Fine,\nand you?".to_string());
+        let path = dir.path().join("export.jsonl");
+        chat.export_sillytavern(&path).unwrap();
+
+        let exported = fs::read_to_string(&path).unwrap();
+        let header: Value = serde_json::from_str(exported.lines().next().unwrap()).unwrap();
+        assert_eq!(header["user_name"], Persona::default_user().name());
+        assert_eq!(header["character_name"], Persona::default_char().name());
+
+        let reimported = Chat::import_sillytavern(
+            &path,
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        )
+        .unwrap();
+        assert_eq!(summary(&reimported), summary(&chat));
+        let last = reimported.get_history().pop().unwrap();
+        assert_eq!(last.text.trim(), "Fine,\nand you?");
+        assert_eq!(reimported.get_history_structure()[3].siblings, 2);
+    }
+}
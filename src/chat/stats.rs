@@ -0,0 +1,121 @@
+use jiff::{Timestamp, civil::Date, tz::TimeZone};
+
+use crate::{
+    chat::{Chat, Node, usage::UsageStats},
+    message::Message,
+};
+
+/// What one participant wrote, grouped by `Chat::owner_name`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OwnerStats {
+    pub name: String,
+    pub messages: usize,
+    /// Words of the cleaned text.
+    pub words: usize,
+    /// Id and words of the longest message.
+    pub longest: Option<(usize, usize)>,
+    /// The usage the messages recorded, zero for user messages.
+    pub usage: UsageStats,
+}
+
+impl OwnerStats {
+    pub fn average_words(&self) -> f64 {
+        match self.messages {
+            0 => 0.0,
+            messages => self.words as f64 / messages as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChatStats {
+    /// In the order the owners first wrote.
+    pub owners: Vec<OwnerStats>,
+    /// Messages written each day in the local time zone, oldest day first. Days without
+    /// messages are left out.
+    pub messages_per_day: Vec<(Date, usize)>,
+}
+
+impl ChatStats {
+    pub fn messages(&self) -> usize {
+        self.owners.iter().map(|o| o.messages).sum()
+    }
+
+    pub fn words(&self) -> usize {
+        self.owners.iter().map(|o| o.words).sum()
+    }
+
+    pub fn usage(&self) -> UsageStats {
+        let mut usage = UsageStats::default();
+        for owner in &self.owners {
+            usage.prompt_tokens += owner.usage.prompt_tokens;
+            usage.completion_tokens += owner.usage.completion_tokens;
+            usage.estimated_cost += owner.usage.estimated_cost;
+        }
+        usage
+    }
+
+    fn add(&mut self, name: &str, message: &Message, time_zone: &TimeZone) {
+        if message.text.trim().is_empty() {
+            return;
+        }
+        let owner = match self.owners.iter().position(|o| o.name == name) {
+            Some(i) => &mut self.owners[i],
+            None => {
+                self.owners.push(OwnerStats {
+                    name: name.to_string(),
+                    ..OwnerStats::default()
+                });
+                self.owners.last_mut().unwrap()
+            }
+        };
+        let words = message.clean().split_whitespace().count();
+        owner.messages += 1;
+        owner.words += words;
+        if owner.longest.is_none_or(|(_, longest)| words > longest) {
+            owner.longest = Some((message.id(), words));
+        }
+        owner.usage += &message.metadata.usage;
+
+        if let Ok(time) = Timestamp::try_from(message.timestamp()) {
+            let day = time.to_zoned(time_zone.clone()).date();
+            match self
+                .messages_per_day
+                .binary_search_by_key(&day, |(d, _)| *d)
+            {
+                Ok(i) => self.messages_per_day[i].1 += 1,
+                Err(i) => self.messages_per_day.insert(i, (day, 1)),
+            }
+        }
+    }
+}
+
+impl Chat {
+    /// Statistics of the selected history.
+    pub fn stats(&self) -> ChatStats {
+        self.stats_with(false)
+    }
+
+    /// Statistics of the selected history, or of every message of the tree with `full_tree`.
+    pub fn stats_with(&self, full_tree: bool) -> ChatStats {
+        let mut stats = ChatStats::default();
+        let time_zone = TimeZone::system();
+        let mut add = |message: &Message| stats.add(self.owner_name(message), message, &time_zone);
+        let root = self.root.lock().unwrap();
+        match full_tree {
+            true => root.for_each_message(&mut add),
+            false => root.for_each_selected(&mut add),
+        }
+        stats
+    }
+}
+
+impl Node {
+    fn for_each_selected(&self, f: &mut dyn FnMut(&Message)) {
+        let mut node = self;
+        while let Some(message) = node.messages.get(node.selected) {
+            f(message);
+            node = &node.childs[node.selected];
+        }
+    }
+}
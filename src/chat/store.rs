@@ -0,0 +1,92 @@
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use log::{error, trace};
+use uuid::Uuid;
+
+use crate::{
+    chat::{Chat, meta::ChatMeta, repair::RepairReport},
+    persona::{Persona, loader},
+    settings::Settings,
+};
+
+/// The saved chats, one `<id>.json` file each in a directory.
+#[derive(Debug, Clone)]
+pub struct ChatStore {
+    dir: PathBuf,
+}
+
+impl ChatStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The `chats` directory of the data directory.
+    pub fn open() -> Option<Self> {
+        loader::cache_path("chats").map(Self::new)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub(crate) fn path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    /// The metadata of every chat, the most recently changed first. Only the headers are read,
+    /// files that fail to parse are skipped.
+    pub fn list(&self) -> Vec<ChatMeta> {
+        let Ok(files) = fs::read_dir(&self.dir) else {
+            return vec![];
+        };
+        let mut metas: Vec<ChatMeta> = files
+            .flatten()
+            .map(|f| f.path())
+            .filter(|p| p.extension().is_some_and(|e| e == "json"))
+            .filter_map(|path| match ChatMeta::read(&path) {
+                Ok(meta) => Some(meta),
+                Err(e) => {
+                    error!("{:?}: {}", path, e);
+                    None
+                }
+            })
+            .collect();
+        metas.sort_by_key(|m| Reverse(m.last_message_at));
+        metas
+    }
+
+    /// The save file only has the names of the personas, the chat continues with `user` and
+    /// `char`. A chat saved before it had metadata takes `id`, so it is saved back in place. A
+    /// damaged save is repaired, see `Chat::load_from`.
+    pub fn load(
+        &self,
+        id: Uuid,
+        user: Persona,
+        char: Persona,
+        settings: Settings,
+    ) -> Result<(Chat, RepairReport)> {
+        trace!("Loading chat {id}");
+        let (chat, report) = Chat::load_from(&self.path(id), user, char, settings)?;
+        chat.meta.lock().unwrap().id = id;
+        Ok((chat, report))
+    }
+
+    pub fn delete(&self, id: Uuid) -> Result<()> {
+        trace!("Deleting chat {id}");
+        fs::remove_file(self.path(id))?;
+        Ok(())
+    }
+
+    /// Writes the chat under its id. It takes the chat mutably to bump its clock, see
+    /// `Chat::save_to`.
+    pub fn save(&self, chat: &mut Chat) -> Result<()> {
+        let id = chat.meta().id;
+        trace!("Saving chat {id}");
+        chat.save_to(&self.path(id))
+    }
+}
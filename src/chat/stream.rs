@@ -0,0 +1,647 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::{Stream, StreamExt};
+use llm::{
+    FunctionCall, LLMProvider, ToolCall,
+    chat::{ChatMessage, StreamResponse},
+    error::LLMError,
+};
+use log::{error, trace};
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    chat::{
+        Chat, ChatUpdate, Node,
+        converted::Lent,
+        error::{ChatError, RetryPolicy},
+        meta::ChatMeta,
+        persist::SavedChat,
+    },
+    message::{FinishReason, Message, MessagePart, MessageStatus, TokenUsage},
+    models::ModelPricing,
+    tools::ToolSpec,
+};
+
+type Chunks = Pin<Box<dyn Stream<Item = Result<StreamResponse, LLMError>> + Send>>;
+
+/// Model turns in one reply, tool calls and automatic continuations included.
+const MAX_ROUNDS: usize = 8;
+
+/// One generation streaming into the `target` message, run on its own task.
+pub(crate) struct ReplyStream {
+    pub(crate) root: Arc<Mutex<Node>>,
+    pub(crate) meta: Arc<Mutex<ChatMeta>>,
+    /// Told about the streamed tokens when the chat autosaves.
+    pub(crate) autosave: Option<watch::Sender<SavedChat>>,
+    pub(crate) target: usize,
+    pub(crate) tx: broadcast::Sender<ChatUpdate>,
+    pub(crate) llm: Box<dyn LLMProvider>,
+    pub(crate) history: Vec<ChatMessage>,
+    /// Where `history` goes back to when the stream ends, aborted or not.
+    pub(crate) lent: Option<Lent>,
+    pub(crate) tools: Vec<Arc<ToolSpec>>,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) max_tokens: u32,
+    pub(crate) auto_continue: bool,
+    pub(crate) stop_sequences: Vec<String>,
+    /// Of the model, to put a cost on the usage.
+    pub(crate) pricing: Option<ModelPricing>,
+    pub(crate) connect_timeout: Option<Duration>,
+    /// Shortest time between two stream updates, `None` sends one per chunk.
+    pub(crate) update_interval: Option<Duration>,
+    /// Longest wait for the next chunk.
+    pub(crate) stall_timeout: Option<Duration>,
+    /// The last history message is the start of the target, which the model continues.
+    pub(crate) continuing: bool,
+    /// Sibling indices from the root to the target, found once rather than searching the tree
+    /// for every token.
+    pub(crate) path: Mutex<Vec<usize>>,
+}
+
+/// The char boundary at or before `index`.
+fn floor_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Coalesces the stream updates of a reply when they come faster than `interval`.
+struct Throttle {
+    interval: Option<Duration>,
+    last: Option<Instant>,
+    /// Tokens were added since the last update.
+    dirty: bool,
+}
+
+impl Drop for ReplyStream {
+    fn drop(&mut self) {
+        if let Some(lent) = self.lent.take() {
+            lent.give_back(std::mem::take(&mut self.history));
+        }
+    }
+}
+
+impl ReplyStream {
+    /// Streams the reply, running the tools the model calls and sending their results back until
+    /// it answers with text only.
+    pub(crate) async fn run(mut self) {
+        let mut reason = FinishReason::Unknown;
+        let mut total = TokenUsage::default();
+        let mut throttle = Throttle {
+            interval: self.update_interval,
+            last: None,
+            dirty: false,
+        };
+        for round in 0..MAX_ROUNDS {
+            let Some(mut stream) = self.request().await else {
+                return;
+            };
+            if round == 0 {
+                self.send(ChatUpdate::RequestOk);
+            }
+
+            // Continuing, the end of the target is in front so a stop sequence it started is found
+            let mut text = self.continued_tail();
+            let seeded = text.len();
+            let mut calls = vec![];
+            let mut usage = None;
+            let mut stopped = false;
+            while !stopped {
+                let chunk = match self.next_chunk(&mut stream).await {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Stream interrupted: {e}");
+                        self.fail(e);
+                        return;
+                    }
+                };
+                usage = chunk.usage.or(usage);
+                for choice in chunk.choices {
+                    if let Some(token) = choice.delta.content
+                        && !token.is_empty()
+                        && !stopped
+                    {
+                        self.set_status(MessageStatus::Streaming);
+                        self.with_target(|message| {
+                            message.text.push_str(&token);
+                            message.invalidate_prompt();
+                        });
+                        text.push_str(&token);
+                        if let Some(cut) = self.stop_position(&text, token.len()) {
+                            trace!("Stop sequence reached");
+                            self.truncate_target(text.len() - cut);
+                            text.truncate(cut);
+                            stopped = true;
+                        }
+                        self.stream_update(&mut throttle);
+                    }
+                    calls.extend(choice.delta.tool_calls.unwrap_or_default());
+                }
+            }
+            self.flush(&mut throttle);
+
+            if let Some(usage) = &usage {
+                total.prompt_tokens += u64::from(usage.prompt_tokens);
+                total.completion_tokens += u64::from(usage.completion_tokens);
+            }
+            // `llm` drops the finish reason of the chunks, the usage tells if the limit was hit
+            reason = match usage {
+                _ if stopped => FinishReason::Stop,
+                Some(usage) if usage.completion_tokens >= self.max_tokens => FinishReason::Length,
+                Some(_) => FinishReason::Stop,
+                None => FinishReason::Unknown,
+            };
+            if stopped {
+                break;
+            } else if !calls.is_empty() {
+                self.run_tools(text.split_off(seeded), calls).await;
+            } else if reason == FinishReason::Length && self.auto_continue {
+                trace!("Reply cut by max_tokens, continuing");
+                self.continue_target();
+            } else {
+                break;
+            }
+        }
+        trace!("Streaming completed.");
+        if let Some(pricing) = self.pricing {
+            total.cost = total.prompt_tokens as f64 * pricing.prompt
+                + total.completion_tokens as f64 * pricing.completion;
+        }
+        self.with_target(|message| {
+            message.status = MessageStatus::Complete;
+            message.finish_reason = Some(reason);
+            message.metadata.usage = total;
+        });
+        self.changed();
+        self.send(ChatUpdate::StreamFinished {
+            reason,
+            usage: total,
+        });
+    }
+
+    async fn request(&self) -> Option<Chunks> {
+        let mut attempt = 0;
+        loop {
+            let stream = self.llm.chat_stream_struct(&self.history);
+            let result = match self.connect_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, stream).await.ok(),
+                None => Some(stream.await),
+            };
+            let error = match result {
+                Some(Ok(stream)) => return Some(stream),
+                Some(Err(e)) => {
+                    if let Some(routing) = Chat::error_routing(&e) {
+                        self.with_target(|message| message.metadata.routing = Some(routing));
+                    }
+                    ChatError::from(&e)
+                }
+                None => ChatError::Timeout,
+            };
+            match self.retry.delay(attempt, &error) {
+                Some(delay) => {
+                    attempt += 1;
+                    trace!("{error}, retry {attempt} in {delay:?}");
+                    self.send(ChatUpdate::Retrying { attempt });
+                    tokio::time::sleep(delay).await;
+                }
+                None => {
+                    error!("{}", error);
+                    self.fail(error);
+                    return None;
+                }
+            }
+        }
+    }
+
+    async fn run_tools(&mut self, text: String, calls: Vec<ToolCall>) {
+        self.history.push(
+            ChatMessage::assistant()
+                .content(text)
+                .tool_use(calls.clone())
+                .build(),
+        );
+        let mut results = vec![];
+        for call in calls {
+            let name = call.function.name.clone();
+            self.push_part(MessagePart::ToolCall {
+                id: call.id.clone(),
+                name: name.clone(),
+                arguments: call.function.arguments.clone(),
+            });
+            let args = serde_json::from_str(&call.function.arguments)
+                .unwrap_or_else(|_| call.function.arguments.clone().into());
+            self.send(ChatUpdate::ToolCalled {
+                name: name.clone(),
+                args,
+            });
+
+            let (content, is_error) = match self.tools.iter().find(|t| t.name == name) {
+                Some(tool) => tool.run(&call.function.arguments).await,
+                None => (format!("Error: unknown tool {name}"), true),
+            };
+            trace!("Tool {name} returned {content:?}");
+            self.push_part(MessagePart::ToolResult {
+                id: call.id.clone(),
+                name: name.clone(),
+                content: content.clone(),
+                is_error,
+            });
+            self.send(ChatUpdate::ToolCallFinished { name, is_error });
+            results.push(ToolCall {
+                id: call.id,
+                call_type: call.call_type,
+                function: FunctionCall {
+                    name: call.function.name,
+                    arguments: content,
+                },
+            });
+        }
+        self.history
+            .push(ChatMessage::user().tool_result(results).build());
+        // Text after the tool calls is a new turn
+        self.continuing = false;
+    }
+
+    /// Where the first stop sequence ending in the last `added` bytes of `text` starts, so a
+    /// sequence split across chunks is found once complete.
+    fn stop_position(&self, text: &str, added: usize) -> Option<usize> {
+        let longest = self.stop_sequences.iter().map(|s| s.len()).max()?;
+        let start = floor_boundary(text, text.len().saturating_sub(added + longest));
+        let new = text.len() - added;
+        self.stop_sequences
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| {
+                text[start..]
+                    .match_indices(s.as_str())
+                    .map(|(position, _)| start + position)
+                    .find(|position| position + s.len() > new)
+            })
+            .min()
+    }
+
+    /// The last bytes of the target a stop sequence can start in when it is continued, empty
+    /// otherwise.
+    fn continued_tail(&self) -> String {
+        let longest = self.stop_sequences.iter().map(|s| s.len()).max();
+        let Some(longest) = longest.filter(|_| self.continuing) else {
+            return String::new();
+        };
+        self.with_target(|message| {
+            let start = floor_boundary(&message.text, message.text.len().saturating_sub(longest));
+            message.text[start..].to_string()
+        })
+        .unwrap_or_default()
+    }
+
+    fn truncate_target(&self, bytes: usize) {
+        self.with_target(|message| {
+            let len = message.text.len().saturating_sub(bytes);
+            message.text.truncate(len);
+            message.invalidate_prompt();
+        });
+    }
+
+    /// Sends the target as written so far for the model to pick up where it stopped.
+    fn continue_target(&mut self) {
+        let Some(text) = self.with_target(|message| message.text.clone()) else {
+            return;
+        };
+        if self.continuing {
+            self.history.pop();
+        }
+        self.history
+            .push(ChatMessage::assistant().content(text).build());
+        self.continuing = true;
+    }
+
+    fn fail(&self, error: ChatError) {
+        self.set_status(MessageStatus::Errored(error.to_string()));
+        self.changed();
+        self.send(ChatUpdate::RequestError(error));
+    }
+
+    /// The message count does not change while streaming, only the time of the last change.
+    fn changed(&self) {
+        self.meta.lock().unwrap().last_message_at = SystemTime::now();
+        if let Some(autosave) = &self.autosave {
+            autosave.send_modify(|_| ());
+        }
+    }
+
+    /// `None` at the end of the stream, a timeout when nothing came for `stall_timeout`.
+    async fn next_chunk(&self, stream: &mut Chunks) -> Result<Option<StreamResponse>, ChatError> {
+        let next = match self.stall_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, stream.next())
+                .await
+                .map_err(|_| ChatError::Timeout)?,
+            None => stream.next().await,
+        };
+        next.transpose().map_err(|e| ChatError::from(&e))
+    }
+
+    fn set_status(&self, status: MessageStatus) {
+        self.with_target(|message| message.status = status);
+    }
+
+    fn push_part(&self, part: MessagePart) {
+        self.with_target(|message| message.parts.push(part));
+    }
+
+    /// Runs `f` on the target through its cached path, which is looked up again when the tree
+    /// changed above it. None once the target was deleted.
+    fn with_target<R>(&self, f: impl FnOnce(&mut Message) -> R) -> Option<R> {
+        let mut root = self.root.lock().unwrap();
+        let mut path = self.path.lock().unwrap();
+        if root
+            .message_at_mut(&path)
+            .is_none_or(|message| message.id() != self.target)
+        {
+            *path = root.path_to(self.target)?;
+        }
+        root.message_at_mut(&path).map(f)
+    }
+
+    /// Tokens arriving before `interval` passed are announced with the next ones or by `flush`.
+    fn stream_update(&self, throttle: &mut Throttle) {
+        throttle.dirty = true;
+        if let Some(interval) = throttle.interval
+            && throttle.last.is_some_and(|last| last.elapsed() < interval)
+        {
+            return;
+        }
+        self.flush(throttle);
+    }
+
+    /// Announces the tokens the throttle held back.
+    fn flush(&self, throttle: &mut Throttle) {
+        if throttle.dirty {
+            throttle.dirty = false;
+            throttle.last = Some(Instant::now());
+            self.changed();
+            self.send(ChatUpdate::StreamUpdate);
+        }
+    }
+
+    fn send(&self, update: ChatUpdate) {
+        Chat::send_update(&self.tx, update);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use llm::chat::MessageType;
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::{
+        persona::Persona,
+        settings::Settings,
+        testing::{MockProvider, generation_end},
+    };
+
+    fn call(id: &str, name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            call_type: "function".to_string(),
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    fn chat_with_tools() -> Chat {
+        let mut chat = Chat::with_personas(
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        chat.register_tool(ToolSpec::new(
+            "roll",
+            "Rolls a die",
+            json!({ "type": "object" }),
+            |args: Value| async move { Ok(json!(args["sides"].as_u64().unwrap_or(6) - 2)) },
+        ));
+        chat.register_tool(ToolSpec::new(
+            "flip",
+            "Flips a coin",
+            json!({ "type": "object" }),
+            |_| async { Ok(json!("heads")) },
+        ));
+        chat.register_tool(ToolSpec::new(
+            "broken",
+            "Fails",
+            json!({ "type": "object" }),
+            |_| async { Err(anyhow!("no dice")) },
+        ));
+        chat
+    }
+
+    /// The updates of a reply to "Roll" by `provider`, up to its end.
+    async fn reply_with(chat: &mut Chat, provider: &MockProvider) -> Vec<ChatUpdate> {
+        let provider = provider.clone();
+        chat.set_provider_factory(move |_, _| Ok(Box::new(provider.clone())));
+        let mut rx = chat.subscribe();
+        chat.add_user_message("Roll".to_string());
+        let mut updates = vec![];
+        loop {
+            let update = rx.recv().await.unwrap();
+            let end = matches!(
+                update,
+                ChatUpdate::StreamFinished { .. } | ChatUpdate::RequestError(_)
+            );
+            updates.push(update);
+            if end {
+                return updates;
+            }
+        }
+    }
+
+    /// The tool results of the last message of a request.
+    fn results(request: &[ChatMessage]) -> &[ToolCall] {
+        match &request.last().unwrap().message_type {
+            MessageType::ToolResult(results) => results,
+            other => panic!("Not a tool result: {other:?}"),
+        }
+    }
+
+    fn finished(updates: &[ChatUpdate]) -> Vec<(&str, bool)> {
+        updates
+            .iter()
+            .filter_map(|u| match u {
+                ChatUpdate::ToolCallFinished { name, is_error } => Some((name.as_str(), *is_error)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn tool_results_are_sent_back_to_the_model() {
+        let mut chat = chat_with_tools();
+        let provider = MockProvider::new(&["Rolling."])
+            .tool_calls(vec![call("1", "roll", r#"{"sides": 20}"#)])
+            .then(MockProvider::new(&[" You got 18."]));
+        let updates = reply_with(&mut chat, &provider).await;
+        assert!(matches!(
+            updates.last(),
+            Some(ChatUpdate::StreamFinished { .. })
+        ));
+        assert!(updates.iter().any(|u| matches!(
+            u,
+            ChatUpdate::ToolCalled { name, args } if name == "roll" && args["sides"] == 20
+        )));
+        assert_eq!(finished(&updates), [("roll", false)]);
+
+        let requests = provider.requests();
+        assert_eq!(requests.len(), 2);
+        let sent = &requests[1];
+        assert!(matches!(
+            &sent[sent.len() - 2].message_type,
+            MessageType::ToolUse(calls) if calls[0].id == "1"
+        ));
+        let results = results(sent);
+        assert_eq!(results[0].id, "1");
+        assert_eq!(results[0].function.arguments, "18");
+
+        let reply = chat.get_history().pop().unwrap();
+        assert_eq!(reply.text.trim(), "Rolling. You got 18.");
+        assert!(matches!(
+            &reply.parts[..],
+            [
+                MessagePart::ToolCall { id, name, .. },
+                MessagePart::ToolResult { content, is_error: false, .. },
+            ] if id == "1" && name == "roll" && content == "18"
+        ));
+    }
+
+    #[tokio::test]
+    async fn handler_errors_are_tool_results() {
+        let mut chat = chat_with_tools();
+        let provider = MockProvider::new(&[])
+            .tool_calls(vec![call("1", "broken", "{}"), call("2", "missing", "{}")])
+            .then(MockProvider::new(&["No luck."]));
+        let updates = reply_with(&mut chat, &provider).await;
+        assert!(matches!(
+            updates.last(),
+            Some(ChatUpdate::StreamFinished { .. })
+        ));
+        assert_eq!(finished(&updates), [("broken", true), ("missing", true)]);
+
+        let requests = provider.requests();
+        let results = results(&requests[1]);
+        assert_eq!(results[0].function.arguments, "Error: no dice");
+        assert_eq!(results[1].function.arguments, "Error: unknown tool missing");
+
+        let reply = chat.get_history().pop().unwrap();
+        assert_eq!(reply.text.trim(), "No luck.");
+        assert!(matches!(
+            &reply.parts[1],
+            MessagePart::ToolResult { content, is_error: true, .. } if content == "Error: no dice"
+        ));
+    }
+
+    #[tokio::test]
+    async fn tools_are_called_in_sequence() {
+        let mut chat = chat_with_tools();
+        let provider = MockProvider::new(&[])
+            .tool_calls(vec![call("1", "roll", "{}")])
+            .then(MockProvider::new(&[]).tool_calls(vec![call("2", "flip", "")]))
+            .then(MockProvider::new(&["A 4 and heads."]));
+        let updates = reply_with(&mut chat, &provider).await;
+        assert_eq!(finished(&updates), [("roll", false), ("flip", false)]);
+
+        let requests = provider.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(results(&requests[1])[0].function.arguments, "4");
+        // The whole exchange is sent again with the second result
+        assert_eq!(requests[2].len(), requests[1].len() + 2);
+        assert_eq!(results(&requests[2])[0].function.arguments, "heads");
+
+        let reply = chat.get_history().pop().unwrap();
+        assert_eq!(reply.text.trim(), "A 4 and heads.");
+        let names: Vec<_> = reply
+            .parts
+            .iter()
+            .map(|p| match p {
+                MessagePart::ToolCall { name, .. } => format!("call {name}"),
+                MessagePart::ToolResult { name, .. } => format!("result {name}"),
+            })
+            .collect();
+        assert_eq!(
+            names,
+            ["call roll", "result roll", "call flip", "result flip"]
+        );
+    }
+
+    #[tokio::test]
+    async fn tool_calls_stop_after_the_last_round() {
+        let mut chat = chat_with_tools();
+        let provider = MockProvider::new(&[]).tool_calls(vec![call("1", "flip", "{}")]);
+        let updates = reply_with(&mut chat, &provider).await;
+        assert!(matches!(
+            updates.last(),
+            Some(ChatUpdate::StreamFinished { .. })
+        ));
+        assert_eq!(provider.requests().len(), MAX_ROUNDS);
+        assert_eq!(finished(&updates).len(), MAX_ROUNDS);
+    }
+
+    /// "Hi" answered by "First", then a slow second sibling of the reply being streamed.
+    async fn streaming_second_reply() -> (Chat, broadcast::Receiver<ChatUpdate>, usize) {
+        let mut chat = Chat::with_personas(
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        chat.set_provider_factory(|_, _| Ok(Box::new(MockProvider::new(&["First"]))));
+        let mut rx = chat.subscribe();
+        chat.add_user_message("Hi".to_string());
+        generation_end(&mut rx).await;
+
+        chat.set_provider_factory(|_, _| {
+            Ok(Box::new(
+                MockProvider::new(&["One", " two", " three"]).delay(Duration::from_millis(40)),
+            ))
+        });
+        chat.next(1);
+        let target = chat.get_history()[1].id();
+        while !matches!(rx.recv().await, Ok(ChatUpdate::StreamUpdate)) {}
+        (chat, rx, target)
+    }
+
+    #[tokio::test]
+    async fn tokens_follow_the_target_when_the_selection_moves() {
+        let (mut chat, mut rx, target) = streaming_second_reply().await;
+        chat.previous(1);
+        assert_eq!(chat.get_history()[1].text.trim(), "First");
+
+        generation_end(&mut rx).await;
+        assert_eq!(chat.get_history()[1].text.trim(), "First");
+        chat.next(1);
+        let history = chat.get_history();
+        assert_eq!(history[1].id(), target);
+        assert_eq!(history[1].text.trim(), "One two three");
+        assert_eq!(history[1].status, MessageStatus::Complete);
+    }
+
+    #[tokio::test]
+    async fn tokens_follow_the_target_when_a_sibling_before_it_is_deleted() {
+        let (mut chat, mut rx, target) = streaming_second_reply().await;
+        chat.previous(1);
+        chat.delete(1);
+
+        generation_end(&mut rx).await;
+        let history = chat.get_history();
+        assert_eq!(chat.get_history_structure()[1].siblings, 1);
+        assert_eq!(history[1].id(), target);
+        assert_eq!(history[1].text.trim(), "One two three");
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt::Display;
+
+use serde_json::{Map, Value};
+
+use crate::{
+    chat::{
+        Chat,
+        meta::ChatMeta,
+        persist::{SAVE_VERSION, SavedChat},
+    },
+    message::Message,
+    persona::Persona,
+    settings::Settings,
+};
+
+/// Where and why `Chat::import_tree_json` rejected a tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeError {
+    /// Into the JSON, as in `root.childs[1].messages[0].text`, empty for the document itself.
+    pub path: String,
+    pub message: String,
+}
+
+impl TreeError {
+    fn new(path: &str, message: impl Display) -> Self {
+        Self {
+            path: path.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for TreeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+impl std::error::Error for TreeError {}
+
+/// The whole tree as JSON, the save file in one document: `SavedChat` with its `meta` and the
+/// `id` of each message, which is derived from its `timestamp` and only checked on import.
+impl Chat {
+    pub fn export_tree_json(&self) -> String {
+        let saved = self.to_saved();
+        let Ok(Value::Object(fields)) = serde_json::to_value(&saved) else {
+            return String::new();
+        };
+        let mut tree = Map::new();
+        for (key, mut value) in fields {
+            if key == "root" {
+                add_ids(&mut value);
+            }
+            tree.insert(key.clone(), value);
+            if key == "version" {
+                tree.insert("meta".to_string(), serde_json::json!(saved.meta));
+            }
+        }
+        serde_json::to_string_pretty(&tree).unwrap_or_default()
+    }
+
+    /// Checks the structure of the tree before loading it: as many childs as messages, a
+    /// selected index in range, readable messages and ids matching their timestamps.
+    pub fn import_tree_json(
+        json: &str,
+        user: Persona,
+        char: Persona,
+        settings: Settings,
+    ) -> Result<Chat, TreeError> {
+        let mut value: Value = serde_json::from_str(json).map_err(|e| TreeError::new("", e))?;
+        let Value::Object(tree) = &mut value else {
+            return Err(TreeError::new("", "Not a JSON object"));
+        };
+        match tree.get("version").and_then(Value::as_u64) {
+            Some(version) if version <= u64::from(SAVE_VERSION) => {}
+            Some(version) => {
+                return Err(TreeError::new(
+                    "version",
+                    format!("Version {version} is newer than {SAVE_VERSION}"),
+                ));
+            }
+            None => return Err(TreeError::new("version", "Missing or not a number")),
+        }
+        let meta = match tree.remove("meta") {
+            Some(Value::Null) | None => None,
+            Some(meta) => Some(
+                serde_json::from_value::<ChatMeta>(meta).map_err(|e| TreeError::new("meta", e))?,
+            ),
+        };
+        let root = tree
+            .get_mut("root")
+            .ok_or(TreeError::new("root", "Missing"))?;
+        validate_node(root, "root")?;
+        let mut saved: SavedChat =
+            serde_json::from_value(value).map_err(|e| TreeError::new("", e))?;
+        saved.meta = meta;
+        // Validated, what is left to repair is reported along the way
+        Ok(Chat::from_saved(saved, user, char, settings).0)
+    }
+}
+
+fn add_ids(node: &mut Value) {
+    let Value::Object(node) = node else {
+        return;
+    };
+    if let Some(Value::Array(messages)) = node.get_mut("messages") {
+        for message in messages {
+            if let Ok(parsed) = serde_json::from_value::<Message>(message.clone())
+                && let Value::Object(fields) = message
+            {
+                fields.insert("id".to_string(), Value::from(parsed.id()));
+            }
+        }
+    }
+    if let Some(Value::Array(childs)) = node.get_mut("childs") {
+        childs.iter_mut().for_each(add_ids);
+    }
+}
+
+/// Strips the ids once checked, `Message` has no such field.
+fn validate_node(node: &mut Value, path: &str) -> Result<(), TreeError> {
+    let Value::Object(fields) = node else {
+        return Err(TreeError::new(path, "Not a JSON object"));
+    };
+    let len = |key: &str, fields: &Map<String, Value>| match fields.get(key) {
+        Some(Value::Array(items)) => Ok(items.len()),
+        _ => Err(TreeError::new(
+            &format!("{path}.{key}"),
+            "Missing or not an array",
+        )),
+    };
+    let messages = len("messages", fields)?;
+    let childs = len("childs", fields)?;
+    if childs != messages {
+        return Err(TreeError::new(
+            &format!("{path}.childs"),
+            format!("{childs} childs for {messages} messages"),
+        ));
+    }
+    let selected = fields
+        .get("selected")
+        .and_then(Value::as_u64)
+        .ok_or(TreeError::new(
+            &format!("{path}.selected"),
+            "Missing or not a number",
+        ))?;
+    if selected >= messages.max(1) as u64 {
+        return Err(TreeError::new(
+            &format!("{path}.selected"),
+            format!("{selected} is out of {messages} messages"),
+        ));
+    }
+    if let Some(Value::Array(messages)) = fields.get_mut("messages") {
+        for (i, message) in messages.iter_mut().enumerate() {
+            let path = format!("{path}.messages[{i}]");
+            validate_message(message, &path)?;
+        }
+    }
+    if let Some(Value::Array(childs)) = fields.get_mut("childs") {
+        for (i, child) in childs.iter_mut().enumerate() {
+            validate_node(child, &format!("{path}.childs[{i}]"))?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_message(message: &mut Value, path: &str) -> Result<(), TreeError> {
+    let Value::Object(fields) = message else {
+        return Err(TreeError::new(path, "Not a JSON object"));
+    };
+    let id = fields.remove("id");
+    let parsed: Message = serde_json::from_value(Value::Object(fields.clone()))
+        .map_err(|e| TreeError::new(path, e))?;
+    match id {
+        None => Ok(()),
+        Some(id) if id.as_u64() == Some(parsed.id() as u64) => Ok(()),
+        Some(id) => Err(TreeError::new(
+            &format!("{path}.id"),
+            format!(
+                "{id} does not match the timestamp, whose id is {}",
+                parsed.id()
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::testing::{MockProvider, generation_end};
+
+    fn import(json: &str) -> Result<Chat, TreeError> {
+        Chat::import_tree_json(
+            json,
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn exported_ids_are_checked_on_import() {
+        let mut chat = Chat::with_personas(
+            Persona::default_user(),
+            Persona::default_char(),
+            Settings::default(),
+        );
+        chat.set_provider_factory(|_, _| Ok(Box::new(MockProvider::new(&["Reply"]))));
+        let mut rx = chat.subscribe();
+        chat.add_user_message("Hi".to_string());
+        generation_end(&mut rx).await;
+        let timestamp = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        chat.root.lock().unwrap().messages[0].set_timestamp(timestamp);
+
+        let json = chat.export_tree_json();
+        // The id a previous build exported for this timestamp
+        assert!(json.contains(r#""id": 6460116012566372928"#));
+        let imported = import(&json).unwrap();
+        assert_eq!(imported.get_history()[0].timestamp(), timestamp);
+
+        let tampered = json.replace("6460116012566372928", "1");
+        let error = import(&tampered).unwrap_err();
+        assert_eq!(error.path, "root.messages[0].id");
+    }
+}
@@ -0,0 +1,44 @@
+use std::ops::AddAssign;
+
+use crate::{
+    chat::{Chat, Node},
+    message::{Message, TokenUsage},
+};
+
+/// Usage summed over every message of the tree, swipes and abandoned branches included.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UsageStats {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// US dollars.
+    pub estimated_cost: f64,
+}
+
+impl AddAssign<&TokenUsage> for UsageStats {
+    fn add_assign(&mut self, usage: &TokenUsage) {
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.estimated_cost += usage.cost;
+    }
+}
+
+impl Chat {
+    /// What the whole chat cost so far, not only the selected path.
+    pub fn usage_totals(&self) -> UsageStats {
+        let mut stats = UsageStats::default();
+        self.root
+            .lock()
+            .unwrap()
+            .for_each_message(&mut |m| stats += &m.metadata.usage);
+        stats
+    }
+}
+
+impl Node {
+    pub(crate) fn for_each_message(&self, f: &mut dyn FnMut(&Message)) {
+        for (message, child) in self.messages.iter().zip(&self.childs) {
+            f(message);
+            child.for_each_message(f);
+        }
+    }
+}
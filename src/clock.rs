@@ -0,0 +1,16 @@
+use std::{fmt::Debug, time::SystemTime};
+
+/// The current time as the chat sees it, for the availability schedules.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The time of the system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
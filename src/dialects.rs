@@ -0,0 +1,411 @@
+use std::sync::Arc;
+
+use llm::chat::ChatRole;
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use crate::{chat::RequestSnapshot, message::PromptMessage};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub enum ExamplePlacement {
+    #[default]
+    System,
+    Turns,
+}
+
+/// Prompt conventions applied as the last step of request assembly.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Dialect {
+    pub merge_system_into_first_user: bool,
+    pub assistant_name_prefix: bool,
+    pub max_system_len: Option<usize>,
+    pub examples: ExamplePlacement,
+    /// The model takes `Settings::reasoning_effort` and `reasoning_max_tokens`.
+    pub reasoning: bool,
+    /// The model rejects requests with tools, the registered ones are not sent.
+    pub no_tools: bool,
+    /// The model takes images, the attachments are left out otherwise.
+    pub vision: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DialectRule {
+    /// Glob over the model id, `*` matches any run of characters and `?` a single one.
+    pub pattern: String,
+    #[serde(flatten)]
+    pub dialect: Dialect,
+}
+
+impl DialectRule {
+    pub fn new(pattern: &str, dialect: Dialect) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            dialect,
+        }
+    }
+
+    pub fn matches(&self, model: &str) -> bool {
+        glob_match(&self.pattern, model)
+    }
+
+    fn specificity(&self) -> usize {
+        self.pattern
+            .chars()
+            .filter(|c| *c != '*' && *c != '?')
+            .count()
+    }
+}
+
+pub fn builtin_rules() -> Vec<DialectRule> {
+    vec![
+        DialectRule::new("*", Dialect::default()),
+        DialectRule::new(
+            "google/gemma*",
+            Dialect {
+                merge_system_into_first_user: true,
+                no_tools: true,
+                vision: true,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "deepseek/deepseek-r1*",
+            Dialect {
+                merge_system_into_first_user: true,
+                reasoning: true,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "mistralai/*",
+            Dialect {
+                examples: ExamplePlacement::Turns,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "meta-llama/*",
+            Dialect {
+                assistant_name_prefix: true,
+                examples: ExamplePlacement::Turns,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "openai/*",
+            Dialect {
+                max_system_len: Some(32000),
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "openai/gpt-4o*",
+            Dialect {
+                max_system_len: Some(32000),
+                vision: true,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "anthropic/*",
+            Dialect {
+                vision: true,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "google/gemini*",
+            Dialect {
+                vision: true,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "openai/o*",
+            Dialect {
+                max_system_len: Some(32000),
+                reasoning: true,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "anthropic/claude-3.7-sonnet*",
+            Dialect {
+                reasoning: true,
+                vision: true,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "anthropic/claude-sonnet-4*",
+            Dialect {
+                reasoning: true,
+                vision: true,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "anthropic/claude-opus-4*",
+            Dialect {
+                reasoning: true,
+                vision: true,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "google/gemini-2.5*",
+            Dialect {
+                reasoning: true,
+                vision: true,
+                ..Default::default()
+            },
+        ),
+        DialectRule::new(
+            "qwen/qwq*",
+            Dialect {
+                reasoning: true,
+                ..Default::default()
+            },
+        ),
+    ]
+}
+
+/// Picks the most specific rule matching `model`, user rules winning ties over built-in ones.
+pub fn resolve(model: &str, user_rules: &[DialectRule]) -> Dialect {
+    let builtin = builtin_rules();
+    let mut best: Option<&DialectRule> = None;
+    for rule in user_rules.iter().chain(builtin.iter()) {
+        if rule.matches(model) && best.is_none_or(|b| rule.specificity() > b.specificity()) {
+            best = Some(rule);
+        }
+    }
+    match best {
+        Some(rule) => {
+            trace!("Model {model} uses dialect {:?}", rule.pattern);
+            rule.dialect.clone()
+        }
+        None => Dialect::default(),
+    }
+}
+
+impl Dialect {
+    /// What goes in front of the char's messages, empty unless `assistant_name_prefix`.
+    pub fn assistant_prefix(&self, char_name: &str) -> String {
+        match self.assistant_name_prefix {
+            true => format!("{char_name}: "),
+            false => String::new(),
+        }
+    }
+
+    /// The history messages come prefixed already, from their cached prompt form.
+    pub fn apply(&self, request: &mut RequestSnapshot, char_name: &str) {
+        if let Some(max) = self.max_system_len
+            && let Some(system) = &mut request.system
+            && let Some((index, _)) = system.char_indices().nth(max)
+        {
+            trace!("Truncating system prompt to {max} chars");
+            system.truncate(index);
+        }
+
+        if self.assistant_name_prefix {
+            let prefix = self.assistant_prefix(char_name);
+            for message in &mut request.messages {
+                if message.role == ChatRole::Assistant && !message.content.starts_with(&prefix) {
+                    message.content = Arc::from(format!("{prefix}{}", message.content));
+                }
+            }
+        }
+
+        if self.merge_system_into_first_user
+            && let Some(system) = request.system.take()
+        {
+            match request.messages.first_mut() {
+                Some(first) if first.role == ChatRole::User => {
+                    first.content = Arc::from(format!("{system}\n{}", first.content))
+                }
+                _ => request
+                    .messages
+                    .insert(0, PromptMessage::new(ChatRole::User, &system)),
+            }
+        }
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp + 1;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chat::Chat,
+        message::RevisionTag,
+        persona::Persona,
+        settings::Settings,
+        testing::{MockProvider, generation_end},
+    };
+
+    fn request(model: &str, system: &str) -> RequestSnapshot {
+        RequestSnapshot {
+            model: model.to_string(),
+            system: Some(system.to_string()),
+            messages: vec![
+                PromptMessage::new(ChatRole::User, "Hi"),
+                PromptMessage::new(ChatRole::Assistant, "Hello"),
+                PromptMessage::new(ChatRole::User, "How are you?"),
+            ],
+            char_revision: RevisionTag {
+                hash: 0,
+                label: String::new(),
+            },
+        }
+    }
+
+    /// The request for `model` once its dialect is applied.
+    fn transformed(model: &str, system: &str) -> RequestSnapshot {
+        let mut request = request(model, system);
+        resolve(model, &[]).apply(&mut request, "Luna");
+        request
+    }
+
+    fn contents(request: &RequestSnapshot) -> Vec<(ChatRole, &str)> {
+        request
+            .messages
+            .iter()
+            .map(|m| (m.role.clone(), &*m.content))
+            .collect()
+    }
+
+    #[test]
+    fn gemma_gets_the_system_prompt_in_the_first_turn() {
+        let request = transformed("google/gemma-2-9b-it", "You are Luna.");
+        assert_eq!(request.system, None);
+        assert_eq!(
+            contents(&request),
+            [
+                (ChatRole::User, "You are Luna.\nHi"),
+                (ChatRole::Assistant, "Hello"),
+                (ChatRole::User, "How are you?"),
+            ]
+        );
+    }
+
+    #[test]
+    fn llama_gets_the_name_before_each_reply() {
+        let mut request = transformed("meta-llama/llama-3.1-70b-instruct", "You are Luna.");
+        assert_eq!(request.system.as_deref(), Some("You are Luna."));
+        assert_eq!(contents(&request)[1], (ChatRole::Assistant, "Luna: Hello"));
+
+        // Not prefixed twice
+        resolve(&request.model.clone(), &[]).apply(&mut request, "Luna");
+        assert_eq!(contents(&request)[1], (ChatRole::Assistant, "Luna: Hello"));
+        assert_eq!(contents(&request)[0], (ChatRole::User, "Hi"));
+    }
+
+    #[test]
+    fn openai_gets_a_shorter_system_prompt() {
+        let system = "é".repeat(40_000);
+        let request = transformed("openai/gpt-4o-mini", &system);
+        assert_eq!(request.system.as_ref().unwrap().chars().count(), 32_000);
+        assert_eq!(contents(&request), contents(&self::request("", "")));
+
+        // Left as is by the catch-all rule
+        let request = transformed("some/unknown-model", &system);
+        assert_eq!(request.system, Some(system));
+    }
+
+    #[test]
+    fn the_most_specific_rule_wins() {
+        assert!(!resolve("anthropic/claude-3-haiku", &[]).reasoning);
+        assert!(resolve("anthropic/claude-sonnet-4.5", &[]).reasoning);
+        assert!(resolve("openai/o3-mini", &[]).reasoning);
+        assert!(!resolve("openai/gpt-4o", &[]).reasoning);
+        assert!(resolve("openai/gpt-4o", &[]).vision);
+        assert_eq!(resolve("unknown", &[]), Dialect::default());
+    }
+
+    #[test]
+    fn user_rules_win_ties_with_builtin_ones() {
+        let rules = [DialectRule::new(
+            "openai/o*",
+            Dialect {
+                no_tools: true,
+                ..Default::default()
+            },
+        )];
+        let dialect = resolve("openai/o3-mini", &rules);
+        assert!(dialect.no_tools);
+        assert!(!dialect.reasoning);
+
+        // A less specific user rule loses
+        let rules = [DialectRule::new(
+            "openai/*",
+            Dialect {
+                no_tools: true,
+                ..Default::default()
+            },
+        )];
+        assert!(!resolve("openai/o3-mini", &rules).no_tools);
+        assert!(resolve("openai/gpt-4-turbo", &rules).no_tools);
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("openai/gpt-?o*", "openai/gpt-4o-mini"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*mini", "openai/gpt-4o-mini"));
+        assert!(!glob_match("openai/gpt-?o", "openai/gpt-4o-mini"));
+        assert!(!glob_match("mistralai/*", "openai/mistral"));
+    }
+
+    #[tokio::test]
+    async fn the_snapshot_is_the_transformed_request() {
+        let settings = Settings {
+            model: "meta-llama/llama-3.1-8b-instruct".to_string(),
+            ..Settings::default()
+        };
+        let mut chat =
+            Chat::with_personas(Persona::default_user(), Persona::default_char(), settings);
+        chat.set_provider_factory(|_, _| Ok(Box::new(MockProvider::new(&["Hello"]))));
+        let mut rx = chat.subscribe();
+        chat.add_user_message("Hi".to_string());
+        generation_end(&mut rx).await;
+        chat.add_user_message("Again".to_string());
+        generation_end(&mut rx).await;
+
+        let request = chat.last_request().unwrap();
+        let prefix = format!("{}: ", chat.char().name());
+        let reply = request
+            .messages
+            .iter()
+            .find(|m| m.role == ChatRole::Assistant)
+            .unwrap();
+        assert!(reply.content.starts_with(&prefix), "{:?}", reply.content);
+    }
+}
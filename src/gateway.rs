@@ -1,65 +1,255 @@
 use anyhow::{Result, anyhow};
 use image::{ImageBuffer, Rgba};
-use log::{error, trace};
+use log::{error, trace, warn};
 use std::{
-    fs::{self, File},
-    path::PathBuf,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
     sync::Arc,
-    time::SystemTime,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::{Mutex, Semaphore, mpsc},
+    task::{JoinHandle, JoinSet},
 };
-use tokio::sync::{Mutex, mpsc};
 
-use crate::persona::{Persona, card::Card};
+pub use crate::persona::loader::LoaderOptions;
+use crate::{
+    lorebook::Lorebook,
+    persona::{Persona, loader},
+};
 
 pub enum GatewayUpdate {
-    Char,
-    User,
+    /// Number of persona directories the startup scan is loading.
+    ScanStarted {
+        chars: usize,
+        users: usize,
+    },
+    /// A persona was pushed into `chars` at this index.
+    CharLoaded(usize),
+    UserLoaded(usize),
+    /// The avatar of the char at this index was decoded after it was announced.
+    CharAvatarReady(usize),
+    UserAvatarReady(usize),
+    /// A persona directory holding the same card as another one, only one of them is listed.
+    DuplicateSkipped(PathBuf),
+    /// Number of lorebooks read from the `lorebooks` directory.
+    LorebooksLoaded(usize),
+    /// Number of personas loaded by the startup scan, some directories may have failed.
+    ScanComplete {
+        chars: usize,
+        users: usize,
+    },
+    /// A persona directory created after the first scan, with the persona name.
+    CharAdded(String),
+    CharRemoved(String),
+    CharChanged(String),
+    UserAdded(String),
+    UserRemoved(String),
+    UserChanged(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    /// Most recently used first.
+    ModifiedTime,
+}
+
+/// A persona read off the runtime, with the avatar left to decode.
+type PersonaLoad = JoinHandle<Result<(Persona, Option<PathBuf>)>>;
+
+/// The scan with the watch, and the avatar decodes it started.
+type Tasks = Arc<std::sync::Mutex<JoinSet<()>>>;
+
+/// The updates sent for the changes of one watched directory.
+struct Watched {
+    subdir: &'static str,
+    added: fn(String) -> GatewayUpdate,
+    removed: fn(String) -> GatewayUpdate,
+    changed: fn(String) -> GatewayUpdate,
 }
 
+const WATCHED_CHARS: Watched = Watched {
+    subdir: "chars",
+    added: GatewayUpdate::CharAdded,
+    removed: GatewayUpdate::CharRemoved,
+    changed: GatewayUpdate::CharChanged,
+};
+
+const WATCHED_USERS: Watched = Watched {
+    subdir: "users",
+    added: GatewayUpdate::UserAdded,
+    removed: GatewayUpdate::UserRemoved,
+    changed: GatewayUpdate::UserChanged,
+};
+
 pub struct Gateway {
     pub chars: Arc<Mutex<Vec<Persona>>>,
     pub users: Arc<Mutex<Vec<Persona>>>,
+    pub lorebooks: Arc<Mutex<Vec<Lorebook>>>,
 
+    tx: mpsc::Sender<GatewayUpdate>,
     rx: mpsc::Receiver<GatewayUpdate>,
+    /// Closed on shutdown, so the loads waiting for a permit give up.
+    decodes: Arc<Semaphore>,
+    tasks: Tasks,
 }
 
 impl Gateway {
     pub fn new() -> Self {
+        Self::with_options(LoaderOptions::default())
+    }
+
+    pub fn with_options(options: LoaderOptions) -> Self {
         let (tx, rx) = mpsc::channel(10);
+        let decodes = Arc::new(Semaphore::new(options.max_concurrent_decodes.max(1)));
         let chars = Arc::new(Mutex::new(vec![]));
         let tchars = chars.clone();
         let users = Arc::new(Mutex::new(vec![]));
         let tusers = users.clone();
-        tokio::spawn(async move {
-            Self::load_users(tusers, &tx).await;
-            Self::load_chars(tchars, &tx).await;
+        let lorebooks = Arc::new(Mutex::new(vec![]));
+        let tlorebooks = lorebooks.clone();
+        let gtx = tx.clone();
+        let gdecodes = decodes.clone();
+        let tasks = Tasks::default();
+        let ttasks = tasks.clone();
+        tasks.lock().unwrap().spawn(async move {
+            // Taken before the scan so changes made during it are picked up by the watch
+            let user_stamps = loader::dir_stamps(loader::cache_path("users"));
+            let char_stamps = loader::dir_stamps(loader::cache_path("chars"));
+            let user_loads = Self::spawn_subdir_loads("users", options, decodes.clone());
+            let char_loads = Self::spawn_subdir_loads("chars", options, decodes.clone());
+            let _ = tx
+                .send(GatewayUpdate::ScanStarted {
+                    chars: char_loads.len(),
+                    users: user_loads.len(),
+                })
+                .await;
+            trace!("Trying to load users");
+            let users = Self::collect_loads(
+                user_loads,
+                &tusers,
+                &tx,
+                [GatewayUpdate::UserLoaded, GatewayUpdate::UserAvatarReady],
+                options,
+                &decodes,
+                &ttasks,
+            )
+            .await;
+            trace!("Trying to load chars");
+            let chars = Self::collect_loads(
+                char_loads,
+                &tchars,
+                &tx,
+                [GatewayUpdate::CharLoaded, GatewayUpdate::CharAvatarReady],
+                options,
+                &decodes,
+                &ttasks,
+            )
+            .await;
+            trace!("Trying to load lorebooks");
+            let books = match Lorebook::dir() {
+                Some(dir) => tokio::task::spawn_blocking(move || Lorebook::load_dir(&dir))
+                    .await
+                    .unwrap_or_default(),
+                None => vec![],
+            };
+            let count = books.len();
+            *tlorebooks.lock().await = books;
+            let _ = tx.send(GatewayUpdate::LorebooksLoaded(count)).await;
+            let _ = tx.send(GatewayUpdate::ScanComplete { chars, users }).await;
+            if let Some(interval) = options.watch_interval {
+                tokio::join!(
+                    Self::watch(WATCHED_USERS, user_stamps, tusers, &tx, options, interval),
+                    Self::watch(WATCHED_CHARS, char_stamps, tchars, &tx, options, interval),
+                );
+            }
         });
-        Self { chars, users, rx }
+        Self {
+            chars,
+            users,
+            lorebooks,
+            tx: gtx,
+            rx,
+            decodes: gdecodes,
+            tasks,
+        }
+    }
+
+    /// Stops the scan, the avatar decodes and the watch, resolving once their tasks exited. The
+    /// updates sent before are still received, then `recv` returns None.
+    pub async fn shutdown(&mut self) {
+        trace!("Shutting down the gateway");
+        self.decodes.close();
+        let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        tasks.abort_all();
+        while tasks.join_next().await.is_some() {}
+        self.rx.close();
     }
 
     pub fn load_most_recent_char() -> Option<Persona> {
-        let path = Self::cache_path("chars");
+        let path = loader::cache_path("chars")?;
         Self::load_most_recent_from_cache(path)
     }
 
     pub fn load_most_recent_user() -> Option<Persona> {
-        let path = Self::cache_path("users");
+        let path = loader::cache_path("users")?;
         Self::load_most_recent_from_cache(path)
     }
 
-    pub async fn recv(&mut self) -> Option<GatewayUpdate> {
-        self.rx.recv().await
+    /// Adds a card file from anywhere to the chars, see `loader::import_file` for the formats.
+    pub async fn import_card(&self, path: &Path) -> Result<Persona> {
+        let path = path.to_path_buf();
+        let persona = tokio::task::spawn_blocking(move || {
+            let chars = loader::cache_path("chars").ok_or_else(|| anyhow!("No data directory"))?;
+            let dir = loader::import_file(&path, &chars)?;
+            trace!("Imported {:?} into {:?}", path, dir);
+            loader::load_dir(dir, &LoaderOptions::default())
+        })
+        .await??;
+        let index = {
+            let mut chars = self.chars.lock().await;
+            chars.push(persona.clone());
+            chars.len() - 1
+        };
+        // Fails only once the gateway is shut down
+        let _ = self.tx.send(GatewayUpdate::CharLoaded(index)).await;
+        Ok(persona)
     }
 
-    pub(crate) fn touch(path: &PathBuf) -> std::io::Result<()> {
-        let dest = File::open(path)?;
-        dest.set_modified(SystemTime::now())
+    /// Chars whose name, creator or one of the tags contains `query`, ignoring case.
+    pub async fn find_chars(&self, query: &str) -> Vec<Persona> {
+        let query = query.to_lowercase();
+        let matches = |s: &str| s.to_lowercase().contains(&query);
+        self.chars
+            .lock()
+            .await
+            .iter()
+            .filter(|p| {
+                matches(p.name()) || matches(p.creator()) || p.tags().iter().any(|t| matches(t))
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub async fn chars_sorted_by(&self, key: SortKey) -> Vec<Persona> {
+        let mut chars = self.chars.lock().await.clone();
+        match key {
+            SortKey::Name => chars.sort_by_cached_key(|p| p.name().to_lowercase()),
+            SortKey::ModifiedTime => chars.sort_by_key(|p| std::cmp::Reverse(p.modified_time())),
+        }
+        chars
+    }
+
+    pub async fn recv(&mut self) -> Option<GatewayUpdate> {
+        self.rx.recv().await
     }
 
     fn load_most_recent_from_cache(path: PathBuf) -> Option<Persona> {
         trace!("Trying to load from {:?}", path);
-        match Self::most_recent_dir(path) {
-            Ok(most_recent) => match Self::try_load_subdir(most_recent) {
+        match loader::most_recent_dir(path) {
+            Ok(most_recent) => match loader::load_dir(most_recent, &LoaderOptions::default()) {
                 Ok(persona) => return Some(persona),
                 Err(e) => error!("{e}"),
             },
@@ -68,135 +258,311 @@ impl Gateway {
         None
     }
 
-    fn most_recent_dir(dir: PathBuf) -> Result<PathBuf> {
-        let mut most_recent_dir: Result<PathBuf> = Err(anyhow!("No file found in {:?}", dir));
-        let mut most_recent_change = SystemTime::UNIX_EPOCH;
-        for entry in (fs::read_dir(dir)?).flatten() {
-            let path = entry.path();
-
-            if path.is_dir() {
-                let modified_time = Self::modified_time(&path);
-                if modified_time >= most_recent_change {
-                    most_recent_change = modified_time;
-                    most_recent_dir = Ok(path)
+    /// Pushes the personas as they finish loading, returning how many loaded. Every one is
+    /// announced, waiting for room in the channel, so progress can be counted. The avatars left
+    /// to decode are decoded in the background and announced with the second update.
+    async fn collect_loads(
+        loads: Vec<PersonaLoad>,
+        personas: &Arc<Mutex<Vec<Persona>>>,
+        tx: &mpsc::Sender<GatewayUpdate>,
+        [loaded, avatar_ready]: [fn(usize) -> GatewayUpdate; 2],
+        options: LoaderOptions,
+        decodes: &Arc<Semaphore>,
+        tasks: &Tasks,
+    ) -> usize {
+        let mut count = 0;
+        let mut hashes: HashMap<u64, usize> = HashMap::new();
+        for handle in loads {
+            match handle.await {
+                Ok(Ok((persona, avatar))) => {
+                    let dir = persona.path().to_path_buf();
+                    let hash = persona.content_hash();
+                    let (index, skipped) = {
+                        let mut personas = personas.lock().await;
+                        match hashes.get(&hash) {
+                            // Re-imports of the same card, the most recently used copy is kept
+                            Some(&index) => {
+                                let kept = &mut personas[index];
+                                match persona.modified_time() > kept.modified_time() {
+                                    true => {
+                                        let skipped = std::mem::replace(kept, persona);
+                                        (Some(index), Some(skipped.path().to_path_buf()))
+                                    }
+                                    false => (None, Some(dir.clone())),
+                                }
+                            }
+                            None => {
+                                personas.push(persona);
+                                hashes.insert(hash, personas.len() - 1);
+                                count += 1;
+                                (Some(personas.len() - 1), None)
+                            }
+                        }
+                    };
+                    if let Some(skipped) = skipped {
+                        warn!("Skipped {:?}, a duplicate card", skipped);
+                        let _ = tx.send(GatewayUpdate::DuplicateSkipped(skipped)).await;
+                    }
+                    let Some(index) = index else {
+                        continue;
+                    };
+                    let _ = tx.send(loaded(index)).await;
+                    let mut tasks = tasks.lock().unwrap();
+                    if let Some(avatar) = avatar
+                        && !decodes.is_closed()
+                    {
+                        while tasks.try_join_next().is_some() {}
+                        let (personas, tx, decodes) =
+                            (personas.clone(), tx.clone(), decodes.clone());
+                        tasks.spawn(async move {
+                            let Some(image) = Self::decode_avatar(avatar, options, decodes).await
+                            else {
+                                return;
+                            };
+                            // The list may have changed while decoding
+                            let mut personas = personas.lock().await;
+                            let Some(index) = personas.iter().position(|p| p.path() == dir) else {
+                                return;
+                            };
+                            personas[index].set_image(Some(image));
+                            drop(personas);
+                            let _ = tx.send(avatar_ready(index)).await;
+                        });
+                    }
                 }
+                Ok(Err(e)) => error!("{e}"),
+                Err(e) => error!("{e}"),
             }
         }
-        most_recent_dir
+        count
     }
 
-    async fn load_users(users: Arc<Mutex<Vec<Persona>>>, tx: &mpsc::Sender<GatewayUpdate>) {
-        trace!("Trying to load users");
-        if let Ok(dir) = fs::read_dir(Self::cache_path("users")) {
-            for entry in dir.flatten() {
-                let path = entry.path();
-                if path.is_dir()
-                    && let Ok(persona) = Self::try_load_subdir(path)
-                {
-                    users.lock().await.push(persona);
-                    let _ = tx.try_send(GatewayUpdate::User);
-                }
-            }
-        }
-    }
+    /// Polls a persona directory, reloading the subdirectories whose files changed. A change is
+    /// only applied once it held for a whole interval so files being copied are not loaded.
+    async fn watch(
+        watched: Watched,
+        mut known: HashMap<PathBuf, SystemTime>,
+        personas: Arc<Mutex<Vec<Persona>>>,
+        tx: &mpsc::Sender<GatewayUpdate>,
+        options: LoaderOptions,
+        interval: Duration,
+    ) {
+        let Some(dir) = loader::cache_path(watched.subdir) else {
+            return;
+        };
+        let mut pending = HashMap::new();
+        while !tx.is_closed() {
+            tokio::time::sleep(interval).await;
+            let scanned = dir.clone();
+            let Ok(stamps) =
+                tokio::task::spawn_blocking(move || loader::dir_stamps(Some(scanned))).await
+            else {
+                continue;
+            };
 
-    async fn load_chars(chars: Arc<Mutex<Vec<Persona>>>, tx: &mpsc::Sender<GatewayUpdate>) {
-        trace!("Trying to load chars");
-        if let Ok(dir) = fs::read_dir(Self::cache_path("chars")) {
-            for entry in dir.flatten() {
-                let path = entry.path();
-                if path.is_dir()
-                    && let Ok(persona) = Self::try_load_subdir(path)
+            for (path, stamp) in &stamps {
+                if known.get(path) == Some(stamp)
+                    || pending.insert(path.clone(), *stamp) != Some(*stamp)
                 {
-                    chars.lock().await.push(persona);
-                    let _ = tx.try_send(GatewayUpdate::Char);
+                    continue;
+                }
+                pending.remove(path);
+                known.insert(path.clone(), *stamp);
+                let load = path.clone();
+                let persona =
+                    match tokio::task::spawn_blocking(move || loader::load_dir(load, &options))
+                        .await
+                    {
+                        Ok(Ok(persona)) => persona,
+                        Ok(Err(e)) => {
+                            warn!("Could not load {:?}: {e}", path);
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("{e}");
+                            continue;
+                        }
+                    };
+                trace!("Reloaded {:?}", path);
+                let name = persona.name().to_string();
+                // Imported personas are already in the list
+                let update = {
+                    let mut personas = personas.lock().await;
+                    match personas.iter_mut().find(|p| p.path() == path) {
+                        Some(known) => {
+                            *known = persona;
+                            (watched.changed)(name)
+                        }
+                        None => {
+                            personas.push(persona);
+                            (watched.added)(name)
+                        }
+                    }
+                };
+                // Waits for room rather than losing the change, the list is unlocked meanwhile
+                if tx.send(update).await.is_err() {
+                    return;
                 }
             }
-        }
-    }
 
-    fn try_load_subdir(dir: PathBuf) -> Result<Persona> {
-        let modified_time = Self::modified_time(&dir);
-
-        let mut image = Err(anyhow!("Persona not found"));
-        let mut persona = Err(anyhow!("Persona not found"));
-        for entry in (fs::read_dir(&dir)?).flatten() {
-            let path = entry.path();
-            if path.is_file()
-                && let Some(ext) = path.extension()
-                && let Some(ext) = ext.to_str()
-            {
-                match ext {
-                    "json" => persona = Self::load_persona(path),
-                    "png" => image = Self::load_image(path),
-                    _ => (),
+            pending.retain(|path, _| stamps.contains_key(path));
+            let removed: Vec<PathBuf> = known
+                .keys()
+                .filter(|path| !stamps.contains_key(*path))
+                .cloned()
+                .collect();
+            for path in removed {
+                known.remove(&path);
+                let removed = {
+                    let mut personas = personas.lock().await;
+                    let i = personas.iter().position(|p| p.path() == path);
+                    i.map(|i| personas.remove(i))
+                };
+                if let Some(persona) = removed {
+                    trace!("Removed {:?}", path);
+                    let update = (watched.removed)(persona.name().to_string());
+                    if tx.send(update).await.is_err() {
+                        return;
+                    }
                 }
             }
         }
+    }
 
-        match persona {
-            Ok(data) => Ok(Persona::new(data, image.ok(), modified_time, dir)),
-            Err(_) => Err(anyhow!("Persona not found")),
-        }
+    async fn decode_avatar(
+        path: PathBuf,
+        options: LoaderOptions,
+        decodes: Arc<Semaphore>,
+    ) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let _permit = decodes.acquire_owned().await.ok()?;
+        tokio::task::spawn_blocking(move || loader::load_avatar(path, &options))
+            .await
+            .ok()?
     }
 
-    fn load_persona(path: PathBuf) -> Result<Card> {
-        let data = fs::read_to_string(&path)?;
-        Card::load_from_json(&data)
+    /// Loads every persona directory of `subdir` off the runtime, at most `decodes` at a time.
+    /// With `lazy_avatars` the avatars are not decoded, their paths are returned instead.
+    fn spawn_subdir_loads(
+        subdir: &str,
+        options: LoaderOptions,
+        decodes: Arc<Semaphore>,
+    ) -> Vec<PersonaLoad> {
+        let Some(Ok(dir)) = loader::cache_path(subdir).map(fs::read_dir) else {
+            return vec![];
+        };
+        dir.flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .map(|path| {
+                let decodes = decodes.clone();
+                tokio::spawn(async move {
+                    let _permit = decodes.acquire_owned().await?;
+                    tokio::task::spawn_blocking(move || {
+                        let (persona, avatar) = match options.lazy_avatars {
+                            true => loader::load_card_dir(path)?,
+                            false => (loader::load_dir(path, &options)?, None),
+                        };
+                        if let Some(size) = options.startup_thumbnail {
+                            persona.load_cached_thumbnail(size);
+                        }
+                        Ok((persona, avatar))
+                    })
+                    .await?
+                })
+            })
+            .collect()
     }
+}
 
-    fn load_image(path: PathBuf) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
-        let mut image = Self::crop_to_square(image::open(path)?.to_rgba8());
+impl Default for Gateway {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        let (width, height) = image.dimensions();
-        let center_x = width as f64 / 2.0;
-        let center_y = height as f64 / 2.0;
-        let radius = width.min(height) as f64 / 2.0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{persona::card::Card, testing::DataDir};
 
-        // Process each pixel
-        for (x, y, pixel) in image.enumerate_pixels_mut() {
-            let distance_from_center =
-                ((x as f64 - center_x).powi(2) + (y as f64 - center_y).powi(2)).sqrt();
+    fn write_char(data_dir: &Path, name: &str, json: &str) {
+        let dir = data_dir.join("chars").join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(format!("{name}.json")), json).unwrap();
+    }
 
-            if distance_from_center > radius {
-                pixel[3] = 0
+    /// The personas of the startup scan, sorted by name.
+    async fn scanned_chars(gateway: &mut Gateway) -> Vec<String> {
+        let scan = async {
+            while let Some(update) = gateway.recv().await {
+                if let GatewayUpdate::ScanComplete { .. } = update {
+                    break;
+                }
             }
-        }
-        Ok(image)
+        };
+        tokio::time::timeout(Duration::from_secs(5), scan)
+            .await
+            .expect("The scan did not complete");
+        let chars = gateway.chars_sorted_by(SortKey::Name).await;
+        chars.iter().map(|c| c.name().to_string()).collect()
     }
 
-    fn crop_to_square(image: ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-        let (width, height) = image.dimensions();
-        let size = width.min(height);
-
-        let x_offset = (width - size) / 2;
-        let y_offset = (height - size) / 2;
+    #[tokio::test]
+    async fn basic_personas_are_loaded_with_the_cards() {
+        let data_dir = DataDir::new("basic");
+        write_char(
+            data_dir.path(),
+            "Basic",
+            r#"{"name": "Basic", "description": "Only a name and a description"}"#,
+        );
+        let card = Card::basic("Card", "A full card");
+        write_char(
+            data_dir.path(),
+            "Card",
+            &serde_json::to_string(&card).unwrap(),
+        );
 
-        image::imageops::crop_imm(&image, x_offset, y_offset, size, size).to_image()
+        let mut gateway = Gateway::new();
+        assert_eq!(scanned_chars(&mut gateway).await, ["Basic", "Card"]);
+        let basic = gateway.find_chars("basic").await.remove(0);
+        assert_eq!(basic.data.description, "Only a name and a description");
+        gateway.shutdown().await;
     }
 
-    fn cache_path(subdir: &str) -> PathBuf {
-        dirs::cache_dir()
-            .map(|mut path| {
-                path.push("moon");
-                path.push(subdir);
-                path
-            })
-            .unwrap_or_default()
+    fn set_mtime(dir: &Path, time: SystemTime) {
+        fs::File::open(dir).unwrap().set_modified(time).unwrap();
     }
 
-    fn modified_time(path: &PathBuf) -> SystemTime {
-        if let Ok(metadata) = fs::metadata(path)
-            && let Ok(modified_time) = metadata.modified()
-        {
-            return modified_time;
-        }
-        SystemTime::UNIX_EPOCH
+    #[test]
+    fn a_fresh_copy_does_not_steal_the_recency() {
+        let data_dir = DataDir::new("recency");
+        let chars = data_dir.path().join("chars");
+        write_char(data_dir.path(), "Old", r#"{"name": "Old"}"#);
+        let mut old = loader::load_dir(chars.join("Old"), &LoaderOptions::default()).unwrap();
+        old.set_modified_time();
+        set_mtime(&chars.join("Old"), SystemTime::UNIX_EPOCH);
+
+        // Copying a card folder gives it a recent mtime
+        write_char(data_dir.path(), "Copy", r#"{"name": "Copy"}"#);
+        assert_eq!(Gateway::load_most_recent_char().unwrap().name(), "Old");
+
+        let mut copy = loader::load_dir(chars.join("Copy"), &LoaderOptions::default()).unwrap();
+        copy.set_modified_time();
+        assert_eq!(Gateway::load_most_recent_char().unwrap().name(), "Copy");
     }
-}
 
-impl Default for Gateway {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn recency_falls_back_to_the_mtimes_without_index() {
+        let data_dir = DataDir::new("mtimes");
+        let chars = data_dir.path().join("chars");
+        write_char(data_dir.path(), "Newer", r#"{"name": "Newer"}"#);
+        write_char(data_dir.path(), "Older", r#"{"name": "Older"}"#);
+        let now = SystemTime::now();
+        set_mtime(&chars.join("Newer"), now);
+        set_mtime(&chars.join("Older"), now - Duration::from_secs(3600));
+
+        assert!(!data_dir.path().join("recent.json").exists());
+        assert_eq!(Gateway::load_most_recent_char().unwrap().name(), "Newer");
+        assert!(Gateway::load_most_recent_user().is_none());
     }
 }
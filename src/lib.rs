@@ -1,6 +1,27 @@
+pub mod budget;
 pub mod chat;
+pub mod clock;
+pub mod dialects;
 pub mod gateway;
+pub mod lorebook;
 pub mod message;
+pub mod models;
 pub mod moon;
 pub mod persona;
+pub mod prompt;
 pub mod settings;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod tokens;
+pub mod tools;
+
+/// Chats and the gateway are moved to background tasks and UI threads, this fails to compile if
+/// one of them stops being thread safe.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn assert_send<T: Send>() {}
+    assert_send_sync::<persona::Persona>();
+    assert_send_sync::<chat::Chat>();
+    assert_send::<gateway::Gateway>();
+    assert_send::<moon::Moon>();
+};
@@ -0,0 +1,507 @@
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+use log::{error, trace};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    message::PromptMessage,
+    persona::{
+        card::{CharacterBook, Entry, Extensions},
+        loader,
+    },
+    tokens::TokenEstimator,
+};
+
+/// Messages scanned for keys when the book does not set `scan_depth`.
+const DEFAULT_SCAN_DEPTH: usize = 4;
+
+/// Scans of triggered content with `recursive_scanning`, entries that mention each other would
+/// otherwise never stop.
+const MAX_RECURSION: usize = 8;
+
+/// Where the content of an entry goes around the char definition, from `Entry::position`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LorePosition {
+    /// Ahead of the description and personality.
+    BeforeChar,
+    /// After the scenario.
+    AfterChar,
+}
+
+impl LorePosition {
+    /// Unknown positions are put before the char, like a missing one.
+    pub fn of(entry: &Entry) -> Self {
+        match entry.position.as_deref() {
+            Some("after_char") => Self::AfterChar,
+            _ => Self::BeforeChar,
+        }
+    }
+}
+
+/// A world info file shared between chars, attached to a chat with `Chat::attach_lorebook`.
+#[derive(Debug, Clone)]
+pub struct Lorebook {
+    pub name: String,
+    pub book: CharacterBook,
+}
+
+/// The world info format of SillyTavern, entries keyed by their uid.
+#[derive(Deserialize)]
+struct WorldInfo {
+    #[serde(default)]
+    name: Option<String>,
+    entries: serde_json::Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WorldInfoEntry {
+    #[serde(default)]
+    uid: Option<i32>,
+    #[serde(default)]
+    key: Vec<String>,
+    #[serde(default)]
+    keysecondary: Vec<String>,
+    #[serde(default)]
+    comment: String,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    constant: bool,
+    #[serde(default)]
+    selective: bool,
+    #[serde(default = "default_order")]
+    order: i32,
+    #[serde(default)]
+    position: i32,
+    #[serde(default)]
+    disable: bool,
+    #[serde(default)]
+    case_sensitive: Option<bool>,
+}
+
+fn default_order() -> i32 {
+    100
+}
+
+impl From<WorldInfoEntry> for Entry {
+    fn from(entry: WorldInfoEntry) -> Self {
+        // The other positions put the entry around the author's note or in the history
+        let position = match entry.position {
+            0 => Some("before_char".to_string()),
+            1 => Some("after_char".to_string()),
+            _ => None,
+        };
+        let comment = Some(entry.comment).filter(|c| !c.is_empty());
+        Entry {
+            keys: entry.key,
+            content: entry.content,
+            extensions: Extensions::new(),
+            enabled: !entry.disable,
+            insertion_order: entry.order,
+            case_sensitive: entry.case_sensitive,
+            name: comment.clone(),
+            priority: None,
+            id: entry.uid,
+            comment,
+            selective: Some(entry.selective),
+            secondary_keys: Some(entry.keysecondary),
+            constant: Some(entry.constant),
+            position,
+        }
+    }
+}
+
+impl Lorebook {
+    /// Reads a SillyTavern world info file, or a character book exported on its own.
+    pub fn load(path: &Path) -> Result<Self> {
+        let value: Value = serde_json::from_slice(&fs::read(path)?)?;
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let (name, book) = match value.get("entries") {
+            Some(Value::Object(_)) => {
+                let info: WorldInfo = serde_json::from_value(value)?;
+                let mut entries = info
+                    .entries
+                    .into_iter()
+                    .map(|(_, entry)| serde_json::from_value::<WorldInfoEntry>(entry))
+                    .collect::<Result<Vec<_>, _>>()?;
+                entries.sort_by_key(|e| e.uid);
+                let book = CharacterBook {
+                    name: info.name.clone(),
+                    description: None,
+                    scan_depth: None,
+                    token_budget: None,
+                    recursive_scanning: None,
+                    extensions: Extensions::new(),
+                    entries: entries.into_iter().map(Entry::from).collect(),
+                };
+                (info.name, book)
+            }
+            Some(Value::Array(_)) => {
+                let book: CharacterBook = serde_json::from_value(value)?;
+                (book.name.clone(), book)
+            }
+            _ => return Err(anyhow!("No lorebook entries in {:?}", path)),
+        };
+        Ok(Self {
+            name: name.filter(|n| !n.trim().is_empty()).unwrap_or(stem),
+            book,
+        })
+    }
+
+    /// The lorebooks of `dir`, sorted by file name. Files that fail to parse are skipped.
+    pub fn load_dir(dir: &Path) -> Vec<Self> {
+        let Ok(files) = fs::read_dir(dir) else {
+            return vec![];
+        };
+        let mut paths: Vec<PathBuf> = files
+            .flatten()
+            .map(|f| f.path())
+            .filter(|p| {
+                p.extension()
+                    .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+            })
+            .collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .filter_map(|path| match Self::load(&path) {
+                Ok(book) => {
+                    trace!("Loaded lorebook {:?}", path);
+                    Some(book)
+                }
+                Err(e) => {
+                    error!("{:?}: {}", path, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// The `lorebooks` directory of the data directory.
+    pub fn dir() -> Option<PathBuf> {
+        loader::cache_path("lorebooks")
+    }
+}
+
+/// Enabled entries of `books` that are constant or have a key in the last messages of `history`,
+/// in insertion order across the books. Each book keeps to its own `token_budget`.
+///
+/// With `recursive_scanning`, the content of the triggered entries is scanned in turn for the keys
+/// of the others.
+pub fn activate<'a>(
+    books: &[&'a CharacterBook],
+    history: &[PromptMessage],
+    estimator: &dyn TokenEstimator,
+) -> Vec<&'a Entry> {
+    let mut active = vec![];
+    for book in books {
+        let depth = book
+            .scan_depth
+            .and_then(|d| usize::try_from(d).ok())
+            .unwrap_or(DEFAULT_SCAN_DEPTH);
+        let window = &history[history.len().saturating_sub(depth)..];
+        let text = window
+            .iter()
+            .map(|m| &*m.content)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut scanned = text;
+        let mut triggered: Vec<&Entry> = vec![];
+        for pass in 0..=MAX_RECURSION {
+            let lowercase = scanned.to_lowercase();
+            let new: Vec<&Entry> = book
+                .entries
+                .iter()
+                .filter(|e| e.enabled && !triggered.iter().any(|t| std::ptr::eq(*t, *e)))
+                .filter(|e| {
+                    (pass == 0 && e.constant.unwrap_or(false)) || triggers(e, &scanned, &lowercase)
+                })
+                .collect();
+            if new.is_empty() || !book.recursive_scanning.unwrap_or(false) {
+                triggered.extend(new);
+                break;
+            }
+            scanned = new
+                .iter()
+                .map(|e| e.content.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            triggered.extend(new);
+        }
+        active.extend(fit_budget(book, triggered, estimator));
+    }
+    active.sort_by_key(|e| e.insertion_order);
+    active
+}
+
+/// If one of the keys of `entry` is in `text`, `lowercase` being the same text in lowercase. A
+/// selective entry also needs one of its secondary keys, when it has any.
+fn triggers(entry: &Entry, text: &str, lowercase: &str) -> bool {
+    let case_sensitive = entry.case_sensitive.unwrap_or(false);
+    let has_key = |keys: &[String]| {
+        keys.iter().any(|key| match case_sensitive {
+            _ if key.trim().is_empty() => false,
+            true => text.contains(key.as_str()),
+            false => lowercase.contains(&key.to_lowercase()),
+        })
+    };
+    let secondary = entry.secondary_keys.as_deref().filter(|keys| {
+        entry.selective.unwrap_or(false) && keys.iter().any(|k| !k.trim().is_empty())
+    });
+    has_key(&entry.keys) && secondary.is_none_or(has_key)
+}
+
+/// Keeps constant entries first, then the highest `priority` and the lowest `insertion_order`,
+/// until the next one would go over the budget of the book.
+fn fit_budget<'a>(
+    book: &CharacterBook,
+    mut entries: Vec<&'a Entry>,
+    estimator: &dyn TokenEstimator,
+) -> Vec<&'a Entry> {
+    let Some(budget) = book.token_budget.and_then(|b| usize::try_from(b).ok()) else {
+        return entries;
+    };
+    entries.sort_by_key(|e| {
+        (
+            !e.constant.unwrap_or(false),
+            Reverse(e.priority.unwrap_or(0)),
+            e.insertion_order,
+        )
+    });
+    let mut used = 0;
+    let fitting = entries
+        .iter()
+        .take_while(|e| {
+            used += estimator.estimate(&e.content);
+            used <= budget
+        })
+        .count();
+    let dropped = entries.split_off(fitting);
+    if !dropped.is_empty() {
+        let names: Vec<&str> = dropped
+            .iter()
+            .map(|e| {
+                e.name
+                    .as_deref()
+                    .or(e.comment.as_deref())
+                    .unwrap_or("unnamed")
+            })
+            .collect();
+        trace!("Lore over the budget of {budget} tokens, dropped {names:?}");
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use llm::chat::ChatRole;
+    use serde_json::json;
+
+    use super::*;
+
+    /// One token per word, so the budgets are easy to count.
+    #[derive(Debug)]
+    struct Words;
+
+    impl TokenEstimator for Words {
+        fn estimate(&self, text: &str) -> usize {
+            text.split_whitespace().count()
+        }
+    }
+
+    /// An enabled entry named `name`, the fields of `fields` added or replaced.
+    fn entry(name: &str, keys: &[&str], content: &str, fields: Value) -> Value {
+        let mut entry = json!({
+            "name": name,
+            "keys": keys,
+            "content": content,
+            "extensions": {},
+            "enabled": true,
+            "insertion_order": 0,
+        });
+        if let (Some(entry), Value::Object(fields)) = (entry.as_object_mut(), fields) {
+            entry.extend(fields);
+        }
+        entry
+    }
+
+    fn book(entries: Vec<Value>, fields: Value) -> CharacterBook {
+        let mut book = json!({ "extensions": {}, "entries": entries });
+        if let (Some(book), Value::Object(fields)) = (book.as_object_mut(), fields) {
+            book.extend(fields);
+        }
+        serde_json::from_value(book).unwrap()
+    }
+
+    fn active_names(book: &CharacterBook, history: &[&str]) -> Vec<String> {
+        let history: Vec<PromptMessage> = history
+            .iter()
+            .map(|text| PromptMessage::new(ChatRole::User, text))
+            .collect();
+        activate(&[book], &history, &Words)
+            .iter()
+            .map(|e| e.name.clone().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn entries_over_the_budget_are_dropped_by_priority() {
+        let entries = vec![
+            entry(
+                "constant",
+                &[],
+                "Always three words",
+                json!({ "constant": true, "insertion_order": 5 }),
+            ),
+            entry(
+                "high",
+                &["moon"],
+                "The moon glows",
+                json!({ "priority": 10, "insertion_order": 3 }),
+            ),
+            entry(
+                "first-tie",
+                &["moon"],
+                "Silver",
+                json!({ "priority": 5, "insertion_order": 2 }),
+            ),
+            entry(
+                "second-tie",
+                &["moon"],
+                "Tides",
+                json!({ "priority": 5, "insertion_order": 4 }),
+            ),
+            // Would fit, but the entries after a dropped one are dropped too
+            entry(
+                "low",
+                &["moon"],
+                "Night",
+                json!({ "priority": 1, "insertion_order": 1 }),
+            ),
+            entry("untriggered", &["sun"], "Day", json!({ "priority": 20 })),
+        ];
+        let history = ["Look at the moon"];
+
+        let limited = book(entries.clone(), json!({ "token_budget": 7 }));
+        assert_eq!(
+            active_names(&limited, &history),
+            ["first-tie", "high", "constant"]
+        );
+
+        let unlimited = book(entries, json!({}));
+        assert_eq!(
+            active_names(&unlimited, &history),
+            ["low", "first-tie", "high", "second-tie", "constant"]
+        );
+    }
+
+    #[test]
+    fn constant_entries_are_dropped_last() {
+        let entries = vec![
+            entry(
+                "triggered",
+                &["moon"],
+                "Two words",
+                json!({ "priority": 100 }),
+            ),
+            entry(
+                "constant",
+                &[],
+                "Three whole words",
+                json!({ "constant": true }),
+            ),
+        ];
+        let limited = book(entries, json!({ "token_budget": 4 }));
+        assert_eq!(active_names(&limited, &["The moon"]), ["constant"]);
+    }
+
+    #[test]
+    fn triggered_content_is_scanned_in_recursive_mode() {
+        let entries = vec![
+            entry(
+                "a",
+                &["castle"],
+                "The castle belongs to the queen",
+                json!({}),
+            ),
+            entry("b", &["queen"], "The queen rules", json!({})),
+        ];
+        let flat = book(entries.clone(), json!({}));
+        assert_eq!(active_names(&flat, &["The castle"]), ["a"]);
+
+        let recursive = book(entries.clone(), json!({ "recursive_scanning": true }));
+        assert_eq!(active_names(&recursive, &["The castle"]), ["a", "b"]);
+
+        // The recursively triggered entries share the budget
+        let limited = book(
+            entries,
+            json!({ "recursive_scanning": true, "token_budget": 8 }),
+        );
+        assert_eq!(active_names(&limited, &["The castle"]), ["a"]);
+    }
+
+    #[test]
+    fn recursion_stops() {
+        let mutual = book(
+            vec![
+                entry("a", &["alpha"], "See beta", json!({})),
+                entry("b", &["beta"], "See alpha", json!({})),
+            ],
+            json!({ "recursive_scanning": true }),
+        );
+        assert_eq!(active_names(&mutual, &["alpha"]), ["a", "b"]);
+
+        // Each entry leads to the next, further than the recursion goes
+        let chain: Vec<Value> = (0..MAX_RECURSION + 4)
+            .map(|i| {
+                let key = format!("key{i}");
+                let content = format!("key{}", i + 1);
+                entry(&key, &[key.as_str()], &content, json!({}))
+            })
+            .collect();
+        let chain = book(chain, json!({ "recursive_scanning": true }));
+        assert_eq!(active_names(&chain, &["key0"]).len(), MAX_RECURSION + 1);
+    }
+
+    #[test]
+    fn selective_entries_need_a_secondary_key() {
+        // selective, secondary keys, case_sensitive, text, triggered
+        let cases: &[(bool, &[&str], bool, &str, bool)] = &[
+            (false, &["Queen"], false, "the castle", true),
+            (true, &["Queen"], false, "the castle", false),
+            (true, &["Queen"], false, "the castle of the queen", true),
+            (true, &["Queen", "King"], false, "the KING's castle", true),
+            (true, &["Queen"], true, "the castle of the queen", false),
+            (true, &["Queen"], true, "the castle of the Queen", true),
+            (true, &["Queen"], true, "the Castle of the Queen", false),
+            (true, &["Queen"], false, "the Castle of the Queen", true),
+            // Behaves as a plain entry without secondary keys
+            (true, &[], false, "the castle", true),
+            (true, &[" "], false, "the castle", true),
+            (true, &[], true, "the Castle", false),
+        ];
+        for &(selective, secondary, case_sensitive, text, triggered) in cases {
+            let fields = json!({
+                "selective": selective,
+                "secondary_keys": secondary,
+                "case_sensitive": case_sensitive,
+            });
+            let book = book(vec![entry("castle", &["castle"], "", fields)], json!({}));
+            assert_eq!(
+                !active_names(&book, &[text]).is_empty(),
+                triggered,
+                "{selective} {secondary:?} {case_sensitive} {text:?}"
+            );
+        }
+    }
+}
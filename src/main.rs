@@ -44,16 +44,58 @@ async fn handle(moon: &mut Moon) {
                     return;
                 }
                 ChatUpdate::StreamUpdate => println!("StreamUpdate "),
-                ChatUpdate::StreamFinished => {
-                    println!("StreamFinished");
+                ChatUpdate::StreamFinished { reason, usage } => {
+                    println!("StreamFinished: {reason:?} {usage:?}");
                     return;
                 }
+                ChatUpdate::BuffersTrimmed { freed_bytes } => {
+                    println!("Trimmed {freed_bytes} bytes")
+                }
+                ChatUpdate::ContextTrimmed { dropped } => {
+                    println!("Dropped {dropped} messages from the context")
+                }
+                ChatUpdate::ToolCalled { name, args } => println!("Calling {name} with {args}"),
+                ChatUpdate::PinsChanged => println!("Pins changed"),
+                ChatUpdate::Saved => println!("Chat saved"),
+                ChatUpdate::SelectionChanged => println!("Selection changed"),
+                ChatUpdate::Recovered { ids } => println!("Interrupted messages {ids:?}"),
+                ChatUpdate::Reset => println!("Chat reset"),
+                ChatUpdate::Retrying { attempt } => println!("Retrying, attempt {attempt}"),
+                ChatUpdate::MessageCreated { owner } => println!("Message created for {owner:?}"),
+                ChatUpdate::ToolCallFinished { name, is_error } => {
+                    println!("{name} finished, error: {is_error}")
+                }
+                ChatUpdate::ReplyScheduled { fire_at } => {
+                    println!("Reply scheduled at {fire_at:?}")
+                }
             },
             MoonUpdate::GU(u) => match u {
-                GatewayUpdate::Char => println!("Char loaded"),
-                GatewayUpdate::User => println!("User loaded"),
+                GatewayUpdate::ScanStarted { chars, users } => {
+                    println!("Loading {chars} chars and {users} users")
+                }
+                GatewayUpdate::CharLoaded(index) => println!("Char {index} loaded"),
+                GatewayUpdate::UserLoaded(index) => println!("User {index} loaded"),
+                GatewayUpdate::CharAvatarReady(index) | GatewayUpdate::UserAvatarReady(index) => {
+                    println!("Avatar {index} ready")
+                }
+                GatewayUpdate::DuplicateSkipped(path) => println!("Duplicate {path:?} skipped"),
+                GatewayUpdate::LorebooksLoaded(count) => println!("{count} lorebooks loaded"),
+                GatewayUpdate::ScanComplete { chars, users } => {
+                    println!("Loaded {chars} chars and {users} users")
+                }
+                GatewayUpdate::CharAdded(name) | GatewayUpdate::UserAdded(name) => {
+                    println!("{name} added")
+                }
+                GatewayUpdate::CharRemoved(name) | GatewayUpdate::UserRemoved(name) => {
+                    println!("{name} removed")
+                }
+                GatewayUpdate::CharChanged(name) | GatewayUpdate::UserChanged(name) => {
+                    println!("{name} changed")
+                }
             },
+            MoonUpdate::SettingsReloaded => println!("Settings reloaded"),
             MoonUpdate::Error(e) => println!("Error: {e}"),
+            MoonUpdate::Shutdown => break,
         }
     }
 }
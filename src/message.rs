@@ -1,35 +1,357 @@
 use std::{
-    hash::{DefaultHasher, Hash, Hasher},
-    time::SystemTime,
+    borrow::Cow,
+    fs,
+    ops::Range,
+    path::Path,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
     vec,
 };
 
-use llm::chat::ChatMessage;
+use anyhow::{Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use llm::chat::{ChatMessage, ChatRole};
+use log::warn;
 use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
 pub enum OwnerType {
     User,
     Char(usize),
+    /// Narration written by the user outside of any persona, see `Chat::add_system_message`.
+    System,
 }
 
+/// Owner name of the system messages.
+pub const NARRATOR: &str = "Narrator";
+
 impl From<OwnerType> for usize {
+    /// Index of the persona. System messages have none, they map past the last one.
     fn from(value: OwnerType) -> Self {
         match value {
             OwnerType::User => 0,
             OwnerType::Char(i) => i + 1,
+            OwnerType::System => usize::MAX,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Upstream provider that served a request routed through OpenRouter.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct RoutingInfo {
+    pub provider_name: Option<String>,
+    pub upstream_model: Option<String>,
+    pub moderation_flags: Vec<String>,
+}
+
+impl RoutingInfo {
+    /// Reads routing fields from either a completion chunk or an error body returned by OpenRouter.
+    pub fn from_openrouter_json(value: &Value) -> Option<Self> {
+        let error_metadata = value.pointer("/error/metadata");
+        let provider_name = value
+            .get("provider")
+            .or_else(|| error_metadata.and_then(|m| m.get("provider_name")))
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let upstream_model = value
+            .get("model")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let moderation_flags = error_metadata
+            .and_then(|m| m.get("reasons"))
+            .and_then(Value::as_array)
+            .map(|reasons| {
+                reasons
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let info = RoutingInfo {
+            provider_name,
+            upstream_model,
+            moderation_flags,
+        };
+        (info != RoutingInfo::default()).then_some(info)
+    }
+}
+
+/// Which revision of the char card produced a message.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RevisionTag {
+    pub hash: u64,
+    pub label: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MessageMetadata {
+    pub routing: Option<RoutingInfo>,
+    pub revision: Option<RevisionTag>,
+    /// Seed the message was generated with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "TokenUsage::is_empty")]
+    pub usage: TokenUsage,
+    /// The settings of the generation, None for user messages and messages saved before it was
+    /// recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<GenerationInfo>,
+}
+
+/// The effective settings a char message was generated with, taken when the request is built.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GenerationInfo {
+    pub model: String,
+    pub temperature: f32,
+    pub max_tokens: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// `OPENROUTER`, or `CUSTOM_BACKEND` for a provider set with `Chat::set_provider_factory`.
+    pub backend: String,
+}
+
+/// Tokens billed for a generation, every round of tool calls included. Zero when the provider
+/// reported nothing, or for messages saved before it was recorded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// US dollars, from the pricing of the model catalog. Zero for models not in the catalog.
+    pub cost: f64,
+}
+
+impl TokenUsage {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// An image sent with a user message. The bytes are kept in the message so it survives being
+/// saved, whatever happens to the original file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Attachment {
+    pub mime: String,
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub data: Arc<[u8]>,
+}
+
+impl Attachment {
+    pub fn new(mime: &str, data: Vec<u8>) -> Self {
+        Self {
+            mime: mime.to_string(),
+            data: Arc::from(data),
+        }
+    }
+
+    /// Reads an image file, the type comes from the extension.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let mime = match extension.as_str() {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "webp" => "image/webp",
+            "gif" => "image/gif",
+            _ => return Err(anyhow!("Unsupported image type {:?}", path)),
+        };
+        Ok(Self::new(mime, fs::read(path)?))
+    }
+
+    /// The image as a data URL, the form OpenAI compatible APIs take inline images in.
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime, STANDARD.encode(&self.data))
+    }
+}
+
+fn to_base64<S: Serializer>(data: &Arc<[u8]>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(data))
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Arc<[u8]>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    STANDARD
+        .decode(encoded)
+        .map(Arc::from)
+        .map_err(serde::de::Error::custom)
+}
+
+/// The id of the message created at `timestamp`. An FNV-1a hash of the time since the epoch,
+/// so the ids of exported trees are the same for every build.
+pub(crate) fn timestamp_id(timestamp: SystemTime) -> usize {
+    let (before_epoch, since) = match timestamp.duration_since(UNIX_EPOCH) {
+        Ok(since) => (false, since),
+        Err(e) => (true, e.duration()),
+    };
+    let bytes = [before_epoch as u8]
+        .into_iter()
+        .chain(since.as_secs().to_le_bytes())
+        .chain(since.subsec_nanos().to_le_bytes());
+    bytes.fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    }) as usize
+}
+
+/// Now, or just after the last message created when the clock did not move since. Ids are
+/// derived from it, so two messages can not get the same.
+fn creation_time() -> SystemTime {
+    static LAST: Mutex<Option<SystemTime>> = Mutex::new(None);
+    let mut last = LAST.lock().unwrap();
+    let now = match *last {
+        Some(last) if SystemTime::now() <= last => last + Duration::from_nanos(1),
+        _ => SystemTime::now(),
+    };
+    *last = Some(now);
+    now
+}
+
+/// A message as it goes into a request, the content is shared with the message's cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptMessage {
+    pub role: ChatRole,
+    pub content: Arc<str>,
+    pub attachments: Vec<Attachment>,
+}
+
+impl PromptMessage {
+    pub fn new(role: ChatRole, content: &str) -> Self {
+        Self {
+            role,
+            content: Arc::from(content),
+            attachments: vec![],
+        }
+    }
+
+    /// The message followed by one message per attachment, as the image messages of `llm` have
+    /// no text. The attachments are dropped for models without `vision`.
+    pub fn to_chat_messages(&self, vision: bool) -> Vec<ChatMessage> {
+        let mut messages = vec![self.to_chat_message()];
+        if !vision {
+            if !self.attachments.is_empty() {
+                warn!(
+                    "The model takes no images, {} left out",
+                    self.attachments.len()
+                );
+            }
+            return messages;
+        }
+        for attachment in &self.attachments {
+            let builder = match self.role {
+                ChatRole::User => ChatMessage::user(),
+                ChatRole::Assistant => ChatMessage::assistant(),
+            };
+            messages.push(builder.image_url(attachment.data_url()).build());
+        }
+        messages
+    }
+
+    pub fn to_chat_message(&self) -> ChatMessage {
+        match self.role {
+            ChatRole::User => ChatMessage::user().content(&*self.content).build(),
+            ChatRole::Assistant => ChatMessage::assistant().content(&*self.content).build(),
+        }
+    }
+}
+
+/// Where a message is in its generation, messages written by hand are `Complete`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub enum MessageStatus {
+    /// Waiting for the first token.
+    Pending,
+    Streaming,
+    #[default]
+    Complete,
+    /// The generation failed, the message keeps its id and can be regenerated.
+    Errored(String),
+}
+
+/// Why the model stopped writing a message.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum FinishReason {
+    Stop,
+    /// Cut by `max_tokens`, the message can be continued.
+    Length,
+    ContentFilter,
+    Unknown,
+}
+
+/// Structured content of a char message besides its text, in the order it was produced.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessagePart {
+    ToolCall {
+        id: String,
+        name: String,
+        /// JSON arguments as produced by the model.
+        arguments: String,
+    },
+    ToolResult {
+        id: String,
+        name: String,
+        content: String,
+        is_error: bool,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Message {
     pub owner: OwnerType,
     pub owner_name: String,
+    /// Changing it in place needs `invalidate_prompt`, the prompt form is cached.
     pub text: String,
+    #[serde(default)]
+    pub metadata: MessageMetadata,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<MessagePart>,
+    /// Images of a user message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    #[serde(default)]
+    pub status: MessageStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<FinishReason>,
+    /// Bookmark set with `Chat::set_label`, kept when the message is regenerated in place.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Shown but not sent to the model, see `Chat::set_hidden`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hidden_from_prompt: bool,
+    /// Texts replaced by `Chat::edit_in_place`, oldest first, with when they were replaced.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub revisions: Vec<(SystemTime, String)>,
     timestamp: SystemTime,
+    /// The prompt form with the assistant prefix it was made with.
+    #[serde(skip)]
+    prompt_cache: Option<(String, Arc<str>)>,
+    #[serde(skip)]
+    spans_cache: SpansCache,
 }
 
+type Spans = Vec<Vec<(String, Style)>>;
+
+/// The last result of `Message::spans` with the text it was parsed from, so it is parsed again
+/// whenever the text changes, streamed tokens included.
+#[derive(Debug, Default)]
+struct SpansCache(Mutex<Option<(String, Spans)>>);
+
+impl Clone for SpansCache {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+static IMAGE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"!\[[^\]]*\]\([^)]*\)[ \t\r\n]*").unwrap());
+
+static NEWLINES_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[ \t\r]*\n[ \t\r]*").unwrap());
+
 impl Message {
     pub fn from_user(owner_name: String, mut text: String) -> Self {
         if !text.ends_with('\n') {
@@ -39,7 +361,17 @@ impl Message {
             owner: OwnerType::User,
             owner_name,
             text,
-            timestamp: SystemTime::now(),
+            metadata: MessageMetadata::default(),
+            parts: vec![],
+            attachments: vec![],
+            status: MessageStatus::Complete,
+            finish_reason: None,
+            label: None,
+            hidden_from_prompt: false,
+            revisions: vec![],
+            timestamp: creation_time(),
+            prompt_cache: None,
+            spans_cache: SpansCache::default(),
         }
     }
 
@@ -51,57 +383,204 @@ impl Message {
             owner: OwnerType::Char(char_id),
             owner_name,
             text,
-            timestamp: SystemTime::now(),
+            metadata: MessageMetadata::default(),
+            parts: vec![],
+            attachments: vec![],
+            status: MessageStatus::Complete,
+            finish_reason: None,
+            label: None,
+            hidden_from_prompt: false,
+            revisions: vec![],
+            timestamp: creation_time(),
+            prompt_cache: None,
+            spans_cache: SpansCache::default(),
+        }
+    }
+
+    pub fn empty_from_user(owner_name: String) -> Self {
+        let mut message = Self::from_user(owner_name, String::new());
+        message.status = MessageStatus::Pending;
+        message
+    }
+
+    pub fn from_system(mut text: String) -> Self {
+        let mut message = Self::from_user(NARRATOR.to_string(), String::new());
+        if !text.ends_with('\n') {
+            text.push('\n');
         }
+        message.owner = OwnerType::System;
+        message.text = text;
+        message
     }
 
     pub fn empty_from_char(char_id: usize, owner_name: String) -> Self {
-        Self::from_char(char_id, owner_name, String::new())
+        let mut message = Self::from_char(char_id, owner_name, String::new());
+        message.status = MessageStatus::Pending;
+        message
     }
 
     pub fn to_chat_message(&self) -> ChatMessage {
         match self.owner {
-            OwnerType::User => ChatMessage::user().content(&self.text).build(),
+            OwnerType::User | OwnerType::System => {
+                ChatMessage::user().content(self.prompt_text()).build()
+            }
             OwnerType::Char(_) => ChatMessage::assistant().content(&self.text).build(),
         }
     }
 
+    /// The text as sent. `llm` has no system role in the history, system messages are user
+    /// messages marked as narration.
+    pub fn prompt_text(&self) -> Cow<'_, str> {
+        match self.owner {
+            OwnerType::System => Cow::Owned(format!("[{NARRATOR}: {}]", self.text.trim_end())),
+            OwnerType::User | OwnerType::Char(_) => Cow::Borrowed(&self.text),
+        }
+    }
+
+    /// The prompt form, made again only after `invalidate_prompt` or for another prefix.
+    /// `assistant_prefix` goes in front of the char's messages not starting with it already, for
+    /// the dialects naming the speaker.
+    pub fn prompt_message(&mut self, assistant_prefix: &str) -> PromptMessage {
+        let prefix = match self.owner {
+            OwnerType::Char(_) => assistant_prefix,
+            OwnerType::User | OwnerType::System => "",
+        };
+        let content = match &self.prompt_cache {
+            Some((cached, content)) if cached == prefix => {
+                debug_assert_eq!(
+                    **content,
+                    *self.prefixed_prompt_text(prefix),
+                    "text changed without invalidate_prompt"
+                );
+                content.clone()
+            }
+            _ => {
+                let content: Arc<str> = Arc::from(&*self.prefixed_prompt_text(prefix));
+                self.prompt_cache = Some((prefix.to_string(), content.clone()));
+                content
+            }
+        };
+        let role = match self.owner {
+            OwnerType::User | OwnerType::System => ChatRole::User,
+            OwnerType::Char(_) => ChatRole::Assistant,
+        };
+        PromptMessage {
+            role,
+            content,
+            attachments: self.attachments.clone(),
+        }
+    }
+
+    fn prefixed_prompt_text(&self, prefix: &str) -> Cow<'_, str> {
+        let text = self.prompt_text();
+        match text.starts_with(prefix) {
+            true => text,
+            false => Cow::Owned(format!("{prefix}{text}")),
+        }
+    }
+
+    /// To call after changing `text` in place, so the next request sees the change.
+    pub fn invalidate_prompt(&mut self) {
+        self.prompt_cache = None;
+    }
+
     pub fn create_brother(&self) -> Self {
         Message {
             owner: self.owner,
             owner_name: self.owner_name.clone(),
             text: String::new(),
-            timestamp: SystemTime::now(),
+            metadata: MessageMetadata::default(),
+            parts: vec![],
+            attachments: vec![],
+            status: MessageStatus::Complete,
+            finish_reason: None,
+            label: None,
+            hidden_from_prompt: false,
+            revisions: vec![],
+            timestamp: creation_time(),
+            prompt_cache: None,
+            spans_cache: SpansCache::default(),
         }
     }
 
+    /// Empties the message for a new generation, keeping its id.
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.invalidate_prompt();
+        self.parts.clear();
+        self.metadata = MessageMetadata::default();
+        self.status = MessageStatus::Pending;
+        self.finish_reason = None;
+    }
+
     pub fn id(&self) -> usize {
-        let mut hasher = DefaultHasher::new();
-        self.timestamp.hash(&mut hasher);
-        hasher.finish() as usize
+        timestamp_id(self.timestamp)
+    }
+
+    /// A copy with a new creation time, so a new id.
+    pub(crate) fn renewed(&self) -> Self {
+        Message {
+            timestamp: creation_time(),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn set_timestamp(&mut self, timestamp: SystemTime) {
+        self.timestamp = timestamp;
     }
 
     pub fn timestamp(&self) -> SystemTime {
         self.timestamp
     }
 
+    /// Code, fenced or inline, is kept as written.
     pub fn clean(&self) -> String {
-        // Remove markdown images
-        let image_re = Regex::new(r"!\[[^\]]*\]\([^)]*\)[ \t\r\n]*").unwrap();
-        let no_images = image_re.replace_all(&self.text, "");
-        // Replace bullshit linebreaks
-        let re_newlines = Regex::new(r"[ \t\r]*\n[ \t\r]*").unwrap();
-        let one_linebreaks = re_newlines.replace_all(&no_images, "\n").to_string();
+        let mut cleaned = String::new();
+        let mut start = 0;
+        for code in code_ranges(&self.text) {
+            cleaned.push_str(&Self::clean_prose(&self.text[start..code.start]));
+            cleaned.push_str(&self.text[code.clone()]);
+            start = code.end;
+        }
+        cleaned.push_str(&Self::clean_prose(&self.text[start..]));
 
         // Trim whitespace from start and end and put a single one at the end
-        let mut cleaned = one_linebreaks.trim().to_string();
+        let mut cleaned = cleaned.trim().to_string();
         cleaned.push('\n');
         cleaned
     }
 
+    fn clean_prose(text: &str) -> String {
+        // Remove markdown images
+        let no_images = IMAGE_RE.replace_all(text, "");
+        // Replace bullshit linebreaks
+        NEWLINES_RE.replace_all(&no_images, "\n").to_string()
+    }
+
+    /// Parsed once per text, see `SpansCache`.
     pub fn spans(&self) -> Vec<Vec<(String, Style)>> {
+        let mut cache = self.spans_cache.0.lock().unwrap();
+        match &*cache {
+            Some((text, spans)) if *text == self.text => spans.clone(),
+            _ => {
+                let spans = self.parse_spans();
+                *cache = Some((self.text.clone(), spans.clone()));
+                spans
+            }
+        }
+    }
+
+    /// The lines of a fenced block, its fences and blank lines included, are each a single
+    /// `CodeBlock` span.
+    fn parse_spans(&self) -> Spans {
         let mut spans = vec![];
-        for s in self.clean().split('\n') {
+        let mut in_fence = false;
+        for s in self.clean().lines() {
+            if in_fence || is_fence(s) {
+                in_fence = in_fence != is_fence(s);
+                spans.push(vec![(s.to_string(), Style::CodeBlock)]);
+                continue;
+            }
             let line = Self::line(s);
             if !line.is_empty() {
                 spans.push(line);
@@ -110,57 +589,512 @@ impl Message {
         spans
     }
 
+    /// Emphasis is only applied between a marker and a matching one, others are text, so a
+    /// reply streamed up to an open `**` renders it as written.
     fn line(text: &str) -> Vec<(String, Style)> {
-        let mut line = vec![];
-        let mut cs = Style::Normal;
-        let mut ct = String::new();
-        for ch in text.chars() {
-            let (ns, push_next) = cs.next(ch);
-            match ns != cs {
-                true => {
-                    push_next.then(|| ct.push(ch));
-                    (!ct.is_empty()).then(|| line.push((ct, cs)));
-                    ct = String::new();
-                    (!push_next).then(|| ct.push(ch));
+        let mut tokens = Token::parse(text);
+        Token::pair_markers(&mut tokens);
+
+        let mut line: Vec<(String, Style)> = vec![];
+        let (mut italic, mut bold, mut quote) = (0, 0, false);
+        for token in tokens {
+            let (range, style) = match token {
+                Token::Text(range) => (range, Style::with(italic > 0, bold > 0, quote)),
+                Token::Code(range) => (range, Style::Code),
+                // Marks are part of what they delimit
+                Token::Quote(range) => {
+                    let before = Style::with(italic > 0, bold > 0, quote);
+                    quote = !quote;
+                    match quote {
+                        true => (range, Style::with(italic > 0, bold > 0, quote)),
+                        false => (range, before),
+                    }
+                }
+                Token::Marker {
+                    range,
+                    matched: Some(opens),
+                    ..
+                } => {
+                    let len = range.len() as i32;
+                    let before = Style::with(italic > 0, bold > 0, quote);
+                    let delta = if opens { 1 } else { -1 };
+                    if len != 2 {
+                        italic += delta;
+                    }
+                    if len != 1 {
+                        bold += delta;
+                    }
+                    match opens {
+                        true => (range, Style::with(italic > 0, bold > 0, quote)),
+                        false => (range, before),
+                    }
+                }
+                Token::Marker { range, .. } => (range, Style::with(italic > 0, bold > 0, quote)),
+            };
+            match line.last_mut() {
+                Some((last, last_style)) if *last_style == style && style != Style::Code => {
+                    last.push_str(&text[range])
                 }
-                false => ct.push(ch),
+                _ => line.push((text[range].to_string(), style)),
             }
-            cs = ns;
         }
-        (!ct.is_empty()).then(|| line.push((ct, cs)));
         line
     }
 }
 
+/// A piece of a line for `Message::line`.
+#[derive(Debug, Clone)]
+enum Token {
+    Text(Range<usize>),
+    Code(Range<usize>),
+    Quote(Range<usize>),
+    /// A run of one to three `*` or `_`. `matched` is set once paired, to whether it opens.
+    Marker {
+        range: Range<usize>,
+        ch: char,
+        can_open: bool,
+        can_close: bool,
+        matched: Option<bool>,
+    },
+}
+
+impl Token {
+    fn parse(text: &str) -> Vec<Token> {
+        let mut tokens = vec![];
+        let push_text = |tokens: &mut Vec<Token>, range: Range<usize>| match tokens.last_mut() {
+            Some(Token::Text(last)) if last.end == range.start => last.end = range.end,
+            _ => tokens.push(Token::Text(range)),
+        };
+        let mut start = 0;
+        for code in inline_code(text)
+            .into_iter()
+            .chain(std::iter::once(text.len()..text.len()))
+        {
+            let mut chars = text[start..code.start].char_indices().peekable();
+            while let Some((i, ch)) = chars.next() {
+                let i = start + i;
+                match ch {
+                    '"' | '“' | '”' => tokens.push(Token::Quote(i..i + ch.len_utf8())),
+                    '*' | '_' => {
+                        let mut end = i + 1;
+                        while chars.next_if(|(_, c)| *c == ch).is_some() {
+                            end += 1;
+                        }
+                        match end - i {
+                            1..=3 => {
+                                let before = text[..i].chars().next_back();
+                                let after = text[end..].chars().next();
+                                let (can_open, can_close) = flanking(ch, before, after);
+                                tokens.push(Token::Marker {
+                                    range: i..end,
+                                    ch,
+                                    can_open,
+                                    can_close,
+                                    matched: None,
+                                })
+                            }
+                            _ => push_text(&mut tokens, i..end),
+                        }
+                    }
+                    _ => push_text(&mut tokens, i..i + ch.len_utf8()),
+                }
+            }
+            if !code.is_empty() {
+                tokens.push(Token::Code(code.clone()));
+            }
+            start = code.end;
+        }
+        tokens
+    }
+
+    /// Closes each marker with the last open one of the same run, markers opened in between
+    /// and never closed being left as text.
+    fn pair_markers(tokens: &mut [Token]) {
+        let mut open: Vec<usize> = vec![];
+        for i in 0..tokens.len() {
+            let Token::Marker {
+                range,
+                ch,
+                can_open,
+                can_close,
+                ..
+            } = tokens[i].clone()
+            else {
+                continue;
+            };
+            let opener = open.iter().rposition(|&j| {
+                matches!(&tokens[j], Token::Marker { range: r, ch: c, .. }
+                    if *c == ch && r.len() == range.len())
+            });
+            match opener {
+                Some(position) if can_close => {
+                    let j = open[position];
+                    open.truncate(position);
+                    for (index, matched) in [(j, true), (i, false)] {
+                        if let Token::Marker { matched: m, .. } = &mut tokens[index] {
+                            *m = Some(matched);
+                        }
+                    }
+                }
+                _ if can_open => open.push(i),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// If a run of `ch` between `before` and `after` can open and close emphasis. It has to touch
+/// the text it delimits, and `_` inside a word, as in snake_case, is text.
+fn flanking(ch: char, before: Option<char>, after: Option<char>) -> (bool, bool) {
+    let text_after = after.is_some_and(|c| !c.is_whitespace());
+    let text_before = before.is_some_and(|c| !c.is_whitespace());
+    match ch {
+        '_' => (
+            text_after && !before.is_some_and(char::is_alphanumeric),
+            text_before && !after.is_some_and(char::is_alphanumeric),
+        ),
+        _ => (text_after, text_before),
+    }
+}
+
+/// A line opening or closing a fenced code block.
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with("```")
+}
+
+/// The inline code of a line, backticks included, each backtick closing the one before it. A
+/// last backtick left alone is text.
+fn inline_code(line: &str) -> Vec<Range<usize>> {
+    let ticks: Vec<usize> = line.match_indices('`').map(|(i, _)| i).collect();
+    ticks.chunks_exact(2).map(|t| t[0]..t[1] + 1).collect()
+}
+
+/// The fenced blocks of `text`, from their opening fence to the end of their closing one, and
+/// the inline code of the other lines. A block still open runs to the end, as while streaming.
+fn code_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = vec![];
+    let mut fence_start = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_end_matches('\n');
+        match (is_fence(content), fence_start) {
+            (true, None) => {
+                fence_start = Some(offset + content.len() - content.trim_start().len());
+            }
+            (true, Some(start)) => {
+                ranges.push(start..offset + content.len());
+                fence_start = None;
+            }
+            (false, Some(_)) => {}
+            (false, None) => ranges.extend(
+                inline_code(content)
+                    .into_iter()
+                    .map(|r| r.start + offset..r.end + offset),
+            ),
+        }
+        offset += line.len();
+    }
+    if let Some(start) = fence_start {
+        ranges.push(start..text.len());
+    }
+    ranges
+}
+
+/// Emphasis is written `*italic*`, `**bold**` and `***both***`, or with `_`.
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Style {
     Normal,
-    Strong,
+    Italic,
+    Bold,
+    BoldItalic,
     Quote,
-    StrongQuote,
+    ItalicQuote,
+    BoldQuote,
+    BoldItalicQuote,
+    /// Inline code, backticks included.
+    Code,
+    /// A line of a fenced block.
+    CodeBlock,
 }
 
 impl Style {
-    fn next(self, ch: char) -> (Style, bool) {
-        let mut push_next = self != Style::Normal;
-        let ns = match ch {
-            '*' => match self {
-                Style::Normal => Style::Strong,
-                Style::Strong => Style::Normal,
-                Style::Quote => Style::StrongQuote,
-                Style::StrongQuote => Style::Quote,
-            },
-            '"' | '“' | '”' => match self {
-                Style::Normal => Style::Quote,
-                Style::Quote => Style::Normal,
-                Style::Strong => Style::StrongQuote,
-                Style::StrongQuote => Style::Strong,
-            },
-            _ => {
-                push_next = false;
-                self
+    fn with(italic: bool, bold: bool, quote: bool) -> Self {
+        match (italic, bold, quote) {
+            (false, false, false) => Style::Normal,
+            (true, false, false) => Style::Italic,
+            (false, true, false) => Style::Bold,
+            (true, true, false) => Style::BoldItalic,
+            (false, false, true) => Style::Quote,
+            (true, false, true) => Style::ItalicQuote,
+            (false, true, true) => Style::BoldQuote,
+            (true, true, true) => Style::BoldItalicQuote,
+        }
+    }
+
+    pub fn is_italic(self) -> bool {
+        matches!(
+            self,
+            Style::Italic | Style::BoldItalic | Style::ItalicQuote | Style::BoldItalicQuote
+        )
+    }
+
+    pub fn is_bold(self) -> bool {
+        matches!(
+            self,
+            Style::Bold | Style::BoldItalic | Style::BoldQuote | Style::BoldItalicQuote
+        )
+    }
+
+    pub fn is_quote(self) -> bool {
+        matches!(
+            self,
+            Style::Quote | Style::ItalicQuote | Style::BoldQuote | Style::BoldItalicQuote
+        )
+    }
+
+    pub fn is_code(self) -> bool {
+        matches!(self, Style::Code | Style::CodeBlock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn ids_do_not_depend_on_the_build() {
+        let timestamp = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        assert_eq!(timestamp_id(timestamp), 6460116012566372928_u64 as usize);
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(
+            timestamp_id(before_epoch),
+            16278332069883333917_u64 as usize
+        );
+    }
+
+    #[test]
+    fn emphasis_table() {
+        use Style::*;
+        let cases: &[(&str, &[(&str, Style)])] = &[
+            ("plain", &[("plain", Normal)]),
+            ("*italic*", &[("*italic*", Italic)]),
+            ("**bold**", &[("**bold**", Bold)]),
+            ("***both***", &[("***both***", BoldItalic)]),
+            ("_italic_", &[("_italic_", Italic)]),
+            ("__bold__", &[("__bold__", Bold)]),
+            ("___both___", &[("___both___", BoldItalic)]),
+            (
+                "a *b* c",
+                &[("a ", Normal), ("*b*", Italic), (" c", Normal)],
+            ),
+            (
+                "*a **b** c*",
+                &[("*a ", Italic), ("**b**", BoldItalic), (" c*", Italic)],
+            ),
+            ("\"hi\"", &[("\"hi\"", Quote)]),
+            ("“curly”", &[("“curly”", Quote)]),
+            (
+                "\"*hi*\"",
+                &[("\"", Quote), ("*hi*", ItalicQuote), ("\"", Quote)],
+            ),
+            (
+                "**\"hi\"**",
+                &[("**", Bold), ("\"hi\"", BoldQuote), ("**", Bold)],
+            ),
+            (
+                "\"***loud***\"",
+                &[
+                    ("\"", Quote),
+                    ("***loud***", BoldItalicQuote),
+                    ("\"", Quote),
+                ],
+            ),
+            // Mixed markers do not close each other
+            ("*mixed_", &[("*mixed_", Normal)]),
+            // Unmatched, as while streaming
+            ("*open", &[("*open", Normal)]),
+            ("**open *it*", &[("**open ", Normal), ("*it*", Italic)]),
+            ("\"open", &[("\"open", Quote)]),
+            ("a ** b", &[("a ** b", Normal)]),
+            ("2 * 3 * 4", &[("2 * 3 * 4", Normal)]),
+            ("snake_case_name", &[("snake_case_name", Normal)]),
+            ("****", &[("****", Normal)]),
+        ];
+        for (input, expected) in cases {
+            let line = Message::line(input);
+            let line: Vec<(&str, Style)> =
+                line.iter().map(|(s, style)| (s.as_str(), *style)).collect();
+            assert_eq!(line, *expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn routing_of_a_completion_chunk() {
+        let chunk = json!({
+            "id": "gen-1",
+            "provider": "Together",
+            "model": "meta-llama/llama-3.1-70b-instruct",
+            "choices": [{ "delta": { "content": "Hi" } }]
+        });
+        assert_eq!(
+            RoutingInfo::from_openrouter_json(&chunk),
+            Some(RoutingInfo {
+                provider_name: Some("Together".to_string()),
+                upstream_model: Some("meta-llama/llama-3.1-70b-instruct".to_string()),
+                moderation_flags: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn routing_of_a_moderation_error() {
+        let error = json!({
+            "error": {
+                "code": 403,
+                "message": "Input was flagged",
+                "metadata": {
+                    "provider_name": "OpenAI",
+                    "reasons": ["harassment", "violence"],
+                    "flagged_input": "..."
+                }
             }
+        });
+        let routing = RoutingInfo::from_openrouter_json(&error).unwrap();
+        assert_eq!(routing.provider_name.as_deref(), Some("OpenAI"));
+        assert_eq!(routing.upstream_model, None);
+        assert_eq!(routing.moderation_flags, ["harassment", "violence"]);
+    }
+
+    #[test]
+    fn no_routing_without_the_fields() {
+        let other = json!({ "choices": [{ "delta": { "content": "Hi" } }] });
+        assert_eq!(RoutingInfo::from_openrouter_json(&other), None);
+    }
+
+    fn cached_text(message: &Message) -> Option<String> {
+        let cache = message.spans_cache.0.lock().unwrap();
+        cache.as_ref().map(|(text, _)| text.clone())
+    }
+
+    #[test]
+    fn spans_are_parsed_again_once_the_text_changes() {
+        let mut message = Message::from_char(0, "Luna".to_string(), "*waves*".to_string());
+        assert_eq!(cached_text(&message), None);
+        let spans = message.spans();
+        assert_eq!(cached_text(&message).as_deref(), Some("*waves*\n"));
+        assert_eq!(message.spans(), spans);
+
+        // As a streamed token is appended
+        message.text.push_str("\"Hi\"");
+        assert_eq!(
+            message.spans(),
+            [
+                vec![("*waves*".to_string(), Style::Italic)],
+                vec![("\"Hi\"".to_string(), Style::Quote)],
+            ]
+        );
+        assert_eq!(message.clone().spans(), message.spans());
+    }
+
+    #[test]
+    fn cached_spans_are_cheaper_on_a_long_history() {
+        let text = "She *smiles*, \"**Welcome** to the _glade_,\" and waits.\n\n\n".repeat(20);
+        let history: Vec<Message> = (0..200)
+            .map(|i| Message::from_char(0, "Luna".to_string(), format!("{i} {text}")))
+            .collect();
+        let frame = || {
+            let start = std::time::Instant::now();
+            let spans: usize = history.iter().map(|m| m.spans().len()).sum();
+            (start.elapsed(), spans)
         };
-        (ns, push_next)
+        let (parsed, spans) = frame();
+        let (cached, cached_spans) = frame();
+        assert_eq!(spans, cached_spans);
+        assert!(
+            cached < parsed,
+            "Cached frame took {cached:?}, parsing it {parsed:?}"
+        );
+    }
+
+    fn char_message(text: &str) -> Message {
+        Message::from_char(0, "Luna".to_string(), text.to_string())
+    }
+
+    fn styles(spans: &[Vec<(String, Style)>]) -> Vec<Vec<(&str, Style)>> {
+        spans
+            .iter()
+            .map(|line| line.iter().map(|(s, style)| (s.as_str(), *style)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn code_is_kept_as_written() {
+        let text = "Here:  \n\n\n```rust\nfn main() {\n\n    let a = *b * c;  \n}\n```\n  \n\n\
+            Use `![x](y)` and `**p`, once.";
+        assert_eq!(
+            char_message(text).clean(),
+            "Here:\n\n\n```rust\nfn main() {\n\n    let a = *b * c;  \n}\n```\n\n\n\
+             Use `![x](y)` and `**p`, once.\n"
+        );
+    }
+
+    #[test]
+    fn code_spans_do_not_toggle_emphasis() {
+        let message = char_message("Run `\"*x*\"` *now*\n```\n*a* \"b\"\n```");
+        use Style::*;
+        assert_eq!(
+            styles(&message.spans()),
+            [
+                vec![
+                    ("Run ", Normal),
+                    ("`\"*x*\"`", Code),
+                    (" ", Normal),
+                    ("*now*", Italic)
+                ],
+                vec![("```", CodeBlock)],
+                vec![("*a* \"b\"", CodeBlock)],
+                vec![("```", CodeBlock)],
+            ]
+        );
+    }
+
+    #[test]
+    fn fences_split_across_streamed_chunks() {
+        use Style::*;
+        let mut message = char_message("");
+        message.text.clear();
+        let chunks = ["Look:\n``", "`py\nx = 2 ", "* 3\n", "``", "`\n*done*"];
+        let mut frames = vec![];
+        for chunk in chunks {
+            message.text.push_str(chunk);
+            frames.push(message.spans());
+        }
+        // Two backticks are an empty inline code, until the third opens the block
+        assert_eq!(
+            styles(&frames[0]),
+            [vec![("Look:", Normal)], vec![("``", Code)]]
+        );
+        // The unterminated block runs to the end of the message
+        assert_eq!(
+            styles(&frames[2]),
+            [
+                vec![("Look:", Normal)],
+                vec![("```py", CodeBlock)],
+                vec![("x = 2 * 3", CodeBlock)],
+            ]
+        );
+        assert_eq!(styles(&frames[3])[3], [("``", CodeBlock)]);
+        assert_eq!(
+            styles(&frames[4]),
+            [
+                vec![("Look:", Normal)],
+                vec![("```py", CodeBlock)],
+                vec![("x = 2 * 3", CodeBlock)],
+                vec![("```", CodeBlock)],
+                vec![("*done*", Italic)],
+            ]
+        );
     }
 }
@@ -0,0 +1,190 @@
+use std::{
+    fs,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::persona::loader;
+
+const OPENROUTER_MODELS: &str = "https://openrouter.ai/api/v1/models";
+
+/// How long the cached catalog is used before asking OpenRouter again.
+pub const CATALOG_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A model OpenRouter serves.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ModelInfo {
+    /// The slug to put in `Settings::model`.
+    pub id: String,
+    pub name: String,
+    pub context_length: Option<u64>,
+    pub pricing: ModelPricing,
+    /// Takes images as input.
+    pub vision: bool,
+    pub tools: bool,
+    pub reasoning: bool,
+}
+
+/// US dollars per token.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct ModelPricing {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<RawModel>,
+}
+
+#[derive(Deserialize)]
+struct RawModel {
+    id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    context_length: Option<u64>,
+    #[serde(default)]
+    pricing: RawPricing,
+    #[serde(default)]
+    architecture: Architecture,
+    #[serde(default)]
+    supported_parameters: Vec<String>,
+}
+
+/// Prices are sent as decimal strings.
+#[derive(Deserialize, Default)]
+struct RawPricing {
+    #[serde(default)]
+    prompt: String,
+    #[serde(default)]
+    completion: String,
+}
+
+#[derive(Deserialize, Default)]
+struct Architecture {
+    #[serde(default)]
+    input_modalities: Vec<String>,
+}
+
+impl From<RawModel> for ModelInfo {
+    fn from(model: RawModel) -> Self {
+        let supports = |param: &str| model.supported_parameters.iter().any(|p| p == param);
+        let price = |price: &str| price.parse().unwrap_or(0.0);
+        Self {
+            name: match model.name.is_empty() {
+                true => model.id.clone(),
+                false => model.name.clone(),
+            },
+            context_length: model.context_length,
+            pricing: ModelPricing {
+                prompt: price(&model.pricing.prompt),
+                completion: price(&model.pricing.completion),
+            },
+            vision: model
+                .architecture
+                .input_modalities
+                .iter()
+                .any(|m| m == "image"),
+            tools: supports("tools"),
+            reasoning: supports("reasoning") || supports("include_reasoning"),
+            id: model.id,
+        }
+    }
+}
+
+/// The catalog as written to models.json.
+#[derive(Deserialize, Serialize)]
+struct Catalog {
+    /// Seconds since the epoch.
+    fetched_at: u64,
+    models: Vec<ModelInfo>,
+}
+
+impl Catalog {
+    fn load() -> Option<Self> {
+        let path = loader::cache_path("models.json")?;
+        let catalog = fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(serde_json::from_slice(&bytes)?));
+        match catalog {
+            Ok(catalog) => Some(catalog),
+            Err(e) => {
+                trace!("No model catalog in {:?}: {e}", path);
+                None
+            }
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = loader::cache_path("models.json").ok_or(anyhow!("No data directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    fn is_fresh(&self) -> bool {
+        now().saturating_sub(self.fetched_at) < CATALOG_TTL.as_secs()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The models of OpenRouter sorted by id, from the cache while it is younger than
+/// `CATALOG_TTL`. When the request fails, the cached copy is returned however old it is.
+pub async fn fetch_openrouter_models(api_key: Option<&str>) -> Result<Vec<ModelInfo>> {
+    let cached = Catalog::load();
+    if let Some(catalog) = cached.as_ref().filter(|c| c.is_fresh()) {
+        return Ok(catalog.models.clone());
+    }
+    match request_models(api_key).await {
+        Ok(mut models) => {
+            models.sort_by(|a, b| a.id.cmp(&b.id));
+            let catalog = Catalog {
+                fetched_at: now(),
+                models,
+            };
+            if let Err(e) = catalog.save() {
+                warn!("Could not cache the model catalog: {e}");
+            }
+            Ok(catalog.models)
+        }
+        Err(e) => match cached {
+            Some(catalog) => {
+                warn!("Could not fetch the models, using the cached catalog: {e}");
+                Ok(catalog.models)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// What `model` costs according to the cached catalog, however old it is.
+pub fn cached_pricing(model: &str) -> Option<ModelPricing> {
+    Catalog::load()?
+        .models
+        .into_iter()
+        .find(|m| m.id == model)
+        .map(|m| m.pricing)
+}
+
+async fn request_models(api_key: Option<&str>) -> Result<Vec<ModelInfo>> {
+    let mut request = reqwest::Client::new()
+        .get(OPENROUTER_MODELS)
+        .timeout(Duration::from_secs(20));
+    if let Some(key) = api_key.filter(|k| !k.is_empty()) {
+        request = request.bearer_auth(key);
+    }
+    let list: ModelList = request.send().await?.error_for_status()?.json().await?;
+    Ok(list.data.into_iter().map(ModelInfo::from).collect())
+}
@@ -1,25 +1,48 @@
-use tokio::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{trace, warn};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+    task::JoinHandle,
+};
 
 use crate::{
-    chat::{Chat, ChatUpdate},
+    chat::{Chat, ChatUpdate, UPDATE_CAPACITY, store::ChatStore},
     gateway::{Gateway, GatewayUpdate},
-    persona::Persona,
-    settings::Settings,
+    models::{self, ModelInfo},
+    persona::{Persona, loader},
+    settings::{OPENROUTER, Settings, SettingsError},
 };
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
 pub enum MoonUpdate {
     CU(ChatUpdate),
     GU(GatewayUpdate),
+    /// settings.json was edited, `Moon::settings` and the chat now use it.
+    SettingsReloaded,
     Error(String),
+    /// `Moon::shutdown` was called and every update before it was received.
+    Shutdown,
 }
 
 pub struct Moon {
-    ctx: mpsc::Sender<ChatUpdate>,
-    crx: mpsc::Receiver<ChatUpdate>,
+    /// Kept across chats, `set_chars` hands it to the new one.
+    ctx: broadcast::Sender<ChatUpdate>,
+    crx: broadcast::Receiver<ChatUpdate>,
+    srx: Option<mpsc::Receiver<Result<Settings, String>>>,
+    settings_watch: Option<JoinHandle<()>>,
 
     pub chat: Chat,
     pub settings: Settings,
     pub gateway: Gateway,
+    /// None when the platform has no data directory.
+    pub store: Option<ChatStore>,
 }
 
 impl Default for Moon {
@@ -30,41 +53,298 @@ impl Default for Moon {
 
 impl Moon {
     pub fn new() -> Self {
+        Self::with_settings(Settings::load())
+    }
+
+    /// Starts with `settings` instead of the ones of settings.json.
+    pub fn with_settings(settings: Settings) -> Self {
+        loader::set_data_dir(settings.data_dir.clone());
         let gateway = Gateway::new();
-        let (ctx, crx) = mpsc::channel(10);
+        let (ctx, crx) = broadcast::channel(UPDATE_CAPACITY);
 
-        let settings = Settings::load();
         let user = Gateway::load_most_recent_user().unwrap_or(Persona::default_user());
-        let mut chat = Chat::with_personas(user, Persona::default_char(), settings.clone());
+        let char = Gateway::load_most_recent_char().unwrap_or(Persona::default_char());
+        let store = ChatStore::open();
+        let mut chat = match store
+            .as_ref()
+            .and_then(|s| Self::reopen(s, &user, &char, &settings))
+        {
+            Some(chat) => chat,
+            None => Chat::with_personas(user, char, settings.clone()),
+        };
         chat.set_tx(ctx.clone());
         Self {
             ctx,
             crx,
+            srx: None,
+            settings_watch: None,
             chat,
             settings,
             gateway,
+            store,
         }
     }
 
-    pub fn set_chars(&mut self, char: Persona) {
+    /// The most recent saved chat with `char`.
+    fn reopen(
+        store: &ChatStore,
+        user: &Persona,
+        char: &Persona,
+        settings: &Settings,
+    ) -> Option<Chat> {
+        let meta = store
+            .list()
+            .into_iter()
+            .find(|m| m.personas.iter().any(|p| p == char.name()))?;
+        match store.load(meta.id, user.clone(), char.clone(), settings.clone()) {
+            Ok((chat, report)) => {
+                if !report.is_clean() {
+                    warn!(
+                        "Reopened chat {} with {} repairs",
+                        meta.id,
+                        report.actions.len()
+                    );
+                }
+                Some(chat)
+            }
+            Err(e) => {
+                warn!("Could not reopen chat {}: {e}", meta.id);
+                None
+            }
+        }
+    }
+
+    /// Starts a chat with `char`, which is marked as the most recently used so the next launch
+    /// starts with it. The generation of the old chat is stopped and its pending changes saved
+    /// before it is dropped.
+    pub async fn set_chars(&mut self, mut char: Persona) {
+        char.set_modified_time();
+        self.chat.stop().await;
+        self.chat.finish_autosave().await;
         let user = self.chat.user();
         self.chat = Chat::with_personas(user, char, self.settings.clone());
         self.chat.set_tx(self.ctx.clone());
     }
 
+    /// See `Chat::set_user`, later chats started with `set_chars` keep this user.
+    pub fn set_user(&mut self, user: Persona) {
+        self.chat.set_user(user);
+    }
+
+    /// Swaps in `char` keeping the conversation when it has the name of the current char, an
+    /// edited version of its card, otherwise starts a chat with it like `set_chars`.
+    pub async fn update_char(&mut self, char: Persona) {
+        match char.name() == self.chat.char().name() {
+            true => self.chat.set_char(char),
+            false => self.set_chars(char).await,
+        }
+    }
+
     pub fn get_settings(&self) -> Settings {
         self.settings.clone()
     }
 
-    pub fn set_settings(&mut self, settings: Settings) {
+    pub fn set_settings(&mut self, settings: Settings) -> Result<(), Vec<SettingsError>> {
+        self.chat.set_settings(settings.clone())?;
         self.settings = settings;
-        self.chat.set_settings(self.settings.clone());
+        Ok(())
+    }
+
+    /// The models of OpenRouter, for a picker. Warns when `Settings::model` is not one of them.
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        let key = self.settings.resolve_api_key(OPENROUTER);
+        let models = models::fetch_openrouter_models(key.as_deref()).await?;
+        if !models.iter().any(|m| m.id == self.settings.model) {
+            warn!("{} is not a model of OpenRouter", self.settings.model);
+        }
+        Ok(models)
+    }
+
+    /// Reloads the settings when settings.json is edited, checking every `interval`.
+    pub fn watch_settings(&mut self, interval: Duration) {
+        let (tx, rx) = mpsc::channel(1);
+        self.srx = Some(rx);
+        if let Some(watch) = self
+            .settings_watch
+            .replace(tokio::spawn(Settings::watch(interval, tx)))
+        {
+            watch.abort();
+        }
+    }
+
+    /// Cancels the generation, saves the pending changes and stops the autosave, the gateway
+    /// and the settings watch, resolving once their tasks exited. The chat updates are then closed, `recv` returns `MoonUpdate::Shutdown`
+    /// after the last one and the subscribers get `RecvError::Closed`.
+    pub async fn shutdown(&mut self) {
+        trace!("Shutting down");
+        self.chat.stop().await;
+        // The save task holds a sender of the chat updates, which would keep them open
+        self.chat.finish_autosave().await;
+        if let Some(watch) = self.settings_watch.take() {
+            watch.abort();
+            let _ = watch.await;
+        }
+        self.srx = None;
+        self.gateway.shutdown().await;
+        // Dropping every sender closes the channel, `crx` is kept to receive what is left
+        let closed = broadcast::channel(1).0;
+        self.chat.set_tx(closed.clone());
+        self.ctx = closed;
+    }
+
+    /// Another listener of the chat updates, which `recv` also returns. It keeps working
+    /// after `set_chars`.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChatUpdate> {
+        self.ctx.subscribe()
     }
 
     pub async fn recv(&mut self) -> MoonUpdate {
-        tokio::select! {
-            Some(update) = self.crx.recv() => MoonUpdate::CU(update),
-            Some(update) = self.gateway.recv() => MoonUpdate::GU(update),
+        // Both only close on shutdown
+        let (mut chat_open, mut gateway_open) = (true, true);
+        let reloaded = loop {
+            if !chat_open && !gateway_open {
+                return MoonUpdate::Shutdown;
+            }
+            let settings_open = self.srx.is_some();
+            tokio::select! {
+                update = self.crx.recv(), if chat_open => match update {
+                    Ok(update) => return MoonUpdate::CU(update),
+                    Err(RecvError::Lagged(missed)) => warn!("Missed {missed} chat updates"),
+                    Err(RecvError::Closed) => chat_open = false,
+                },
+                update = self.gateway.recv(), if gateway_open => match update {
+                    Some(update) => return MoonUpdate::GU(update),
+                    None => gateway_open = false,
+                },
+                // A new future each time round, the previous one may have completed
+                reloaded = async { self.srx.as_mut()?.recv().await }, if settings_open => {
+                    match reloaded {
+                        Some(reloaded) => break reloaded,
+                        None => {
+                            trace!("The settings watch stopped");
+                            self.srx = None;
+                        }
+                    }
+                }
+            }
+        };
+        match reloaded {
+            Ok(mut settings) => {
+                if settings.device_id.is_empty() {
+                    settings.device_id = self.settings.device_id.clone();
+                }
+                self.chat.reload_settings(settings.clone());
+                self.settings = settings;
+                MoonUpdate::SettingsReloaded
+            }
+            Err(e) => MoonUpdate::Error(e),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::testing::{DataDir, MockProvider};
+
+    fn moon(data_dir: &DataDir, provider: MockProvider) -> Moon {
+        let settings = Settings {
+            data_dir: Some(data_dir.path().to_path_buf()),
+            ..Settings::default()
+        };
+        let mut moon = Moon::with_settings(settings);
+        moon.chat
+            .set_provider_factory(move |_, _| Ok(Box::new(provider.clone())));
+        moon
+    }
+
+    /// Receives until `done` accepts an update, panics when it takes more than a few seconds.
+    async fn recv_until(moon: &mut Moon, done: impl Fn(&MoonUpdate) -> bool) {
+        let recv = async { while !done(&moon.recv().await) {} };
+        tokio::time::timeout(Duration::from_secs(5), recv)
+            .await
+            .expect("The update never came");
+    }
+
+    fn write_char(data_dir: &Path, name: &str) -> Persona {
+        let dir = data_dir.join("chars").join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        let json = format!(r#"{{"name": "{name}"}}"#);
+        std::fs::write(dir.join(format!("{name}.json")), json).unwrap();
+        loader::load_dir(dir, &Default::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replies_arrive_through_recv() {
+        let data_dir = DataDir::new("moon");
+        write_char(data_dir.path(), "Other");
+        write_char(data_dir.path(), "Luna").set_modified_time();
+
+        let mut moon = moon(&data_dir, MockProvider::new(&["Hello"]));
+        assert_eq!(moon.chat.char().name(), "Luna");
+        moon.chat.add_user_message("Hi".to_string());
+        recv_until(&mut moon, |u| {
+            matches!(u, MoonUpdate::CU(ChatUpdate::StreamFinished { .. }))
+        })
+        .await;
+        assert_eq!(moon.chat.get_history().last().unwrap().text.trim(), "Hello");
+        moon.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_mid_stream_is_bounded() {
+        let data_dir = DataDir::new("shutdown");
+        let tokens = &["token "; 100];
+        let provider = MockProvider::new(tokens).delay(Duration::from_millis(50));
+        let mut moon = moon(&data_dir, provider);
+        let mut subscriber = moon.subscribe();
+        moon.chat.add_user_message("Hi".to_string());
+        recv_until(&mut moon, |u| {
+            matches!(u, MoonUpdate::CU(ChatUpdate::StreamUpdate))
+        })
+        .await;
+
+        tokio::time::timeout(Duration::from_secs(1), moon.shutdown())
+            .await
+            .expect("The shutdown did not complete");
+        assert!(!moon.chat.is_generating());
+        recv_until(&mut moon, |u| matches!(u, MoonUpdate::Shutdown)).await;
+        // Closed for the other subscribers too, once they received what was sent
+        let closed = async { while !matches!(subscriber.recv().await, Err(RecvError::Closed)) {} };
+        tokio::time::timeout(Duration::from_secs(1), closed)
+            .await
+            .expect("The chat updates were not closed");
+    }
+
+    #[tokio::test]
+    async fn set_chars_stops_the_old_chat() {
+        let data_dir = DataDir::new("set_chars");
+        let other = write_char(data_dir.path(), "Other");
+        let tokens = &["token "; 100];
+        let provider = MockProvider::new(tokens).delay(Duration::from_millis(50));
+        let mut moon = moon(&data_dir, provider);
+        let store = ChatStore::new(data_dir.path().join("saved"));
+        moon.chat
+            .enable_autosave(store.clone(), Duration::from_secs(60));
+        moon.chat.add_user_message("Hi".to_string());
+        recv_until(&mut moon, |u| {
+            matches!(u, MoonUpdate::CU(ChatUpdate::StreamUpdate))
+        })
+        .await;
+
+        tokio::time::timeout(Duration::from_secs(1), moon.set_chars(other))
+            .await
+            .expect("The old chat was not stopped");
+        assert_eq!(moon.chat.char().name(), "Other");
+        // The pending change was saved without waiting for the debounce
+        let saved = store.list();
+        assert_eq!(saved.len(), 1);
+        assert!(saved[0].message_count >= 1);
+        // Nothing streams into the new chat
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(moon.chat.get_history().is_empty());
+        moon.shutdown().await;
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use log::trace;
+use tokio::{
+    runtime::{self, Runtime},
+    sync::{mpsc as async_mpsc, oneshot},
+};
+
+use crate::{
+    chat::HistoryLevel,
+    message::Message,
+    models::ModelInfo,
+    moon::{self, MoonUpdate},
+    persona::Persona,
+    settings::{Settings, SettingsError},
+};
+
+/// Runs on the task of the moon, between two updates.
+type Command = Box<dyn for<'a> FnOnce(&'a mut moon::Moon) -> BoxFuture<'a, ()> + Send>;
+
+/// `moon::Moon` for frontends without an async runtime. It owns a runtime where the moon lives
+/// on its own task, the methods wait for it to run the operation and the updates arrive on a
+/// std channel.
+///
+/// The methods must not be called from an async context, and panic once `shutdown` returned.
+pub struct Moon {
+    runtime: Option<Runtime>,
+    commands: async_mpsc::UnboundedSender<Command>,
+    updates: Receiver<MoonUpdate>,
+}
+
+impl Moon {
+    pub fn new() -> Result<Self> {
+        let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+        let (commands, mut pending) = async_mpsc::unbounded_channel::<Command>();
+        let (tx, updates) = mpsc::channel();
+        runtime.spawn(async move {
+            let mut moon = moon::Moon::new();
+            loop {
+                tokio::select! {
+                    Some(command) = pending.recv() => command(&mut moon).await,
+                    update = moon.recv() => {
+                        let shutdown = matches!(update, MoonUpdate::Shutdown);
+                        if tx.send(update).is_err() || shutdown {
+                            break;
+                        }
+                    }
+                }
+            }
+            trace!("Moon task exited");
+        });
+        Ok(Self {
+            runtime: Some(runtime),
+            commands,
+            updates,
+        })
+    }
+
+    /// Runs `f` on the moon and returns its result, for whatever has no method here.
+    pub fn with<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut moon::Moon) -> R + Send + 'static,
+    ) -> R {
+        self.run(move |moon| Box::pin(async move { f(moon) }))
+    }
+
+    fn run<R: Send + 'static>(
+        &self,
+        f: impl for<'a> FnOnce(&'a mut moon::Moon) -> BoxFuture<'a, R> + Send + 'static,
+    ) -> R {
+        let (tx, rx) = oneshot::channel();
+        let command: Command = Box::new(move |moon| {
+            Box::pin(async move {
+                let _ = tx.send(f(moon).await);
+            })
+        });
+        let _ = self.commands.send(command);
+        rx.blocking_recv().expect("the moon was shut down")
+    }
+
+    /// Every update in order, `MoonUpdate::Shutdown` being the last.
+    pub fn updates(&self) -> &Receiver<MoonUpdate> {
+        &self.updates
+    }
+
+    /// The next update if one arrived, to call from a frame loop.
+    pub fn poll_update(&self) -> Option<MoonUpdate> {
+        self.updates.try_recv().ok()
+    }
+
+    pub fn add_user_message(&self, text: String) {
+        self.with(move |moon| moon.chat.add_user_message(text))
+    }
+
+    pub fn next(&self, depth: usize) {
+        self.with(move |moon| moon.chat.next(depth))
+    }
+
+    pub fn previous(&self, depth: usize) {
+        self.with(move |moon| moon.chat.previous(depth))
+    }
+
+    pub fn add_edit(&self, depth: usize, text: String) {
+        self.with(move |moon| moon.chat.add_edit(depth, text))
+    }
+
+    pub fn regenerate(&self, depth: usize) {
+        self.with(move |moon| moon.chat.regenerate(depth))
+    }
+
+    pub fn delete(&self, depth: usize) -> usize {
+        self.with(move |moon| moon.chat.delete(depth))
+    }
+
+    pub fn cancel(&self) -> bool {
+        self.with(|moon| moon.chat.cancel())
+    }
+
+    pub fn get_history(&self) -> Vec<Message> {
+        self.with(|moon| moon.chat.get_history())
+    }
+
+    pub fn get_history_structure(&self) -> Vec<HistoryLevel> {
+        self.with(|moon| moon.chat.get_history_structure())
+    }
+
+    pub fn set_chars(&self, char: Persona) {
+        self.run(move |moon| Box::pin(moon.set_chars(char)))
+    }
+
+    pub fn update_char(&self, char: Persona) {
+        self.run(move |moon| Box::pin(moon.update_char(char)))
+    }
+
+    pub fn set_user(&self, user: Persona) {
+        self.with(move |moon| moon.set_user(user))
+    }
+
+    pub fn chars(&self) -> Vec<Persona> {
+        self.run(|moon| Box::pin(async move { moon.gateway.chars.lock().await.clone() }))
+    }
+
+    pub fn users(&self) -> Vec<Persona> {
+        self.run(|moon| Box::pin(async move { moon.gateway.users.lock().await.clone() }))
+    }
+
+    pub fn get_settings(&self) -> Settings {
+        self.with(|moon| moon.get_settings())
+    }
+
+    pub fn set_settings(&self, settings: Settings) -> Result<(), Vec<SettingsError>> {
+        self.with(move |moon| moon.set_settings(settings))
+    }
+
+    pub fn watch_settings(&self, interval: Duration) {
+        self.with(move |moon| moon.watch_settings(interval))
+    }
+
+    pub fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        self.run(|moon| Box::pin(moon.list_models()))
+    }
+
+    /// `moon::Moon::shutdown`, then stops the runtime. The updates left can still be read.
+    pub fn shutdown(&mut self) {
+        self.run(|moon| Box::pin(moon.shutdown()));
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_timeout(Duration::from_secs(1));
+        }
+    }
+}
+
+impl Drop for Moon {
+    fn drop(&mut self) {
+        // A runtime can not be dropped from an async context, nor block in it
+        if let Some(runtime) = self.runtime.take() {
+            thread::spawn(move || drop(runtime));
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::{Arc, OnceLock};
+
+use image::{ImageBuffer, Rgba, imageops::FilterType};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AvatarStyle {
+    /// Cropped to a square with the corners outside the inscribed circle transparent.
+    #[default]
+    Circle,
+    /// The image as decoded, only downscaled.
+    Original,
+}
+
+/// Width, height and RGBA pixels, the form immediate mode UIs upload textures from.
+pub type RawImage = Arc<(u32, u32, Vec<u8>)>;
+
+/// A persona image, its styled variants and their raw forms are computed on first use.
+#[derive(Debug, Clone)]
+pub(crate) struct Avatar {
+    original: Arc<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    circle: OnceLock<Arc<ImageBuffer<Rgba<u8>, Vec<u8>>>>,
+    raw: [OnceLock<RawImage>; 2],
+}
+
+impl Avatar {
+    pub(crate) fn new(image: ImageBuffer<Rgba<u8>, Vec<u8>>) -> Self {
+        Self {
+            original: Arc::new(image),
+            circle: OnceLock::new(),
+            raw: Default::default(),
+        }
+    }
+
+    pub(crate) fn raw(&self, style: AvatarStyle) -> RawImage {
+        self.raw[style as usize]
+            .get_or_init(|| {
+                let image = self.get(style);
+                let (width, height) = image.dimensions();
+                Arc::new((width, height, image.to_vec()))
+            })
+            .clone()
+    }
+
+    pub(crate) fn get(&self, style: AvatarStyle) -> &Arc<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        match style {
+            AvatarStyle::Circle => self
+                .circle
+                .get_or_init(|| Arc::new(circle_crop(&self.original))),
+            AvatarStyle::Original => &self.original,
+        }
+    }
+}
+
+fn circle_crop(image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let mut image = crop_to_square(image);
+
+    let (width, height) = image.dimensions();
+    let center_x = width as f64 / 2.0;
+    let center_y = height as f64 / 2.0;
+    let radius = width.min(height) as f64 / 2.0;
+
+    // Process each pixel
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let distance_from_center =
+            ((x as f64 - center_x).powi(2) + (y as f64 - center_y).powi(2)).sqrt();
+
+        if distance_from_center > radius {
+            pixel[3] = 0
+        }
+    }
+    image
+}
+
+/// The image cropped to a square and resized to `size`.
+pub(crate) fn thumbnail(
+    image: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    size: u32,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let square = crop_to_square(image);
+    image::imageops::resize(&square, size, size, FilterType::Triangle)
+}
+
+fn crop_to_square(image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let size = width.min(height);
+
+    let x_offset = (width - size) / 2;
+    let y_offset = (height - size) / 2;
+
+    image::imageops::crop_imm(image, x_offset, y_offset, size, size).to_image()
+}
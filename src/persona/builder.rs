@@ -0,0 +1,86 @@
+use crate::persona::{Persona, card::Card};
+
+/// Edits the fields of a card. Everything it does not cover, the extensions included, is kept
+/// as loaded.
+#[derive(Debug, Clone)]
+pub struct CardBuilder {
+    card: Card,
+    /// The persona being edited, which keeps its avatar and directory.
+    base: Option<Persona>,
+}
+
+impl CardBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            card: Card::basic(name, ""),
+            base: None,
+        }
+    }
+
+    pub fn from_persona(persona: &Persona) -> Self {
+        Self {
+            card: persona.data.clone(),
+            base: Some(persona.clone()),
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.card.data.name = name.to_string();
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.card.data.description = description.to_string();
+        self
+    }
+
+    pub fn personality(mut self, personality: &str) -> Self {
+        self.card.data.personality = personality.to_string();
+        self
+    }
+
+    pub fn scenario(mut self, scenario: &str) -> Self {
+        self.card.data.scenario = scenario.to_string();
+        self
+    }
+
+    pub fn first_mes(mut self, first_mes: Option<&str>) -> Self {
+        self.card.data.first_mes = first_mes.map(|m| m.to_string());
+        self
+    }
+
+    pub fn alternate_greetings(mut self, greetings: Vec<String>) -> Self {
+        self.card.data.alternate_greetings = greetings;
+        self
+    }
+
+    pub fn system_prompt(mut self, system_prompt: &str) -> Self {
+        self.card.data.system_prompt = system_prompt.to_string();
+        self
+    }
+
+    pub fn post_history_instructions(mut self, instructions: &str) -> Self {
+        self.card.data.post_history_instructions = instructions.to_string();
+        self
+    }
+
+    pub fn tags(mut self, tags: Vec<String>) -> Self {
+        self.card.data.tags = tags;
+        self
+    }
+
+    pub fn build_card(self) -> Card {
+        self.card
+    }
+
+    /// The edited persona, to be written with `Persona::save`.
+    pub fn build(self) -> Persona {
+        match self.base {
+            Some(mut persona) => {
+                persona.data = self.card;
+                persona
+            }
+            None => Persona::from_card(self.card),
+        }
+    }
+}
@@ -1,9 +1,14 @@
-use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
 
 use anyhow::Result;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use crate::persona::Persona;
+use crate::{
+    message::OwnerType,
+    persona::Persona,
+    prompt::{PromptBuilder, PromptSection},
+};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Card {
@@ -36,7 +41,7 @@ impl Card {
                 tags: vec![],
                 creator: String::new(),
                 character_version: String::new(),
-                extensions: HashMap::new(),
+                extensions: Extensions::new(),
                 character_book: None,
             },
         }
@@ -50,6 +55,25 @@ impl Card {
         &self.data.name
     }
 
+    pub fn creator(&self) -> &str {
+        &self.data.creator
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.data.tags
+    }
+
+    /// Stable across runs, the fields serialize in declaration order and the extensions in file
+    /// order.
+    pub fn content_hash(&self) -> u64 {
+        let canonical = serde_json::to_value(self)
+            .map(|v| v.to_string())
+            .unwrap_or_default();
+        let mut hasher = DefaultHasher::new();
+        canonical.hash(&mut hasher);
+        hasher.finish()
+    }
+
     pub fn greetings(&self, partner_name: Option<&str>) -> Option<Vec<String>> {
         match &self.data.first_mes {
             Some(message) => {
@@ -66,27 +90,89 @@ impl Card {
         }
     }
 
-    pub fn system_prompt(&self, partner_name: Option<&str>) -> String {
-        let data = self.data.clone();
-        Persona::replace_names(
-            &[
-                data.system_prompt,
-                data.description,
-                data.scenario,
-                data.mes_example,
-            ]
-            .iter()
-            .filter(|s| !s.is_empty())
-            .map(|s| s.as_str())
-            .collect::<Vec<&str>>()
-            .join("/n"),
-            &self.data.name,
-            partner_name,
-        )
+    pub fn system_prompt(&self, partner_name: Option<&str>, include_examples: bool) -> String {
+        let order = [
+            PromptSection::SystemPrompt,
+            PromptSection::Description,
+            PromptSection::Scenario,
+            PromptSection::Examples,
+        ];
+        let mut builder = PromptBuilder::new(self, &order).examples(include_examples);
+        if let Some(partner_name) = partner_name {
+            builder = builder.partner(partner_name);
+        }
+        builder.build()
+    }
+
+    /// Splits `mes_example` on `<START>` markers into conversations of `{{user}}:`/`{{char}}:` turns,
+    /// the names themselves are accepted as prefixes too. Returns nothing when the block has no
+    /// `<START>` marker.
+    pub fn example_dialogues(&self, partner_name: Option<&str>) -> Vec<Vec<(OwnerType, String)>> {
+        let start_re = Regex::new(r"(?i)<start>").unwrap();
+        if !start_re.is_match(&self.data.mes_example) {
+            return vec![];
+        }
+
+        let mut dialogues = vec![];
+        for block in start_re.split(&self.data.mes_example) {
+            let mut turns: Vec<(OwnerType, String)> = vec![];
+            for line in block.lines() {
+                let trimmed = line.trim();
+                if let Some(text) = strip_speaker(trimmed, "{{user}}", partner_name) {
+                    turns.push((OwnerType::User, text.trim().to_string()));
+                } else if let Some(text) = strip_speaker(trimmed, "{{char}}", Some(&self.data.name))
+                {
+                    turns.push((OwnerType::Char(0), text.trim().to_string()));
+                } else if let Some((_, text)) = turns.last_mut() {
+                    text.push('\n');
+                    text.push_str(line);
+                }
+            }
+            let turns: Vec<(OwnerType, String)> = turns
+                .into_iter()
+                .map(|(owner, text)| {
+                    let text = Persona::replace_names(text.trim(), &self.data.name, partner_name);
+                    (owner, text)
+                })
+                .collect();
+            if !turns.is_empty() {
+                dialogues.push(turns);
+            }
+        }
+        dialogues
     }
 }
 
+/// The rest of an example line spoken by `placeholder` or `name`.
+fn strip_speaker<'a>(line: &'a str, placeholder: &str, name: Option<&str>) -> Option<&'a str> {
+    [Some(placeholder), name]
+        .into_iter()
+        .flatten()
+        .filter(|n| !n.is_empty())
+        .find_map(|n| line.strip_prefix(n)?.strip_prefix(':'))
+}
+
 /// Contains core character properties along with new V2 fields.
+/// The minimal persona file, a name and a description.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Basic {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+impl Basic {
+    pub fn load_from_json(data: &str) -> Result<Self> {
+        Ok(serde_json::from_str(data)?)
+    }
+}
+
+impl From<Basic> for Card {
+    fn from(basic: Basic) -> Self {
+        Card::basic(&basic.name, &basic.description)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CharacterData {
     /// The character's display name.
@@ -136,7 +222,8 @@ pub struct CharacterData {
     pub character_book: Option<CharacterBook>,
 }
 
-pub type Extensions = HashMap<String, serde_json::Value>;
+/// Keys keep the order of the file so unknown extensions are written back as they were read.
+pub type Extensions = serde_json::Map<String, serde_json::Value>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Entry {
@@ -221,3 +308,56 @@ pub struct CharacterBook {
     /// Array of lore entries that comprise the lorebook.
     pub entries: Vec<Entry>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// From a published card, with its mixed prefixes and a turn on two lines.
+    const EXAMPLES: &str = "<START>\n\
+        {{user}}: \"Where am I?\"\n\
+        {{char}}: *Seraphina smiles softly.* \"You're safe in my glade.\"\n\
+        *She brushes a lock of hair from her face.*\n\
+        <start>\n\
+        Ann: Who are you?\n\
+        Seraphina: \"I am Seraphina, guardian of this forest, {{user}}.\"\n";
+
+    fn card(mes_example: &str) -> Card {
+        let mut card = Card::basic("Seraphina", "");
+        card.data.mes_example = mes_example.to_string();
+        card
+    }
+
+    #[test]
+    fn splits_the_examples_in_conversations() {
+        let dialogues = card(EXAMPLES).example_dialogues(Some("Ann"));
+        assert_eq!(dialogues.len(), 2);
+
+        let [(first_owner, first), (second_owner, second)] = &dialogues[0][..] else {
+            panic!("Two turns expected in {:?}", dialogues[0]);
+        };
+        assert!(matches!(first_owner, OwnerType::User));
+        assert_eq!(first, "\"Where am I?\"");
+        assert!(matches!(second_owner, OwnerType::Char(0)));
+        assert_eq!(
+            second,
+            "*Seraphina smiles softly.* \"You're safe in my glade.\"\n\
+             *She brushes a lock of hair from her face.*"
+        );
+
+        let owners: Vec<usize> = dialogues[1].iter().map(|(o, _)| usize::from(*o)).collect();
+        assert_eq!(owners, [0, 1]);
+        assert_eq!(dialogues[1][0].1, "Who are you?");
+        assert_eq!(
+            dialogues[1][1].1,
+            "\"I am Seraphina, guardian of this forest, Ann.\""
+        );
+    }
+
+    #[test]
+    fn no_conversations_without_start() {
+        let examples = "{{user}}: Hi\n{{char}}: Hello";
+        assert!(card(examples).example_dialogues(Some("Ann")).is_empty());
+        assert!(card("").example_dialogues(Some("Ann")).is_empty());
+    }
+}
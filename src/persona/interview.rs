@@ -0,0 +1,282 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use llm::{LLMProvider, chat::ChatMessage};
+use log::{error, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::persona::card::Card;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum InterviewStep {
+    Appearance,
+    Personality,
+    SpeechStyle,
+    Scenario,
+    Greeting,
+}
+
+const STEPS: [InterviewStep; 5] = [
+    InterviewStep::Appearance,
+    InterviewStep::Personality,
+    InterviewStep::SpeechStyle,
+    InterviewStep::Scenario,
+    InterviewStep::Greeting,
+];
+
+impl InterviewStep {
+    fn topic(&self) -> &'static str {
+        match self {
+            InterviewStep::Appearance => "appearance",
+            InterviewStep::Personality => "personality",
+            InterviewStep::SpeechStyle => "way of speaking",
+            InterviewStep::Scenario => "scenario, where and when the story takes place",
+            InterviewStep::Greeting => "first words when meeting the user",
+        }
+    }
+
+    fn template_question(&self, name: &str) -> String {
+        match self {
+            InterviewStep::Appearance => format!("What does {name} look like?"),
+            InterviewStep::Personality => format!("How would you describe {name}'s personality?"),
+            InterviewStep::SpeechStyle => format!("How does {name} speak?"),
+            InterviewStep::Scenario => format!("Where and when does the story with {name} start?"),
+            InterviewStep::Greeting => format!("How does {name} greet the user?"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct InterviewSession {
+    seed_name: String,
+    questions: Vec<String>,
+    answers: Vec<String>,
+    #[serde(skip)]
+    provider: Option<Arc<dyn LLMProvider>>,
+}
+
+/// Starts an interview, asking the first question. Without a provider questions and drafts are templated.
+pub async fn start(provider: Option<Arc<dyn LLMProvider>>, seed_name: &str) -> InterviewSession {
+    let mut session = InterviewSession {
+        seed_name: seed_name.to_string(),
+        questions: vec![],
+        answers: vec![],
+        provider,
+    };
+    session.ask_next().await;
+    session
+}
+
+impl InterviewSession {
+    pub fn resume(state: &str, provider: Option<Arc<dyn LLMProvider>>) -> Result<Self> {
+        let mut session: Self = serde_json::from_str(state)?;
+        session.provider = provider;
+        Ok(session)
+    }
+
+    pub fn save_state(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.provider.is_none()
+    }
+
+    pub fn step(&self) -> Option<InterviewStep> {
+        STEPS.get(self.answers.len()).copied()
+    }
+
+    pub fn question(&self) -> Option<&str> {
+        match self.is_finished() {
+            true => None,
+            false => self.questions.last().map(|q| q.as_str()),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.answers.len() >= STEPS.len()
+    }
+
+    /// Records the answer to the current question and returns the next one.
+    pub async fn answer(&mut self, text: &str) -> Option<&str> {
+        if self.is_finished() {
+            return None;
+        }
+        self.answers.push(text.trim().to_string());
+        self.ask_next().await;
+        self.question()
+    }
+
+    pub async fn build_card(&self) -> Card {
+        let name = &self.seed_name;
+        let appearance = self.answer_for(InterviewStep::Appearance);
+        let speech = self.answer_for(InterviewStep::SpeechStyle);
+        let description = [
+            (!appearance.is_empty()).then(|| format!("{{{{char}}}}'s appearance: {appearance}")),
+            (!speech.is_empty()).then(|| format!("{{{{char}}}} speaks like this: {speech}")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<String>>()
+        .join("\n");
+
+        let mut card = Card::basic(name, &description);
+        card.data.personality = self.answer_for(InterviewStep::Personality).to_string();
+        card.data.scenario = self.answer_for(InterviewStep::Scenario).to_string();
+
+        let greeting = self.answer_for(InterviewStep::Greeting);
+        let drafted = self
+            .query(&format!(
+                "Write the first message {name} sends in a roleplay, in {name}'s voice. \
+                 Use {{{{user}}}} for the other person. Reply with the message only.\n\
+                 Character description:\n{description}\nPersonality: {}\nScenario: {}\n\
+                 Greeting idea: {greeting}",
+                card.data.personality, card.data.scenario
+            ))
+            .await;
+        card.data.first_mes = match drafted {
+            Some(message) => Some(message),
+            None if !greeting.is_empty() => Some(greeting.to_string()),
+            None => None,
+        };
+
+        if let Some(tags) = self
+            .query(&format!(
+                "Suggest up to five short tags for this character, comma separated, nothing else.\n\
+                 {description}\n{}",
+                card.data.personality
+            ))
+            .await
+        {
+            card.data.tags = tags
+                .split(',')
+                .map(|t| t.trim().to_lowercase())
+                .filter(|t| !t.is_empty())
+                .take(5)
+                .collect();
+        }
+        card
+    }
+
+    fn answer_for(&self, step: InterviewStep) -> &str {
+        STEPS
+            .iter()
+            .position(|s| *s == step)
+            .and_then(|i| self.answers.get(i))
+            .map(|a| a.as_str())
+            .unwrap_or_default()
+    }
+
+    async fn ask_next(&mut self) {
+        let Some(step) = self.step() else {
+            return;
+        };
+
+        let mut context = String::new();
+        for (question, answer) in self.questions.iter().zip(&self.answers) {
+            context.push_str(&format!("Q: {question}\nA: {answer}\n"));
+        }
+        let question = self
+            .query(&format!(
+                "You are helping a writer create a roleplay character named {}.\n{context}\
+                 Ask one short question about the character's {}. Reply with the question only.",
+                self.seed_name,
+                step.topic()
+            ))
+            .await
+            .unwrap_or_else(|| step.template_question(&self.seed_name));
+        trace!("Interview question: {question}");
+        self.questions.push(question);
+    }
+
+    async fn query(&self, prompt: &str) -> Option<String> {
+        let provider = self.provider.as_ref()?;
+        let messages = [ChatMessage::user().content(prompt).build()];
+        match provider.chat(&messages).await {
+            Ok(response) => response
+                .text()
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty()),
+            Err(e) => {
+                error!("Interview query failed: {e}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockProvider;
+
+    const ANSWERS: [&str; 5] = [
+        "Tall, silver hair",
+        "Curious",
+        "In riddles",
+        "A lighthouse at night",
+        "Welcome, traveler",
+    ];
+
+    async fn answer_all(session: &mut InterviewSession) {
+        for answer in ANSWERS {
+            session.answer(answer).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn full_interview_with_a_model() {
+        let provider = Arc::new(MockProvider::new(&["Calm", ", Brave"]));
+        let mut session = start(Some(provider), "Ada").await;
+        assert!(!session.is_offline());
+        assert_eq!(session.step(), Some(InterviewStep::Appearance));
+        assert_eq!(session.question(), Some("Calm, Brave"));
+
+        answer_all(&mut session).await;
+        assert!(session.is_finished());
+        assert_eq!(session.question(), None);
+        assert_eq!(session.answer("Too late").await, None);
+
+        let card = session.build_card().await;
+        assert_eq!(card.name(), "Ada");
+        assert!(card.data.description.contains("Tall, silver hair"));
+        assert!(card.data.description.contains("In riddles"));
+        assert_eq!(card.data.personality, "Curious");
+        assert_eq!(card.data.scenario, "A lighthouse at night");
+        assert_eq!(card.data.first_mes.as_deref(), Some("Calm, Brave"));
+        assert_eq!(card.tags(), ["calm", "brave"]);
+    }
+
+    #[tokio::test]
+    async fn offline_interview_templates_the_answers() {
+        let mut session = start(None, "Ada").await;
+        assert!(session.is_offline());
+        assert_eq!(session.question(), Some("What does Ada look like?"));
+        assert_eq!(
+            session.answer(ANSWERS[0]).await,
+            Some("How would you describe Ada's personality?")
+        );
+        for answer in &ANSWERS[1..] {
+            session.answer(answer).await;
+        }
+
+        let card = session.build_card().await;
+        assert_eq!(card.data.first_mes.as_deref(), Some("Welcome, traveler"));
+        assert!(card.tags().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resumes_where_it_stopped() {
+        let mut session = start(None, "Ada").await;
+        session.answer(ANSWERS[0]).await;
+        session.answer(ANSWERS[1]).await;
+
+        let mut resumed = InterviewSession::resume(&session.save_state().unwrap(), None).unwrap();
+        assert_eq!(resumed.step(), Some(InterviewStep::SpeechStyle));
+        assert_eq!(resumed.question(), session.question());
+        for answer in &ANSWERS[2..] {
+            resumed.answer(answer).await;
+        }
+        assert_eq!(resumed.build_card().await.data.personality, "Curious");
+    }
+}
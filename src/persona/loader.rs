@@ -0,0 +1,643 @@
+use anyhow::{Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use image::{ImageBuffer, ImageReader, Limits, Rgba};
+use log::{error, trace, warn};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{Once, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::persona::{
+    Persona,
+    card::{Basic, Card},
+};
+
+static DATA_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+static MIGRATION: Once = Once::new();
+
+const RECENT_INDEX: &str = "recent.json";
+const THUMBNAILS: &str = "thumbnails";
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoaderOptions {
+    /// Images with more pixels are not decoded, the persona is loaded without its avatar.
+    pub max_pixels: u64,
+    /// Number of images decoded at the same time.
+    pub max_concurrent_decodes: usize,
+    /// Avatars are downscaled to fit this size.
+    pub max_avatar_size: u32,
+    /// How often the directories are checked for changes after the first scan, never when absent.
+    pub watch_interval: Option<Duration>,
+    /// The Gateway scan announces personas before their avatars are decoded, see
+    /// `GatewayUpdate::CharAvatarReady`.
+    pub lazy_avatars: bool,
+    /// The Gateway scan loads the thumbnails of this size already cached on disk, so pickers
+    /// can show them before the avatars are decoded. See `Persona::thumbnail`.
+    pub startup_thumbnail: Option<u32>,
+}
+
+impl Default for LoaderOptions {
+    fn default() -> Self {
+        Self {
+            max_pixels: 64 * 1024 * 1024,
+            max_concurrent_decodes: 4,
+            max_avatar_size: 512,
+            watch_interval: Some(Duration::from_secs(2)),
+            lazy_avatars: true,
+            startup_thumbnail: None,
+        }
+    }
+}
+
+/// Last use of each persona directory, kept apart from the directory mtimes which copies reset
+/// and some platforms cannot set on directories.
+fn recent_index() -> HashMap<PathBuf, SystemTime> {
+    data_dir()
+        .and_then(|dir| fs::read_to_string(dir.join(RECENT_INDEX)).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Records the persona in `dir` as used now.
+pub(crate) fn mark_used(dir: &Path) -> Result<()> {
+    let data_dir = data_dir().ok_or_else(|| anyhow!("No data directory"))?;
+    let mut index = recent_index();
+    index.retain(|path, _| path.is_dir());
+    index.insert(dir.to_path_buf(), SystemTime::now());
+    fs::create_dir_all(&data_dir)?;
+    fs::write(
+        data_dir.join(RECENT_INDEX),
+        serde_json::to_string_pretty(&index)?,
+    )?;
+    Ok(())
+}
+
+/// Latest modification of each persona directory in `dir`, files inside included.
+pub(crate) fn dir_stamps(dir: Option<PathBuf>) -> HashMap<PathBuf, SystemTime> {
+    let Some(Ok(entries)) = dir.map(fs::read_dir) else {
+        return HashMap::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .map(|path| {
+            let files = fs::read_dir(&path)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| modified_time(&entry.path()));
+            let stamp = files.fold(modified_time(&path), SystemTime::max);
+            (path, stamp)
+        })
+        .collect()
+}
+
+/// The directory of `dir` used last according to the recency index, the last modified one when
+/// none of them is indexed.
+pub(crate) fn most_recent_dir(dir: PathBuf) -> Result<PathBuf> {
+    let subdirs: Vec<PathBuf> = fs::read_dir(&dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    let index = recent_index();
+    let indexed = subdirs
+        .iter()
+        .filter_map(|path| Some((index.get(path)?, path)))
+        .max();
+    let most_recent = match indexed {
+        Some((_, path)) => Some(path),
+        None => subdirs.iter().max_by_key(|path| modified_time(path)),
+    };
+    most_recent
+        .cloned()
+        .ok_or_else(|| anyhow!("No file found in {:?}", dir))
+}
+
+/// Loads the persona of `dir` with its avatar.
+pub(crate) fn load_dir(dir: PathBuf, options: &LoaderOptions) -> Result<Persona> {
+    let (mut persona, avatar) = load_card_dir(dir)?;
+    if let Some(avatar) = avatar {
+        persona.set_image(load_avatar(avatar, options));
+    }
+    Ok(persona)
+}
+
+/// Loads the persona of `dir` without decoding its avatar, returning the avatar path instead.
+pub(crate) fn load_card_dir(dir: PathBuf) -> Result<(Persona, Option<PathBuf>)> {
+    let modified_time = recent_index()
+        .get(&dir)
+        .copied()
+        .unwrap_or_else(|| modified_time(&dir));
+
+    let mut png = None;
+    let mut other_image = None;
+    let mut persona = Err(anyhow!("Persona not found"));
+    let mut embedded = None;
+    for entry in (fs::read_dir(&dir)?).flatten() {
+        let path = entry.path();
+        if path.is_file()
+            && let Some(ext) = path.extension()
+            && let Some(ext) = ext.to_str()
+        {
+            match ext.to_ascii_lowercase().as_str() {
+                "json" => persona = load_persona(path),
+                "png" => {
+                    match load_embedded_card(&path) {
+                        Ok(card) => embedded = embedded.or(card),
+                        Err(e) => error!("Invalid card embedded in {:?}: {e}", path),
+                    }
+                    png = Some(path);
+                }
+                // The first frame of a gif
+                "jpg" | "jpeg" | "webp" | "gif" => other_image = Some(path),
+                _ => (),
+            }
+        }
+    }
+
+    let avatar = png.or(other_image);
+    match persona.ok().or(embedded) {
+        Some(data) => {
+            let mut persona = Persona::new(data, None, modified_time, dir);
+            persona.set_avatar_path(avatar.clone());
+            Ok((persona, avatar))
+        }
+        None => Err(anyhow!("Persona not found")),
+    }
+}
+
+fn thumbnail_path(source: &Path, size: u32) -> Option<PathBuf> {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    modified_time(&source.to_path_buf()).hash(&mut hasher);
+    size.hash(&mut hasher);
+    Some(
+        data_dir()?
+            .join(THUMBNAILS)
+            .join(format!("{:016x}.png", hasher.finish())),
+    )
+}
+
+/// The cached thumbnail of `source`, keyed by its path, mtime and the size.
+pub(crate) fn read_thumbnail(source: &Path, size: u32) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    let path = thumbnail_path(source, size)?;
+    let image = image::open(&path).ok()?.to_rgba8();
+    (image.dimensions() == (size, size)).then_some(image)
+}
+
+/// Reads the thumbnail from the cache, generating and caching it when missing or unreadable.
+pub(crate) fn cached_thumbnail(
+    source: &Path,
+    size: u32,
+    generate: impl FnOnce() -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    if let Some(thumbnail) = read_thumbnail(source, size) {
+        return Some(thumbnail);
+    }
+    let thumbnail = generate()?;
+    if let Some(path) = thumbnail_path(source, size) {
+        let saved = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(thumbnail.save(&path)?));
+        if let Err(e) = saved {
+            warn!("Could not cache the thumbnail {:?}: {e}", path);
+        }
+    }
+    Some(thumbnail)
+}
+
+pub(crate) fn load_avatar(
+    path: PathBuf,
+    options: &LoaderOptions,
+) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    load_image(path, options)
+        .inspect_err(|e| warn!("Persona loaded without its avatar: {e}"))
+        .ok()
+}
+
+/// Copies a `.json` card or a `.png` avatar, with or without an embedded card, into a new
+/// directory of `base_dir`. Returns the directory, suffixed with a number when the name is taken.
+pub(crate) fn import_file(path: &Path, base_dir: &Path) -> Result<PathBuf> {
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| anyhow!("Invalid file name {:?}", path))?;
+    let (card, avatar) = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => (load_persona(path.to_path_buf())?, false),
+        Some("png") => {
+            let card = load_embedded_card(&path.to_path_buf())?;
+            (card.unwrap_or_else(|| Card::basic(stem, "")), true)
+        }
+        _ => return Err(anyhow!("{:?} is not a json or png card", path)),
+    };
+
+    let name = card.name().replace(['/', '\\'], "_");
+    let mut dir_name = name.clone();
+    let mut suffix = 1;
+    while base_dir.join(&dir_name).exists() {
+        suffix += 1;
+        dir_name = format!("{name} {suffix}");
+    }
+    let dir = base_dir.join(&dir_name);
+    fs::create_dir_all(&dir)?;
+    fs::write(
+        dir.join(format!("{dir_name}.json")),
+        serde_json::to_string_pretty(&card)?,
+    )?;
+    if avatar {
+        fs::copy(path, dir.join(format!("{dir_name}.png")))?;
+    }
+    Ok(dir)
+}
+
+fn load_embedded_card(path: &PathBuf) -> Result<Option<Card>> {
+    let bytes = fs::read(path)?;
+    match png_text_chunk(&bytes, "chara") {
+        Some(text) => {
+            let json = STANDARD.decode(text.trim())?;
+            Ok(Some(Card::load_from_json(std::str::from_utf8(&json)?)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Writes `image` as a PNG carrying `card` in a `chara` tEXt chunk, the SillyTavern format.
+pub(crate) fn write_card_png(
+    path: &Path,
+    image: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    card: &Card,
+) -> Result<()> {
+    let (width, height) = image.dimensions();
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.add_text_chunk(
+        "chara".to_string(),
+        STANDARD.encode(serde_json::to_string(card)?),
+    )?;
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(image.as_raw())?;
+    writer.finish()?;
+    Ok(())
+}
+
+fn png_text_chunk<'a>(bytes: &'a [u8], keyword: &str) -> Option<&'a str> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    let mut rest = bytes.strip_prefix(SIGNATURE)?;
+
+    // Each chunk is length (4) + type (4) + data (length) + crc (4)
+    while rest.len() >= 12 {
+        let length = u32::from_be_bytes(rest[0..4].try_into().ok()?) as usize;
+        let chunk_type = &rest[4..8];
+        let data = rest.get(8..8 + length)?;
+        if chunk_type == b"tEXt"
+            && let Some((key, text)) = data.split_at_checked(keyword.len())
+            && key == keyword.as_bytes()
+            && let Some(text) = text.strip_prefix(b"\0")
+        {
+            return std::str::from_utf8(text).ok();
+        }
+        if chunk_type == b"IEND" {
+            break;
+        }
+        rest = rest.get(12 + length..)?;
+    }
+    None
+}
+
+/// Reads a card, or a `Basic` persona when the file is not a card.
+fn load_persona(path: PathBuf) -> Result<Card> {
+    let data = fs::read_to_string(&path)?;
+    Card::load_from_json(&data)
+        .or_else(|e| Basic::load_from_json(&data).map(Card::from).map_err(|_| e))
+}
+
+pub(crate) fn load_image(
+    path: PathBuf,
+    options: &LoaderOptions,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    // Only the header is read here, huge images are rejected before allocating anything
+    let (width, height) = ImageReader::open(&path)?
+        .with_guessed_format()?
+        .into_dimensions()?;
+    let pixels = width as u64 * height as u64;
+    if pixels > options.max_pixels {
+        return Err(anyhow!(
+            "{:?} is {width}x{height}, above the budget of {} pixels",
+            path,
+            options.max_pixels
+        ));
+    }
+
+    let mut reader = ImageReader::open(&path)?.with_guessed_format()?;
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(options.max_pixels.saturating_mul(8));
+    reader.limits(limits);
+    let mut decoded = reader.decode()?;
+    let size = options.max_avatar_size;
+    if width.min(height) > size {
+        // Fit the short side so a square crop keeps the full avatar size
+        let scale = size as f64 / width.min(height) as f64;
+        decoded = decoded.thumbnail(
+            (width as f64 * scale).ceil() as u32,
+            (height as f64 * scale).ceil() as u32,
+        );
+    }
+    Ok(decoded.to_rgba8())
+}
+
+/// Overrides the default data directory, `MOON_DATA_DIR` still takes precedence.
+pub fn set_data_dir(dir: Option<PathBuf>) {
+    *DATA_DIR.write().unwrap() = dir;
+}
+
+/// Where the personas live: `MOON_DATA_DIR`, then `set_data_dir`, then the platform data
+/// directory. None when the platform has no data directory.
+pub fn data_dir() -> Option<PathBuf> {
+    let dir = std::env::var_os("MOON_DATA_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| DATA_DIR.read().unwrap().clone())
+        .or_else(|| dirs::data_dir().map(|dir| dir.join("moon")))?;
+    MIGRATION.call_once(|| migrate_cache(&dir));
+    Some(dir)
+}
+
+pub(crate) fn cache_path(subdir: &str) -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join(subdir))
+}
+
+/// Personas used to be kept in the cache directory, which gets wiped. Moves them once into the
+/// data directory, unless it already has personas.
+fn migrate_cache(dir: &Path) {
+    let Some(old) = dirs::cache_dir().map(|dir| dir.join("moon")) else {
+        return;
+    };
+    if !old.is_dir() || old == dir {
+        return;
+    }
+    for subdir in ["chars", "users"] {
+        let (from, to) = (old.join(subdir), dir.join(subdir));
+        if !from.is_dir() || to.exists() {
+            continue;
+        }
+        trace!("Moving {:?} to {:?}", from, to);
+        let moved = fs::create_dir_all(dir)
+            .and_then(|_| fs::rename(&from, &to))
+            // Across filesystems
+            .or_else(|_| copy_dir(&from, &to).and_then(|_| fs::remove_dir_all(&from)));
+        if let Err(e) = moved {
+            error!("Could not move {:?} to {:?}: {e}", from, to);
+        }
+    }
+}
+
+/// Tests never move the personas of the real cache.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn skip_migration() {
+    MIGRATION.call_once(|| ());
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        let dest = to.join(path.file_name().unwrap_or_default());
+        match path.is_dir() {
+            true => copy_dir(&path, &dest)?,
+            false => fs::copy(&path, &dest).map(|_| ())?,
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn modified_time(path: &PathBuf) -> SystemTime {
+    if let Ok(metadata) = fs::metadata(path)
+        && let Ok(modified_time) = metadata.modified()
+    {
+        return modified_time;
+    }
+    SystemTime::UNIX_EPOCH
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn write_png_with_text(path: &Path, keyword: &str, text: &str) {
+        let file = File::create(path).unwrap();
+        let mut encoder = png::Encoder::new(BufWriter::new(file), 2, 2);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .add_text_chunk(keyword.to_string(), text.to_string())
+            .unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[255; 16]).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn loads_a_card_embedded_in_the_only_png() {
+        let dir = TempDir::new("embedded");
+        let card = Card::basic("Embedded", "Lives in a png");
+        write_card_png(&dir.path().join("card.png"), &RgbaImage::new(8, 8), &card).unwrap();
+
+        let persona = load_dir(dir.path().to_path_buf(), &LoaderOptions::default()).unwrap();
+        assert_eq!(persona.name(), "Embedded");
+        assert_eq!(persona.data.data.description, "Lives in a png");
+        assert!(persona.image_raw().is_some());
+    }
+
+    #[test]
+    fn malformed_embedded_cards_are_errors() {
+        let dir = TempDir::new("malformed");
+        write_png_with_text(&dir.path().join("base64.png"), "chara", "not base64 !");
+        assert!(load_embedded_card(&dir.path().join("base64.png")).is_err());
+        write_png_with_text(
+            &dir.path().join("json.png"),
+            "chara",
+            &STANDARD.encode("{ not json"),
+        );
+        assert!(load_embedded_card(&dir.path().join("json.png")).is_err());
+
+        assert!(load_card_dir(dir.path().to_path_buf()).is_err());
+    }
+
+    #[test]
+    fn png_without_card_is_the_avatar_of_the_json() {
+        let dir = TempDir::new("plain");
+        let card = Card::basic("Plain", "");
+        fs::write(
+            dir.path().join("plain.json"),
+            serde_json::to_string(&card).unwrap(),
+        )
+        .unwrap();
+        write_png_with_text(&dir.path().join("plain.png"), "Comment", "not a card");
+
+        let (persona, avatar) = load_card_dir(dir.path().to_path_buf()).unwrap();
+        assert_eq!(persona.name(), "Plain");
+        assert_eq!(avatar, Some(dir.path().join("plain.png")));
+    }
+
+    #[test]
+    fn json_card_takes_precedence_over_the_embedded_one() {
+        let dir = TempDir::new("precedence");
+        fs::write(
+            dir.path().join("card.json"),
+            serde_json::to_string(&Card::basic("Json", "")).unwrap(),
+        )
+        .unwrap();
+        write_card_png(
+            &dir.path().join("card.png"),
+            &RgbaImage::new(2, 2),
+            &Card::basic("Png", ""),
+        )
+        .unwrap();
+
+        let (persona, _) = load_card_dir(dir.path().to_path_buf()).unwrap();
+        assert_eq!(persona.name(), "Json");
+    }
+
+    #[test]
+    fn directories_without_persona_are_errors() {
+        let dir = TempDir::new("empty");
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+        assert!(load_card_dir(dir.path().to_path_buf()).is_err());
+        assert!(load_card_dir(dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn imports_get_a_directory_of_their_own() {
+        let source = TempDir::new("import-source");
+        let chars = TempDir::new("import-chars");
+        let json = source.path().join("luna.json");
+        fs::write(
+            &json,
+            serde_json::to_string(&Card::basic("Luna", "")).unwrap(),
+        )
+        .unwrap();
+
+        let first = import_file(&json, chars.path()).unwrap();
+        let second = import_file(&json, chars.path()).unwrap();
+        assert_eq!(first, chars.path().join("Luna"));
+        assert_eq!(second, chars.path().join("Luna 2"));
+        assert!(second.join("Luna 2.json").is_file());
+        let persona = load_dir(second, &LoaderOptions::default()).unwrap();
+        assert_eq!(persona.name(), "Luna");
+    }
+
+    #[test]
+    fn imported_pngs_keep_their_avatar() {
+        let source = TempDir::new("import-png");
+        let chars = TempDir::new("import-png-chars");
+        let card = source.path().join("card.png");
+        write_card_png(&card, &RgbaImage::new(4, 4), &Card::basic("Sol", "")).unwrap();
+        let plain = source.path().join("Stella.png");
+        write_png_with_text(&plain, "Comment", "not a card");
+
+        let dir = import_file(&card, chars.path()).unwrap();
+        assert!(dir.join("Sol.png").is_file());
+        let persona = load_dir(dir, &LoaderOptions::default()).unwrap();
+        assert_eq!(persona.name(), "Sol");
+        assert!(persona.image_raw().is_some());
+
+        // Named after the file without a card
+        let dir = import_file(&plain, chars.path()).unwrap();
+        let (persona, avatar) = load_card_dir(dir.clone()).unwrap();
+        assert_eq!(persona.name(), "Stella");
+        assert_eq!(avatar, Some(dir.join("Stella.png")));
+
+        let text = source.path().join("card.txt");
+        fs::write(&text, "").unwrap();
+        assert!(import_file(&text, chars.path()).is_err());
+    }
+
+    #[test]
+    fn large_avatars_are_downscaled_or_skipped() {
+        let dir = TempDir::new("large");
+        fs::write(
+            dir.path().join("big.json"),
+            serde_json::to_string(&Card::basic("Big", "")).unwrap(),
+        )
+        .unwrap();
+        RgbaImage::new(64, 32)
+            .save(dir.path().join("big.png"))
+            .unwrap();
+
+        let options = LoaderOptions {
+            max_avatar_size: 16,
+            ..LoaderOptions::default()
+        };
+        let persona = load_dir(dir.path().to_path_buf(), &options).unwrap();
+        assert_eq!(persona.image_raw().unwrap().dimensions(), (32, 16));
+
+        let options = LoaderOptions {
+            max_pixels: 100,
+            ..LoaderOptions::default()
+        };
+        let persona = load_dir(dir.path().to_path_buf(), &options).unwrap();
+        assert_eq!(persona.name(), "Big");
+        assert!(persona.image_raw().is_none());
+    }
+
+    fn write_json_card(dir: &Path, name: &str) {
+        fs::write(
+            dir.join(format!("{name}.json")),
+            serde_json::to_string(&Card::basic(name, "")).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn other_formats_are_avatars_too() {
+        let image = RgbaImage::from_pixel(6, 4, Rgba([40, 80, 120, 255]));
+        for ext in ["jpg", "jpeg", "webp", "gif"] {
+            let dir = TempDir::new(ext);
+            write_json_card(dir.path(), "Avatar");
+            let path = dir.path().join(format!("avatar.{ext}"));
+            match ext {
+                // No alpha in jpegs
+                "jpg" | "jpeg" => image::DynamicImage::from(image.clone())
+                    .to_rgb8()
+                    .save(&path)
+                    .unwrap(),
+                _ => image.save(&path).unwrap(),
+            }
+
+            let persona = load_dir(dir.path().to_path_buf(), &LoaderOptions::default()).unwrap();
+            assert_eq!(persona.avatar_path, Some(path.clone()), "{ext}");
+            assert_eq!(persona.image_raw().unwrap().dimensions(), (6, 4), "{ext}");
+            // Cropped and masked like a png
+            let circle = persona.image().unwrap();
+            assert_eq!(circle.dimensions(), (4, 4), "{ext}");
+            assert_eq!(circle.get_pixel(0, 0)[3], 0, "{ext}");
+        }
+    }
+
+    #[test]
+    fn png_is_preferred_over_other_formats() {
+        let dir = TempDir::new("preferred");
+        write_json_card(dir.path(), "Both");
+        let image = RgbaImage::new(2, 2);
+        image.save(dir.path().join("a.webp")).unwrap();
+        image.save(dir.path().join("b.png")).unwrap();
+        image.save(dir.path().join("c.gif")).unwrap();
+
+        let (_, avatar) = load_card_dir(dir.path().to_path_buf()).unwrap();
+        assert_eq!(avatar, Some(dir.path().join("b.png")));
+    }
+}
@@ -1,16 +1,45 @@
-use std::{fmt::Debug, ops::Deref, path::PathBuf, time::SystemTime};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
+use anyhow::{Result, anyhow};
 use image::{ImageBuffer, Rgba};
 use log::error;
 
-use crate::{gateway::Gateway, persona::card::Card};
+use crate::{
+    persona::{
+        avatar::{Avatar, AvatarStyle, RawImage},
+        card::Card,
+        loader::LoaderOptions,
+        schedule::{Availability, Schedule},
+    },
+    settings::SettingsOverrides,
+};
 
+pub mod avatar;
+pub mod builder;
 pub mod card;
+pub mod interview;
+pub mod loader;
+pub mod schedule;
+
+/// Thumbnails by size, shared between the clones of a persona.
+type Thumbnails = Arc<Mutex<HashMap<u32, Arc<ImageBuffer<Rgba<u8>, Vec<u8>>>>>>;
 
 #[derive(Clone)]
 pub struct Persona {
     data: Card,
-    image: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    /// Shared between the clones handed to chats and threads.
+    image: Option<Avatar>,
+    /// File the avatar was decoded from, the source of the thumbnails.
+    avatar_path: Option<PathBuf>,
+    thumbnails: Thumbnails,
     modified_time: SystemTime,
     path: PathBuf,
 }
@@ -40,73 +69,205 @@ impl Persona {
     ) -> Self {
         Persona {
             data,
-            image,
+            image: image.map(Avatar::new),
+            avatar_path: None,
+            thumbnails: Arc::default(),
             modified_time,
             path,
         }
     }
 
+    pub fn from_card(data: Card) -> Self {
+        Self::new(data, None, SystemTime::now(), PathBuf::new())
+    }
+
     pub fn default_user() -> Self {
-        Self {
-            data: Card::basic("User", ""),
-            image: None,
-            modified_time: SystemTime::now(),
-            path: PathBuf::new(),
-        }
+        Self::new(
+            Card::basic("User", ""),
+            None,
+            SystemTime::now(),
+            PathBuf::new(),
+        )
     }
 
     pub fn default_char() -> Self {
-        Self {
-            data: Card::basic("Luna", "You are Luna, an helpfull AI assistant."),
-            image: None,
+        Self::new(
+            Card::basic("Luna", "You are Luna, an helpfull AI assistant."),
+            None,
+            SystemTime::now(),
+            PathBuf::new(),
+        )
+    }
 
-            modified_time: SystemTime::now(),
+    /// Writes the card and avatar into a subdirectory of `base_dir` named after the character.
+    pub fn save(&mut self, base_dir: &Path, overwrite: bool) -> Result<()> {
+        let dir_name = self.name().replace(['/', '\\'], "_");
+        let dir = base_dir.join(&dir_name);
+        if dir.exists() && !overwrite {
+            return Err(anyhow!("Persona already exists at {:?}", dir));
+        }
+        fs::create_dir_all(&dir)?;
 
-            path: PathBuf::new(),
+        let content = serde_json::to_string_pretty(&self.data)?;
+        fs::write(dir.join(format!("{dir_name}.json")), content)?;
+        if let Some(image) = &self.image {
+            let avatar_path = dir.join(format!("{dir_name}.png"));
+            image.get(AvatarStyle::Original).save(&avatar_path)?;
+            self.set_avatar_path(Some(avatar_path));
         }
+
+        self.path = dir;
+        self.modified_time = SystemTime::now();
+        Ok(())
     }
 
-    // pub fn save(&self, path: PathBuf) -> Result<(), Box<dyn Error>> {
-    //     if !path.exists() {
-    //         fs::create_dir_all(&path)?;
-    //     }
-    //
-    //     let config_path = path.join(format!("{}.json", self.name()));
-    //     let content = match &self.ptype {
-    //         PType::Basic(basic) => serde_json::to_string_pretty(basic)?,
-    //         PType::Card(card) => serde_json::to_string_pretty(card)?,
-    //     };
-    //     fs::write(config_path, content)?;
-    //
-    //     Ok(())
-    // }
+    /// Writes a PNG of the avatar with the card embedded, importable by SillyTavern. Personas
+    /// without an avatar get a plain square in a color derived from the card.
+    pub fn export_png(&self, path: &Path) -> Result<()> {
+        let image = match &self.image {
+            Some(avatar) => avatar.get(AvatarStyle::Original).clone(),
+            None => {
+                let [r, g, b, ..] = self.content_hash().to_le_bytes();
+                Arc::new(ImageBuffer::from_pixel(256, 256, Rgba([r, g, b, 255])))
+            }
+        };
+        loader::write_card_png(path, &image, &self.data)
+    }
 
+    /// The circular avatar.
     pub fn image(&self) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
-        self.image.clone()
+        self.image_with(AvatarStyle::Circle)
     }
 
-    pub fn raw_image(&self) -> Option<(u32, u32, Vec<u8>)> {
-        match self.image.clone() {
-            Some(image) => {
-                let (width, height) = image.dimensions();
-                let content = image.to_vec();
-                Some((width, height, content))
-            }
-            None => None,
+    /// The avatar as decoded, neither cropped nor masked.
+    pub fn image_raw(&self) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        self.image_with(AvatarStyle::Original)
+    }
+
+    pub fn image_with(&self, style: AvatarStyle) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        self.shared_image(style).as_deref().cloned()
+    }
+
+    pub(crate) fn set_image(&mut self, image: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>) {
+        self.image = image.map(Avatar::new);
+    }
+
+    pub(crate) fn set_avatar_path(&mut self, path: Option<PathBuf>) {
+        self.avatar_path = path;
+        self.thumbnails = Arc::default();
+    }
+
+    /// The circular avatar downscaled to `size`, kept on disk under the data directory so the
+    /// original is only decoded when it changed.
+    pub fn thumbnail(&self, size: u32) -> Option<Arc<ImageBuffer<Rgba<u8>, Vec<u8>>>> {
+        if let Some(thumbnail) = self.thumbnails.lock().unwrap().get(&size) {
+            return Some(thumbnail.clone());
         }
+        let generate = || {
+            let avatar = match &self.image {
+                Some(avatar) => avatar.clone(),
+                None => Avatar::new(loader::load_avatar(
+                    self.avatar_path.clone()?,
+                    &LoaderOptions::default(),
+                )?),
+            };
+            Some(avatar::thumbnail(avatar.get(AvatarStyle::Circle), size))
+        };
+        let thumbnail = match &self.avatar_path {
+            Some(source) => loader::cached_thumbnail(source, size, generate),
+            None => generate(),
+        }?;
+        let thumbnail = Arc::new(thumbnail);
+        self.thumbnails
+            .lock()
+            .unwrap()
+            .insert(size, thumbnail.clone());
+        Some(thumbnail)
+    }
+
+    /// Loads the thumbnail from the disk cache only, returning false when it is not there.
+    pub(crate) fn load_cached_thumbnail(&self, size: u32) -> bool {
+        let Some(thumbnail) = self
+            .avatar_path
+            .as_ref()
+            .and_then(|source| loader::read_thumbnail(source, size))
+        else {
+            return false;
+        };
+        self.thumbnails
+            .lock()
+            .unwrap()
+            .insert(size, Arc::new(thumbnail));
+        true
+    }
+
+    /// The avatar without copying its pixels.
+    pub fn shared_image(&self, style: AvatarStyle) -> Option<Arc<ImageBuffer<Rgba<u8>, Vec<u8>>>> {
+        self.image.as_ref().map(|image| image.get(style).clone())
+    }
+
+    /// Converted once per style, later calls only clone the `Arc`.
+    pub fn raw_image(&self, style: AvatarStyle) -> Option<RawImage> {
+        self.image.as_ref().map(|image| image.raw(style))
+    }
+
+    /// Directory the persona was loaded from or saved to, empty for built-in personas.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     pub fn modified_time(&self) -> SystemTime {
         self.modified_time
     }
 
+    /// Marks the persona as the most recently used, see `Gateway::load_most_recent_char`.
     pub fn set_modified_time(&mut self) {
         self.modified_time = SystemTime::now();
-        if let Err(e) = Gateway::touch(&self.path) {
+        if self.path.as_os_str().is_empty() {
+            return;
+        }
+        if let Err(e) = loader::mark_used(&self.path) {
             error!("{e}");
         }
     }
 
+    /// Evaluates the `libmoon.schedule` extension of the card, always available without one.
+    pub fn availability(&self, now: SystemTime) -> Availability {
+        let Some(schedule) = self
+            .data
+            .data
+            .extensions
+            .get("libmoon")
+            .and_then(|libmoon| libmoon.get("schedule"))
+        else {
+            return Availability::Available;
+        };
+        match serde_json::from_value::<Schedule>(schedule.clone())
+            .map_err(anyhow::Error::from)
+            .and_then(|schedule| schedule.availability(now))
+        {
+            Ok(availability) => availability,
+            Err(e) => {
+                error!("Invalid schedule for {}: {e}", self.name());
+                Availability::Available
+            }
+        }
+    }
+
+    /// The `moon` extension of the card, no overrides without one.
+    pub fn settings_overrides(&self) -> SettingsOverrides {
+        let Some(overrides) = self.data.data.extensions.get("moon") else {
+            return SettingsOverrides::default();
+        };
+        match serde_json::from_value(overrides.clone()) {
+            Ok(overrides) => overrides,
+            Err(e) => {
+                error!("Invalid settings overrides for {}: {e}", self.name());
+                SettingsOverrides::default()
+            }
+        }
+    }
+
     pub fn replace_names(s: &str, self_name: &str, partner_name: Option<&str>) -> String {
         let replaced_char_name = s.replace("{{char}}", self_name);
         match partner_name {
@@ -115,3 +276,63 @@ impl Persona {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn edited_card() -> Card {
+        let mut card = Card::basic("Luna", "A moon spirit.");
+        let data = &mut card.data;
+        data.personality = "Calm".to_string();
+        data.scenario = "A night in the forest".to_string();
+        data.first_mes = Some("Good evening, {{user}}.".to_string());
+        data.mes_example = "<START>\n{{user}}: Hi\n{{char}}: Hello".to_string();
+        data.creator_notes = "Ünïcode and \"quotes\"".to_string();
+        data.system_prompt = "Stay in character.".to_string();
+        data.post_history_instructions = "Be brief.".to_string();
+        data.alternate_greetings = vec!["Hello again.".to_string()];
+        data.tags = vec!["fantasy".to_string(), "moon".to_string()];
+        data.creator = "Karvyz".to_string();
+        data.character_version = "1.2".to_string();
+        data.extensions.insert(
+            "depth_prompt".to_string(),
+            json!({ "depth": 4, "prompt": "Sigh" }),
+        );
+        card
+    }
+
+    #[test]
+    fn exported_pngs_are_imported_back() {
+        let dir = TempDir::new("export");
+        let card = edited_card();
+        let mut persona = Persona::from_card(card.clone());
+        let avatar = ImageBuffer::from_pixel(3, 5, Rgba([1, 2, 3, 255]));
+        persona.set_image(Some(avatar.clone()));
+        persona.export_png(&dir.path().join("luna.png")).unwrap();
+
+        let imported =
+            loader::load_dir(dir.path().to_path_buf(), &LoaderOptions::default()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&imported.data).unwrap(),
+            serde_json::to_value(&card).unwrap()
+        );
+        assert_eq!(imported.image_raw(), Some(avatar));
+    }
+
+    #[test]
+    fn personas_without_avatar_get_a_placeholder() {
+        let dir = TempDir::new("placeholder");
+        let persona = Persona::from_card(Card::basic("Plain", ""));
+        persona.export_png(&dir.path().join("plain.png")).unwrap();
+
+        let imported =
+            loader::load_dir(dir.path().to_path_buf(), &LoaderOptions::default()).unwrap();
+        assert_eq!(imported.name(), "Plain");
+        let placeholder = image::open(dir.path().join("plain.png")).unwrap();
+        assert_eq!((placeholder.width(), placeholder.height()), (256, 256));
+    }
+}
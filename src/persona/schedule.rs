@@ -0,0 +1,238 @@
+use std::time::SystemTime;
+
+use anyhow::{Result, anyhow};
+use jiff::{
+    Timestamp, Zoned,
+    civil::{Date, Time, Weekday},
+    tz::TimeZone,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceMode {
+    DelayedReply,
+    AwayMessage,
+}
+
+/// A weekly window, `end` before `start` means the window crosses midnight.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Window {
+    /// Three letter day names (`mon`, `tue`...) the window starts on, empty for every day.
+    #[serde(default)]
+    pub days: Vec<String>,
+    pub start: String,
+    pub end: String,
+    pub mode: PresenceMode,
+    /// What the character is doing, e.g. "asleep" or "at work".
+    #[serde(default)]
+    pub situation: String,
+}
+
+/// Stored in the card extensions under `libmoon.schedule`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Schedule {
+    /// IANA timezone name, the system timezone when absent.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    pub windows: Vec<Window>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Availability {
+    Available,
+    DelayedReply {
+        fire_at: SystemTime,
+        situation: String,
+    },
+    AwayMessage {
+        situation: String,
+    },
+}
+
+impl Schedule {
+    pub fn availability(&self, now: SystemTime) -> Result<Availability> {
+        let tz = match &self.timezone {
+            Some(name) => TimeZone::get(name)?,
+            None => TimeZone::system(),
+        };
+        let now = Timestamp::try_from(now)?.to_zoned(tz.clone());
+        for window in &self.windows {
+            if let Some(end) = window.current_end(&now, &tz)? {
+                return Ok(match window.mode {
+                    PresenceMode::DelayedReply => Availability::DelayedReply {
+                        fire_at: SystemTime::from(end.timestamp()),
+                        situation: window.situation.clone(),
+                    },
+                    PresenceMode::AwayMessage => Availability::AwayMessage {
+                        situation: window.situation.clone(),
+                    },
+                });
+            }
+        }
+        Ok(Availability::Available)
+    }
+}
+
+impl Window {
+    /// The end of the occurrence of this window containing `now`, if any.
+    fn current_end(&self, now: &Zoned, tz: &TimeZone) -> Result<Option<Zoned>> {
+        let start: Time = self.start.parse()?;
+        let end: Time = self.end.parse()?;
+        let today = now.date();
+        let time = now.time();
+
+        let occurrence = match start <= end {
+            true => (start <= time && time < end && self.starts_on(today)?).then_some(today),
+            false if time >= start && self.starts_on(today)? => Some(today.tomorrow()?),
+            false if time < end && self.starts_on(today.yesterday()?)? => Some(today),
+            false => None,
+        };
+        match occurrence {
+            Some(end_date) => Ok(Some(end_date.to_datetime(end).to_zoned(tz.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn starts_on(&self, date: Date) -> Result<bool> {
+        if self.days.is_empty() {
+            return Ok(true);
+        }
+        for day in &self.days {
+            if Self::parse_day(day)? == date.weekday() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn parse_day(day: &str) -> Result<Weekday> {
+        let prefix: String = day.to_lowercase().chars().take(3).collect();
+        match prefix.as_str() {
+            "mon" => Ok(Weekday::Monday),
+            "tue" => Ok(Weekday::Tuesday),
+            "wed" => Ok(Weekday::Wednesday),
+            "thu" => Ok(Weekday::Thursday),
+            "fri" => Ok(Weekday::Friday),
+            "sat" => Ok(Weekday::Saturday),
+            "sun" => Ok(Weekday::Sunday),
+            _ => Err(anyhow!("Unknown day {day:?} in schedule")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `time` is RFC 3339, 2024-05-01 is a Wednesday.
+    fn at(time: &str) -> SystemTime {
+        SystemTime::from(time.parse::<Timestamp>().unwrap())
+    }
+
+    fn schedule(
+        timezone: &str,
+        days: &[&str],
+        start: &str,
+        end: &str,
+        mode: PresenceMode,
+    ) -> Schedule {
+        Schedule {
+            timezone: Some(timezone.to_string()),
+            windows: vec![Window {
+                days: days.iter().map(|d| d.to_string()).collect(),
+                start: start.to_string(),
+                end: end.to_string(),
+                mode,
+                situation: "away".to_string(),
+            }],
+        }
+    }
+
+    fn is_away(schedule: &Schedule, time: &str) -> bool {
+        schedule.availability(at(time)).unwrap() != Availability::Available
+    }
+
+    #[test]
+    fn windows_start_and_end_at_their_times() {
+        let work = schedule("UTC", &["wed"], "09:00", "17:00", PresenceMode::AwayMessage);
+        assert!(!is_away(&work, "2024-05-01T08:59:59Z"));
+        assert_eq!(
+            work.availability(at("2024-05-01T09:00:00Z")).unwrap(),
+            Availability::AwayMessage {
+                situation: "away".to_string()
+            }
+        );
+        assert!(is_away(&work, "2024-05-01T16:59:59Z"));
+        assert!(!is_away(&work, "2024-05-01T17:00:00Z"));
+        // Only on the days of the window
+        assert!(!is_away(&work, "2024-05-02T10:00:00Z"));
+    }
+
+    #[test]
+    fn windows_cross_midnight() {
+        let night = schedule(
+            "UTC",
+            &["Wednesday"],
+            "22:00",
+            "07:00",
+            PresenceMode::DelayedReply,
+        );
+        let asleep_until = |fire_at: &str| Availability::DelayedReply {
+            fire_at: at(fire_at),
+            situation: "away".to_string(),
+        };
+        for time in [
+            "2024-05-01T22:00:00Z",
+            "2024-05-01T23:30:00Z",
+            "2024-05-02T06:59:00Z",
+        ] {
+            assert_eq!(
+                night.availability(at(time)).unwrap(),
+                asleep_until("2024-05-02T07:00:00Z"),
+                "{time}"
+            );
+        }
+        assert!(!is_away(&night, "2024-05-01T21:59:59Z"));
+        assert!(!is_away(&night, "2024-05-02T07:00:00Z"));
+        // The night from Tuesday is not in the window
+        assert!(!is_away(&night, "2024-05-01T06:00:00Z"));
+        assert!(!is_away(&night, "2024-05-02T23:00:00Z"));
+    }
+
+    #[test]
+    fn windows_are_in_the_timezone_of_the_schedule() {
+        let work = schedule(
+            "Europe/Paris",
+            &[],
+            "09:00",
+            "17:00",
+            PresenceMode::AwayMessage,
+        );
+        // 09:30 in Paris, summer time
+        assert!(is_away(&work, "2024-05-01T07:30:00Z"));
+        assert!(!is_away(&work, "2024-05-01T15:30:00Z"));
+    }
+
+    #[test]
+    fn invalid_schedules_are_errors() {
+        let typo = schedule(
+            "UTC",
+            &["someday"],
+            "09:00",
+            "17:00",
+            PresenceMode::AwayMessage,
+        );
+        assert!(typo.availability(at("2024-05-01T10:00:00Z")).is_err());
+        let time = schedule("UTC", &[], "9h", "17:00", PresenceMode::AwayMessage);
+        assert!(time.availability(at("2024-05-01T10:00:00Z")).is_err());
+        let timezone = schedule(
+            "Nowhere/City",
+            &[],
+            "09:00",
+            "17:00",
+            PresenceMode::AwayMessage,
+        );
+        assert!(timezone.availability(at("2024-05-01T10:00:00Z")).is_err());
+    }
+}
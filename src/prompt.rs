@@ -0,0 +1,212 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    lorebook::LorePosition,
+    persona::{
+        Persona,
+        card::{Card, Entry},
+    },
+};
+
+/// A part of the system prompt, assembled in the order of `Settings::prompt_order`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptSection {
+    SystemPrompt,
+    /// Triggered lore entries positioned `before_char`.
+    LoreBeforeChar,
+    Description,
+    Personality,
+    Scenario,
+    /// Triggered lore entries positioned `after_char`.
+    #[serde(alias = "lorebook")]
+    LoreAfterChar,
+    /// `mes_example`, left out when the examples are sent as turns.
+    Examples,
+    UserPersona,
+    AuthorsNote,
+}
+
+pub fn default_prompt_order() -> Vec<PromptSection> {
+    vec![
+        PromptSection::SystemPrompt,
+        PromptSection::LoreBeforeChar,
+        PromptSection::Description,
+        PromptSection::Scenario,
+        PromptSection::LoreAfterChar,
+        PromptSection::Examples,
+        PromptSection::UserPersona,
+        PromptSection::AuthorsNote,
+    ]
+}
+
+/// Assembles the card fields and the chat context into a system prompt, one section per line.
+#[derive(Debug, Clone)]
+pub struct PromptBuilder<'a> {
+    card: &'a Card,
+    order: &'a [PromptSection],
+    partner_name: Option<&'a str>,
+    examples: bool,
+    user_persona: Option<String>,
+    lore_before: Vec<&'a str>,
+    lore_after: Vec<&'a str>,
+    authors_note: Option<&'a str>,
+}
+
+impl<'a> PromptBuilder<'a> {
+    pub fn new(card: &'a Card, order: &'a [PromptSection]) -> Self {
+        Self {
+            card,
+            order,
+            partner_name: None,
+            examples: true,
+            user_persona: None,
+            lore_before: vec![],
+            lore_after: vec![],
+            authors_note: None,
+        }
+    }
+
+    /// Name `{{user}}` is replaced with.
+    pub fn partner(mut self, name: &'a str) -> Self {
+        self.partner_name = Some(name);
+        self
+    }
+
+    pub fn examples(mut self, include: bool) -> Self {
+        self.examples = include;
+        self
+    }
+
+    /// Description of the persona the user plays, its own `{{char}}` being the user.
+    pub fn user_persona(mut self, persona: &Card) -> Self {
+        self.user_persona = Some(Persona::replace_names(
+            &persona.data.description,
+            persona.name(),
+            Some(self.card.name()),
+        ));
+        self
+    }
+
+    /// Triggered entries in insertion order, split by their position.
+    pub fn lorebook(mut self, entries: &[&'a Entry]) -> Self {
+        let (before, after): (Vec<&Entry>, _) = entries
+            .iter()
+            .partition(|e| LorePosition::of(e) == LorePosition::BeforeChar);
+        self.lore_before = before.iter().map(|e| e.content.as_str()).collect();
+        self.lore_after = after.iter().map(|e| e.content.as_str()).collect();
+        self
+    }
+
+    pub fn authors_note(mut self, note: Option<&'a str>) -> Self {
+        self.authors_note = note;
+        self
+    }
+
+    pub fn build(&self) -> String {
+        let data = &self.card.data;
+        let sections: Vec<&str> = self
+            .order
+            .iter()
+            .flat_map(|section| match section {
+                PromptSection::SystemPrompt => vec![data.system_prompt.as_str()],
+                PromptSection::Description => vec![data.description.as_str()],
+                PromptSection::Personality => vec![data.personality.as_str()],
+                PromptSection::Scenario => vec![data.scenario.as_str()],
+                PromptSection::Examples if self.examples => vec![data.mes_example.as_str()],
+                PromptSection::Examples => vec![],
+                PromptSection::UserPersona => self.user_persona.as_deref().into_iter().collect(),
+                PromptSection::LoreBeforeChar => self.lore_before.clone(),
+                PromptSection::LoreAfterChar => self.lore_after.clone(),
+                PromptSection::AuthorsNote => self.authors_note.into_iter().collect(),
+            })
+            .filter(|s| !s.trim().is_empty())
+            .collect();
+        Persona::replace_names(&sections.join("\n"), self.card.name(), self.partner_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card() -> Card {
+        let mut card = Card::basic("Luna", "{{char}} is a moon spirit.");
+        card.data.system_prompt = "Stay in character.".to_string();
+        card.data.scenario = "{{user}} meets {{char}} at night.".to_string();
+        card.data.mes_example = "<START>\n{{user}}: Hi\n{{char}}: Hello".to_string();
+        card
+    }
+
+    #[test]
+    fn sections_are_joined_with_newlines() {
+        let card = card();
+        let prompt = PromptBuilder::new(&card, &default_prompt_order())
+            .partner("Ann")
+            .build();
+        assert!(!prompt.contains("/n"));
+        assert_eq!(
+            prompt,
+            "Stay in character.\nLuna is a moon spirit.\nAnn meets Luna at night.\n\
+             <START>\nAnn: Hi\nLuna: Hello"
+        );
+    }
+
+    #[test]
+    fn the_order_changes_the_prompt() {
+        let card = card();
+        let order = [PromptSection::Scenario, PromptSection::Description];
+        let reordered = PromptBuilder::new(&card, &order).partner("Ann").build();
+        assert_eq!(
+            reordered,
+            "Ann meets Luna at night.\nLuna is a moon spirit."
+        );
+        let default = PromptBuilder::new(&card, &default_prompt_order())
+            .partner("Ann")
+            .build();
+        assert_ne!(reordered, default);
+    }
+
+    #[test]
+    fn empty_and_excluded_sections_are_left_out() {
+        let card = card();
+        let order = [
+            PromptSection::Personality,
+            PromptSection::Description,
+            PromptSection::Examples,
+            PromptSection::AuthorsNote,
+        ];
+        let prompt = PromptBuilder::new(&card, &order).examples(false).build();
+        assert_eq!(prompt, "Luna is a moon spirit.");
+    }
+
+    #[test]
+    fn user_persona_and_authors_note() {
+        let card = card();
+        let user = Card::basic("Ann", "{{char}} is a sailor looking for {{user}}.");
+        let order = [
+            PromptSection::Description,
+            PromptSection::UserPersona,
+            PromptSection::AuthorsNote,
+        ];
+        let prompt = PromptBuilder::new(&card, &order)
+            .partner("Ann")
+            .user_persona(&user)
+            .authors_note(Some("Keep it short."))
+            .build();
+        assert_eq!(
+            prompt,
+            "Luna is a moon spirit.\nAnn is a sailor looking for Luna.\nKeep it short."
+        );
+    }
+
+    #[test]
+    fn card_system_prompt_has_real_newlines() {
+        let prompt = card().system_prompt(Some("Ann"), false);
+        assert!(!prompt.contains("/n"));
+        assert_eq!(
+            prompt,
+            "Stay in character.\nLuna is a moon spirit.\nAnn meets Luna at night."
+        );
+    }
+}
@@ -1,51 +1,551 @@
-use std::fs;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
 
 use dirs::config_dir;
-use log::{error, trace};
+use log::{error, trace, warn};
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use uuid::Uuid;
 
+use crate::{
+    dialects::{DialectRule, ExamplePlacement},
+    prompt::{PromptSection, default_prompt_order},
+};
+
+/// OpenRouter provider routing, see https://openrouter.ai/docs/features/provider-routing
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProviderPrefs {
+    pub order: Vec<String>,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub allow_fallbacks: Option<bool>,
+}
+
+impl ProviderPrefs {
+    pub fn to_openrouter_json(&self) -> serde_json::Value {
+        let mut provider = serde_json::Map::new();
+        if !self.order.is_empty() {
+            provider.insert("order".to_string(), self.order.clone().into());
+        }
+        if !self.allow.is_empty() {
+            provider.insert("only".to_string(), self.allow.clone().into());
+        }
+        if !self.deny.is_empty() {
+            provider.insert("ignore".to_string(), self.deny.clone().into());
+        }
+        if let Some(allow_fallbacks) = self.allow_fallbacks {
+            provider.insert("allow_fallbacks".to_string(), allow_fallbacks.into());
+        }
+        serde_json::json!({ "provider": provider })
+    }
+}
+
+/// Version of the settings file written by this build, older files are migrated on load.
+pub const SETTINGS_VERSION: u32 = 3;
+
+type Fields = serde_json::Map<String, serde_json::Value>;
+
+/// What `Settings::save` last wrote, the watch ignores the file while it still holds it.
+static WRITTEN: Mutex<Option<String>> = Mutex::new(None);
+
+/// The backend the requests go to, and the name its key is stored under.
+pub const OPENROUTER: &str = "openrouter";
+
+/// The backend recorded for the generations of a provider set with `Chat::set_provider_factory`.
+pub const CUSTOM_BACKEND: &str = "custom";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+impl ReasoningEffort {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReasoningEffort::Low => "low",
+            ReasoningEffort::Medium => "medium",
+            ReasoningEffort::High => "high",
+        }
+    }
+}
+
+/// A setting rejected by `Settings::validate`, `field` is its name in settings.json.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingsError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+/// Settings a persona replaces for its chats, stored in the card extensions under `moon`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SettingsOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+}
+
+impl SettingsOverrides {
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(model) = &self.model {
+            settings.model = model.clone();
+        }
+        if let Some(temperature) = self.temperature {
+            settings.temperature = temperature;
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            settings.max_tokens = max_tokens;
+        }
+    }
+}
+
+/// Missing fields take their default, files written by older builds keep loading.
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Settings {
-    pub api_key: String,
+    /// See `SETTINGS_VERSION`, files without one are version 1.
+    pub version: u32,
+    /// API keys by backend name, a missing one is taken from the environment, see
+    /// `resolve_api_key`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub keys: HashMap<String, String>,
     pub model: String,
     pub temperature: f32,
     pub max_tokens: u32,
-    pub reasoning: bool,
+    /// Sent to the models whose dialect has `reasoning`, see `Chat::supports_reasoning`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+    /// Thinking tokens allowed, replaces the effort when both are set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_max_tokens: Option<u32>,
+    #[serde(default)]
+    pub dialect_rules: Vec<DialectRule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_preferences: Option<ProviderPrefs>,
+    /// Kill-switch for the characters' availability schedules.
+    #[serde(default = "default_true")]
+    pub presence_schedules: bool,
+    /// Tags the chats saved from this device so edits from several devices can be merged.
+    #[serde(default)]
+    pub device_id: String,
+    /// Seeds the chat randomness so sessions can be replayed, each generation being sent a seed
+    /// drawn from it for reproducible generations where supported. Entropy when absent, and no
+    /// seed is sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Context window of the model in tokens, the oldest messages are dropped to stay below it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_limit: Option<usize>,
+    /// Retries of a request failing with a rate limit, network or server error.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on each following one.
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    /// Longest wait for the provider to start streaming, 0 to wait forever.
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Longest wait between two chunks of a stream before it is abandoned, 0 to wait forever.
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Shortest time between two `ChatUpdate::StreamUpdate`, tokens arriving in between are
+    /// announced together. 0 sends one per chunk and waits for the receiver to keep up.
+    #[serde(default = "default_stream_update_interval_ms")]
+    pub stream_update_interval_ms: u64,
+    /// Continue replies cut by `max_tokens` automatically.
+    #[serde(default)]
+    pub auto_continue: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f32>,
+    /// Sent to the provider and enforced while streaming, as some backends ignore them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    /// Order of the sections of the system prompt, those left out are not sent.
+    #[serde(default = "default_prompt_order")]
+    pub prompt_order: Vec<PromptSection>,
+    /// Where the card's example dialogues go, the dialect of the model decides when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub example_placement: Option<ExamplePlacement>,
+    /// Where the personas are stored, read once at startup, see `loader::data_dir`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    1000
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    20
+}
+
+fn default_request_timeout_secs() -> u64 {
+    60
+}
+
+fn default_stream_update_interval_ms() -> u64 {
+    50
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
-            api_key: "sk-TESTKEY".to_string(),
+            version: SETTINGS_VERSION,
+            keys: HashMap::new(),
             model: "google/gemma-3-27b-it".to_string(),
             temperature: 0.5,
             max_tokens: 1000,
-            reasoning: false,
+            reasoning_effort: None,
+            reasoning_max_tokens: None,
+            dialect_rules: vec![],
+            provider_preferences: None,
+            presence_schedules: true,
+            device_id: Uuid::new_v4().to_string(),
+            seed: None,
+            context_limit: None,
+            max_retries: default_max_retries(),
+            retry_base_delay_ms: default_retry_base_delay_ms(),
+            connect_timeout_secs: default_connect_timeout_secs(),
+            request_timeout_secs: default_request_timeout_secs(),
+            stream_update_interval_ms: default_stream_update_interval_ms(),
+            auto_continue: false,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            min_p: None,
+            stop_sequences: vec![],
+            prompt_order: default_prompt_order(),
+            example_placement: None,
+            data_dir: None,
         }
     }
 }
 
 impl Settings {
+    /// The key of `backend` when set, otherwise `<BACKEND>_API_KEY` or `MOON_API_KEY` so the key
+    /// can stay out of the settings file.
+    pub fn resolve_api_key(&self, backend: &str) -> Option<String> {
+        let from_env = [
+            format!("{}_API_KEY", backend.to_uppercase()),
+            "MOON_API_KEY".to_string(),
+        ]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok());
+        self.keys
+            .get(backend)
+            .cloned()
+            .into_iter()
+            .chain(from_env)
+            .map(|key| key.trim().to_string())
+            .find(|key| !key.is_empty())
+    }
+
+    pub fn top_p(&self) -> Option<f32> {
+        Self::clamped("top_p", self.top_p, 0.0, 1.0)
+    }
+
+    /// The `reasoning` body field of OpenRouter, `None` when neither reasoning setting is set.
+    pub fn reasoning_body(&self) -> Option<serde_json::Value> {
+        match (self.reasoning_max_tokens, self.reasoning_effort) {
+            (Some(max_tokens), _) => Some(serde_json::json!({ "max_tokens": max_tokens })),
+            (None, Some(effort)) => Some(serde_json::json!({ "effort": effort.as_str() })),
+            (None, None) => None,
+        }
+    }
+
+    /// The samplers and stop sequences `llm` has no builder option for, sent as extra body fields.
+    pub fn sampler_body(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut body = serde_json::Map::new();
+        let samplers = [
+            ("frequency_penalty", self.frequency_penalty, -2.0, 2.0),
+            ("presence_penalty", self.presence_penalty, -2.0, 2.0),
+            ("min_p", self.min_p, 0.0, 1.0),
+        ];
+        for (name, value, min, max) in samplers {
+            if let Some(value) = Self::clamped(name, value, min, max) {
+                body.insert(name.to_string(), value.into());
+            }
+        }
+        let stops: Vec<&str> = self
+            .stop_sequences
+            .iter()
+            .map(|s| s.as_str())
+            .filter(|s| !s.is_empty())
+            .collect();
+        if !stops.is_empty() {
+            body.insert("stop".to_string(), stops.into());
+        }
+        body
+    }
+
+    fn clamped(name: &str, value: Option<f32>, min: f32, max: f32) -> Option<f32> {
+        let value = value?;
+        let clamped = value.clamp(min, max);
+        if clamped != value {
+            warn!("{name} {value} outside of [{min}, {max}], using {clamped}");
+        }
+        Some(clamped)
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<SettingsError>> {
+        let mut errors = vec![];
+        let mut check = |valid: bool, field: &'static str, message: &str| {
+            if !valid {
+                errors.push(SettingsError {
+                    field,
+                    message: message.to_string(),
+                });
+            }
+        };
+        // Every request goes to OpenRouter, whose keys all start the same. A missing key is
+        // reported by the provider. A key from the environment can not be repaired by editing
+        // the settings, it is only warned about
+        let well_formed = |key: &str| key.starts_with("sk-") && !key.contains(char::is_whitespace);
+        match self
+            .keys
+            .get(OPENROUTER)
+            .map(|k| k.trim())
+            .filter(|k| !k.is_empty())
+        {
+            Some(key) => check(
+                well_formed(key),
+                "keys",
+                "OpenRouter keys start with \"sk-\" and have no spaces",
+            ),
+            None => {
+                if self
+                    .resolve_api_key(OPENROUTER)
+                    .is_some_and(|k| !well_formed(&k))
+                {
+                    warn!("The OpenRouter key of the environment does not start with \"sk-\"");
+                }
+            }
+        }
+        check(!self.model.trim().is_empty(), "model", "No model set");
+        check(
+            (0.0..=2.0).contains(&self.temperature),
+            "temperature",
+            "Must be between 0 and 2",
+        );
+        check(self.max_tokens >= 1, "max_tokens", "Must be at least 1");
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    /// Puts the fields `validate` rejects back to their defaults, the temperature is clamped.
+    fn repair(&mut self) {
+        let Err(errors) = self.validate() else {
+            return;
+        };
+        let default = Self::default();
+        for error in errors {
+            error!("Invalid setting {error}, replaced");
+            match error.field {
+                "keys" => {
+                    self.keys.remove(OPENROUTER);
+                }
+                "model" => self.model = default.model.clone(),
+                "temperature" if self.temperature.is_nan() => {
+                    self.temperature = default.temperature
+                }
+                "temperature" => self.temperature = self.temperature.clamp(0.0, 2.0),
+                "max_tokens" => self.max_tokens = default.max_tokens,
+                _ => {}
+            }
+        }
+    }
+
+    /// Brings the fields of an older file to `SETTINGS_VERSION`, returns false if it already was.
+    fn migrate(file: &mut Fields) -> bool {
+        let version = file
+            .get("version")
+            .and_then(|v| v.as_u64())
+            .map_or(1, |v| v as u32);
+        if version > SETTINGS_VERSION {
+            warn!("Settings version {version} is newer than {SETTINGS_VERSION}, loading as is");
+        }
+        if version >= SETTINGS_VERSION {
+            return false;
+        }
+        trace!("Migrating settings from version {version}");
+        if version < 2 {
+            Self::migrate_v1_to_v2(file);
+        }
+        if version < 3 {
+            Self::migrate_v2_to_v3(file);
+        }
+        file.insert("version".to_string(), SETTINGS_VERSION.into());
+        true
+    }
+
+    /// The single OpenRouter key moves into the per-backend keys.
+    fn migrate_v1_to_v2(file: &mut Fields) {
+        if let Some(serde_json::Value::String(key)) = file.remove("api_key")
+            && !key.is_empty()
+            && let serde_json::Value::Object(keys) =
+                file.entry("keys").or_insert_with(|| Fields::new().into())
+        {
+            keys.entry(OPENROUTER).or_insert(key.into());
+        }
+    }
+
+    /// The reasoning switch becomes an effort level, on being the medium one.
+    fn migrate_v2_to_v3(file: &mut Fields) {
+        if let Some(serde_json::Value::Bool(true)) = file.remove("reasoning") {
+            file.entry("reasoning_effort").or_insert("medium".into());
+        }
+    }
+
+    /// Parses the settings one field at a time, a field of the wrong type is replaced by its
+    /// default instead of failing the whole file. Also tells if the file was migrated.
+    fn parse(content: &str) -> serde_json::Result<(Self, bool)> {
+        let mut file: Fields = serde_json::from_str(content)?;
+        let migrated = Self::migrate(&mut file);
+        let serde_json::Value::Object(mut fields) = serde_json::to_value(Self::default())? else {
+            unreachable!("Settings serialize to an object");
+        };
+        // Generated and saved by `load` when the file has none
+        fields.insert("device_id".to_string(), "".into());
+        for (key, value) in file {
+            let previous = fields.insert(key.clone(), value);
+            if let Err(e) = serde_json::from_value::<Self>(fields.clone().into()) {
+                error!("Invalid setting {key}: {e}, using the default");
+                match previous {
+                    Some(previous) => fields.insert(key, previous),
+                    None => fields.remove(&key),
+                };
+            }
+        }
+        Ok((serde_json::from_value(fields.into())?, migrated))
+    }
+
+    fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("moon").join("settings.json"))
+    }
+
+    /// Parses and validates without falling back to any default, so a broken edit is reported
+    /// instead of replacing the settings. The device id is left empty when the file has none.
+    fn parse_strict(content: &str) -> Result<Self, String> {
+        let mut file: Fields =
+            serde_json::from_str(content).map_err(|e| format!("Invalid settings: {e}"))?;
+        Self::migrate(&mut file);
+        file.entry("device_id").or_insert("".into());
+        let settings: Self =
+            serde_json::from_value(file.into()).map_err(|e| format!("Invalid settings: {e}"))?;
+        settings.validate().map_err(|errors| {
+            let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            format!("Invalid settings: {}", errors.join(", "))
+        })?;
+        Ok(settings)
+    }
+
+    /// Polls the settings file, sending the settings each time an edit made outside of `save`
+    /// has held for a whole interval.
+    pub(crate) async fn watch(interval: Duration, tx: mpsc::Sender<Result<Self, String>>) {
+        let Some(path) = Self::path() else {
+            return;
+        };
+        let stamp = async || {
+            tokio::fs::metadata(&path)
+                .await
+                .and_then(|m| m.modified())
+                .ok()
+        };
+        let mut known = stamp().await;
+        let mut pending = None;
+        while !tx.is_closed() {
+            tokio::time::sleep(interval).await;
+            let current = stamp().await;
+            if current == known || pending.replace(current) != Some(current) {
+                continue;
+            }
+            pending = None;
+            known = current;
+            let Ok(content) = tokio::fs::read_to_string(&path).await else {
+                continue;
+            };
+            if WRITTEN.lock().unwrap().as_deref() == Some(content.as_str()) {
+                continue;
+            }
+            trace!("Settings file changed, reloading");
+            let _ = tx.send(Self::parse_strict(&content)).await;
+        }
+    }
+
     pub fn load() -> Self {
-        let path = config_dir()
-            .map(|mut path| {
-                path.push("moon");
-                path.push("settings.json");
-                path
-            })
-            .unwrap();
+        Self::load_from(&Self::path().unwrap())
+    }
+
+    fn load_from(path: &Path) -> Self {
         trace!("Trying to load from {:?}", path);
 
         match path.exists() {
-            true => match fs::read_to_string(&path) {
-                Ok(content) => match serde_json::from_str(&content) {
-                    Ok(settings) => {
+            true => match fs::read_to_string(path) {
+                Ok(content) => match Self::parse(&content) {
+                    Ok((mut settings, migrated)) => {
                         trace!("Loaded settings");
+                        settings.repair();
+                        if settings.device_id.is_empty() {
+                            settings.device_id = Uuid::new_v4().to_string();
+                            settings.save_to(path).unwrap_or_else(|e| error!("{e}"));
+                        } else if migrated {
+                            settings.save_to(path).unwrap_or_else(|e| error!("{e}"));
+                        }
                         settings
                     }
                     Err(e) => {
                         error!("Error parsing config: {}", e);
-                        Self::default()
+                        // Kept for the user to recover their settings from
+                        let backup = path.with_extension("json.bak");
+                        match fs::copy(path, &backup) {
+                            Ok(_) => {
+                                warn!("Broken config moved to {:?}, writing default", backup);
+                                let default = Self::default();
+                                default.save_to(path).unwrap_or_else(|e| error!("{e}"));
+                                default
+                            }
+                            Err(e) => {
+                                error!("Error backing up config: {}", e);
+                                Self::default()
+                            }
+                        }
                     }
                 },
                 Err(e) => {
@@ -56,24 +556,174 @@ impl Settings {
             false => {
                 let default = Self::default();
                 error!("Config not found. Writing default");
-                default.save().unwrap_or_else(|e| error!("{e}"));
+                default.save_to(path).unwrap_or_else(|e| error!("{e}"));
                 default
             }
         }
     }
 
     pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let config_dir = config_dir().ok_or("Unable to find config directory")?;
+        self.save_to(&Self::path().ok_or("Unable to find config directory")?)
+    }
 
-        let fullmoon_dir = config_dir.join("moon");
-        if !fullmoon_dir.exists() {
-            fs::create_dir_all(&fullmoon_dir)?;
+    fn save_to(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(dir) = path.parent()
+            && !dir.exists()
+        {
+            fs::create_dir_all(dir)?;
         }
 
-        let config_path = fullmoon_dir.join("settings.json");
         let content = serde_json::to_string_pretty(self)?;
-        fs::write(config_path, content)?;
+        fs::write(path, &content)?;
+        *WRITTEN.lock().unwrap() = Some(content);
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    const V1: &str = r#"{
+        "api_key": "sk-or-v1",
+        "model": "mistralai/mistral-nemo",
+        "temperature": 0.7,
+        "max_tokens": 300
+    }"#;
+
+    const V2: &str = r#"{
+        "version": 2,
+        "keys": { "openrouter": "sk-or-v2" },
+        "model": "mistralai/mistral-nemo",
+        "temperature": 0.7,
+        "max_tokens": 300,
+        "reasoning": true
+    }"#;
+
+    const V3: &str = r#"{
+        "version": 3,
+        "keys": { "openrouter": "sk-or-v3" },
+        "model": "mistralai/mistral-nemo",
+        "temperature": 0.7,
+        "max_tokens": 300,
+        "reasoning_effort": "high",
+        "device_id": "desktop"
+    }"#;
+
+    #[test]
+    fn loads_every_version() {
+        let (v1, migrated) = Settings::parse(V1).unwrap();
+        assert!(migrated);
+        assert_eq!(v1.version, SETTINGS_VERSION);
+        assert_eq!(
+            v1.keys.get(OPENROUTER).map(String::as_str),
+            Some("sk-or-v1")
+        );
+        assert_eq!(v1.model, "mistralai/mistral-nemo");
+        assert_eq!(v1.max_tokens, 300);
+        assert_eq!(v1.reasoning_effort, None);
+
+        let (v2, migrated) = Settings::parse(V2).unwrap();
+        assert!(migrated);
+        assert_eq!(
+            v2.keys.get(OPENROUTER).map(String::as_str),
+            Some("sk-or-v2")
+        );
+        assert_eq!(v2.reasoning_effort, Some(ReasoningEffort::Medium));
+
+        let (v3, migrated) = Settings::parse(V3).unwrap();
+        assert!(!migrated);
+        assert_eq!(v3.reasoning_effort, Some(ReasoningEffort::High));
+        assert_eq!(v3.device_id, "desktop");
+    }
+
+    #[test]
+    fn a_field_of_the_wrong_type_keeps_the_others() {
+        let content =
+            r#"{ "version": 3, "keys": { "openrouter": "sk-or" }, "max_tokens": "many" }"#;
+        let (settings, _) = Settings::parse(content).unwrap();
+        assert_eq!(settings.max_tokens, Settings::default().max_tokens);
+        assert_eq!(
+            settings.keys.get(OPENROUTER).map(String::as_str),
+            Some("sk-or")
+        );
+        assert!(Settings::parse_strict(content).is_err());
+    }
+
+    #[test]
+    fn migrated_files_are_rewritten() {
+        let dir = TempDir::new("settings-migrate");
+        let path = dir.path().join("settings.json");
+        fs::write(&path, V1).unwrap();
+
+        let settings = Settings::load_from(&path);
+        assert!(!settings.device_id.is_empty());
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["version"], json!(SETTINGS_VERSION));
+        assert_eq!(written["keys"]["openrouter"], json!("sk-or-v1"));
+        assert!(written.get("api_key").is_none());
+        assert_eq!(Settings::load_from(&path).device_id, settings.device_id);
+    }
+
+    #[test]
+    fn broken_files_are_backed_up() {
+        let dir = TempDir::new("settings-broken");
+        let path = dir.path().join("settings.json");
+        fs::write(&path, "{ \"keys\": ").unwrap();
+
+        let settings = Settings::load_from(&path);
+        assert_eq!(settings.model, Settings::default().model);
+        assert_eq!(
+            fs::read_to_string(path.with_extension("json.bak")).unwrap(),
+            "{ \"keys\": "
+        );
+        assert!(Settings::parse(&fs::read_to_string(&path).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn unset_samplers_are_not_written() {
+        let written = serde_json::to_value(Settings::default()).unwrap();
+        for sampler in ["top_p", "frequency_penalty", "presence_penalty", "min_p"] {
+            assert!(written.get(sampler).is_none(), "{sampler}");
+        }
+        assert!(Settings::default().sampler_body().is_empty());
+    }
+
+    #[test]
+    fn samplers_round_trip() {
+        let settings = Settings {
+            top_p: Some(0.9),
+            frequency_penalty: Some(0.5),
+            presence_penalty: Some(-0.5),
+            min_p: Some(0.05),
+            ..Settings::default()
+        };
+        let written = serde_json::to_string(&settings).unwrap();
+        let (read, _) = Settings::parse(&written).unwrap();
+        assert_eq!(read.top_p, Some(0.9));
+        assert_eq!(read.frequency_penalty, Some(0.5));
+        assert_eq!(read.presence_penalty, Some(-0.5));
+        assert_eq!(read.min_p, Some(0.05));
+    }
+
+    #[test]
+    fn samplers_out_of_range_are_clamped() {
+        let settings = Settings {
+            top_p: Some(1.5),
+            frequency_penalty: Some(-3.0),
+            presence_penalty: Some(1.0),
+            min_p: Some(2.0),
+            ..Settings::default()
+        };
+        assert_eq!(settings.top_p(), Some(1.0));
+        let body = settings.sampler_body();
+        assert_eq!(body.get("frequency_penalty"), Some(&json!(-2.0)));
+        assert_eq!(body.get("presence_penalty"), Some(&json!(1.0)));
+        assert_eq!(body.get("min_p"), Some(&json!(1.0)));
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use futures::{StreamExt, stream};
+use llm::{
+    LLMProvider, ToolCall,
+    chat::{
+        ChatMessage, ChatProvider, ChatResponse, StreamChoice, StreamDelta, StreamResponse, Tool,
+        Usage,
+    },
+    completion::{CompletionProvider, CompletionRequest, CompletionResponse},
+    embedding::EmbeddingProvider,
+    error::LLMError,
+    models::ModelsProvider,
+    stt::SpeechToTextProvider,
+    tts::TextToSpeechProvider,
+};
+use tokio::sync::broadcast;
+
+use crate::{chat::ChatUpdate, clock::Clock, persona::loader};
+
+/// The next `StreamFinished` or `RequestError` of a chat, panics when none comes within a few
+/// seconds.
+pub async fn generation_end(rx: &mut broadcast::Receiver<ChatUpdate>) -> ChatUpdate {
+    let end = async {
+        loop {
+            match rx.recv().await {
+                Ok(update @ (ChatUpdate::StreamFinished { .. } | ChatUpdate::RequestError(_))) => {
+                    return update;
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => (),
+                Err(broadcast::error::RecvError::Closed) => panic!("The chat was dropped"),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), end)
+        .await
+        .expect("The generation did not end")
+}
+
+/// A directory of the system temp directory, removed with its content on drop.
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("libmoon-{name}-{}-{count}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("Could not create the temp directory");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A temp directory set as the data directory while it lives, see `loader::set_data_dir`. Holds a
+/// lock so the tests sharing the data directory run one at a time. `MOON_DATA_DIR` must be unset.
+#[derive(Debug)]
+pub struct DataDir {
+    dir: TempDir,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl DataDir {
+    pub fn new(name: &str) -> Self {
+        static LOCK: Mutex<()> = Mutex::new(());
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = TempDir::new(name);
+        loader::skip_migration();
+        loader::set_data_dir(Some(dir.path().to_path_buf()));
+        Self { dir, _lock: lock }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+impl Drop for DataDir {
+    fn drop(&mut self) {
+        loader::set_data_dir(None);
+    }
+}
+
+/// A clock standing still until it is set or advanced, see `Chat::set_wall_clock`.
+#[derive(Debug)]
+pub struct TestClock(Mutex<SystemTime>);
+
+impl TestClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self(Mutex::new(now))
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.0.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+type Chunks = Pin<Box<dyn futures::Stream<Item = Result<StreamResponse, LLMError>> + Send>>;
+
+/// Streams the same tokens for every request, or answers them whole when not streamed. To be
+/// returned by `Chat::set_provider_factory`.
+#[derive(Debug, Clone, Default)]
+pub struct MockProvider {
+    tokens: Vec<String>,
+    /// Wait before each token.
+    delay: Duration,
+    /// Tokens sent before the stream goes silent without ending.
+    stall_after: Option<usize>,
+    tool_calls: Vec<ToolCall>,
+    usage: Option<Usage>,
+    /// Answers to the requests after the first, the last one repeated.
+    then: Vec<MockProvider>,
+    /// Histories of the requests so far, shared with the clones.
+    requests: Arc<Mutex<Vec<Vec<ChatMessage>>>>,
+}
+
+impl MockProvider {
+    pub fn new(tokens: &[&str]) -> Self {
+        Self {
+            tokens: tokens.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn stall_after(mut self, tokens: usize) -> Self {
+        self.stall_after = Some(tokens);
+        self
+    }
+
+    /// Sent in a last chunk, after the tokens.
+    pub fn tool_calls(mut self, calls: Vec<ToolCall>) -> Self {
+        self.tool_calls = calls;
+        self
+    }
+
+    /// Reported in a last chunk without content, like OpenRouter does.
+    pub fn usage(mut self, prompt_tokens: u32, completion_tokens: u32) -> Self {
+        self.usage = Some(Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            completion_tokens_details: None,
+            prompt_tokens_details: None,
+        });
+        self
+    }
+
+    /// Answers the next request with `next`, the tool results of a reply being sent in a new
+    /// request for instance.
+    pub fn then(mut self, next: MockProvider) -> Self {
+        self.then.push(next);
+        self
+    }
+
+    /// The histories the provider and its clones were sent, oldest first.
+    pub fn requests(&self) -> Vec<Vec<ChatMessage>> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Records `messages` and returns what answers them.
+    fn answer(&self, messages: &[ChatMessage]) -> &MockProvider {
+        let mut requests = self.requests.lock().unwrap();
+        requests.push(messages.to_vec());
+        match requests.len() - 1 {
+            0 => self,
+            n => self.then.get(n - 1).or(self.then.last()).unwrap_or(self),
+        }
+    }
+
+    fn chunks(&self) -> Chunks {
+        let sent = self.stall_after.unwrap_or(self.tokens.len());
+        let mut chunks: Vec<StreamResponse> = self
+            .tokens
+            .iter()
+            .take(sent)
+            .map(|token| Self::chunk(Some(token.clone()), None))
+            .collect();
+        if self.stall_after.is_none() && !self.tool_calls.is_empty() {
+            chunks.push(Self::chunk(None, Some(self.tool_calls.clone())));
+        }
+        if self.stall_after.is_none()
+            && let Some(usage) = &self.usage
+        {
+            chunks.push(StreamResponse {
+                choices: vec![],
+                usage: Some(usage.clone()),
+            });
+        }
+        let delay = self.delay;
+        let chunks = stream::iter(chunks).then(move |chunk| async move {
+            tokio::time::sleep(delay).await;
+            Ok(chunk)
+        });
+        match self.stall_after {
+            Some(_) => Box::pin(chunks.chain(stream::pending())),
+            None => Box::pin(chunks),
+        }
+    }
+
+    fn chunk(content: Option<String>, tool_calls: Option<Vec<ToolCall>>) -> StreamResponse {
+        StreamResponse {
+            choices: vec![StreamChoice {
+                delta: StreamDelta {
+                    content,
+                    tool_calls,
+                },
+            }],
+            usage: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MockResponse {
+    text: String,
+    tool_calls: Vec<ToolCall>,
+    usage: Option<Usage>,
+}
+
+impl ChatResponse for MockResponse {
+    fn text(&self) -> Option<String> {
+        Some(self.text.clone())
+    }
+
+    fn tool_calls(&self) -> Option<Vec<ToolCall>> {
+        Some(self.tool_calls.clone()).filter(|calls| !calls.is_empty())
+    }
+
+    fn usage(&self) -> Option<Usage> {
+        self.usage.clone()
+    }
+}
+
+impl std::fmt::Display for MockResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn unsupported<T>() -> Result<T, LLMError> {
+    Err(LLMError::Generic(
+        "MockProvider only answers chats".to_string(),
+    ))
+}
+
+#[async_trait]
+impl ChatProvider for MockProvider {
+    async fn chat_with_tools(
+        &self,
+        messages: &[ChatMessage],
+        _tools: Option<&[Tool]>,
+    ) -> Result<Box<dyn ChatResponse>, LLMError> {
+        let answer = self.answer(messages);
+        tokio::time::sleep(answer.delay * answer.tokens.len() as u32).await;
+        Ok(Box::new(MockResponse {
+            text: answer.tokens.concat(),
+            tool_calls: answer.tool_calls.clone(),
+            usage: answer.usage.clone(),
+        }))
+    }
+
+    async fn chat_stream_struct(&self, messages: &[ChatMessage]) -> Result<Chunks, LLMError> {
+        Ok(self.answer(messages).chunks())
+    }
+}
+
+#[async_trait]
+impl CompletionProvider for MockProvider {
+    async fn complete(&self, _req: &CompletionRequest) -> Result<CompletionResponse, LLMError> {
+        unsupported()
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for MockProvider {
+    async fn embed(&self, _input: Vec<String>) -> Result<Vec<Vec<f32>>, LLMError> {
+        unsupported()
+    }
+}
+
+#[async_trait]
+impl SpeechToTextProvider for MockProvider {
+    async fn transcribe(&self, _audio: Vec<u8>) -> Result<String, LLMError> {
+        unsupported()
+    }
+}
+
+#[async_trait]
+impl TextToSpeechProvider for MockProvider {}
+
+#[async_trait]
+impl ModelsProvider for MockProvider {}
+
+impl LLMProvider for MockProvider {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    async fn streamed(provider: &MockProvider) -> Vec<StreamResponse> {
+        let stream = provider.chat_stream_struct(&[]).await.unwrap();
+        stream.map(Result::unwrap).collect().await
+    }
+
+    fn content(chunk: &StreamResponse) -> Option<&str> {
+        chunk.choices.first()?.delta.content.as_deref()
+    }
+
+    #[tokio::test]
+    async fn streams_the_tokens_then_the_tool_calls_and_usage() {
+        let call = ToolCall {
+            id: "call".to_string(),
+            call_type: "function".to_string(),
+            function: llm::FunctionCall {
+                name: "roll".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        let provider = MockProvider::new(&["Hel", "lo"])
+            .tool_calls(vec![call])
+            .usage(3, 2);
+        let chunks = streamed(&provider).await;
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(content(&chunks[0]), Some("Hel"));
+        assert_eq!(content(&chunks[1]), Some("lo"));
+        let calls = chunks[2].choices[0].delta.tool_calls.as_ref().unwrap();
+        assert_eq!(calls[0].function.name, "roll");
+        assert_eq!(chunks[3].usage.as_ref().unwrap().total_tokens, 5);
+
+        let response = provider.chat(&[]).await.unwrap();
+        assert_eq!(response.text().as_deref(), Some("Hello"));
+        assert_eq!(response.tool_calls().unwrap().len(), 1);
+        assert_eq!(response.usage().unwrap().prompt_tokens, 3);
+    }
+
+    #[tokio::test]
+    async fn waits_before_each_token() {
+        let provider = MockProvider::new(&["a", "b", "c"]).delay(Duration::from_millis(20));
+        let start = Instant::now();
+        assert_eq!(streamed(&provider).await.len(), 3);
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[tokio::test]
+    async fn stalled_streams_never_end() {
+        let provider = MockProvider::new(&["a", "b", "c"]).stall_after(1);
+        let mut stream = provider.chat_stream_struct(&[]).await.unwrap();
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(content(&first), Some("a"));
+        let next = tokio::time::timeout(Duration::from_millis(50), stream.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_the_following_requests_in_turn() {
+        let provider = MockProvider::new(&["a"])
+            .then(MockProvider::new(&["b"]))
+            .then(MockProvider::new(&["c"]));
+        let clone = provider.clone();
+        let mut answers = vec![];
+        for _ in 0..4 {
+            let chunks = streamed(&clone).await;
+            answers.push(content(&chunks[0]).unwrap().to_string());
+        }
+        assert_eq!(answers, ["a", "b", "c", "c"]);
+        assert_eq!(provider.requests().len(), 4);
+    }
+}
@@ -0,0 +1,32 @@
+use std::fmt::Debug;
+
+use crate::message::PromptMessage;
+
+/// Framing tokens chat templates add around each message.
+pub const MESSAGE_OVERHEAD: usize = 4;
+
+/// Counts the tokens a text takes in the model context.
+pub trait TokenEstimator: Debug + Send + Sync {
+    fn estimate(&self, text: &str) -> usize;
+
+    /// Tokens taken by a whole prompt, including the per-message framing of chat templates.
+    fn estimate_prompt(&self, system: Option<&str>, messages: &[PromptMessage]) -> usize {
+        let system = system.map_or(0, |s| self.estimate(s) + MESSAGE_OVERHEAD);
+        let messages: usize = messages
+            .iter()
+            .map(|m| self.estimate(&m.content) + MESSAGE_OVERHEAD)
+            .sum();
+        system + messages
+    }
+}
+
+/// About four characters per token for English text, rounded up so the estimate errs on the
+/// large side.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicEstimator;
+
+impl TokenEstimator for HeuristicEstimator {
+    fn estimate(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
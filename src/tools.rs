@@ -0,0 +1,134 @@
+use std::fmt;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use llm::builder::FunctionBuilder;
+use serde_json::Value;
+
+#[async_trait]
+pub trait ToolHandler: Send + Sync {
+    /// Runs the tool with the arguments the model produced, errors are shown to the model.
+    async fn call(&self, arguments: Value) -> Result<Value>;
+}
+
+#[async_trait]
+impl<F, Fut> ToolHandler for F
+where
+    F: Fn(Value) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Value>> + Send,
+{
+    async fn call(&self, arguments: Value) -> Result<Value> {
+        self(arguments).await
+    }
+}
+
+/// A function the model can call while generating.
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments object.
+    pub json_schema: Value,
+    pub handler: Box<dyn ToolHandler>,
+}
+
+impl fmt::Debug for ToolSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ToolSpec")
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .field("json_schema", &self.json_schema)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ToolSpec {
+    /// `handler` can be an async closure taking and returning JSON.
+    pub fn new(
+        name: &str,
+        description: &str,
+        json_schema: Value,
+        handler: impl ToolHandler + 'static,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            json_schema,
+            handler: Box::new(handler),
+        }
+    }
+
+    pub(crate) fn to_function(&self) -> FunctionBuilder {
+        FunctionBuilder::new(&self.name)
+            .description(&self.description)
+            .json_schema(self.json_schema.clone())
+    }
+
+    /// Runs the handler, the result is what the model sees, including errors.
+    pub(crate) async fn run(&self, arguments: &str) -> (String, bool) {
+        let arguments = match arguments.trim() {
+            "" => Ok(Value::Object(Default::default())),
+            arguments => serde_json::from_str(arguments),
+        };
+        let result = match arguments {
+            Ok(arguments) => self.handler.call(arguments).await,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(Value::String(text)) => (text, false),
+            Ok(value) => (value.to_string(), false),
+            Err(e) => (format!("Error: {e}"), true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use serde_json::json;
+
+    use super::*;
+
+    fn echo() -> ToolSpec {
+        ToolSpec::new(
+            "echo",
+            "Returns its arguments",
+            json!({ "type": "object" }),
+            |args: Value| async move {
+                match args.get("text") {
+                    Some(text) => Ok(text.clone()),
+                    None => Ok(args),
+                }
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn strings_are_returned_as_is_and_values_as_json() {
+        let tool = echo();
+        assert_eq!(
+            tool.run(r#"{"text": "hi"}"#).await,
+            ("hi".to_string(), false)
+        );
+        assert_eq!(
+            tool.run(r#"{"n": 1}"#).await,
+            (r#"{"n":1}"#.to_string(), false)
+        );
+        // Calls without arguments get an empty object
+        assert_eq!(tool.run(" ").await, ("{}".to_string(), false));
+    }
+
+    #[tokio::test]
+    async fn errors_are_results_for_the_model() {
+        let tool = ToolSpec::new("fail", "", json!({}), |_| async {
+            Err(anyhow!("out of range"))
+        });
+        assert_eq!(
+            tool.run("{}").await,
+            ("Error: out of range".to_string(), true)
+        );
+
+        let (content, is_error) = echo().run("{not json").await;
+        assert!(content.starts_with("Error: "));
+        assert!(is_error);
+    }
+}